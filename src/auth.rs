@@ -0,0 +1,410 @@
+use std::env;
+use std::io::{self, Write};
+use std::process::{Command, Output, Stdio};
+use crate::api::*;
+use crate::cli::*;
+use crate::exec::*;
+use crate::theme::*;
+
+// Keychain entries are filed under this service, one account per key
+// variable, so the OpenRouter and Anthropic keys are kept apart
+pub(crate) const KEYCHAIN_SERVICE: &str = "ask";
+
+// `security` exits with this when there is no matching item
+const SECURITY_ITEM_NOT_FOUND: i32 = 44;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AuthAction {
+    Login,
+    Status,
+    Logout,
+}
+
+impl AuthAction {
+    pub(crate) fn from_str(word: &str) -> Option<Self> {
+        match word {
+            "login" => Some(Self::Login),
+            "status" => Some(Self::Status),
+            "logout" => Some(Self::Logout),
+            _ => None,
+        }
+    }
+}
+
+/// Somewhere API keys are kept, each under the name of the environment
+/// variable it stands in for.
+pub(crate) trait KeyStore {
+    /// What messages call the store.
+    fn name(&self) -> &'static str;
+    fn get(&self, account: &str) -> Result<Option<String>, String>;
+    fn set(&self, account: &str, key: &str) -> Result<(), String>;
+    /// Whether there was a key to remove.
+    fn delete(&self, account: &str) -> Result<bool, String>;
+}
+
+/// The macOS Keychain, through the `security` CLI.
+pub(crate) struct MacKeychain;
+
+impl KeyStore for MacKeychain {
+    fn name(&self) -> &'static str {
+        "macOS Keychain"
+    }
+
+    fn get(&self, account: &str) -> Result<Option<String>, String> {
+        let output =
+            run_keychain_tool("security", &["find-generic-password", "-s", KEYCHAIN_SERVICE, "-a", account, "-w"], None)?;
+        match output.status.code() {
+            Some(0) => Ok(Some(String::from_utf8_lossy(&output.stdout).trim_end().to_string())),
+            Some(SECURITY_ITEM_NOT_FOUND) => Ok(None),
+            _ => Err(tool_failure("security", &output)),
+        }
+    }
+
+    fn set(&self, account: &str, key: &str) -> Result<(), String> {
+        // `security -i` reads the command from stdin, which keeps the key
+        // off its command line, where `ps` would show it
+        let args = ["add-generic-password", "-U", "-s", KEYCHAIN_SERVICE, "-a", account, "-l", "ask API key", "-w", key];
+        let output = run_keychain_tool("security", &["-i"], Some(&security_command_line(&args)))?;
+        // Interactive mode reports a failed command on stderr, not in its status
+        if output.status.success() && output.stderr.iter().all(u8::is_ascii_whitespace) {
+            Ok(())
+        } else {
+            Err(tool_failure("security", &output))
+        }
+    }
+
+    fn delete(&self, account: &str) -> Result<bool, String> {
+        let output =
+            run_keychain_tool("security", &["delete-generic-password", "-s", KEYCHAIN_SERVICE, "-a", account], None)?;
+        match output.status.code() {
+            Some(0) => Ok(true),
+            Some(SECURITY_ITEM_NOT_FOUND) => Ok(false),
+            _ => Err(tool_failure("security", &output)),
+        }
+    }
+}
+
+/// The Secret Service (GNOME Keyring, KWallet), through libsecret's
+/// `secret-tool`.
+pub(crate) struct SecretTool;
+
+impl KeyStore for SecretTool {
+    fn name(&self) -> &'static str {
+        "Secret Service"
+    }
+
+    fn get(&self, account: &str) -> Result<Option<String>, String> {
+        let output =
+            run_keychain_tool("secret-tool", &["lookup", "service", KEYCHAIN_SERVICE, "account", account], None)?;
+        let key = String::from_utf8_lossy(&output.stdout).trim_end().to_string();
+        if output.status.success() {
+            Ok(Some(key).filter(|key| !key.is_empty()))
+        } else if output.stderr.is_empty() {
+            // A lookup that finds nothing fails without saying anything
+            Ok(None)
+        } else {
+            Err(tool_failure("secret-tool", &output))
+        }
+    }
+
+    fn set(&self, account: &str, key: &str) -> Result<(), String> {
+        let label = format!("--label=ask API key ({account})");
+        let args = ["store", label.as_str(), "service", KEYCHAIN_SERVICE, "account", account];
+        let output = run_keychain_tool("secret-tool", &args, Some(key))?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(tool_failure("secret-tool", &output))
+        }
+    }
+
+    fn delete(&self, account: &str) -> Result<bool, String> {
+        // `clear` succeeds whether or not there was anything to clear
+        if self.get(account)?.is_none() {
+            return Ok(false);
+        }
+        let output =
+            run_keychain_tool("secret-tool", &["clear", "service", KEYCHAIN_SERVICE, "account", account], None)?;
+        if output.status.success() {
+            Ok(true)
+        } else {
+            Err(tool_failure("secret-tool", &output))
+        }
+    }
+}
+
+/// A line for `security -i`: each word double-quoted, with quotes and
+/// backslashes escaped.
+pub(crate) fn security_command_line(args: &[&str]) -> String {
+    let quoted: Vec<String> =
+        args.iter().map(|arg| format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))).collect();
+    format!("{}\n", quoted.join(" "))
+}
+
+/// The keychain this platform has.
+pub(crate) fn system_keychain() -> Box<dyn KeyStore> {
+    if cfg!(target_os = "macos") {
+        Box::new(MacKeychain)
+    } else {
+        Box::new(SecretTool)
+    }
+}
+
+/// Runs a keychain tool with `input` on its stdin; a tool that isn't
+/// installed says so.
+fn run_keychain_tool(program: &str, args: &[&str], input: Option<&str>) -> Result<Output, String> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => format!("{program} is not installed"),
+            _ => format!("{program}: {err}"),
+        })?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        let _ = stdin.write_all(input.as_bytes());
+    }
+    child.wait_with_output().map_err(|err| format!("{program}: {err}"))
+}
+
+fn tool_failure(program: &str, output: &Output) -> String {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    match stderr.lines().map(str::trim).find(|line| !line.is_empty()) {
+        Some(line) => format!("{program}: {line}"),
+        None => format!("{program} failed ({})", output.status),
+    }
+}
+
+/// The API key held in `var`: the environment variable wins, then the
+/// keychain. A keychain that can't be read counts as empty.
+pub(crate) fn resolve_api_key(
+    var: &str,
+    env: impl Fn(&str) -> Option<String>,
+    store: &dyn KeyStore,
+) -> Option<String> {
+    if let Some(key) = env(var).filter(|key| !key.trim().is_empty()) {
+        return Some(key);
+    }
+    match store.get(var) {
+        Ok(key) => key.filter(|key| !key.trim().is_empty()),
+        Err(err) => {
+            debug_log(1, &format!("keychain: {err}"));
+            None
+        }
+    }
+}
+
+/// Why there is nothing to send, and the two ways to fix it.
+pub(crate) fn missing_key_message(var: &str) -> String {
+    format!("No API key: set the {var} environment variable, or run `ask auth login` to keep it in the system keychain.")
+}
+
+/// `ask auth login|status|logout`, for the key the current provider uses.
+pub(crate) fn auth_command(action: AuthAction, provider: Provider, theme: &Theme) -> Result<(), Box<dyn std::error::Error>> {
+    let var = provider.key_var();
+    let store = system_keychain();
+    let env_key = |name: &str| env::var(name).ok();
+    match action {
+        AuthAction::Login => {
+            let key = if stdin_is_piped() {
+                // `pass show openrouter | ask auth login`
+                let mut line = String::new();
+                io::stdin().read_line(&mut line)?;
+                line
+            } else {
+                let mut out = prompt_writer();
+                write!(out, "{} ", theme.prompt_text(&format!("{var} (hidden):")))?;
+                out.flush()?;
+                read_hidden_line().map_err(|err| match err.kind() {
+                    io::ErrorKind::Interrupted => io::Error::other("Cancelled with Ctrl-C; nothing was stored."),
+                    _ => err,
+                })?
+            };
+            println!("{}", auth_login(var, &key, store.as_ref())?);
+            if env_key(var).is_some_and(|key| !key.trim().is_empty()) {
+                let note = format!("{var} is also set in the environment and takes precedence; unset it to use the keychain.");
+                eprintln!("{}", theme.warning_text(&note));
+            }
+        }
+        AuthAction::Status => {
+            for line in auth_status(var, env_key, store.as_ref()) {
+                println!("{line}");
+            }
+        }
+        AuthAction::Logout => println!("{}", auth_logout(var, store.as_ref())?),
+    }
+    Ok(())
+}
+
+/// Stores the key typed at `ask auth login`.
+pub(crate) fn auth_login(var: &str, input: &str, store: &dyn KeyStore) -> Result<String, String> {
+    let key = input.trim();
+    if key.is_empty() {
+        return Err("No key entered; nothing was stored.".to_string());
+    }
+    if key.chars().any(char::is_whitespace) {
+        return Err("API keys have no spaces in them; nothing was stored.".to_string());
+    }
+    store
+        .set(var, key)
+        .map_err(|err| format!("Couldn't store the key in the {}: {err}", store.name()))?;
+    Ok(format!("Stored {var} ({}) in the {}.", masked_key(key), store.name()))
+}
+
+/// What `ask auth status` shows: where the key is found, and which one
+/// requests use.
+pub(crate) fn auth_status(var: &str, env: impl Fn(&str) -> Option<String>, store: &dyn KeyStore) -> Vec<String> {
+    let env_key = env(var).filter(|key| !key.trim().is_empty());
+    let mut lines = vec![format!("key: {var}")];
+    lines.push(match &env_key {
+        Some(key) => format!("environment: set ({}), in use", masked_key(key)),
+        None => "environment: not set".to_string(),
+    });
+    let stored = store.get(var);
+    lines.push(match &stored {
+        Ok(Some(key)) if env_key.is_some() => {
+            format!("{}: stored ({}), overridden by the environment", store.name(), masked_key(key))
+        }
+        Ok(Some(key)) => format!("{}: stored ({}), in use", store.name(), masked_key(key)),
+        Ok(None) => format!("{}: not stored", store.name()),
+        Err(err) => format!("{}: unavailable ({err})", store.name()),
+    });
+    if env_key.is_none() && !matches!(stored, Ok(Some(_))) {
+        lines.push(missing_key_message(var));
+    }
+    lines
+}
+
+/// Removes the key `ask auth login` stored.
+pub(crate) fn auth_logout(var: &str, store: &dyn KeyStore) -> Result<String, String> {
+    match store.delete(var) {
+        Ok(true) => Ok(format!("Removed {var} from the {}.", store.name())),
+        Ok(false) => Ok(format!("No {var} was stored in the {}.", store.name())),
+        Err(err) => Err(format!("Couldn't remove the key from the {}: {err}", store.name())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    const VAR: &str = "OPENROUTER_ASK_API_KEY";
+    const KEY: &str = "sk-or-v1-0123456789abcdef";
+
+    /// A keychain kept in memory, for tests.
+    #[derive(Default)]
+    struct MemoryKeyStore {
+        keys: RefCell<HashMap<String, String>>,
+        broken: bool,
+    }
+
+    impl KeyStore for MemoryKeyStore {
+        fn name(&self) -> &'static str {
+            "test keychain"
+        }
+
+        fn get(&self, account: &str) -> Result<Option<String>, String> {
+            if self.broken {
+                Err("secret-tool is not installed".to_string())
+            } else {
+                Ok(self.keys.borrow().get(account).cloned())
+            }
+        }
+
+        fn set(&self, account: &str, key: &str) -> Result<(), String> {
+            self.get(account)?;
+            self.keys.borrow_mut().insert(account.to_string(), key.to_string());
+            Ok(())
+        }
+
+        fn delete(&self, account: &str) -> Result<bool, String> {
+            self.get(account)?;
+            Ok(self.keys.borrow_mut().remove(account).is_some())
+        }
+    }
+
+    fn stored(key: &str) -> MemoryKeyStore {
+        let store = MemoryKeyStore::default();
+        store.keys.borrow_mut().insert(VAR.to_string(), key.to_string());
+        store
+    }
+
+    fn no_env(_: &str) -> Option<String> {
+        None
+    }
+
+    #[test]
+    fn the_environment_wins_over_the_keychain_and_blank_values_fall_through() {
+        let store = stored("sk-from-keychain");
+        let env = |name: &str| (name == VAR).then(|| "sk-from-env".to_string());
+        assert_eq!(resolve_api_key(VAR, env, &store).as_deref(), Some("sk-from-env"));
+        assert_eq!(resolve_api_key(VAR, |_| Some("  ".to_string()), &store).as_deref(), Some("sk-from-keychain"));
+        assert_eq!(resolve_api_key("ANTHROPIC_API_KEY", no_env, &store), None);
+        let broken = MemoryKeyStore { broken: true, ..Default::default() };
+        assert_eq!(resolve_api_key(VAR, no_env, &broken), None);
+        let message = missing_key_message(VAR);
+        assert!(message.contains(VAR) && message.contains("ask auth login"), "{message}");
+    }
+
+    #[test]
+    fn login_stores_the_trimmed_key_and_refuses_blank_or_spaced_input() {
+        let store = MemoryKeyStore::default();
+        let message = auth_login(VAR, &format!("  {KEY}\n"), &store).unwrap();
+        assert_eq!(store.keys.borrow().get(VAR).map(String::as_str), Some(KEY));
+        assert!(message.contains("****cdef") && !message.contains(KEY), "{message}");
+        assert!(auth_login(VAR, "\n", &store).unwrap_err().contains("nothing was stored"));
+        assert!(auth_login(VAR, "sk-or one", &store).is_err());
+        assert_eq!(store.keys.borrow().get(VAR).map(String::as_str), Some(KEY));
+        let broken = MemoryKeyStore { broken: true, ..Default::default() };
+        assert!(auth_login(VAR, KEY, &broken).unwrap_err().contains("not installed"));
+    }
+
+    #[test]
+    fn status_says_which_key_is_used_without_showing_it() {
+        let store = stored(KEY);
+        let lines = auth_status(VAR, no_env, &store);
+        assert_eq!(lines[1], "environment: not set");
+        assert_eq!(lines[2], "test keychain: stored (****cdef), in use");
+        let lines = auth_status(VAR, |_| Some("sk-env-key-ffff0000".to_string()), &store);
+        assert_eq!(lines[1], "environment: set (****0000), in use");
+        assert!(lines[2].ends_with("overridden by the environment"), "{}", lines[2]);
+        let lines = auth_status(VAR, no_env, &MemoryKeyStore { broken: true, ..Default::default() });
+        assert!(lines[2].contains("unavailable (secret-tool is not installed)"), "{}", lines[2]);
+        assert!(lines.last().unwrap().contains("ask auth login"));
+    }
+
+    #[test]
+    fn logout_removes_the_key_and_says_when_there_was_none() {
+        let store = stored(KEY);
+        assert_eq!(auth_logout(VAR, &store).unwrap(), format!("Removed {VAR} from the test keychain."));
+        assert!(store.keys.borrow().is_empty());
+        assert!(auth_logout(VAR, &store).unwrap().starts_with("No OPENROUTER_ASK_API_KEY was stored"));
+    }
+
+    #[test]
+    fn the_key_goes_to_security_on_stdin_quoted() {
+        assert_eq!(
+            security_command_line(&["add-generic-password", "-l", "ask API key", "-w", r#"sk-"q\x"#]),
+            "\"add-generic-password\" \"-l\" \"ask API key\" \"-w\" \"sk-\\\"q\\\\x\"\n"
+        );
+    }
+
+    #[test]
+    fn auth_parses_only_its_three_actions() {
+        assert_eq!(AuthAction::from_str("login"), Some(AuthAction::Login));
+        assert_eq!(AuthAction::from_str("status"), Some(AuthAction::Status));
+        assert_eq!(AuthAction::from_str("logout"), Some(AuthAction::Logout));
+        assert_eq!(AuthAction::from_str("token"), None);
+        let words = |line: &str| line.split_whitespace().map(str::to_string).collect::<Vec<_>>();
+        assert!(matches!(
+            parse_subcommand(&words("auth logout")).unwrap(),
+            Some(Subcommand::Auth { action: AuthAction::Logout })
+        ));
+        assert!(parse_subcommand(&words("auth")).unwrap().is_none());
+        assert!(parse_subcommand(&words("auth users against ldap")).unwrap().is_none());
+    }
+}
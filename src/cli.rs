@@ -0,0 +1,1215 @@
+use serde_json::json;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::process::exit;
+use crate::api::*;
+use crate::auth::*;
+use crate::commit::*;
+use crate::completions::*;
+use crate::config::*;
+use crate::exec::*;
+use crate::history::*;
+use crate::repl::*;
+use crate::safety::*;
+use crate::theme::*;
+
+/// Prints a fatal error the way --output asked for and returns the exit code
+/// the process should end with.
+pub fn report_error(err: &(dyn std::error::Error + 'static)) -> i32 {
+    if JSON_OUTPUT.load(std::sync::atomic::Ordering::Relaxed) {
+        let error = json!({ "schema_version": SCHEMA_VERSION, "error": { "code": error_code(err), "message": err.to_string() } });
+        eprintln!("{error}");
+    } else {
+        eprintln!("Error: {err}");
+    }
+    if err.is::<NeedsConfirmation>() {
+        EXIT_NEEDS_CONFIRMATION
+    } else if err.is::<AutoLimitReached>() {
+        EXIT_AUTO_LIMIT
+    } else if err.is::<OverBudget>() {
+        EXIT_OVER_BUDGET
+    } else if let Some(failed) = err.downcast_ref::<TurnFailed>() {
+        // The same code the failed command exited with
+        shell_exit_code(failed.failure.status)
+    } else {
+        1
+    }
+}
+
+// Set by --output json: errors go to stderr as JSON as well
+pub(crate) static JSON_OUTPUT: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// The machine-readable name of an error in `--output json` mode.
+pub(crate) fn error_code(err: &(dyn std::error::Error + 'static)) -> &'static str {
+    if err.is::<NeedsConfirmation>() {
+        "needs_confirmation"
+    } else if err.is::<AutoLimitReached>() {
+        "auto_limit_reached"
+    } else if err.is::<TurnFailed>() || err.is::<CommandFailed>() {
+        "command_failed"
+    } else if err.is::<ApiFailure>() {
+        "api_error"
+    } else if err.is::<HungUp>() {
+        "hung_up"
+    } else {
+        "error"
+    }
+}
+
+/// Points stdout at stderr and hands back the real stdout, so prompts,
+/// progress and command output stay off the JSON a caller is reading.
+pub(crate) fn take_stdout() -> io::Result<fs::File> {
+    use std::os::unix::io::FromRawFd;
+    io::stdout().flush()?;
+    let saved = unsafe { libc::dup(1) };
+    if saved < 0 || unsafe { libc::dup2(2, 1) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { fs::File::from_raw_fd(saved) })
+}
+
+/// Returns true when stdin is connected to a pipe (not a terminal).
+pub(crate) fn stdin_is_piped() -> bool {
+    unsafe { libc_isatty(io::stdin().as_raw_fd()) == 0 }
+}
+
+// Minimal FFI – avoids pulling in the libc crate just for isatty.
+unsafe extern "C" {
+    #[link_name = "isatty"]
+    pub(crate) fn libc_isatty(fd: i32) -> i32;
+}
+
+/// Reads the input data from stdin when it is piped or `-` asked for it
+/// (even from a terminal, until Ctrl-D). `None` when there is nothing to read.
+pub(crate) fn read_piped_stdin(requested: bool) -> Option<String> {
+    if !requested && !stdin_is_piped() {
+        return None;
+    }
+    let text = read_capped(io::stdin().lock())?;
+    if text.len() > MAX_PIPE_BYTES {
+        let kb = MAX_PIPE_BYTES / 1024;
+        eprintln!("Note: the input is over {kb} KB; only the first {kb} KB is sent.");
+    }
+    Some(text)
+}
+
+/// Reads up to one byte past `MAX_PIPE_BYTES`, so build_messages can tell
+/// the input was cut short without waiting for an endless pipe to finish.
+pub(crate) fn read_capped(reader: impl Read) -> Option<String> {
+    let mut buf = Vec::with_capacity(8192);
+    let _ = reader.take(MAX_PIPE_BYTES as u64 + 1).read_to_end(&mut buf);
+    (!buf.is_empty()).then(|| String::from_utf8_lossy(&buf).to_string())
+}
+
+/// The request settings `settings` give, sent to the endpoint and written
+/// for the platform chosen at startup.
+pub(crate) fn request_settings(settings: &Settings, endpoint: Endpoint, platform: Platform) -> RequestSettings {
+    let mut request = RequestSettings {
+        model: settings.model(),
+        endpoint,
+        platform,
+        redaction: settings.redaction(),
+        temperature: settings.temperature(),
+        top_p: settings.top_p(),
+        max_tokens: settings.max_tokens(),
+        max_attempts: settings.retry_attempts(),
+        refusal_patterns: settings.refusal_patterns(),
+        fix_attempts: settings.fix_attempts(),
+        context: settings.context(),
+        extra_prompt: settings.extra_prompt(),
+        custom_prompt: settings.custom_prompt().ok().flatten(),
+        fallback_models: settings.fallback_models(),
+        context_window: None,
+        auto_context: settings.auto_context(),
+        structured: settings.structured(),
+        tools: settings.tools(),
+    };
+    request.context_window = context_window(&request);
+    request
+}
+
+/// How commands get approved under `settings`. `auto_yes` is --yes and
+/// `print_only` is --print; a root session is hardened, which fails when
+/// --yes is asked for without root_ok.
+pub(crate) fn safety_policy(settings: &Settings, auto_yes: bool, print_only: bool) -> Result<SafetyPolicy, String> {
+    let mut policy = SafetyPolicy::new(auto_yes, &settings.dangerous_patterns(), settings.max_auto_commands());
+    policy.audit_log = settings.audit_log();
+    policy.print_only = print_only;
+    policy.auto_safe = settings.auto_run();
+    if running_as_root() {
+        policy.harden_for_root(settings.root_ok())?;
+    }
+    Ok(policy)
+}
+
+/// Project file lines that were left out, and with --verbose the config
+/// files that were read.
+pub(crate) fn report_config_files(settings: &Settings, theme: &Theme) {
+    for warning in &settings.warnings {
+        eprintln!("{}", theme.warning_text(warning));
+    }
+    if verbosity() == 0 {
+        return;
+    }
+    let global = global_config_file();
+    let files = global.is_file().then_some(global).into_iter().chain(settings.project.clone());
+    for file in files {
+        eprintln!("{}", theme.helper_text(&format!("config: {}", file.display())));
+    }
+    let limit = match settings.max_output_bytes() {
+        0 => "no limit".to_string(),
+        bytes => format!("{bytes} bytes per stream"),
+    };
+    eprintln!("{}", theme.helper_text(&format!("captured command output: {limit} (max_output_bytes)")));
+}
+
+pub fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let args = parse_args()?;
+    // Evaluated by every new shell, so nothing else may print
+    if let Some(Subcommand::Init { script }) = args.subcommand {
+        print!("{script}");
+        return Ok(());
+    }
+    match &args.subcommand {
+        Some(Subcommand::Completions { script }) => {
+            print!("{script}");
+            return Ok(());
+        }
+        // Run by the completion scripts on every Tab after --model
+        Some(Subcommand::CachedModels) => {
+            for id in cached_model_ids() {
+                println!("{id}");
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+    let no_color = env::var("NO_COLOR").ok();
+    let force_color = env::var("ASK_FORCE_COLOR").ok();
+    let flags = ColorFlags {
+        no_color: args.no_color,
+        force_color: args.force_color,
+        machine_output: args.json || args.output_json || args.print || args.stdin_commands,
+    };
+    let color_env = ColorEnv { no_color: no_color.as_deref(), force_color: force_color.as_deref() };
+    let stdout_is_tty = unsafe { libc_isatty(io::stdout().as_raw_fd()) } == 1;
+    let colors = color_enabled(flags, color_env, stdout_is_tty);
+    let theme = if colors {
+        // With no theme configured or passed, the terminal's background decides
+        let mode = match args.settings.get("theme") {
+            Some(setting) if setting.origin != Origin::Default => args.settings.theme(),
+            _ if stdout_is_tty => detect_theme_mode(),
+            _ => ThemeMode::Dark,
+        };
+        Theme::from_mode(mode).with_colors(&args.settings.theme_colors())
+    } else {
+        Theme::plain()
+    };
+    if let Some(warning) = resolved_data_dir().warning() {
+        eprintln!("{}", theme.warning_text(&warning));
+    }
+    ENV_BOOTSTRAP.store(args.settings.env_bootstrap(), std::sync::atomic::Ordering::Relaxed);
+    MAX_OUTPUT_BYTES.store(args.settings.max_output_bytes(), std::sync::atomic::Ordering::Relaxed);
+    let _ = TOKEN_COUNTER.set(args.settings.token_counter());
+    let _ = HANGUP_MODE.set(args.settings.hangup_mode());
+    let _ = TTY_COMMANDS.set(args.settings.all("tty_command"));
+    VERBOSE.store(args.verbose, std::sync::atomic::Ordering::Relaxed);
+    let _ = DEBUG_THEME.set(theme.clone());
+    SHOW_USAGE.store(args.show_usage, std::sync::atomic::Ordering::Relaxed);
+    report_config_files(&args.settings, &theme);
+    if running_as_root() {
+        eprintln!("{}", theme.warning_text("Running as root: every command runs with full privileges."));
+    }
+    // Commands piped in by another tool are still confirmed one by one.
+    let mut policy = safety_policy(&args.settings, args.yes && !args.stdin_commands, args.print)?;
+    policy.copy_only = args.copy;
+    if running_as_root() {
+        let root_ok = args.settings.root_ok();
+        if !root_ok {
+            eprintln!(
+                "{}",
+                theme.helper_text("Every command needs confirmation this session (--i-know or root_ok=true relaxes this).")
+            );
+        }
+    }
+    if let Some(path) = &policy.audit_log {
+        if policy.audit_log.as_deref() != args.settings.audit_log().as_deref() {
+            eprintln!("{}", theme.helper_text(&format!("Audit log: {}", path.display())));
+        }
+        let _ = AUDIT_LOG.set(path.clone());
+    }
+
+    if args.read_stdin && args.stdin_commands {
+        return Err("- reads input data; --stdin-commands reads commands. Use one or the other".into());
+    }
+    // Commands from another tool never go to the model, so no API key needed.
+    if args.stdin_commands {
+        let code = run_stdin_commands(&theme, &policy)?;
+        exit(code);
+    }
+
+    // Read piped data BEFORE anything else touches stdin.
+    let piped_data = read_piped_stdin(args.read_stdin);
+    if args.read_stdin && piped_data.is_none() {
+        return Err("- reads the input from stdin, but there was none".into());
+    }
+    if args.print && args.prompt.is_none() && piped_data.is_none() && args.subcommand.is_none() {
+        return Err("--print needs a prompt (e.g. ask -p \"list open ports\")".into());
+    }
+    if args.copy && args.prompt.is_none() && piped_data.is_none() {
+        return Err("--copy needs a prompt (e.g. ask --copy \"list open ports\")".into());
+    }
+    if args.copy && (args.print || args.output_json || args.yes) {
+        return Err("--copy runs nothing, so it can't be combined with --print, --output json or --yes".into());
+    }
+    if args.estimate && args.prompt.is_none() && piped_data.is_none() {
+        return Err("--estimate needs a prompt (e.g. ask --estimate \"list open ports\")".into());
+    }
+    if args.json && !args.estimate {
+        return Err("--json only applies to --estimate".into());
+    }
+    if args.output_json && (args.estimate || args.stdin_commands || args.subcommand.is_some()) {
+        return Err("--output json is for a single prompt (--estimate has --json; --stdin-commands already prints JSON)".into());
+    }
+    if args.output_json && args.prompt.is_none() && piped_data.is_none() {
+        return Err("--output json needs a prompt (e.g. ask --output json \"list open ports\")".into());
+    }
+    if args.save_session.is_some() && args.prompt.is_none() && piped_data.is_none() {
+        return Err("--save-session is for a single prompt; use /save in interactive mode".into());
+    }
+    if args.resume.is_some() && (args.prompt.is_some() || piped_data.is_some()) {
+        return Err("--resume starts an interactive session, so it takes no prompt or piped input".into());
+    }
+    if args.show_context && args.prompt.is_none() && piped_data.is_none() {
+        return Err("--show-context is for a single prompt; use /context in interactive mode".into());
+    }
+    if args.force && args.save_session.is_none() {
+        return Err("--force only applies to --save-session".into());
+    }
+
+    // Settings migration is purely local, so it must not require an API key.
+    let remote = match args.subcommand {
+        Some(Subcommand::ExportSettings { path, include_sessions }) => {
+            return export_settings(&path, include_sessions, &theme);
+        }
+        Some(Subcommand::ImportSettings { path }) => {
+            return import_settings(&path, &theme);
+        }
+        Some(Subcommand::ConfigShow { origins }) => {
+            show_config(&args.settings, origins, &theme);
+            return Ok(());
+        }
+        Some(Subcommand::Doctor) => {
+            doctor(&args.settings, &theme);
+            return Ok(());
+        }
+        Some(Subcommand::Profiles) => {
+            show_profiles(&args.settings, &theme);
+            return Ok(());
+        }
+        Some(Subcommand::Auth { action }) => return auth_command(action, args.settings.provider(), &theme),
+        Some(sub @ (Subcommand::Models { .. } | Subcommand::Commit { .. })) => Some(sub),
+        Some(Subcommand::Init { .. } | Subcommand::Completions { .. } | Subcommand::CachedModels) | None => None,
+    };
+
+    // Local servers, Ollama included, usually need no key; hosted ones do.
+    let api_url = args.settings.api_url();
+    let provider = args.settings.provider();
+    if provider == Provider::Anthropic && args.settings.chosen_provider().is_none() {
+        let model = args.settings.model();
+        eprintln!(
+            "{}",
+            theme.helper_text(&format!(
+                "Sending {model} straight to Anthropic because an ANTHROPIC_API_KEY is set or stored (--provider openai keeps it on OpenRouter)"
+            ))
+        );
+    }
+    let key_var = provider.key_var();
+    let hosted = provider != Provider::Ollama && !api_url_is_local(&api_url);
+    let api_key = if hosted {
+        resolve_api_key(key_var, |name| env::var(name).ok(), system_keychain().as_ref())
+    } else {
+        // A key kept for a hosted API isn't handed to a local server
+        env::var(key_var).ok().filter(|key| !key.trim().is_empty())
+    };
+    let api_key = match api_key {
+        Some(key) => Some(key),
+        None if !hosted => None,
+        // --estimate and `ask models` never call the chat endpoint
+        None if args.estimate || matches!(remote, Some(Subcommand::Models { .. })) => None,
+        None => return Err(missing_key_message(key_var).into()),
+    };
+    // A prompt_file that can't be used stops here rather than being skipped
+    args.settings.custom_prompt()?;
+    let endpoint = Endpoint::for_provider(provider, api_url, api_key);
+    let settings = request_settings(&args.settings, endpoint, Platform::detect(args.os.as_deref()));
+
+    match remote {
+        Some(Subcommand::Models { query }) => return models_command(&query, &settings, &theme),
+        Some(Subcommand::Commit { amend, kind }) => {
+            return commit_command(amend, kind.as_deref(), &settings, &theme, &policy);
+        }
+        _ => {}
+    }
+
+    if args.estimate {
+        let prompt = args.prompt.as_deref().unwrap_or(SUMMARIZE_PROMPT);
+        let prompt = &attach_files(prompt, settings.attachment_budget(), &settings.redaction)?.message;
+        return estimate_cost(prompt, &settings, piped_data.as_deref(), args.json, args.settings.warn_cost());
+    }
+
+    if args.show_context {
+        let prompt = args.prompt.as_deref().unwrap_or(SUMMARIZE_PROMPT);
+        let prompt = &attach_files(prompt, settings.attachment_budget(), &settings.redaction)?.message;
+        eprint!("{}", render_context(&settings, &[], Some(prompt), piped_data.as_deref(), &theme));
+    }
+
+    // Everything else goes to stderr, so stdout is only the JSON result
+    let json_out = if args.output_json { Some(take_stdout()?) } else { None };
+    let result = match args.prompt {
+        Some(prompt) => {
+            // Single prompt mode (with optional piped data)
+            report_usage(process_prompt(&prompt, &settings, &theme, piped_data.as_deref(), &policy), &theme)?
+        }
+        None if piped_data.is_some() => {
+            // Data piped in but no prompt – summarize / analyse by default
+            report_usage(
+                process_prompt(SUMMARIZE_PROMPT, &settings, &theme, piped_data.as_deref(), &policy),
+                &theme,
+            )?
+        }
+        None => {
+            // Interactive mode (no pipe)
+            if args.settings.persistent_shell() {
+                start_persistent_shell(&theme);
+            }
+            let _ = PAGER_MODE.set(args.settings.pager_mode());
+            SHOW_USAGE.store(true, std::sync::atomic::Ordering::Relaxed);
+            let options = InteractiveOptions {
+                paste_guard: args.settings.paste_guard(),
+                session_limits: args.settings.session_limits(),
+                transcript_lines: args.settings.transcript_output_lines(),
+                session: SavedSession::named(args.resume.as_deref().unwrap_or(LAST_SESSION))?,
+                resume: args.resume.is_some(),
+                config: args.settings,
+                colors,
+            };
+            run_interactive_mode(&settings, &theme, options, &policy)?;
+            return Ok(());
+        }
+    };
+    if let Some(mut out) = json_out {
+        writeln!(out, "{}", result.to_json())?;
+    }
+    let code = result.exit_code();
+    let turn = result.into_turn()?;
+
+    if let Some(path) = &args.save_session {
+        let transcript = render_transcript(&[turn], args.settings.transcript_output_lines(), unix_now());
+        write_transcript(path, &transcript, args.force)?;
+        eprintln!("{}", theme.helper_text(&format!("Saved the session to {}", path.display())));
+    }
+    if code != 0 {
+        exit(code);
+    }
+    Ok(())
+}
+
+// Exit codes for --stdin-commands
+pub(crate) const EXIT_COMMAND_FAILED: i32 = 1;
+pub(crate) const EXIT_CANCELLED: i32 = 2;
+// Commands were suggested but nobody was there to confirm them
+pub(crate) const EXIT_NEEDS_CONFIRMATION: i32 = 3;
+// --yes hit max_auto_commands and nobody was there to approve the rest
+pub(crate) const EXIT_AUTO_LIMIT: i32 = 4;
+// --estimate came out above warn_cost
+pub(crate) const EXIT_OVER_BUDGET: i32 = 5;
+// Commands --yes may run in one turn before asking again
+pub(crate) const DEFAULT_MAX_AUTO_COMMANDS: usize = 5;
+// Tries per model request, the first included, when it is rate limited or
+// the connection fails
+pub(crate) const DEFAULT_RETRY_ATTEMPTS: u32 = 4;
+// Repairs --auto-fix asks for before giving up on a failing command
+pub(crate) const DEFAULT_AUTO_FIX_ATTEMPTS: usize = 2;
+
+/// Runs commands produced by another tool (`other-tool | ask --stdin-commands`)
+/// through the usual confirmation. Confirmation reads from /dev/tty because
+/// stdin carries the commands; stdout carries one JSON result per command so
+/// the caller can parse it, while command output is echoed on stderr.
+pub(crate) fn run_stdin_commands(theme: &Theme, policy: &SafetyPolicy) -> Result<i32, Box<dyn std::error::Error>> {
+    if fs::OpenOptions::new().read(true).open("/dev/tty").is_err() {
+        return Err("--stdin-commands needs a terminal (/dev/tty) to confirm commands".into());
+    }
+    let mut input = String::new();
+    io::stdin().read_to_string(&mut input)?;
+
+    let mut summary = StdinSummary::default();
+    let mut cancelled = false;
+    // Set by "all"; dangerous commands are still confirmed one by one
+    let mut approve_rest = false;
+    for command in parse_commands(&input) {
+        if let Some(comment) = command.strip_prefix('#') {
+            eprintln!("{}", theme.helper_text(comment.trim()));
+            continue;
+        }
+        if cancelled {
+            emit_stdin_result(&command, "cancelled", None, &mut summary);
+            continue;
+        }
+
+        let mut to_run = Vec::new();
+        let status = if approve_rest && !policy.is_dangerous(&command) {
+            eprintln!("{} {}", theme.prompt_text("run>"), theme.command_text(&display_command(&command)));
+            to_run.push(command.clone());
+            "approved"
+        } else {
+            loop {
+                match confirm(&command, theme, policy)? {
+                    ConfirmResponse::Yes => {
+                        to_run.push(command.clone());
+                        break "approved";
+                    }
+                    ConfirmResponse::All => {
+                        approve_rest = true;
+                        to_run.push(command.clone());
+                        break "approved";
+                    }
+                    ConfirmResponse::Edit(edited) => {
+                        to_run.push(edited);
+                        break "approved";
+                    }
+                    ConfirmResponse::Skip | ConfirmResponse::Copy => break "skipped",
+                    ConfirmResponse::No => {
+                        cancelled = true;
+                        break "cancelled";
+                    }
+                    ConfirmResponse::Instruct(custom) => {
+                        if !custom.is_empty() {
+                            let result = run_command_to(&custom, true);
+                            emit_stdin_result(&custom, result_status(&result), Some(&result), &mut summary);
+                        }
+                        eprintln!("\nReturning to original command:");
+                    }
+                }
+            }
+        };
+
+        match to_run.pop() {
+            Some(run) => {
+                let result = run_command_to(&run, true);
+                emit_stdin_result(&run, result_status(&result), Some(&result), &mut summary);
+            }
+            None => emit_stdin_result(&command, status, None, &mut summary),
+        }
+    }
+
+    eprintln!(
+        "{}",
+        theme.helper_text(&format!(
+            "Ran {}, failed {}, skipped {}, cancelled {}",
+            summary.ran, summary.failed, summary.skipped, summary.cancelled
+        ))
+    );
+    Ok(if summary.failed > 0 {
+        EXIT_COMMAND_FAILED
+    } else if summary.cancelled > 0 {
+        EXIT_CANCELLED
+    } else {
+        0
+    })
+}
+
+#[derive(Default)]
+pub(crate) struct StdinSummary {
+    pub(crate) ran: usize,
+    pub(crate) failed: usize,
+    pub(crate) skipped: usize,
+    pub(crate) cancelled: usize,
+}
+
+pub(crate) fn result_status(result: &Result<CommandOutput, Box<dyn std::error::Error>>) -> &'static str {
+    match result {
+        Ok(output) if output.status.success() && !output.interrupted => "ok",
+        _ => "failed",
+    }
+}
+
+pub(crate) fn emit_stdin_result(
+    command: &str,
+    status: &str,
+    result: Option<&Result<CommandOutput, Box<dyn std::error::Error>>>,
+    summary: &mut StdinSummary,
+) {
+    match status {
+        "ok" => summary.ran += 1,
+        "failed" => summary.failed += 1,
+        "skipped" => summary.skipped += 1,
+        _ => summary.cancelled += 1,
+    }
+    let mut record = json!({ "schema_version": SCHEMA_VERSION, "command": command, "status": status });
+    match result {
+        Some(Ok(output)) => {
+            match output.failure() {
+                Some(failure) => record["error"] = json!(failure.to_string()),
+                None => record["output"] = json!(output.history_output()),
+            }
+            record["exit_code"] = json!(output.exit_code());
+        }
+        Some(Err(err)) => record["error"] = json!(err.to_string()),
+        None => {}
+    }
+    println!("{}", record);
+}
+
+pub(crate) struct Args {
+    pub(crate) prompt: Option<String>,  // None indicates interactive mode
+    pub(crate) os: Option<String>,
+    pub(crate) settings: Settings,
+    pub(crate) stdin_commands: bool,
+    pub(crate) yes: bool,
+    pub(crate) print: bool,
+    // --copy: put the first suggested command on the clipboard instead
+    pub(crate) copy: bool,
+    // How many times -v was given
+    pub(crate) verbose: u8,
+    // --show-usage: print tokens and cost after a single prompt
+    pub(crate) show_usage: bool,
+    // --show-context: print what the single prompt sends before sending it
+    pub(crate) show_context: bool,
+    pub(crate) no_color: bool,
+    // --force-color: keep colors when stdout is piped (e.g. into less -R)
+    pub(crate) force_color: bool,
+    // --estimate: price the request instead of sending it; --json for CI
+    pub(crate) estimate: bool,
+    pub(crate) json: bool,
+    // --output json: one JSON object on stdout for a single prompt
+    pub(crate) output_json: bool,
+    // --save-session PATH: write the single prompt's transcript; --force
+    // replaces an existing file
+    pub(crate) save_session: Option<PathBuf>,
+    pub(crate) force: bool,
+    // --resume [NAME]: the saved session to start from
+    pub(crate) resume: Option<String>,
+    // `-`: read the input data from stdin even when it is a terminal
+    pub(crate) read_stdin: bool,
+    pub(crate) subcommand: Option<Subcommand>,
+}
+
+pub(crate) enum Subcommand {
+    ExportSettings { path: PathBuf, include_sessions: bool },
+    ImportSettings { path: PathBuf },
+    ConfigShow { origins: bool },
+    Init { script: &'static str },
+    Doctor,
+    Models { query: String },
+    Profiles,
+    Auth { action: AuthAction },
+    Commit { amend: bool, kind: Option<String> },
+    Completions { script: String },
+    CachedModels,
+}
+
+// `eval "$(ask --init zsh)"`: Ctrl-G turns the request typed at the prompt
+// into a command left in the edit buffer; `ask-cmd REQUEST` queues it for
+// the next prompt with `print -z`.
+pub(crate) const ZSH_INIT: &str = r#"# ask shell integration for zsh
+_ask_widget() {
+  [[ -z $BUFFER ]] && return
+  local cmd
+  cmd=$(command ask --print -- "$BUFFER" </dev/tty) || { zle reset-prompt; return }
+  BUFFER=$cmd
+  CURSOR=${#BUFFER}
+  zle reset-prompt
+}
+zle -N _ask_widget
+bindkey '^G' _ask_widget
+
+ask-cmd() {
+  local cmd
+  cmd=$(command ask --print -- "$*") && print -z -- "$cmd"
+}
+"#;
+
+// `eval "$(ask --init bash)"`: Ctrl-G swaps the request typed at the prompt
+// for the command through READLINE_LINE.
+pub(crate) const BASH_INIT: &str = r#"# ask shell integration for bash
+_ask_readline() {
+  [[ -z $READLINE_LINE ]] && return
+  local cmd
+  cmd=$(command ask --print -- "$READLINE_LINE" </dev/tty) || return
+  READLINE_LINE=$cmd
+  READLINE_POINT=${#READLINE_LINE}
+}
+bind -x '"\C-g": _ask_readline'
+"#;
+
+pub(crate) fn init_script(shell: &str) -> Result<&'static str, String> {
+    match shell {
+        "zsh" => Ok(ZSH_INIT),
+        "bash" => Ok(BASH_INIT),
+        _ => Err(format!("--init supports zsh and bash, not '{shell}'")),
+    }
+}
+
+pub(crate) fn parse_subcommand(parts: &[String]) -> Result<Option<Subcommand>, Box<dyn std::error::Error>> {
+    let Some((name, rest)) = parts.split_first() else {
+        return Ok(None);
+    };
+    match name.as_str() {
+        "export-settings" => {
+            let include_sessions = rest.iter().any(|a| a == "--include-sessions");
+            let path = rest
+                .iter()
+                .find(|a| !a.starts_with("--"))
+                .ok_or("export-settings requires a bundle path (e.g. bundle.tar.gz)")?;
+            Ok(Some(Subcommand::ExportSettings {
+                path: PathBuf::from(path),
+                include_sessions,
+            }))
+        }
+        // Only the exact form; "config show me my ssh config" is a prompt
+        "config" if rest.first().is_some_and(|a| a == "show")
+            && rest[1..].iter().all(|a| a == "--origins") =>
+        {
+            Ok(Some(Subcommand::ConfigShow {
+                origins: rest.len() > 1,
+            }))
+        }
+        "doctor" if rest.is_empty() => Ok(Some(Subcommand::Doctor)),
+        "profiles" if rest.is_empty() => Ok(Some(Subcommand::Profiles)),
+        // Only `auth login|status|logout`; "auth users against ldap" is a prompt
+        "auth" => Ok(match rest {
+            [word] => AuthAction::from_str(word).map(|action| Subcommand::Auth { action }),
+            _ => None,
+        }),
+        // One query word at most; "models of german cars" is a prompt
+        "models" if rest.len() <= 1 => Ok(Some(Subcommand::Models {
+            query: rest.first().cloned().unwrap_or_default(),
+        })),
+        "commit" => parse_commit_args(rest),
+        "completions" => match rest {
+            [flag] if flag == CACHED_MODELS_FLAG => Ok(Some(Subcommand::CachedModels)),
+            [shell] => Ok(Some(Subcommand::Completions { script: completion_script(shell)? })),
+            _ => Err("completions requires a shell (zsh or bash)".into()),
+        },
+        "import-settings" => {
+            let path = rest
+                .first()
+                .ok_or("import-settings requires a bundle path (e.g. bundle.tar.gz)")?;
+            Ok(Some(Subcommand::ImportSettings {
+                path: PathBuf::from(path),
+            }))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// How many times a stacked short flag like `-vv` gives -v.
+pub(crate) fn verbose_count(arg: &str) -> Option<u8> {
+    let vs = arg.strip_prefix('-')?;
+    if vs.is_empty() || !vs.chars().all(|c| c == 'v') {
+        return None;
+    }
+    Some(vs.len().min(u8::MAX as usize) as u8)
+}
+
+/// The command line as given, before any config is read.
+#[derive(Default)]
+pub(crate) struct CommandLine {
+    pub(crate) prompt_parts: Vec<String>,
+    // Settings given as flags; they outrank every other source
+    pub(crate) flags: Vec<(String, String)>,
+    pub(crate) os: Option<String>,
+    pub(crate) stdin_commands: bool,
+    pub(crate) yes: bool,
+    pub(crate) print: bool,
+    pub(crate) copy: bool,
+    pub(crate) verbose: u8,
+    pub(crate) show_usage: bool,
+    pub(crate) show_context: bool,
+    pub(crate) no_color: bool,
+    pub(crate) force_color: bool,
+    pub(crate) estimate: bool,
+    pub(crate) json: bool,
+    pub(crate) output_json: bool,
+    pub(crate) save_session: Option<PathBuf>,
+    pub(crate) force: bool,
+    pub(crate) resume: Option<String>,
+    pub(crate) save_theme: Option<ThemeMode>,
+    pub(crate) save_model: Option<String>,
+    pub(crate) init: Option<&'static str>,
+    pub(crate) data_dir: Option<PathBuf>,
+    pub(crate) read_stdin: bool,
+    pub(crate) version: bool,
+    pub(crate) help: bool,
+}
+
+/// Sorts the arguments into flags and prompt words. Whether an argument is
+/// a flag, and whether it takes a value, comes from FLAGS.
+pub(crate) fn parse_command_line(
+    args: impl IntoIterator<Item = String>,
+) -> Result<CommandLine, Box<dyn std::error::Error>> {
+    let mut args = args.into_iter().peekable();
+    let mut line = CommandLine::default();
+
+    while let Some(arg) = args.next() {
+        let Some(flag) = find_flag(&arg) else {
+            match arg.as_str() {
+                "--" => {
+                    line.prompt_parts.extend(args);
+                    break;
+                }
+                "-" => line.read_stdin = true,
+                _ => match verbose_count(&arg) {
+                    Some(count) => line.verbose = line.verbose.saturating_add(count),
+                    None => line.prompt_parts.push(arg),
+                },
+            }
+            continue;
+        };
+        let value = match flag.value {
+            FlagValue::None => None,
+            FlagValue::Optional(_) => args.next_if(|a| !a.starts_with('-')),
+            _ => args.next(),
+        };
+        match flag.long {
+            "--help" => {
+                line.help = true;
+                return Ok(line);
+            }
+            // Printed once the config is loaded, so -v can say which was read
+            "--version" => line.version = true,
+            "--model" => {
+                let value = value.ok_or("--model requires a value")?;
+                line.flags.push(("model".to_string(), value));
+            }
+            "--save-model" => {
+                let value = value
+                    .filter(|value| !value.trim().is_empty() && !value.contains(char::is_whitespace))
+                    .ok_or("--save-model requires a model name")?;
+                line.flags.push(("model".to_string(), value.clone()));
+                line.save_model = Some(value);
+            }
+            "--profile" => {
+                let value = value.filter(|v| !v.is_empty()).ok_or("--profile requires a profile name")?;
+                line.flags.push(("profile".to_string(), value));
+            }
+            "--prompt-file" => {
+                let value = value.filter(|v| !v.is_empty()).ok_or("--prompt-file requires a file path")?;
+                // Absolute, so it still names the same file after a cd
+                let path = env::current_dir().unwrap_or_default().join(expand_path(&value));
+                line.flags.push(("prompt_file".to_string(), path.display().to_string()));
+            }
+            "--i-know" => line.flags.push(("root_ok".to_string(), "true".to_string())),
+            "--no-redact" => line.flags.push(("redact".to_string(), "off".to_string())),
+            "--no-tools" => line.flags.push(("tools".to_string(), "off".to_string())),
+            "--auto-fix" => line.flags.push(("auto_fix".to_string(), "on".to_string())),
+            "--no-pager" => line.flags.push(("pager".to_string(), "off".to_string())),
+            "--stdin-commands" => line.stdin_commands = true,
+            "--yes" => line.yes = true,
+            "--print" => line.print = true,
+            "--copy" => line.copy = true,
+            "--verbose" => line.verbose = line.verbose.saturating_add(1),
+            "--show-usage" => line.show_usage = true,
+            "--show-context" => line.show_context = true,
+            "--no-color" => line.no_color = true,
+            "--force-color" => line.force_color = true,
+            "--context" => {
+                let value = match value.as_deref() {
+                    Some("auto") => "on",
+                    Some("off") => "off",
+                    _ => return Err("--context takes auto or off".into()),
+                };
+                line.flags.push(("auto_context".to_string(), value.to_string()));
+            }
+            "--data-dir" => {
+                let value = value.filter(|v| !v.is_empty()).ok_or("--data-dir requires a directory")?;
+                line.data_dir = Some(expand_path(&value));
+            }
+            "--save-session" => {
+                let value = value.filter(|v| !v.is_empty()).ok_or("--save-session requires a file path")?;
+                line.save_session = Some(expand_path(&value));
+            }
+            "--force" => line.force = true,
+            "--resume" => line.resume = Some(value.unwrap_or_else(|| LAST_SESSION.to_string())),
+            "--estimate" => line.estimate = true,
+            "--json" => line.json = true,
+            "--output" => {
+                line.output_json = match value.as_deref() {
+                    Some("json") => true,
+                    Some("text") => false,
+                    _ => return Err("--output requires text or json".into()),
+                };
+                // Later errors, even from parsing, are reported as JSON too
+                JSON_OUTPUT.store(line.output_json, std::sync::atomic::Ordering::Relaxed);
+            }
+            "--init" => {
+                let shell = value.ok_or("--init requires a shell (zsh or bash)")?;
+                line.init = Some(init_script(&shell)?);
+            }
+            "--temperature" | "--top-p" | "--max-tokens" => {
+                let key = arg.trim_start_matches("--").replace('-', "_");
+                let value = value.ok_or_else(|| format!("{arg} requires a value"))?;
+                sampling_value(&key, &value).map_err(|err| format!("--{}", err.replacen('_', "-", 1)))?;
+                line.flags.push((key, value));
+            }
+            "--max-auto" => {
+                let value = value
+                    .filter(|value| value.parse::<usize>().is_ok())
+                    .ok_or("--max-auto requires a number")?;
+                line.flags.push(("max_auto_commands".to_string(), value));
+            }
+            "--os" => line.os = Some(value.ok_or("--os requires a value")?),
+            "--fallback" => {
+                let value = value
+                    .filter(|value| valid_setting("fallback_models", value))
+                    .ok_or("--fallback requires a comma-separated list of models")?;
+                line.flags.push(("fallback_models".to_string(), value));
+            }
+            "--provider" => {
+                let value = value
+                    .filter(|value| Provider::from_str(value).is_some())
+                    .ok_or("--provider requires openai, ollama or anthropic")?;
+                line.flags.push(("provider".to_string(), value.to_lowercase()));
+            }
+            "--api-url" => {
+                let value = value.ok_or("--api-url requires a value")?;
+                line.flags.push(("api_url".to_string(), value));
+            }
+            "--theme" => {
+                let value = value.ok_or("--theme requires a value")?;
+                let theme = ThemeMode::from_str(&value)
+                    .ok_or_else(|| format!("Invalid theme. Use one of {}.", THEME_NAMES.join(", ")))?;
+                line.flags.push(("theme".to_string(), theme.as_str().to_string()));
+                line.save_theme = Some(theme);
+            }
+            long => return Err(format!("{long} is in FLAGS but not handled").into()),
+        }
+    }
+    Ok(line)
+}
+
+pub(crate) fn parse_args() -> Result<Args, Box<dyn std::error::Error>> {
+    let mut line = parse_command_line(env::args().skip(1))?;
+    if line.help {
+        print_help();
+        exit(0);
+    }
+    if line.no_color && line.force_color {
+        return Err("--no-color and --force-color contradict each other; pick one".into());
+    }
+
+    // The config file lives in the data directory, so settle that first
+    let _ = DATA_DIR.set(DataDir::from_env(line.data_dir.as_deref()));
+    let profile = line.flags.iter().rev().find(|(key, _)| key == "profile").map(|(_, name)| name.clone());
+    let settings = Settings::load(line.flags, &env::current_dir().unwrap_or_default());
+    if let Some(name) = profile {
+        settings.check_profile(&name)?;
+    }
+    if line.version {
+        print_version(&settings, line.verbose > 0);
+        exit(0);
+    }
+    if env::var_os("ASK_DEBUG").is_some() {
+        for note in &settings.notes {
+            eprintln!("debug: {note}");
+        }
+    }
+
+    let subcommand = match line.init {
+        Some(script) => Some(Subcommand::Init { script }),
+        None => parse_subcommand(&line.prompt_parts)?,
+    };
+    if subcommand.is_some() {
+        line.prompt_parts.clear();
+    }
+
+    // If no prompt provided, enter interactive mode
+    let prompt = if line.prompt_parts.is_empty() {
+        None
+    } else {
+        Some(line.prompt_parts.join(" "))
+    };
+
+    if line.save_theme.is_some() || line.save_model.is_some() {
+        let mut config = Config::load();
+        if let Some(theme) = line.save_theme {
+            config.theme = Some(theme);
+        }
+        if line.save_model.is_some() {
+            config.model = line.save_model;
+        }
+        if let Err(err) = config.save() {
+            eprintln!("Warning: could not save preferences: {err}");
+        }
+    }
+
+    Ok(Args {
+        prompt,
+        os: line.os,
+        settings,
+        stdin_commands: line.stdin_commands,
+        yes: line.yes,
+        print: line.print,
+        copy: line.copy,
+        verbose: line.verbose,
+        show_usage: line.show_usage,
+        show_context: line.show_context,
+        no_color: line.no_color,
+        force_color: line.force_color,
+        estimate: line.estimate,
+        json: line.json,
+        output_json: line.output_json,
+        save_session: line.save_session,
+        force: line.force,
+        resume: line.resume,
+        read_stdin: line.read_stdin,
+        subcommand,
+    })
+}
+
+// Filled in by build.rs; "unknown" when built outside a git checkout
+pub(crate) const VERSION_LINE: &str = concat!(
+    "ask ",
+    env!("CARGO_PKG_VERSION"),
+    " (",
+    env!("ASK_GIT_HASH"),
+    " ",
+    env!("ASK_BUILD_DATE"),
+    ")"
+);
+
+/// `ask --version`; with -v also the model and config files, for bug reports.
+pub(crate) fn print_version(settings: &Settings, verbose: bool) {
+    println!("{VERSION_LINE}");
+    if !verbose {
+        return;
+    }
+    println!("model: {}", settings.model());
+    println!("config path: {}", config_path().display());
+    let global = global_config_file();
+    if global.is_file() {
+        println!("config loaded: {}", global.display());
+    } else {
+        println!("config loaded: none");
+    }
+    if let Some(project) = &settings.project {
+        println!("project config: {}", project.display());
+    }
+}
+
+/// The Options section of --help, one row per entry in FLAGS.
+fn options_help() -> String {
+    let indent = format!("\n{:20}", "");
+    let rows: Vec<(String, String)> = FLAGS.iter().map(|flag| (flag.usage(), flag.help.join(&indent))).collect();
+    help_table(rows.iter().map(|(usage, help)| (usage.as_str(), help.as_str())), |text: &str| text.to_string())
+}
+
+pub(crate) fn print_help() {
+    print!("{}", help_text());
+}
+
+/// Everything --help prints.
+pub(crate) fn help_text() -> String {
+    let options = options_help();
+    let mut text = format!(
+        "ask - MacOS command assistant
+
+Usage:
+  ask [--model MODEL] [--theme light|dark] <prompt>   # Single prompt mode
+  ask [--model MODEL] [--theme light|dark]             # Interactive mode
+  ask --resume [NAME]                                   # Interactive mode, continuing a saved session
+  command | ask \"prompt\"                                # Pipe mode
+  command | ask                                         # Pipe mode (auto-summarize)
+  ask - \"prompt\" < FILE                                 # Pipe mode from a file, or typed until Ctrl-D
+  ask \"explain @deploy.sh\"                              # Attach a file's contents (@@ for a literal @)
+  tool | ask --stdin-commands                          # Confirm and run commands from another tool
+  ask export-settings FILE [--include-sessions]         # Pack ~/.ask into FILE
+  ask import-settings FILE                              # Restore ~/.ask from FILE
+  ask config show [--origins]                           # Effective settings (and where each came from)
+  ask doctor                                            # Where data is kept and the shell environment commands get
+  ask models [QUERY]                                    # Pick a model from the provider's list; prints its slug
+  ask profiles                                          # List the [profile.NAME] tables in ~/.ask/config.toml
+  ask auth login|status|logout                          # Keep the API key in the system keychain instead of the env
+  ask commit [--amend] [--type TYPE]                    # Write a commit message for the staged diff and commit
+  ask completions zsh|bash                              # Tab completion for ask's own flags and subcommands
+  eval \"$(ask --init zsh)\"                              # Shell integration (zsh or bash); Ctrl-G fills in the command
+
+Modes:
+  Single prompt:    Provide a prompt and get commands to execute
+  Interactive:      Enter multiple prompts in a session (type 'exit' or 'quit' to end)
+  Pipe:             Pipe data from any command for AI analysis and transformation
+
+Options:
+{options}
+Environment:
+  OPENROUTER_ASK_API_KEY must be set with your OpenRouter API key, unless
+  `ask auth login` keeps it in the system keychain (the variable wins when both
+  are set; optional when the API URL points at localhost or the provider is Ollama).
+  ANTHROPIC_API_KEY is used instead with --provider anthropic; when it is set or
+  stored, anthropic/NAME models go straight to Anthropic rather than through
+  OpenRouter, unless a provider is set (--provider openai keeps them on OpenRouter).
+  ASK_<KEY> overrides any config key, e.g. ASK_MODEL or ASK_API_URL.
+
+Config:
+  Preferences are stored in ~/.ask/config.toml as TOML keys
+  (theme=dark|light|solarized|high-contrast|plain, model=MODEL, fallback_models=A,B, api_url=URL, provider=openai|ollama|anthropic,
+  paste_guard=on|off, prompt_git=on|off,
+  session_max_turns=N, session_max_bytes=N, max_auto_commands=N,
+  dangerous_pattern=PATTERN, audit_log=PATH, root_ok=true, redact=on|off, redact_pattern=PATTERN, env_bootstrap=on|off,
+  persistent_shell=on|off, tokenizer=cl100k|heuristic, retry_attempts=N,
+  temperature=T, top_p=P, max_tokens=N, warn_cost=USD,
+  transcript_output_lines=N, max_output_bytes=N, on_hangup=forward|detach, pager=off|auto|always,
+  refusal_pattern=PHRASE, tty_command=PROGRAM, auto_fix=on|off, auto_fix_attempts=N,
+  auto_run=on|off, context=TEXT|@FILE, auto_context=on|off, structured=on|off, tools=on|off,
+  extra_prompt=TEXT, profile=NAME, prompt_file=PATH,
+  helper_color|command_color|prompt_color|warning_color|hint_color=NAME|#RRGGBB|SGR).
+  [profile.NAME] tables hold settings picked together with --profile NAME.
+  model sets the default model; {DEFAULT_MODEL} is used when none is set.
+  An older ~/.ask/config of key=value lines is read until the first save.
+  A .ask or .ask.toml file in the current directory or above sets keys for
+  that project (except api_url, redact, audit_log and root_ok).
+
+The tool sends your prompt to OpenRouter, previews the generated commands,
+and asks for confirmation before executing each one in your shell.
+
+Pipe mode examples:
+  git diff | ask \"write a commit message\"
+  cat error.log | ask \"what went wrong?\"
+  ps aux | ask \"what's using the most memory?\"
+  curl -s api.example.com | ask \"extract all emails\"
+  docker logs app | ask \"summarize errors\"
+  cat data.csv | ask                                   # auto-summarizes
+
+Command confirmation options:
+"
+    );
+    let plain = |text: &str| text.to_string();
+    text.push_str(&help_table(CONFIRM_KEYS.iter().copied(), plain));
+    text.push_str("\nInteractive mode commands:\n");
+    text.push_str(&help_table(builtin_rows(BUILTINS), plain));
+    text.push_str("\nInteractive mode shortcuts:\n");
+    text.push_str(&help_table(builtin_rows(SHORTCUTS), plain));
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+
+    // --- piped input ---
+
+    #[test]
+    fn read_capped_stops_one_byte_past_the_limit() {
+        assert_eq!(read_capped(io::Cursor::new("")), None);
+        assert_eq!(read_capped(io::Cursor::new("a,b\n1,2\n")).as_deref(), Some("a,b\n1,2\n"));
+        let exact = "x".repeat(MAX_PIPE_BYTES);
+        assert_eq!(read_capped(io::Cursor::new(exact.clone())).unwrap().len(), MAX_PIPE_BYTES);
+        // An endless pipe must not be read to the end
+        let endless = io::repeat(b'y');
+        assert_eq!(read_capped(endless).unwrap().len(), MAX_PIPE_BYTES + 1);
+    }
+
+    #[test]
+    fn piped_input_is_delimited_and_marked_when_truncated() {
+        let settings = retry_settings(DEFAULT_API_URL.to_string(), 1);
+        let messages = build_messages("write a commit message", &settings, &[], Some("+fn main() {}\n"));
+        let prompt = &messages.last().unwrap().content;
+        assert!(prompt.contains("---BEGIN PIPED DATA---\n+fn main() {}\n\n---END PIPED DATA---"));
+        assert!(prompt.contains("write a commit message"));
+        assert!(!prompt.contains("truncated"));
+
+        let big = read_capped(io::repeat(b'z')).unwrap();
+        let messages = build_messages("summarize", &settings, &[], Some(&big));
+        let prompt = &messages.last().unwrap().content;
+        assert!(prompt.contains(&format!("(truncated – only the first {MAX_PIPE_BYTES} bytes are shown)")));
+        assert!(!prompt.contains(&"z".repeat(MAX_PIPE_BYTES + 1)));
+    }
+
+    #[test]
+    fn os_display_name_normalizes_common_names() {
+        assert_eq!(os_display_name("macos"), "MacOS");
+        assert_eq!(os_display_name("Darwin"), "MacOS");
+        assert_eq!(os_display_name("linux"), "Linux");
+        assert_eq!(os_display_name("plan9"), "plan9");
+    }
+
+    #[test]
+    fn shell_display_name_uses_basename() {
+        assert_eq!(shell_display_name("/bin/zsh"), "Zsh");
+        assert_eq!(shell_display_name("/usr/bin/bash"), "Bash");
+        assert_eq!(shell_display_name("/opt/homebrew/bin/fish"), "Fish");
+        assert_eq!(shell_display_name(""), "Zsh");
+    }
+
+    #[test]
+    fn platform_override_wins_over_local_os() {
+        assert_eq!(Platform::detect(Some("linux")).os, "Linux");
+        assert_eq!(Platform::detect(Some("macos")).os, "MacOS");
+    }
+
+    // --- stdin commands ---
+
+    #[test]
+    fn emit_stdin_result_tallies_each_status() {
+        let mut summary = StdinSummary::default();
+        emit_stdin_result("ls", "ok", Some(&Ok(CommandOutput::success("a\n"))), &mut summary);
+        emit_stdin_result("false", "failed", Some(&Err("exit 1".into())), &mut summary);
+        emit_stdin_result("rm x", "skipped", None, &mut summary);
+        emit_stdin_result("rm y", "cancelled", None, &mut summary);
+        assert_eq!((summary.ran, summary.failed, summary.skipped, summary.cancelled), (1, 1, 1, 1));
+    }
+
+    #[test]
+    fn run_command_to_captures_when_echoing_on_stderr() {
+        let result = run_command_to("echo piped", true);
+        assert_eq!(result_status(&result), "ok");
+        assert_eq!(result.unwrap().stdout, "piped\n");
+        assert_eq!(result_status(&run_command_to("exit 2", true)), "failed");
+    }
+
+    // --- shell integration ---
+
+    #[test]
+    fn zsh_init_injects_into_the_edit_buffer() {
+        let script = init_script("zsh").unwrap();
+        assert!(script.contains("ask --print -- \"$BUFFER\""));
+        assert!(script.contains("zle -N _ask_widget") && script.contains("bindkey '^G' _ask_widget"));
+        assert!(script.contains("print -z -- \"$cmd\""));
+    }
+
+    #[test]
+    fn bash_init_injects_through_readline() {
+        let script = init_script("bash").unwrap();
+        assert!(script.contains("ask --print -- \"$READLINE_LINE\""));
+        assert!(script.contains("READLINE_LINE=$cmd") && script.contains("READLINE_POINT="));
+        assert!(script.contains(r#"bind -x '"\C-g": _ask_readline'"#));
+    }
+
+    #[test]
+    fn stacked_v_flags_count_each_v() {
+        assert_eq!(verbose_count("-v"), Some(1));
+        assert_eq!(verbose_count("-vv"), Some(2));
+        assert_eq!(verbose_count("-vvv"), Some(3));
+        assert_eq!(verbose_count("-"), None);
+        assert_eq!(verbose_count("-vx"), None);
+        assert_eq!(verbose_count("--verbose"), None);
+    }
+
+    #[test]
+    fn version_line_carries_the_crate_version_and_build_info() {
+        let rest = VERSION_LINE.strip_prefix(&format!("ask {} (", env!("CARGO_PKG_VERSION"))).unwrap();
+        let (hash, date) = rest.trim_end_matches(')').split_once(' ').unwrap();
+        assert!(!hash.is_empty());
+        assert!(date == "unknown" || is_iso_date(date), "{date}");
+    }
+
+    #[test]
+    fn init_rejects_other_shells() {
+        assert!(init_script("fish").unwrap_err().contains("fish"));
+    }
+}
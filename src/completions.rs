@@ -0,0 +1,543 @@
+use std::fs;
+use crate::config::*;
+use crate::api::*;
+use crate::theme::*;
+
+/// What a flag takes after it, which decides how its value completes.
+pub(crate) enum FlagValue {
+    None,
+    // Free-form (a number, a URL); named in the usage, nothing to complete
+    Text(&'static str),
+    // One of a fixed list, named in the usage
+    Choice(&'static str, &'static [&'static str]),
+    File,
+    Dir,
+    // Completed from the cached models list
+    Model,
+    // May be left off (`--resume [NAME]`)
+    Optional(&'static str),
+}
+
+/// One option on the command line. parse_args looks every argument up
+/// here, and --help and the completion scripts are built from it.
+pub(crate) struct Flag {
+    pub(crate) long: &'static str,
+    pub(crate) short: Option<char>,
+    pub(crate) value: FlagValue,
+    // Given more than once (-vv)
+    pub(crate) repeats: bool,
+    // One line, for the completion menus
+    pub(crate) about: &'static str,
+    // What --help says, one entry per line
+    pub(crate) help: &'static [&'static str],
+}
+
+const fn flag(long: &'static str, value: FlagValue, about: &'static str, help: &'static [&'static str]) -> Flag {
+    Flag { long, short: None, value, repeats: false, about, help }
+}
+
+const fn switch(long: &'static str, short: char, about: &'static str, help: &'static [&'static str]) -> Flag {
+    Flag { long, short: Some(short), value: FlagValue::None, repeats: false, about, help }
+}
+
+impl Flag {
+    /// How --help shows the flag: `-p, --print`, `--model MODEL`.
+    pub(crate) fn usage(&self) -> String {
+        let value = match self.value {
+            FlagValue::None => String::new(),
+            FlagValue::Text(name) | FlagValue::Choice(name, _) => format!(" {name}"),
+            FlagValue::Optional(name) => format!(" [{name}]"),
+            FlagValue::File => " FILE".to_string(),
+            FlagValue::Dir => " DIR".to_string(),
+            FlagValue::Model => " MODEL".to_string(),
+        };
+        match self.short {
+            Some(c) => format!("-{c}, {}{value}", self.long),
+            None => format!("{}{value}", self.long),
+        }
+    }
+}
+
+/// The flag `arg` names, by its long or short form.
+pub(crate) fn find_flag(arg: &str) -> Option<&'static Flag> {
+    FLAGS.iter().find(|f| f.long == arg || f.short.is_some_and(|c| arg.len() == 2 && arg.starts_with('-') && arg.ends_with(c)))
+}
+
+pub(crate) const PROVIDER_NAMES: &[&str] = &["openai", "ollama", "anthropic"];
+pub(crate) const OS_NAMES: &[&str] = &["macos", "linux"];
+pub(crate) const SHELL_NAMES: &[&str] = &["zsh", "bash"];
+
+// In the order --help lists them
+pub(crate) const FLAGS: &[Flag] = &[
+    flag("--model", FlagValue::Model, "Model to use", &["Override the default LLM model"]),
+    flag(
+        "--save-model",
+        FlagValue::Model,
+        "Use a model and save it as the default",
+        &["Use MODEL and save it as the default in ~/.ask/config.toml"],
+    ),
+    flag(
+        "--api-url",
+        FlagValue::Text("URL"),
+        "Chat completions endpoint",
+        &["Chat completions endpoint of any OpenAI-compatible server", "(--model ollama/NAME uses a local Ollama instead)"],
+    ),
+    flag(
+        "--profile",
+        FlagValue::Text("NAME"),
+        "Use the settings of a config profile",
+        &["Use the model, sampling and extra_prompt of [profile.NAME] in", "~/.ask/config.toml (ask profiles lists them)"],
+    ),
+    flag(
+        "--prompt-file",
+        FlagValue::File,
+        "Replace or extend the command prompt with a file",
+        &["Use FILE as the command prompt when it has a {query} placeholder;", "otherwise send it as an extra system message"],
+    ),
+    flag(
+        "--fallback",
+        FlagValue::Text("A,B"),
+        "Models to try when the model keeps failing",
+        &["Models to try in order when the model still fails with a rate", "limit or server error after its retries"],
+    ),
+    flag(
+        "--provider",
+        FlagValue::Choice("NAME", PROVIDER_NAMES),
+        "API to speak",
+        &["API to speak: openai (OpenRouter and compatible servers, the", "default), ollama or anthropic"],
+    ),
+    flag(
+        "--os",
+        FlagValue::Choice("OS", OS_NAMES),
+        "Target OS for generated commands",
+        &["Target OS for generated commands (macos, linux; default: this machine)"],
+    ),
+    switch(
+        "--print",
+        'p',
+        "Print the suggested commands instead of running them",
+        &[
+            "Print the suggested commands (one per line) instead of running",
+            "them; commentary goes to stderr. Exits 1 if there are none.",
+        ],
+    ),
+    flag(
+        "--copy",
+        FlagValue::None,
+        "Copy the first suggested command to the clipboard instead of running it",
+        &["Put the first suggested command on the clipboard instead of", "running anything (pbcopy, wl-copy or xclip)"],
+    ),
+    flag(
+        "--estimate",
+        FlagValue::None,
+        "Price the request without sending it",
+        &[
+            "Price the request without sending it: approximate prompt tokens",
+            "and a cost range up to max_tokens. Exits 5 above warn_cost.",
+        ],
+    ),
+    flag("--json", FlagValue::None, "With --estimate, print JSON", &["With --estimate, print the estimate as one JSON object"]),
+    flag(
+        "--output",
+        FlagValue::Choice("text|json", &["text", "json"]),
+        "Output format for a single prompt",
+        &[
+            "text by default; json prints only one JSON object on stdout for",
+            "a single prompt: the reply's lines and each command's exit code",
+            "and output",
+        ],
+    ),
+    flag(
+        "--resume",
+        FlagValue::Optional("NAME"),
+        "Continue the last or a named session",
+        &["Start interactive mode from the last saved session (or the", "one saved as NAME)"],
+    ),
+    flag(
+        "--save-session",
+        FlagValue::File,
+        "Save the prompt, commands and output as markdown",
+        &["Write the prompt, commands and output to FILE as markdown"],
+    ),
+    flag(
+        "--force",
+        FlagValue::None,
+        "With --save-session, replace an existing file",
+        &["With --save-session, replace an existing file"],
+    ),
+    switch(
+        "--yes",
+        'y',
+        "Run suggested commands without asking",
+        &[
+            "Run suggested commands without asking. Without it, ask refuses",
+            "to run anything when no terminal is attached (exit code 3).",
+        ],
+    ),
+    flag(
+        "--max-auto",
+        FlagValue::Text("N"),
+        "With --yes, run at most N commands per turn",
+        &["With --yes, run at most N commands per turn before asking", "again (default 5; exit code 4 when no terminal is attached)"],
+    ),
+    flag(
+        "--temperature",
+        FlagValue::Text("T"),
+        "Sampling temperature, 0 to 2",
+        &["Sampling temperature, 0 to 2 (default: the provider's)"],
+    ),
+    flag(
+        "--top-p",
+        FlagValue::Text("P"),
+        "Nucleus sampling, above 0 and at most 1",
+        &["Nucleus sampling, above 0 and at most 1 (default: the provider's)"],
+    ),
+    flag(
+        "--max-tokens",
+        FlagValue::Text("N"),
+        "Cap the length of each reply",
+        &["Cap the length of each reply (default: the provider's)"],
+    ),
+    flag(
+        "--i-know",
+        FlagValue::None,
+        "Running as root, allow --yes and direct commands",
+        &["Running as root: allow --yes and direct commands anyway"],
+    ),
+    flag(
+        "--no-redact",
+        FlagValue::None,
+        "Send command output without masking secrets",
+        &["Send command output to the model without masking secrets"],
+    ),
+    flag(
+        "--no-tools",
+        FlagValue::None,
+        "Don't let the model run read-only commands before answering",
+        &[
+            "Don't let the model run read-only commands to look around",
+            "before it answers (same as tools=off; OpenAI-compatible",
+            "APIs only, never Anthropic or Ollama)",
+        ],
+    ),
+    flag(
+        "--auto-fix",
+        FlagValue::None,
+        "Ask the model to fix a failed command",
+        &["When a command fails, send its error back to the model and", "confirm the fix it suggests (auto_fix_attempts times at most)"],
+    ),
+    flag(
+        "--context",
+        FlagValue::Choice("auto|off", &["auto", "off"]),
+        "Send the directory listing and git status with each request",
+        &[
+            "auto sends the current directory, its listing and git status",
+            "with each request (same as auto_context=on); off turns it off",
+        ],
+    ),
+    flag(
+        "--stdin-commands",
+        FlagValue::None,
+        "Confirm and run commands read from stdin",
+        &[
+            "Read commands (one per line) from stdin, confirm each on the",
+            "terminal, run approved ones, and print a JSON result per command.",
+            "Exits 1 if any command failed, 2 if the run was cancelled.",
+        ],
+    ),
+    flag(
+        "--init",
+        FlagValue::Choice("SHELL", SHELL_NAMES),
+        "Print the shell integration script",
+        &["Print the shell integration script for zsh or bash; Ctrl-G", "fills in the command for what is typed at the prompt"],
+    ),
+    flag(
+        "--theme",
+        FlagValue::Choice("MODE", &THEME_NAMES),
+        "Color theme",
+        &[
+            "Color theme: dark, light, solarized, high-contrast or plain",
+            "(default: light or dark to suit the terminal's background;",
+            "/theme switches it in interactive mode)",
+        ],
+    ),
+    flag(
+        "--no-color",
+        FlagValue::None,
+        "Plain text with no colors",
+        &["Plain text with no colors (also NO_COLOR=1, or when stdout", "is not a terminal)"],
+    ),
+    flag(
+        "--no-pager",
+        FlagValue::None,
+        "Print long output instead of paging it",
+        &["In interactive mode, print long output instead of opening", "it in $PAGER (same as pager=off)"],
+    ),
+    flag(
+        "--force-color",
+        FlagValue::None,
+        "Keep colors when piping",
+        &["Keep colors when piping, e.g. into less -R or fzf --ansi", "(also ASK_FORCE_COLOR=1, which outranks NO_COLOR)"],
+    ),
+    flag(
+        "--data-dir",
+        FlagValue::Dir,
+        "Keep config, notes and history in this directory",
+        &["Keep config, notes and history in DIR instead of ~/.ask", "(also ASK_DATA_DIR)"],
+    ),
+    flag(
+        "--show-usage",
+        FlagValue::None,
+        "Print tokens and cost after a single prompt",
+        &["After a single prompt, print the tokens it used and their cost"],
+    ),
+    flag(
+        "--show-context",
+        FlagValue::None,
+        "Print the messages a single prompt sends before sending",
+        &[
+            "Before sending a single prompt, print the messages it sends",
+            "(interactive mode always shows them; /usage totals the session)",
+        ],
+    ),
+    Flag {
+        repeats: true,
+        ..switch(
+            "--verbose",
+            'v',
+            "Show requests, responses and routing; twice for safety decisions",
+            &[
+                "Show how each prompt was routed (command or conversation),",
+                "which config files were read, and each request (key masked),",
+                "raw response and timing on stderr; -vv also shows why each",
+                "command was or wasn't treated as safe",
+            ],
+        )
+    },
+    switch(
+        "--version",
+        'V',
+        "Print the version and build",
+        &["Print the version, git commit and build date (with -v also", "the model and the config files that were read)"],
+    ),
+    switch("--help", 'h', "Show the help message", &["Show this help message"]),
+];
+
+// The first word that makes the command line a subcommand, and what it does
+pub(crate) const SUBCOMMANDS: &[(&str, &str)] = &[
+    ("export-settings", "Pack the data directory into a bundle"),
+    ("import-settings", "Restore the data directory from a bundle"),
+    ("config", "Show the effective settings"),
+    ("doctor", "Where data is kept and the shell environment"),
+    ("models", "Pick a model from the provider list"),
+    ("profiles", "List the config profiles"),
+    ("auth", "Keep the API key in the system keychain"),
+    ("commit", "Write a commit message for the staged diff"),
+    ("completions", "Print a completion script for zsh or bash"),
+];
+
+// `ask completions --cached-models`: what the scripts run to complete --model
+pub(crate) const CACHED_MODELS_FLAG: &str = "--cached-models";
+
+pub(crate) fn completion_script(shell: &str) -> Result<String, String> {
+    match shell {
+        "zsh" => Ok(zsh_completions()),
+        "bash" => Ok(bash_completions()),
+        _ => Err(format!("completions supports zsh and bash, not '{shell}'")),
+    }
+}
+
+/// The model ids in the cached `/models` lists of every endpoint, without
+/// going to the network; empty until `ask models` (or a price lookup) has
+/// fetched one.
+pub(crate) fn cached_model_ids() -> Vec<String> {
+    let Ok(entries) = fs::read_dir(data_dir()) else {
+        return Vec::new();
+    };
+    let mut ids: Vec<String> = entries
+        .flatten()
+        .filter(|entry| entry.file_name().to_str().is_some_and(|name| name.starts_with("models-") && name.ends_with(".json")))
+        .filter_map(|entry| serde_json::from_str::<ModelList>(&fs::read_to_string(entry.path()).ok()?).ok())
+        .flat_map(|list| list.data.into_iter().map(|m| m.id))
+        .collect();
+    ids.sort();
+    ids.dedup();
+    ids
+}
+
+pub(crate) fn bash_completions() -> String {
+    let words: Vec<String> = FLAGS
+        .iter()
+        .flat_map(|f| f.short.map(|c| format!("-{c}")).into_iter().chain([f.long.to_string()]))
+        .collect();
+    let mut cases = String::new();
+    for f in FLAGS {
+        let names = f.short.map_or(f.long.to_string(), |c| format!("-{c}|{}", f.long));
+        let reply = match f.value {
+            FlagValue::None | FlagValue::Optional(_) => continue,
+            FlagValue::Text(_) => "return".to_string(),
+            FlagValue::Choice(_, choices) => format!("COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")); return", choices.join(" ")),
+            FlagValue::File => "COMPREPLY=($(compgen -f -- \"$cur\")); return".to_string(),
+            FlagValue::Dir => "COMPREPLY=($(compgen -d -- \"$cur\")); return".to_string(),
+            FlagValue::Model => format!(
+                "COMPREPLY=($(compgen -W \"$(command ask completions {CACHED_MODELS_FLAG} 2>/dev/null)\" -- \"$cur\")); return"
+            ),
+        };
+        cases.push_str(&format!("    {names}) {reply} ;;\n"));
+    }
+    let subcommands: Vec<&str> = SUBCOMMANDS.iter().map(|(name, _)| *name).collect();
+    format!(
+        r#"# ask completions for bash
+_ask() {{
+  local cur=${{COMP_WORDS[COMP_CWORD]}} prev=${{COMP_WORDS[COMP_CWORD-1]}}
+  COMPREPLY=()
+  case $prev in
+{cases}  esac
+  if [[ $COMP_CWORD -eq 2 && ${{COMP_WORDS[1]}} == completions ]]; then
+    COMPREPLY=($(compgen -W "{shells}" -- "$cur"))
+  elif [[ $cur == -* ]]; then
+    COMPREPLY=($(compgen -W "{flags}" -- "$cur"))
+  elif [[ $COMP_CWORD -eq 1 ]]; then
+    COMPREPLY=($(compgen -W "{subcommands}" -- "$cur"))
+  fi
+}}
+complete -o default -F _ask ask
+"#,
+        shells = SHELL_NAMES.join(" "),
+        flags = words.join(" "),
+        subcommands = subcommands.join(" "),
+    )
+}
+
+/// Escapes text for a zsh `[description]` or `name:description` pair.
+fn zsh_quote(text: &str) -> String {
+    text.replace('\'', "'\\''").replace('[', "\\[").replace(']', "\\]").replace(':', "\\:")
+}
+
+pub(crate) fn zsh_completions() -> String {
+    let mut specs = String::new();
+    for f in FLAGS {
+        let about = zsh_quote(f.about);
+        let value = match f.value {
+            FlagValue::None => String::new(),
+            FlagValue::Text(name) => format!(":{}: ", name.to_lowercase()),
+            FlagValue::Optional(name) => format!("::{}: ", name.to_lowercase()),
+            FlagValue::Choice(_, choices) => format!(":value:({})", choices.join(" ")),
+            FlagValue::File => ":file:_files".to_string(),
+            FlagValue::Dir => ":directory:_files -/".to_string(),
+            FlagValue::Model => ":model:_ask_models".to_string(),
+        };
+        let spec = match (f.short, f.repeats) {
+            (Some(c), true) => format!("'*'{{-{c},{}}}'[{about}]{value}'", f.long),
+            (Some(c), false) => format!("'(-{c} {long})'{{-{c},{long}}}'[{about}]{value}'", long = f.long),
+            (None, _) => format!("'{}[{about}]{value}'", f.long),
+        };
+        specs.push_str(&format!("    {spec} \\\n"));
+    }
+    let subcommands: Vec<String> =
+        SUBCOMMANDS.iter().map(|(name, about)| format!("    '{name}:{}'", zsh_quote(about))).collect();
+    format!(
+        r#"#compdef ask
+# ask completions for zsh
+
+_ask_models() {{
+  local -a models
+  models=(${{(f)"$(command ask completions {CACHED_MODELS_FLAG} 2>/dev/null)"}})
+  compadd -a models
+}}
+
+_ask_subcommands() {{
+  local -a subcommands=(
+{subcommands}
+  )
+  _describe -t commands 'ask command' subcommands
+}}
+
+_ask() {{
+  if [[ $words[2] == completions ]]; then
+    _arguments '2:shell:({shells})'
+    return
+  fi
+  _arguments -s \
+{specs}    '1: :_ask_subcommands' \
+    '*::prompt: '
+}}
+
+if [[ $funcstack[1] == _ask ]]; then
+  _ask "$@"
+else
+  compdef _ask ask
+fi
+"#,
+        subcommands = subcommands.join("\n"),
+        shells = SHELL_NAMES.join(" "),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::*;
+
+    // A value parse_command_line accepts for the flag
+    fn sample_value(flag: &Flag) -> Option<String> {
+        match flag.value {
+            FlagValue::None | FlagValue::Optional(_) => None,
+            FlagValue::Text("N") => Some("3".to_string()),
+            FlagValue::Text("T" | "P") => Some("0.5".to_string()),
+            FlagValue::Text(_) => Some("x".to_string()),
+            FlagValue::Choice(_, choices) => Some(choices[0].to_string()),
+            FlagValue::File => Some("notes.md".to_string()),
+            FlagValue::Dir => Some("/tmp".to_string()),
+            FlagValue::Model => Some("openai/gpt-4o".to_string()),
+        }
+    }
+
+    #[test]
+    fn every_flag_is_parsed_documented_and_in_both_scripts() {
+        let (zsh, bash, help) = (zsh_completions(), bash_completions(), help_text());
+        for flag in FLAGS {
+            let args = std::iter::once(flag.long.to_string()).chain(sample_value(flag));
+            if let Err(err) = parse_command_line(args) {
+                panic!("{}: {err}", flag.long);
+            }
+            assert!(help.contains(&format!("  {}", flag.usage())), "{} is missing from --help", flag.long);
+            let mut bash_words = bash.split(|c: char| c.is_whitespace() || "\"|)".contains(c));
+            assert!(bash_words.any(|word| word == flag.long), "{} missing from the bash script", flag.long);
+            assert!(zsh.contains(flag.long), "{} missing from the zsh script", flag.long);
+        }
+    }
+
+    #[test]
+    fn arguments_are_flags_only_when_flags_lists_them() {
+        let args = ["-vv", "-p", "--resume", "list", "--frobnicate", "--", "-y"].map(String::from);
+        let line = parse_command_line(args).unwrap();
+        assert_eq!((line.verbose, line.print, line.yes), (2, true, false));
+        assert_eq!(line.resume.as_deref(), Some("list"));
+        assert_eq!(line.prompt_parts, ["--frobnicate", "-y"]);
+        assert_eq!(find_flag("-y").map(|f| f.long), Some("--yes"));
+        assert!(find_flag("-x").is_none() && find_flag("--yess").is_none());
+        assert!(parse_command_line(["--max-auto".to_string(), "many".to_string()]).is_err());
+    }
+
+    #[test]
+    fn choices_are_values_the_parser_accepts() {
+        assert!(PROVIDER_NAMES.iter().all(|name| Provider::from_str(name).is_some()));
+        assert!(THEME_NAMES.iter().all(|name| ThemeMode::from_str(name).is_some()));
+        assert!(SHELL_NAMES.iter().all(|name| init_script(name).is_ok() && completion_script(name).is_ok()));
+        assert!(completion_script("fish").unwrap_err().contains("fish"));
+    }
+
+    #[test]
+    fn subcommands_are_offered_and_models_complete_from_the_cache() {
+        let (zsh, bash) = (zsh_completions(), bash_completions());
+        for (name, _) in SUBCOMMANDS {
+            assert!(bash.contains(name) && zsh.contains(&format!("'{name}:")), "{name}");
+        }
+        assert!(bash.contains("--model) COMPREPLY=($(compgen -W \"$(command ask completions --cached-models"));
+        assert!(zsh.contains("'--model[Model to use]:model:_ask_models'"));
+        assert!(zsh.contains("'(-y --yes)'{-y,--yes}'[Run suggested commands without asking]'"));
+        assert!(zsh.contains("'*'{-v,--verbose}"));
+        assert!(zsh.starts_with("#compdef ask\n"));
+    }
+}
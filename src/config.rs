@@ -0,0 +1,2318 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use crate::api::*;
+use crate::cli::*;
+use crate::exec::*;
+use crate::history::*;
+use crate::repl::*;
+use crate::safety::*;
+use crate::theme::*;
+
+/// What ~/.ask/config.toml holds, as written back: each key the file set,
+/// and nothing else, so defaults stay defaults.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    // Unset leaves the theme to the terminal's background
+    pub(crate) theme: Option<ThemeMode>,
+    pub(crate) model: Option<String>,
+    pub(crate) fallback_models: Option<String>,
+    pub(crate) api_url: Option<String>,
+    pub(crate) provider: Option<String>,
+    pub(crate) paste_guard: Option<bool>,
+    // Branch and dirty mark in the interactive prompt
+    pub(crate) prompt_git: Option<bool>,
+    pub(crate) session_max_turns: Option<usize>,
+    pub(crate) session_max_bytes: Option<usize>,
+    pub(crate) max_auto_commands: Option<usize>,
+    pub(crate) retry_attempts: Option<u32>,
+    pub(crate) transcript_output_lines: Option<usize>,
+    pub(crate) max_output_bytes: Option<usize>,
+    // Added to DANGEROUS_PATTERNS
+    #[serde(rename = "dangerous_pattern", skip_serializing_if = "Vec::is_empty")]
+    pub(crate) dangerous_patterns: Vec<String>,
+    pub(crate) redact: Option<bool>,
+    // Extra secret shapes
+    #[serde(rename = "redact_pattern", skip_serializing_if = "Vec::is_empty")]
+    pub(crate) redact_patterns: Vec<String>,
+    // Extra refusal phrases
+    #[serde(rename = "refusal_pattern", skip_serializing_if = "Vec::is_empty")]
+    pub(crate) refusal_patterns: Vec<String>,
+    // Extra programs run attached to the terminal
+    #[serde(rename = "tty_command", skip_serializing_if = "Vec::is_empty")]
+    pub(crate) tty_commands: Vec<String>,
+    pub(crate) env_bootstrap: Option<bool>,
+    pub(crate) persistent_shell: Option<bool>,
+    // A look at the current directory goes with every request
+    pub(crate) auto_context: Option<bool>,
+    // Commands are asked for as JSON rather than plain lines
+    pub(crate) structured: Option<bool>,
+    // The model may run read-only commands before answering
+    pub(crate) tools: Option<bool>,
+    pub(crate) auto_fix: Option<bool>,
+    pub(crate) auto_fix_attempts: Option<usize>,
+    pub(crate) auto_run: Option<bool>,
+    // Prompt additions
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) context: Vec<String>,
+    pub(crate) root_ok: Option<bool>,
+    pub(crate) audit_log: Option<String>,
+    pub(crate) tokenizer: Option<String>,
+    pub(crate) on_hangup: Option<String>,
+    pub(crate) pager: Option<String>,
+    pub(crate) temperature: Option<f64>,
+    pub(crate) top_p: Option<f64>,
+    pub(crate) max_tokens: Option<u32>,
+    pub(crate) warn_cost: Option<f64>,
+    pub(crate) extra_prompt: Option<String>,
+    pub(crate) prompt_file: Option<String>,
+    pub(crate) helper_color: Option<String>,
+    pub(crate) command_color: Option<String>,
+    pub(crate) prompt_color: Option<String>,
+    pub(crate) warning_color: Option<String>,
+    pub(crate) hint_color: Option<String>,
+    // The default profile and the `[profile.NAME]` tables
+    #[serde(rename = "profile", skip_serializing_if = "ProfileTables::is_empty")]
+    pub(crate) profiles: ProfileTables,
+}
+
+/// The `[profile]` table: `default` names the profile used unless
+/// --profile picks another, and each `[profile.NAME]` under it holds that
+/// profile's keys.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ProfileTables {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) default: Option<String>,
+    #[serde(flatten)]
+    pub(crate) tables: toml::Table,
+}
+
+impl ProfileTables {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.default.is_none() && self.tables.is_empty()
+    }
+}
+
+impl Config {
+    /// The config file alone, over defaults. Used when writing it back.
+    pub fn load() -> Self {
+        Self::from_settings(&Settings::resolve(&[SettingsLayer::defaults(), SettingsLayer::global().0]))
+    }
+
+    /// The settings that didn't come from the defaults, typed.
+    pub(crate) fn from_settings(settings: &Settings) -> Self {
+        let set = |key| settings.get(key).filter(|s| s.origin != Origin::Default);
+        let text = |key| set(key).map(|s: &Setting| s.value.clone());
+        let flag = |key, value: bool| set(key).map(|_| value);
+        let number = |key| set(key).map(|s: &Setting| settings.number(key, s.value.parse().unwrap_or_default()));
+        Self {
+            theme: set("theme").map(|_| settings.theme()),
+            model: text("model"),
+            fallback_models: text("fallback_models"),
+            api_url: text("api_url"),
+            provider: text("provider"),
+            paste_guard: flag("paste_guard", settings.paste_guard()),
+            prompt_git: flag("prompt_git", settings.prompt_git()),
+            session_max_turns: set("session_max_turns").map(|_| settings.session_limits().max_turns),
+            session_max_bytes: set("session_max_bytes").map(|_| settings.session_limits().max_bytes),
+            max_auto_commands: set("max_auto_commands").map(|_| settings.max_auto_commands()),
+            retry_attempts: set("retry_attempts").map(|_| settings.retry_attempts()),
+            transcript_output_lines: set("transcript_output_lines").map(|_| settings.transcript_output_lines()),
+            max_output_bytes: set("max_output_bytes").map(|_| settings.max_output_bytes()),
+            dangerous_patterns: settings.dangerous_patterns(),
+            redact: flag("redact", settings.redact()),
+            redact_patterns: settings.all("redact_pattern"),
+            refusal_patterns: settings.refusal_patterns(),
+            tty_commands: settings.all("tty_command"),
+            env_bootstrap: flag("env_bootstrap", settings.env_bootstrap()),
+            persistent_shell: flag("persistent_shell", settings.persistent_shell()),
+            auto_context: flag("auto_context", settings.auto_context()),
+            structured: flag("structured", settings.structured()),
+            tools: flag("tools", settings.tools()),
+            auto_fix: flag("auto_fix", settings.auto_fix()),
+            auto_fix_attempts: number("auto_fix_attempts").map(|attempts| attempts.max(1)),
+            auto_run: flag("auto_run", settings.auto_run()),
+            context: settings.all("context"),
+            root_ok: flag("root_ok", settings.root_ok()),
+            audit_log: text("audit_log"),
+            tokenizer: text("tokenizer"),
+            on_hangup: text("on_hangup"),
+            pager: text("pager"),
+            temperature: settings.temperature(),
+            top_p: settings.top_p(),
+            max_tokens: settings.max_tokens(),
+            warn_cost: settings.warn_cost(),
+            extra_prompt: text("extra_prompt"),
+            prompt_file: text("prompt_file"),
+            helper_color: text("helper_color"),
+            command_color: text("command_color"),
+            prompt_color: text("prompt_color"),
+            warning_color: text("warning_color"),
+            hint_color: text("hint_color"),
+            profiles: ProfileTables {
+                default: text("profile"),
+                tables: settings
+                    .profiles()
+                    .into_iter()
+                    .map(|name| (name.to_string(), toml::Value::Table(profile_table_values(&settings.profile_values(name)))))
+                    .collect(),
+            },
+        }
+    }
+
+    /// Writes config.toml. It goes to a new temporary file that is synced
+    /// and then renamed over the old one, so a crash leaves either the old
+    /// file or the whole new one, and an older `key=value` config is then
+    /// set aside.
+    pub(crate) fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let path = config_path();
+        let dir = path.parent().unwrap_or(Path::new("."));
+        fs::create_dir_all(dir)?;
+        let contents = self.to_toml()?;
+        let (partial, mut file) = create_unique_file(dir, "config.toml")?;
+        let written = file.write_all(contents.as_bytes()).and_then(|_| file.sync_all());
+        if let Err(err) = written.and_then(|_| fs::rename(&partial, &path)) {
+            let _ = fs::remove_file(&partial);
+            return Err(err.into());
+        }
+        let legacy = legacy_config_path();
+        if legacy.is_file() {
+            let _ = fs::rename(&legacy, data_dir().join("config.old"));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn to_toml(&self) -> Result<String, toml::ser::Error> {
+        toml::to_string(self)
+    }
+}
+
+/// A profile's key=value pairs as a TOML table, in SETTING_KEYS order;
+/// the repeatable keys become arrays.
+pub(crate) fn profile_table_values(values: &[(String, String)]) -> toml::Table {
+    let mut table = toml::Table::new();
+    for key in SETTING_KEYS {
+        let all: Vec<toml::Value> = values.iter().filter(|(k, _)| k == key).map(|(_, v)| toml_setting(v)).collect();
+        match <[toml::Value; 1]>::try_from(all) {
+            Ok([value]) if !REPEATABLE_CONFIG_KEYS.contains(key) => {
+                table.insert(key.to_string(), value);
+            }
+            Ok([value]) => {
+                table.insert(key.to_string(), toml::Value::Array(vec![value]));
+            }
+            Err(all) if !all.is_empty() => {
+                table.insert(key.to_string(), toml::Value::Array(all));
+            }
+            Err(_) => {}
+        }
+    }
+    table
+}
+
+/// Creates `NAME.<unique>.partial` in `dir`, failing rather than opening a
+/// file that is already there.
+pub(crate) fn create_unique_file(dir: &Path, name: &str) -> Result<(PathBuf, fs::File), io::Error> {
+    loop {
+        let path = dir.join(format!("{name}.{}.partial", unique_suffix()));
+        match fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// A name part no other call, in this process or another, comes up with:
+/// the pid, the clock, and a counter.
+pub(crate) fn unique_suffix() -> String {
+    static COUNTER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since| since.subsec_nanos());
+    let count = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    format!("{}-{nanos:x}-{count}", std::process::id())
+}
+
+/// Where an effective setting came from. Later variants win.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Origin {
+    Default,
+    Global,
+    Profile,
+    Workspace,
+    Env,
+    Flag,
+}
+
+// Highest precedence first; `config show --origins` prints this order
+pub(crate) const ORIGIN_PRECEDENCE: [Origin; 6] = [
+    Origin::Flag,
+    Origin::Env,
+    Origin::Workspace,
+    Origin::Profile,
+    Origin::Global,
+    Origin::Default,
+];
+
+impl Origin {
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            Origin::Default => "default",
+            Origin::Global => "global",
+            Origin::Profile => "profile",
+            Origin::Workspace => "workspace",
+            Origin::Env => "env",
+            Origin::Flag => "flag",
+        }
+    }
+}
+
+// Every key a settings source may set, in display order
+pub(crate) const SETTING_KEYS: &[&str] = &[
+    "theme",
+    "profile",
+    "model",
+    "fallback_models",
+    "api_url",
+    "provider",
+    "paste_guard",
+    "prompt_git",
+    "session_max_turns",
+    "session_max_bytes",
+    "max_auto_commands",
+    "dangerous_pattern",
+    "audit_log",
+    "root_ok",
+    "redact",
+    "redact_pattern",
+    "env_bootstrap",
+    "persistent_shell",
+    "tokenizer",
+    "retry_attempts",
+    "temperature",
+    "top_p",
+    "max_tokens",
+    "warn_cost",
+    "transcript_output_lines",
+    "max_output_bytes",
+    "on_hangup",
+    "pager",
+    "refusal_pattern",
+    "tty_command",
+    "auto_fix",
+    "auto_fix_attempts",
+    "auto_run",
+    "context",
+    "auto_context",
+    "structured",
+    "tools",
+    "extra_prompt",
+    "prompt_file",
+    "helper_color",
+    "command_color",
+    "prompt_color",
+    "warning_color",
+    "hint_color",
+];
+
+// Project settings files, looked for in the working directory and each
+// directory above it
+pub(crate) const PROJECT_CONFIG_NAMES: &[&str] = &[".ask", ".ask.toml"];
+
+// Keys a project file may not set: a cloned repository must not be able to
+// point the API key at another server, turn off redaction, move the audit
+// log, relax the root posture, rewrite the prompt, or hide warnings and
+// commands behind invisible colors (`warning_color = "8"` conceals them)
+pub(crate) const PROJECT_LOCKED_KEYS: &[&str] = &[
+    "api_url",
+    "redact",
+    "audit_log",
+    "root_ok",
+    "prompt_file",
+    "helper_color",
+    "command_color",
+    "prompt_color",
+    "warning_color",
+    "hint_color",
+];
+
+// Text read for each `context=@FILE`
+pub(crate) const MAX_CONTEXT_BYTES: usize = 8192;
+
+/// `("jq", "model")` for `profile.jq.model`, the key a `model` line under
+/// `[profile.jq]` is kept as. A profile may set any key except `profile`.
+pub(crate) fn profile_key(key: &str) -> Option<(&str, &str)> {
+    let (name, setting) = key.strip_prefix("profile.")?.rsplit_once('.')?;
+    (!name.is_empty() && setting != "profile" && SETTING_KEYS.contains(&setting)).then_some((name, setting))
+}
+
+/// The setting a key sets, whether it is top-level or in a profile.
+pub(crate) fn setting_key(key: &str) -> &str {
+    profile_key(key).map_or(key, |(_, setting)| setting)
+}
+
+pub(crate) fn known_key(key: &str) -> bool {
+    SETTING_KEYS.contains(&key) || profile_key(key).is_some()
+}
+
+/// The nearest `.ask` or `.ask.toml` file at or above `dir`. The ~/.ask
+/// data directory is a directory, so it never counts.
+pub(crate) fn find_project_config(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .flat_map(|dir| PROJECT_CONFIG_NAMES.iter().map(move |name| dir.join(name)))
+        .find(|path| path.is_file())
+}
+
+/// `file` (relative to the project's `dir`) when it is a file that is still
+/// inside the project once symlinks and `..` are followed.
+pub(crate) fn project_file(dir: &Path, file: &str) -> Option<PathBuf> {
+    let dir = dir.canonicalize().ok()?;
+    let path = dir.join(file).canonicalize().ok()?;
+    (path.starts_with(&dir) && path.is_file()).then_some(path)
+}
+
+/// Settings from a TOML file: top-level keys whose values are strings,
+/// numbers, booleans, or arrays of those (one value each, for the
+/// repeatable keys). Numbers and booleans come back as their text, so
+/// `paste_guard = false` reads like `paste_guard=false`. Keys under
+/// `[profile.NAME]` come back as `profile.NAME.KEY`, and `[profile]`'s
+/// `default` as `profile`; other tables and values of other types are
+/// skipped with a warning. A file that isn't valid TOML is one warning.
+pub(crate) fn parse_toml_settings(text: &str) -> (Vec<(String, String)>, Vec<String>) {
+    let mut pairs = Vec::new();
+    let mut warnings = Vec::new();
+    let table = match text.parse::<toml::Table>() {
+        Ok(table) => table,
+        Err(err) => {
+            let line = err.span().map_or(1, |span| text[..span.start].lines().count().max(1));
+            let message = err.message().lines().collect::<Vec<_>>().join("; ");
+            return (pairs, vec![format!("line {line}: {message} (nothing in the file was read)")]);
+        }
+    };
+    for (key, value) in table {
+        match value {
+            toml::Value::Table(profiles) if key == "profile" => {
+                for (name, value) in profiles {
+                    match value {
+                        toml::Value::Table(values) => {
+                            for (key, value) in values {
+                                toml_pairs(format!("profile.{name}.{key}"), value, &mut pairs, &mut warnings);
+                            }
+                        }
+                        value if name == "default" => toml_pairs("profile".to_string(), value, &mut pairs, &mut warnings),
+                        _ => warnings.push(format!("profile.{name}: expected a [profile.{name}] table or default = NAME")),
+                    }
+                }
+            }
+            toml::Value::Table(_) => warnings.push(format!(
+                "keys under [{key}] are ignored; settings are top-level keys or under [profile.NAME]"
+            )),
+            value => toml_pairs(key, value, &mut pairs, &mut warnings),
+        }
+    }
+    (pairs, warnings)
+}
+
+/// The texts of one TOML value under `key`: one for a scalar, one per item
+/// for an array of a repeatable key.
+fn toml_pairs(key: String, value: toml::Value, pairs: &mut Vec<(String, String)>, warnings: &mut Vec<String>) {
+    let values = match value {
+        toml::Value::Array(items) if !REPEATABLE_CONFIG_KEYS.contains(&setting_key(&key)) => {
+            warnings.push(format!("{key} takes a single value, not an array ({} given)", items.len()));
+            return;
+        }
+        toml::Value::Array(items) => items,
+        value => vec![value],
+    };
+    for value in values {
+        match value {
+            toml::Value::String(text) => pairs.push((key.clone(), text)),
+            toml::Value::Integer(number) => pairs.push((key.clone(), number.to_string())),
+            toml::Value::Float(number) => pairs.push((key.clone(), number.to_string())),
+            toml::Value::Boolean(flag) => pairs.push((key.clone(), flag.to_string())),
+            other => warnings.push(format!("{key}: expected a string, a number or true/false, found {}", other.type_str())),
+        }
+    }
+}
+
+/// `value` as TOML: a boolean or number when it reads as one, otherwise a
+/// string.
+pub(crate) fn toml_setting(value: &str) -> toml::Value {
+    match value {
+        "true" => toml::Value::Boolean(true),
+        "false" => toml::Value::Boolean(false),
+        _ => match (value.parse::<i64>(), value.parse::<f64>()) {
+            (Ok(number), _) => toml::Value::Integer(number),
+            (_, Ok(number)) if number.is_finite() && value.contains('.') => toml::Value::Float(number),
+            _ => toml::Value::String(value.to_string()),
+        },
+    }
+}
+
+/// One settings file: TOML when its name ends in `.toml`, otherwise the
+/// older `key=value` lines. A missing file is the same as an empty one.
+/// Unknown keys are left out with a warning, as are lines that don't parse.
+pub(crate) fn read_settings_file(path: &Path, origin: Origin) -> (SettingsLayer, Vec<String>) {
+    let text = fs::read_to_string(path).unwrap_or_default();
+    let (layer, pairs, mut warnings) = if path.extension().is_some_and(|ext| ext == "toml") {
+        let (pairs, warnings) = parse_toml_settings(&text);
+        let values = pairs.iter().filter(|(key, _)| known_key(key)).cloned().collect();
+        (SettingsLayer { origin, values }, pairs, warnings)
+    } else {
+        (SettingsLayer::from_text(origin, &text), key_value_lines(&text), Vec::new())
+    };
+    for (key, _) in pairs.iter().filter(|(key, _)| !known_key(key)) {
+        warnings.push(format!("unknown key {key} (ignored)"));
+    }
+    // A bad color is dropped like any invalid value, but said out loud
+    for (key, value) in layer.values.iter().filter(|(key, _)| COLOR_KEYS.contains(&setting_key(key))) {
+        if let Err(err) = parse_color(value) {
+            warnings.push(format!("{key}: {err} (ignored)"));
+        }
+    }
+    let warnings = warnings.into_iter().map(|warning| format!("{}: {warning}", path.display())).collect();
+    (layer, warnings)
+}
+
+/// The `key=value` lines of a file, skipping `#` comments.
+pub(crate) fn key_value_lines(text: &str) -> Vec<(String, String)> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+/// Raw `key=value` pairs from one source, in the order they appeared.
+pub(crate) struct SettingsLayer {
+    pub(crate) origin: Origin,
+    pub(crate) values: Vec<(String, String)>,
+}
+
+impl SettingsLayer {
+    pub(crate) fn defaults() -> Self {
+        let values = [
+            ("theme", ThemeMode::Dark.as_str().to_string()),
+            ("model", DEFAULT_MODEL.to_string()),
+            ("api_url", DEFAULT_API_URL.to_string()),
+            ("provider", "openai".to_string()),
+            ("paste_guard", "on".to_string()),
+            ("prompt_git", "on".to_string()),
+            ("session_max_turns", DEFAULT_SESSION_LIMITS.max_turns.to_string()),
+            ("session_max_bytes", DEFAULT_SESSION_LIMITS.max_bytes.to_string()),
+            ("max_auto_commands", DEFAULT_MAX_AUTO_COMMANDS.to_string()),
+            ("redact", "on".to_string()),
+            ("env_bootstrap", "on".to_string()),
+            ("persistent_shell", "off".to_string()),
+            ("auto_context", "off".to_string()),
+            ("structured", "off".to_string()),
+            ("tools", "on".to_string()),
+            ("tokenizer", "cl100k".to_string()),
+            ("retry_attempts", DEFAULT_RETRY_ATTEMPTS.to_string()),
+            ("transcript_output_lines", DEFAULT_TRANSCRIPT_OUTPUT_LINES.to_string()),
+            ("max_output_bytes", DEFAULT_MAX_OUTPUT_BYTES.to_string()),
+            ("on_hangup", "forward".to_string()),
+            ("pager", "auto".to_string()),
+            ("auto_fix", "off".to_string()),
+            ("auto_fix_attempts", DEFAULT_AUTO_FIX_ATTEMPTS.to_string()),
+            ("auto_run", "on".to_string()),
+        ];
+        Self {
+            origin: Origin::Default,
+            values: values.into_iter().map(|(k, v)| (k.to_string(), v)).collect(),
+        }
+    }
+
+    /// ~/.ask/config.toml, or the older ~/.ask/config until that has been
+    /// migrated. Also returns a warning per line left out.
+    pub(crate) fn global() -> (Self, Vec<String>) {
+        read_settings_file(&global_config_file(), Origin::Global)
+    }
+
+    /// `key=value` lines, the format before config.toml. Unknown keys are
+    /// dropped.
+    pub(crate) fn from_text(origin: Origin, text: &str) -> Self {
+        let values = key_value_lines(text)
+            .into_iter()
+            .filter(|(key, _)| known_key(key))
+            .collect();
+        Self { origin, values }
+    }
+
+    /// A project's `.ask` or `.ask.toml`. Keys that could send the API key
+    /// or unredacted output somewhere else, or relax the root posture, are
+    /// only read from the user's own config, as are profiles, and
+    /// `context=@FILE` must name a file inside the project. Also returns a
+    /// warning per line left out.
+    pub(crate) fn project(path: &Path) -> (Self, Vec<String>) {
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let (mut layer, mut warnings) = read_settings_file(path, Origin::Workspace);
+        layer.values.retain_mut(|(key, value)| {
+            if PROJECT_LOCKED_KEYS.contains(&key.as_str()) {
+                warnings.push(format!("{}: {key} is ignored in project files; set it in ~/.ask/config.toml", path.display()));
+                return false;
+            }
+            if profile_key(key).is_some() {
+                warnings.push(format!("{}: {key} is ignored; profiles are read from ~/.ask/config.toml only", path.display()));
+                return false;
+            }
+            if key == "context"
+                && let Some(file) = value.strip_prefix('@')
+            {
+                let Some(file) = project_file(dir, file) else {
+                    warnings.push(format!("{}: {value} is not a file inside the project; ignored", path.display()));
+                    return false;
+                };
+                *value = format!("@{}", file.display());
+            }
+            true
+        });
+        (layer, warnings)
+    }
+
+    /// `ASK_<KEY>` overrides any key, e.g. ASK_MODEL or ASK_API_URL.
+    pub(crate) fn env() -> Self {
+        let values = SETTING_KEYS
+            .iter()
+            .filter_map(|key| {
+                let value = env::var(format!("ASK_{}", key.to_uppercase())).ok()?;
+                Some((key.to_string(), value.trim().to_string()))
+            })
+            .collect();
+        Self {
+            origin: Origin::Env,
+            values,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Setting {
+    pub(crate) value: String,
+    pub(crate) origin: Origin,
+}
+
+/// The effective settings after layering every source. The rest of the
+/// program reads configuration from here and nowhere else.
+pub(crate) struct Settings {
+    // One entry per key, except repeatable keys which collect every value
+    pub(crate) values: std::collections::BTreeMap<String, Vec<Setting>>,
+    // Overridden or rejected values, shown when ASK_DEBUG is set
+    pub(crate) notes: Vec<String>,
+    // The project file in the workspace layer, if one was found
+    pub(crate) project: Option<PathBuf>,
+    // Config file lines that were left out, shown at startup
+    pub(crate) warnings: Vec<String>,
+    // The flag layer, kept so the settings can be loaded again after a cd
+    pub(crate) flags: Vec<(String, String)>,
+}
+
+impl Settings {
+    /// Applies `layers` in precedence order (flags > env > workspace >
+    /// profile > global > defaults) regardless of the order they are passed
+    /// in. Within one layer the last line for a key wins. Invalid values are
+    /// skipped so a typo falls back to the next source down.
+    pub(crate) fn resolve(layers: &[SettingsLayer]) -> Self {
+        let mut ordered: Vec<&SettingsLayer> = layers.iter().collect();
+        ordered.sort_by_key(|layer| layer.origin);
+
+        let mut values: std::collections::BTreeMap<String, Vec<Setting>> = Default::default();
+        let mut notes = Vec::new();
+        for layer in ordered {
+            for (key, value) in &layer.values {
+                if !valid_setting(setting_key(key), value) {
+                    notes.push(format!("ignoring {}={} from {}", key, value, layer.origin.label()));
+                    continue;
+                }
+                let setting = Setting {
+                    value: value.clone(),
+                    origin: layer.origin,
+                };
+                let entry = values.entry(key.clone()).or_default();
+                if REPEATABLE_CONFIG_KEYS.contains(&setting_key(key)) {
+                    if !entry.iter().any(|s| s.value == *value) {
+                        entry.push(setting);
+                    }
+                    continue;
+                }
+                if let Some(previous) = entry.first()
+                    && previous.origin != Origin::Default
+                    && previous.origin != layer.origin
+                    && previous.value != *value
+                {
+                    notes.push(format!(
+                        "{}: {} value '{}' overrides {} value '{}'",
+                        key,
+                        layer.origin.label(),
+                        value,
+                        previous.origin.label(),
+                        previous.value
+                    ));
+                }
+                *entry = vec![setting];
+            }
+        }
+        Self { values, notes, project: None, warnings: Vec::new(), flags: Vec::new() }
+    }
+
+    /// Every source as seen from `dir`: the defaults, ~/.ask/config.toml, the
+    /// nearest project file, ASK_* variables and `flags`, and the profile
+    /// the `profile` key names. An unknown profile is left out with a
+    /// warning.
+    pub(crate) fn load(flags: Vec<(String, String)>, dir: &Path) -> Self {
+        let project = find_project_config(dir);
+        let (global, mut warnings) = SettingsLayer::global();
+        let mut layers = vec![
+            SettingsLayer::defaults(),
+            global,
+            SettingsLayer::env(),
+            SettingsLayer {
+                origin: Origin::Flag,
+                values: flags.clone(),
+            },
+        ];
+        if let Some(path) = &project {
+            let (layer, left_out) = SettingsLayer::project(path);
+            layers.push(layer);
+            warnings.extend(left_out);
+        }
+        let (mut settings, unknown) = Self::resolve_with_profile(layers);
+        warnings.extend(unknown);
+        settings.project = project;
+        settings.warnings = warnings;
+        settings.flags = flags;
+        settings
+    }
+
+    /// `layers` resolved, then again with the keys of the profile they name
+    /// (the `profile` setting) as the profile layer. Also returns a warning
+    /// when that profile doesn't exist.
+    pub(crate) fn resolve_with_profile(mut layers: Vec<SettingsLayer>) -> (Self, Option<String>) {
+        let settings = Self::resolve(&layers);
+        let Some(name) = settings.value("profile") else {
+            return (settings, None);
+        };
+        if let Err(err) = settings.check_profile(name) {
+            return (settings, Some(format!("{err} (ignored)")));
+        }
+        layers.push(SettingsLayer { origin: Origin::Profile, values: settings.profile_values(name) });
+        (Self::resolve(&layers), None)
+    }
+
+    /// The same flags, loaded again for another directory.
+    pub(crate) fn for_dir(&self, dir: &Path) -> Self {
+        Self::load(self.flags.clone(), dir)
+    }
+
+    /// The same flags with profile `name` instead, loaded for `dir`.
+    pub(crate) fn with_profile(&self, name: &str, dir: &Path) -> Result<Self, String> {
+        self.check_profile(name)?;
+        let mut flags: Vec<(String, String)> = self.flags.iter().filter(|(key, _)| key != "profile").cloned().collect();
+        flags.push(("profile".to_string(), name.to_string()));
+        Ok(Self::load(flags, dir))
+    }
+
+    /// The names of the `[profile.NAME]` tables, sorted.
+    pub(crate) fn profiles(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.values.keys().filter_map(|key| profile_key(key)).map(|(name, _)| name).collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// The profile in use: the `profile` setting, when that profile exists.
+    pub(crate) fn profile(&self) -> Option<&str> {
+        self.value("profile").filter(|name| self.profiles().contains(name))
+    }
+
+    /// The keys profile `name` sets, as `key=value` pairs.
+    pub(crate) fn profile_values(&self, name: &str) -> Vec<(String, String)> {
+        self.values
+            .iter()
+            .filter_map(|(key, all)| profile_key(key).filter(|(profile, _)| *profile == name).map(|(_, key)| (key, all)))
+            .flat_map(|(key, all)| all.iter().map(move |s| (key.to_string(), s.value.clone())))
+            .collect()
+    }
+
+    pub(crate) fn check_profile(&self, name: &str) -> Result<(), String> {
+        let names = self.profiles();
+        if names.contains(&name) {
+            return Ok(());
+        }
+        if names.is_empty() {
+            return Err(format!("Unknown profile '{name}'; none are defined (add a [profile.NAME] table to ~/.ask/config.toml)"));
+        }
+        Err(format!("Unknown profile '{name}'; available: {}", names.join(", ")))
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<&Setting> {
+        self.values.get(key).and_then(|v| v.first())
+    }
+
+    pub(crate) fn value(&self, key: &str) -> Option<&str> {
+        self.get(key).map(|s| s.value.as_str())
+    }
+
+    pub(crate) fn theme(&self) -> ThemeMode {
+        self.value("theme").and_then(ThemeMode::from_str).unwrap_or(ThemeMode::Dark)
+    }
+
+    /// The `*_color` overrides as escape sequences, for Theme::with_colors.
+    pub(crate) fn theme_colors(&self) -> Vec<(&'static str, String)> {
+        COLOR_KEYS
+            .iter()
+            .filter_map(|key| self.value(key).and_then(|spec| parse_color(spec).ok()).map(|color| (*key, color)))
+            .collect()
+    }
+
+    pub(crate) fn model(&self) -> String {
+        self.value("model").unwrap_or(DEFAULT_MODEL).to_string()
+    }
+
+    /// The set api_url, or where the provider usually listens when none was.
+    pub(crate) fn api_url(&self) -> String {
+        match self.get("api_url") {
+            Some(setting) if setting.origin != Origin::Default => setting.value.clone(),
+            _ => self.provider().default_url().to_string(),
+        }
+    }
+
+    pub(crate) fn provider(&self) -> Provider {
+        self.provider_for(&self.model())
+    }
+
+    /// The provider `model` runs on. An ANTHROPIC_API_KEY, set or stored
+    /// in the keychain, only reroutes `anthropic/` models while no provider
+    /// was set on purpose, so `--provider openai` keeps them on OpenRouter.
+    pub(crate) fn provider_for(&self, model: &str) -> Provider {
+        let chosen = self.chosen_provider();
+        Provider::for_model(model, chosen.unwrap_or(Provider::OpenAi), chosen.is_none() && anthropic_key().is_some())
+    }
+
+    /// The provider set by a flag, the environment or a config file.
+    pub(crate) fn chosen_provider(&self) -> Option<Provider> {
+        self.get("provider").filter(|setting| setting.origin != Origin::Default).and_then(|setting| Provider::from_str(&setting.value))
+    }
+
+    /// `fallback_models=a,b`: the models to try, in order, when the model
+    /// keeps failing.
+    pub(crate) fn fallback_models(&self) -> Vec<String> {
+        self.value("fallback_models")
+            .map(|v| v.split(',').map(|m| m.trim().to_string()).filter(|m| !m.is_empty()).collect())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn paste_guard(&self) -> bool {
+        !matches!(self.value("paste_guard"), Some("off" | "false" | "0"))
+    }
+
+    pub(crate) fn prompt_git(&self) -> bool {
+        !matches!(self.value("prompt_git"), Some("off" | "false" | "0"))
+    }
+
+    pub(crate) fn number(&self, key: &str, default: usize) -> usize {
+        self.value(key).and_then(|v| v.parse().ok()).unwrap_or(default)
+    }
+
+    pub(crate) fn session_limits(&self) -> SessionLimits {
+        SessionLimits {
+            max_turns: self.number("session_max_turns", DEFAULT_SESSION_LIMITS.max_turns).max(1),
+            max_bytes: self.number("session_max_bytes", DEFAULT_SESSION_LIMITS.max_bytes),
+        }
+    }
+
+    pub(crate) fn max_auto_commands(&self) -> usize {
+        self.number("max_auto_commands", DEFAULT_MAX_AUTO_COMMANDS)
+    }
+
+    pub(crate) fn temperature(&self) -> Option<f64> {
+        self.value("temperature").and_then(|v| v.parse().ok())
+    }
+
+    pub(crate) fn top_p(&self) -> Option<f64> {
+        self.value("top_p").and_then(|v| v.parse().ok())
+    }
+
+    pub(crate) fn max_tokens(&self) -> Option<u32> {
+        self.value("max_tokens").and_then(|v| v.parse().ok())
+    }
+
+    pub(crate) fn warn_cost(&self) -> Option<f64> {
+        self.value("warn_cost").and_then(|v| v.parse().ok())
+    }
+
+    pub(crate) fn retry_attempts(&self) -> u32 {
+        self.number("retry_attempts", DEFAULT_RETRY_ATTEMPTS as usize) as u32
+    }
+
+    pub(crate) fn transcript_output_lines(&self) -> usize {
+        self.number("transcript_output_lines", DEFAULT_TRANSCRIPT_OUTPUT_LINES)
+    }
+
+    /// Bytes of each command's stdout and stderr kept; 0 keeps everything.
+    pub(crate) fn max_output_bytes(&self) -> usize {
+        self.number("max_output_bytes", DEFAULT_MAX_OUTPUT_BYTES)
+    }
+
+    pub(crate) fn audit_log(&self) -> Option<PathBuf> {
+        self.value("audit_log").map(expand_path)
+    }
+
+    pub(crate) fn root_ok(&self) -> bool {
+        matches!(self.value("root_ok"), Some("on" | "true" | "1"))
+    }
+
+    pub(crate) fn dangerous_patterns(&self) -> Vec<String> {
+        self.all("dangerous_pattern")
+    }
+
+    pub(crate) fn refusal_patterns(&self) -> Vec<String> {
+        self.all("refusal_pattern")
+    }
+
+    pub(crate) fn token_counter(&self) -> &'static dyn CountTokens {
+        match self.value("tokenizer") {
+            Some("heuristic") => &ByteHeuristic,
+            _ => &Cl100kCounter,
+        }
+    }
+
+    pub(crate) fn hangup_mode(&self) -> HangupMode {
+        match self.value("on_hangup") {
+            Some("detach") => HangupMode::Detach,
+            _ => HangupMode::Forward,
+        }
+    }
+
+    pub(crate) fn pager_mode(&self) -> PagerMode {
+        self.value("pager").and_then(PagerMode::from_str).unwrap_or(PagerMode::Auto)
+    }
+
+    pub(crate) fn persistent_shell(&self) -> bool {
+        matches!(self.value("persistent_shell"), Some("on" | "true" | "1"))
+    }
+
+    pub(crate) fn auto_context(&self) -> bool {
+        matches!(self.value("auto_context"), Some("on" | "true" | "1"))
+    }
+
+    pub(crate) fn structured(&self) -> bool {
+        matches!(self.value("structured"), Some("on" | "true" | "1"))
+    }
+
+    pub(crate) fn tools(&self) -> bool {
+        !matches!(self.value("tools"), Some("off" | "false" | "0"))
+    }
+
+    pub(crate) fn auto_fix(&self) -> bool {
+        matches!(self.value("auto_fix"), Some("on" | "true" | "1"))
+    }
+
+    /// Repairs to ask for when a command fails: auto_fix_attempts, or 0
+    /// unless auto_fix is on.
+    pub(crate) fn fix_attempts(&self) -> usize {
+        if !self.auto_fix() {
+            return 0;
+        }
+        self.number("auto_fix_attempts", DEFAULT_AUTO_FIX_ATTEMPTS).max(1)
+    }
+
+    pub(crate) fn env_bootstrap(&self) -> bool {
+        !matches!(self.value("env_bootstrap"), Some("off" | "false" | "0"))
+    }
+
+    pub(crate) fn auto_run(&self) -> bool {
+        !matches!(self.value("auto_run"), Some("off" | "false" | "0"))
+    }
+
+    /// The `context=` lines for the prompt, one per line, with `@FILE`
+    /// values replaced by the file's text (cut at MAX_CONTEXT_BYTES). A file
+    /// that can't be read is left out.
+    pub(crate) fn context(&self) -> Option<String> {
+        let parts: Vec<String> = self
+            .all("context")
+            .into_iter()
+            .filter_map(|value| match value.strip_prefix('@') {
+                Some(file) => fs::read_to_string(expand_path(file))
+                    .ok()
+                    .map(|text| truncate_at_char_boundary(text.trim(), MAX_CONTEXT_BYTES).to_string()),
+                None => Some(value),
+            })
+            .filter(|part| !part.is_empty())
+            .collect();
+        (!parts.is_empty()).then(|| parts.join("\n"))
+    }
+
+    pub(crate) fn prompt_file(&self) -> Option<PathBuf> {
+        self.value("prompt_file").map(expand_path)
+    }
+
+    /// The prompt_file's template or system message, if one is set.
+    pub(crate) fn custom_prompt(&self) -> Result<Option<CustomPrompt>, String> {
+        self.prompt_file().map(|path| CustomPrompt::load(&path)).transpose()
+    }
+
+    /// `extra_prompt=`, usually from a profile: added to every prompt.
+    pub(crate) fn extra_prompt(&self) -> Option<String> {
+        self.value("extra_prompt").map(str::to_string)
+    }
+
+    pub(crate) fn redact(&self) -> bool {
+        !matches!(self.value("redact"), Some("off" | "false" | "0"))
+    }
+
+    pub(crate) fn redaction(&self) -> Redaction {
+        Redaction::new(self.redact(), &self.all("redact_pattern"))
+    }
+
+    /// Every value of a repeatable key.
+    pub(crate) fn all(&self, key: &str) -> Vec<String> {
+        self.values
+            .get(key)
+            .map(|all| all.iter().map(|s| s.value.clone()).collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Checks a sampling parameter against the range providers accept, so a
+/// bad value is caught here instead of coming back as an API 400.
+pub(crate) fn sampling_value(key: &str, value: &str) -> Result<(), String> {
+    let in_range = match key {
+        "temperature" => value.parse::<f64>().is_ok_and(|t| (0.0..=2.0).contains(&t)),
+        "top_p" => value.parse::<f64>().is_ok_and(|p| p > 0.0 && p <= 1.0),
+        _ => value.parse::<u32>().is_ok_and(|n| n >= 1),
+    };
+    let expected = match key {
+        "temperature" => "a number from 0 to 2",
+        "top_p" => "a number above 0 and at most 1",
+        _ => "a whole number of at least 1",
+    };
+    if in_range {
+        Ok(())
+    } else {
+        Err(format!("{key} must be {expected}, got '{value}'"))
+    }
+}
+
+pub(crate) fn valid_setting(key: &str, value: &str) -> bool {
+    match key {
+        "theme" => ThemeMode::from_str(value).is_some(),
+        "provider" => Provider::from_str(value).is_some(),
+        "fallback_models" => value.split(',').all(|m| !m.trim().is_empty() && !m.trim().contains(char::is_whitespace)),
+        "paste_guard" | "prompt_git" | "root_ok" | "redact" | "env_bootstrap" | "persistent_shell" | "auto_fix"
+        | "auto_run" | "auto_context" | "structured" | "tools" => {
+            matches!(value, "on" | "off" | "true" | "false" | "1" | "0")
+        }
+        "session_max_turns" | "session_max_bytes" | "max_auto_commands" | "transcript_output_lines"
+        | "max_output_bytes" => {
+            value.parse::<usize>().is_ok()
+        }
+        "tokenizer" => matches!(value, "cl100k" | "heuristic"),
+        "on_hangup" => matches!(value, "forward" | "detach"),
+        "pager" => PagerMode::from_str(value).is_some(),
+        "retry_attempts" => value.parse::<u32>().is_ok_and(|n| n >= 1),
+        "auto_fix_attempts" => value.parse::<usize>().is_ok_and(|n| n >= 1),
+        "temperature" | "top_p" | "max_tokens" => sampling_value(key, value).is_ok(),
+        "warn_cost" => value.parse::<f64>().is_ok_and(|cost| cost.is_finite() && cost >= 0.0),
+        key if COLOR_KEYS.contains(&key) => parse_color(value).is_ok(),
+        _ => !value.is_empty(),
+    }
+}
+
+/// `ask doctor`: how commands will be run on this machine.
+pub(crate) fn doctor(settings: &Settings, theme: &Theme) {
+    let data = resolved_data_dir();
+    let writable = |writable: bool| if writable { "writable" } else { "NOT writable" };
+    println!("data dir: {} ({}, {})", data.path.display(), data.source, writable(data.writable));
+    println!("config: {}", global_config_file().display());
+    let spill = env::temp_dir();
+    println!("session spill: {} ({})", spill.display(), writable(dir_is_writable(&spill)));
+    if let Some(log) = settings.audit_log() {
+        let dir = log.parent().unwrap_or(Path::new("."));
+        println!("audit log: {} ({})", log.display(), writable(dir_is_writable(dir)));
+    }
+    let shell = user_shell();
+    println!("shell: {}", shell);
+    if !settings.env_bootstrap() {
+        println!("env bootstrap: off {}", theme.helper_text("(env_bootstrap=off; commands get ask's own environment)"));
+        return;
+    }
+    let captured = ShellEnv::capture(&shell);
+    if let Some(error) = &captured.error {
+        println!("env bootstrap: failed {}", theme.helper_text(&format!("({error})")));
+        return;
+    }
+    println!("env bootstrap: on");
+    let own = env::var("PATH").ok();
+    for (name, value) in captured.overrides(|name| env::var(name).ok()) {
+        println!("{}={}", name, value);
+        if name == "PATH"
+            && let Some(own) = &own
+        {
+            let added: Vec<&str> = value.split(':').filter(|e| !own.split(':').any(|o| o == *e)).collect();
+            if !added.is_empty() {
+                println!("{}", theme.helper_text(&format!("  added from the login shell: {}", added.join(":"))));
+            }
+        }
+    }
+}
+
+/// `ask profiles`: each `[profile.NAME]` table and what it sets, with the
+/// one in use marked.
+pub(crate) fn show_profiles(settings: &Settings, theme: &Theme) {
+    let names = settings.profiles();
+    if names.is_empty() {
+        println!("{}", theme.helper_text("No profiles. Add a [profile.NAME] table to ~/.ask/config.toml"));
+        return;
+    }
+    for name in names {
+        let active = if settings.profile() == Some(name) { " (active)" } else { "" };
+        println!("{}{}", name, theme.helper_text(active));
+        for (key, value) in settings.profile_values(name) {
+            println!("  {key} = {}", toml_setting(&value));
+        }
+    }
+}
+
+/// `ask config show [--origins]`: the effective value of every key, and
+/// with --origins the source that set it.
+pub(crate) fn show_config(settings: &Settings, origins: bool, theme: &Theme) {
+    if origins {
+        let order: Vec<&str> = ORIGIN_PRECEDENCE.iter().map(|o| o.label()).collect();
+        println!("{}", theme.helper_text(&format!("Precedence: {}", order.join(" > "))));
+    }
+    for key in SETTING_KEYS {
+        for setting in settings.values.get(*key).into_iter().flatten() {
+            if origins {
+                println!("{}={}  {}", key, setting.value, theme.helper_text(&format!("({})", setting.origin.label())));
+            } else {
+                println!("{}={}", key, setting.value);
+            }
+        }
+    }
+}
+
+/// Where ask keeps its files, and which source picked it.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct DataDir {
+    pub(crate) path: PathBuf,
+    pub(crate) source: &'static str,
+    pub(crate) writable: bool,
+}
+
+impl DataDir {
+    /// --data-dir or $ASK_DATA_DIR when given, writable or not. Otherwise
+    /// the first usable one of ~/.ask, $XDG_DATA_HOME/ask,
+    /// $XDG_CONFIG_HOME/ask and `temp`, where usable means a file can be
+    /// written in it or it can be created. Only the one picked is created;
+    /// `temp` must also pass private_dir. When none is usable, ~/.ask (or
+    /// the first candidate) is still returned: reads work and every write
+    /// fails with a warning.
+    pub(crate) fn resolve(flag: Option<&Path>, home: Option<PathBuf>, var: impl Fn(&str) -> Option<String>, temp: PathBuf) -> Self {
+        let var = |name: &str| var(name).filter(|v| !v.is_empty()).map(PathBuf::from);
+        let explicit = match (flag, var("ASK_DATA_DIR")) {
+            (Some(path), _) => Some((path.to_path_buf(), "--data-dir")),
+            (None, Some(path)) => Some((path, "ASK_DATA_DIR")),
+            (None, None) => None,
+        };
+        if let Some((path, source)) = explicit {
+            let writable = dir_is_writable(&path) && fs::create_dir_all(&path).is_ok();
+            return Self { path, source, writable };
+        }
+        let candidates = [
+            (home.map(|home| home.join(".ask")), "home directory"),
+            (var("XDG_DATA_HOME").map(|dir| dir.join("ask")), "XDG_DATA_HOME"),
+            (var("XDG_CONFIG_HOME").map(|dir| dir.join("ask")), "XDG_CONFIG_HOME"),
+            (Some(temp), TEMP_DATA_DIR),
+        ];
+        let candidates: Vec<(PathBuf, &'static str)> =
+            candidates.into_iter().filter_map(|(path, source)| Some((path?, source))).collect();
+        candidates
+            .iter()
+            .find(|(path, source)| match *source {
+                TEMP_DATA_DIR => private_dir(path).is_ok(),
+                _ => dir_is_writable(path) && fs::create_dir_all(path).is_ok(),
+            })
+            .map(|(path, source)| Self { path: path.clone(), source, writable: true })
+            .unwrap_or_else(|| {
+                let (path, source) = candidates[0].clone();
+                Self { path, source, writable: false }
+            })
+    }
+
+    pub(crate) fn from_env(flag: Option<&Path>) -> Self {
+        let uid = unsafe { libc::geteuid() };
+        Self::resolve(flag, dirs::home_dir(), |name| env::var(name).ok(), env::temp_dir().join(format!("ask-{uid}")))
+    }
+
+    /// The one-time warning for a data directory that won't last or can't
+    /// be written, if any.
+    pub(crate) fn warning(&self) -> Option<String> {
+        if !self.writable {
+            Some(format!("Warning: {} is not writable; history, notes and settings will not be saved", self.path.display()))
+        } else if self.source == TEMP_DATA_DIR {
+            Some(format!("Warning: no home directory; keeping data in {} for now (set --data-dir or ASK_DATA_DIR)", self.path.display()))
+        } else {
+            None
+        }
+    }
+}
+
+// DataDir::source for the last resort; files there may not outlive a reboot
+pub(crate) const TEMP_DATA_DIR: &str = "temporary directory";
+
+// Resolved once, after --data-dir is parsed
+pub(crate) static DATA_DIR: std::sync::OnceLock<DataDir> = std::sync::OnceLock::new();
+
+pub(crate) fn resolved_data_dir() -> &'static DataDir {
+    DATA_DIR.get_or_init(|| DataDir::from_env(None))
+}
+
+pub(crate) fn data_dir() -> PathBuf {
+    resolved_data_dir().path.clone()
+}
+
+/// Whether a file can be written in `dir`, or, when it doesn't exist yet,
+/// whether it could be created. Nothing is created.
+pub(crate) fn dir_is_writable(dir: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    let existing = dir
+        .ancestors()
+        .map(|d| if d.as_os_str().is_empty() { Path::new(".") } else { d })
+        .find(|d| d.exists());
+    let Some(existing) = existing else {
+        return false;
+    };
+    let Ok(path) = std::ffi::CString::new(existing.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // With the effective uid, as the writes themselves will be
+    existing.is_dir()
+        && unsafe { libc::faccessat(libc::AT_FDCWD, path.as_ptr(), libc::W_OK | libc::X_OK, libc::AT_EACCESS) } == 0
+}
+
+/// Creates `dir` readable by this user alone, or checks that the one
+/// already there is a real directory owned by this user with mode 0700.
+/// For names in the shared temp directory, which anyone could make first.
+pub(crate) fn private_dir(dir: &Path) -> io::Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+    match fs::DirBuilder::new().mode(0o700).create(dir) {
+        Ok(()) => return Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(err) => return Err(err),
+    }
+    let meta = fs::symlink_metadata(dir)?;
+    let uid = unsafe { libc::geteuid() };
+    if !meta.is_dir() || meta.uid() != uid || meta.mode() & 0o777 != 0o700 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is not a directory of this user's alone (mode 0700)", dir.display()),
+        ));
+    }
+    Ok(())
+}
+
+/// Makes a new directory of this user's alone (mode 0700) in the temp
+/// directory, named `{prefix}-` plus random hex. Never reuses one that
+/// already exists, so nobody can prepare it in advance.
+pub(crate) fn staging_dir(prefix: &str) -> io::Result<PathBuf> {
+    use std::io::Read;
+    use std::os::unix::fs::DirBuilderExt;
+    let mut urandom = fs::File::open("/dev/urandom")?;
+    loop {
+        let mut bytes = [0u8; 16];
+        urandom.read_exact(&mut bytes)?;
+        let name: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+        let dir = env::temp_dir().join(format!("{prefix}-{name}"));
+        match fs::DirBuilder::new().mode(0o700).create(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Where the config is saved.
+pub(crate) fn config_path() -> PathBuf {
+    data_dir().join("config.toml")
+}
+
+/// The `key=value` config written before config.toml. It is still read
+/// while config.toml doesn't exist, and set aside as config.old once the
+/// config has been saved in the new format.
+pub(crate) fn legacy_config_path() -> PathBuf {
+    data_dir().join("config")
+}
+
+/// The config file settings are read from.
+pub(crate) fn global_config_file() -> PathBuf {
+    let path = config_path();
+    let legacy = legacy_config_path();
+    if !path.exists() && legacy.is_file() { legacy } else { path }
+}
+
+// Bundle format written by export-settings; bump when the layout changes.
+pub(crate) const BUNDLE_FORMAT: u32 = 1;
+pub(crate) const BUNDLE_MANIFEST: &str = "manifest";
+// Largest single file import will read; ~/.ask holds text, not blobs.
+pub(crate) const MAX_BUNDLE_FILE_BYTES: u64 = 32 << 20;
+// Per-session data is only exported on request; it can be large and personal.
+pub(crate) const SESSION_DATA_DIRS: &[&str] = &["sessions", "history"];
+// key=value files whose entries can be merged line-by-line on import
+pub(crate) const MERGEABLE_FILES: &[&str] = &["config", "config.toml", "aliases"];
+// Config keys that may appear on several lines, each adding a value
+pub(crate) const REPEATABLE_CONFIG_KEYS: &[&str] =
+    &["dangerous_pattern", "redact_pattern", "refusal_pattern", "tty_command", "context"];
+
+/// Packs everything under ~/.ask into a gzipped tarball with a manifest.
+/// API keys never live in ~/.ask (they come from the environment or the
+/// keychain), so nothing secret ends up in the bundle.
+pub(crate) fn export_settings(bundle: &Path, include_sessions: bool, theme: &Theme) -> Result<(), Box<dyn std::error::Error>> {
+    let source = data_dir();
+    let staging = staging_dir("ask-export")?;
+
+    let result = (|| -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+        let mut exported = Vec::new();
+        if source.is_dir() {
+            for rel in collect_files(&source)? {
+                let top = rel.components().next().map(|c| c.as_os_str().to_string_lossy().to_string());
+                if !include_sessions && top.is_some_and(|t| SESSION_DATA_DIRS.contains(&t.as_str())) {
+                    continue;
+                }
+                let dest = staging.join(&rel);
+                if let Some(dir) = dest.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                fs::copy(source.join(&rel), dest)?;
+                exported.push(rel);
+            }
+        }
+
+        let manifest = format!(
+            "schema_version={}\nformat={}\nversion={}\nsessions={}\nexcluded=API keys (environment variables and keychain entries are never exported)\n",
+            SCHEMA_VERSION,
+            BUNDLE_FORMAT,
+            env!("CARGO_PKG_VERSION"),
+            if include_sessions { "included" } else { "excluded" },
+        );
+        fs::write(staging.join(BUNDLE_MANIFEST), manifest)?;
+
+        let status = Command::new("tar")
+            .arg("-czf")
+            .arg(bundle)
+            .arg("-C")
+            .arg(&staging)
+            .arg(".")
+            .status()?;
+        if !status.success() {
+            return Err(format!("tar exited with status {status}").into());
+        }
+        Ok(exported)
+    })();
+    let _ = fs::remove_dir_all(&staging);
+    let exported = result?;
+
+    for rel in &exported {
+        println!("  {}", theme.command_text(&rel.display().to_string()));
+    }
+    println!(
+        "{}",
+        theme.helper_text(&format!("Exported {} file(s) to {}", exported.len(), bundle.display()))
+    );
+    println!(
+        "{}",
+        theme.helper_text("API keys are not included; set OPENROUTER_ASK_API_KEY or run `ask auth login` again on the new machine.")
+    );
+    if !include_sessions {
+        println!("{}", theme.helper_text("Sessions and history were skipped (use --include-sessions)."));
+    }
+    Ok(())
+}
+
+/// Unpacks a bundle made by export-settings into ~/.ask, asking before
+/// replacing any file that already exists with different contents.
+pub(crate) fn import_settings(bundle: &Path, theme: &Theme) -> Result<(), Box<dyn std::error::Error>> {
+    let dest_root = data_dir();
+
+    // Validate every entry before anything touches the disk.
+    let listing = Command::new("tar").arg("-tvzf").arg(bundle).output()?;
+    if !listing.status.success() {
+        return Err(format!("Could not read {}: {}", bundle.display(),
+            String::from_utf8_lossy(&listing.stderr).trim()).into());
+    }
+    for line in String::from_utf8_lossy(&listing.stdout).lines() {
+        if !matches!(line.chars().next(), Some('-') | Some('d')) {
+            return Err(format!("Refusing to import: bundle contains a link or special file: {line}").into());
+        }
+    }
+    let names = Command::new("tar").arg("-tzf").arg(bundle).output()?;
+    for name in String::from_utf8_lossy(&names.stdout).lines() {
+        validate_bundle_path(name)?;
+    }
+
+    let staging = staging_dir("ask-import")?;
+    let result = (|| -> Result<(), Box<dyn std::error::Error>> {
+        let status = Command::new("tar")
+            .arg("-xzf")
+            .arg(bundle)
+            .arg("-C")
+            .arg(&staging)
+            .status()?;
+        if !status.success() {
+            return Err(format!("tar exited with status {status}").into());
+        }
+
+        let manifest = read_bundle_file(&staging.join(BUNDLE_MANIFEST))
+            .map_err(|_| "Not an ask settings bundle (manifest missing)")?;
+        manifest_schema_version(&manifest)?;
+        warn_about_manifest(&manifest, theme);
+
+        let mut imported = 0;
+        for rel in collect_files(&staging)? {
+            if rel == Path::new(BUNDLE_MANIFEST) {
+                continue;
+            }
+            let incoming = read_bundle_file(&staging.join(&rel))
+                .map_err(|err| format!("Refusing to import {}: {err}", rel.display()))?;
+            let dest = dest_root.join(&rel);
+            let contents = match fs::read_to_string(&dest) {
+                Ok(existing) if existing == incoming => continue,
+                Ok(existing) => match ask_import_conflict(&rel, theme)? {
+                    ImportChoice::Keep => continue,
+                    ImportChoice::Overwrite => incoming,
+                    ImportChoice::Merge => merge_settings_file(&rel, &existing, &incoming)?,
+                },
+                Err(_) => incoming,
+            };
+            if let Some(dir) = dest.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::write(&dest, contents)?;
+            println!("  {}", theme.command_text(&rel.display().to_string()));
+            imported += 1;
+        }
+        println!(
+            "{}",
+            theme.helper_text(&format!("Imported {} file(s) into {}", imported, dest_root.display()))
+        );
+        Ok(())
+    })();
+    let _ = fs::remove_dir_all(&staging);
+    result
+}
+
+/// Reads one file unpacked from a bundle, refusing anything over
+/// MAX_BUNDLE_FILE_BYTES rather than loading it all into memory.
+pub(crate) fn read_bundle_file(path: &Path) -> io::Result<String> {
+    use std::io::Read;
+    let mut text = String::new();
+    fs::File::open(path)?.take(MAX_BUNDLE_FILE_BYTES + 1).read_to_string(&mut text)?;
+    if text.len() as u64 > MAX_BUNDLE_FILE_BYTES {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("larger than {} MiB", MAX_BUNDLE_FILE_BYTES >> 20),
+        ));
+    }
+    Ok(text)
+}
+
+/// Rejects absolute paths and anything that could climb out of ~/.ask.
+pub(crate) fn validate_bundle_path(name: &str) -> Result<PathBuf, String> {
+    let trimmed = name.trim_start_matches("./");
+    if trimmed.is_empty() || trimmed == "." {
+        return Ok(PathBuf::new());
+    }
+    let path = Path::new(trimmed);
+    for component in path.components() {
+        match component {
+            std::path::Component::Normal(_) | std::path::Component::CurDir => {}
+            _ => return Err(format!("Refusing to import unsafe path: {name}")),
+        }
+    }
+    Ok(path.to_path_buf())
+}
+
+/// The manifest's schema version. Version 1 manifests have no
+/// `schema_version=` line; their `format=` stands in for it.
+pub(crate) fn manifest_schema_version(manifest: &str) -> Result<u32, String> {
+    let Some(raw) = manifest
+        .lines()
+        .find_map(|line| line.strip_prefix("schema_version="))
+        .map(str::trim)
+    else {
+        return Ok(1);
+    };
+    let version = raw
+        .parse::<u32>()
+        .map_err(|_| format!("Invalid bundle manifest: schema_version={raw}"))?;
+    check_schema_version(version, "This bundle")
+}
+
+pub(crate) fn warn_about_manifest(manifest: &str, theme: &Theme) {
+    let value = |key: &str| {
+        manifest
+            .lines()
+            .find_map(|line| line.strip_prefix(&format!("{key}=")))
+            .map(str::trim)
+    };
+    if let Some(format) = value("format").and_then(|f| f.parse::<u32>().ok())
+        && format > BUNDLE_FORMAT
+    {
+        println!(
+            "{}",
+            theme.helper_text("Warning: this bundle was made by a newer ask; some files may be ignored.")
+        );
+    }
+    if let Some(version) = value("version")
+        && version != env!("CARGO_PKG_VERSION")
+    {
+        println!(
+            "{}",
+            theme.helper_text(&format!("Note: bundle exported from ask {version}, this is {}.", env!("CARGO_PKG_VERSION")))
+        );
+    }
+}
+
+pub(crate) enum ImportChoice {
+    Keep,
+    Overwrite,
+    Merge,
+}
+
+pub(crate) fn ask_import_conflict(rel: &Path, theme: &Theme) -> Result<ImportChoice, io::Error> {
+    let mergeable = rel
+        .to_str()
+        .is_some_and(|name| MERGEABLE_FILES.contains(&name));
+    let options = if mergeable { "[K/o/m]" } else { "[K/o]" };
+    loop {
+        print!(
+            "{} {} already exists.  {}  ",
+            theme.prompt_text("import>"),
+            theme.command_text(&rel.display().to_string()),
+            options
+        );
+        io::stdout().flush()?;
+        match normalize_confirmation_input(&read_confirmation_line()?).as_str() {
+            "" | "k" | "keep" => return Ok(ImportChoice::Keep),
+            "o" | "overwrite" => return Ok(ImportChoice::Overwrite),
+            "m" | "merge" if mergeable => return Ok(ImportChoice::Merge),
+            _ if mergeable => println!("Please use k(eep), o(verwrite), or m(erge)."),
+            _ => println!("Please use k(eep) or o(verwrite)."),
+        }
+    }
+}
+
+/// Merges an imported copy of a mergeable file into the one already there:
+/// config.toml as TOML tables, the others line by line.
+pub(crate) fn merge_settings_file(rel: &Path, existing: &str, incoming: &str) -> Result<String, String> {
+    if rel != Path::new("config.toml") {
+        return Ok(merge_key_value(existing, incoming));
+    }
+    let parse = |text: &str, which: &str| {
+        text.parse::<toml::Table>()
+            .map_err(|err| format!("Can't merge config.toml: the {which} one isn't valid TOML ({})", err.message().trim()))
+    };
+    let mut merged = parse(existing, "existing")?;
+    merge_toml_tables(&mut merged, parse(incoming, "imported")?);
+    toml::to_string(&merged).map_err(|err| format!("Can't merge config.toml: {err}"))
+}
+
+/// Folds `incoming` into `existing`: existing values win, tables such as
+/// `[profile.NAME]` merge key by key, and repeatable keys take the union
+/// of both lists.
+pub(crate) fn merge_toml_tables(existing: &mut toml::Table, incoming: toml::Table) {
+    let as_list = |value: toml::Value| match value {
+        toml::Value::Array(items) => items,
+        value => vec![value],
+    };
+    for (key, value) in incoming {
+        let Some(current) = existing.get_mut(&key) else {
+            existing.insert(key, value);
+            continue;
+        };
+        match (current, value) {
+            (toml::Value::Table(current), toml::Value::Table(value)) => merge_toml_tables(current, value),
+            (current, value) if REPEATABLE_CONFIG_KEYS.contains(&key.as_str()) => {
+                let mut items = as_list(current.clone());
+                for item in as_list(value) {
+                    if !items.contains(&item) {
+                        items.push(item);
+                    }
+                }
+                *current = toml::Value::Array(items);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Merges two key=value files: existing entries win, new keys are appended.
+pub(crate) fn merge_key_value(existing: &str, incoming: &str) -> String {
+    let key_of = |line: &str| line.split_once('=').map(|(k, _)| k.trim().to_string());
+    let existing_keys: Vec<String> = existing.lines().filter_map(key_of).collect();
+    let mut merged = existing.trim_end().to_string();
+    for line in incoming.lines() {
+        match key_of(line) {
+            Some(key) if !existing_keys.contains(&key) => {
+                merged.push('\n');
+                merged.push_str(line);
+            }
+            // List-valued keys take the union of both files
+            Some(key) if REPEATABLE_CONFIG_KEYS.contains(&key.as_str()) && !existing.lines().any(|l| l == line) => {
+                merged.push('\n');
+                merged.push_str(line);
+            }
+            _ => {}
+        }
+    }
+    merged.push('\n');
+    merged
+}
+
+/// Lists regular files under `root` as paths relative to it.
+pub(crate) fn collect_files(root: &Path) -> Result<Vec<PathBuf>, io::Error> {
+    let mut files = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                dirs.push(entry.path());
+            } else if file_type.is_file()
+                && let Ok(rel) = entry.path().strip_prefix(root)
+            {
+                files.push(rel.to_path_buf());
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+
+    // --- settings bundles ---
+
+    #[test]
+    fn validate_bundle_path_accepts_relative_entries() {
+        assert_eq!(validate_bundle_path("./config").unwrap(), PathBuf::from("config"));
+        assert_eq!(validate_bundle_path("sessions/a.json").unwrap(), PathBuf::from("sessions/a.json"));
+        assert_eq!(validate_bundle_path("./").unwrap(), PathBuf::new());
+    }
+
+    #[test]
+    fn validate_bundle_path_rejects_traversal_and_absolute_paths() {
+        for name in ["../evil", "./sessions/../../x", "/etc/passwd", "a/../../b"] {
+            assert!(validate_bundle_path(name).is_err(), "expected rejection: {name}");
+        }
+    }
+
+    #[test]
+    fn staging_dirs_are_private_and_never_reused() {
+        use std::os::unix::fs::PermissionsExt;
+        let first = staging_dir("ask-staging-test").unwrap();
+        let second = staging_dir("ask-staging-test").unwrap();
+        assert_ne!(first, second);
+        assert!(first.file_name().unwrap().to_string_lossy().starts_with("ask-staging-test-"));
+        assert_eq!(fs::metadata(&first).unwrap().permissions().mode() & 0o777, 0o700);
+        fs::remove_dir_all(&first).unwrap();
+        fs::remove_dir_all(&second).unwrap();
+    }
+
+    #[test]
+    fn bundle_files_over_the_cap_are_refused() {
+        let dir = staging_dir("ask-bundle-cap-test").unwrap();
+        let small = dir.join("aliases");
+        fs::write(&small, "ll=ls -l\n").unwrap();
+        assert_eq!(read_bundle_file(&small).unwrap(), "ll=ls -l\n");
+
+        let big = dir.join("history");
+        fs::File::create(&big).unwrap().set_len(MAX_BUNDLE_FILE_BYTES + 1).unwrap();
+        let err = read_bundle_file(&big).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("larger than 32 MiB"), "{err}");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn merge_key_value_keeps_existing_and_appends_new_keys() {
+        let merged = merge_key_value("theme=dark\nmodel=a\n", "theme=light\npaste_guard=off\n");
+        assert_eq!(merged, "theme=dark\nmodel=a\npaste_guard=off\n");
+    }
+
+    #[test]
+    fn merge_key_value_unions_repeatable_keys() {
+        let merged = merge_key_value(
+            "dangerous_pattern=terraform destroy\n",
+            "dangerous_pattern=terraform destroy\ndangerous_pattern=kubectl delete\n",
+        );
+        assert_eq!(merged, "dangerous_pattern=terraform destroy\ndangerous_pattern=kubectl delete\n");
+    }
+
+    #[test]
+    fn merging_config_toml_keeps_tables_and_unions_arrays() {
+        let existing = r#"model = "a"
+dangerous_pattern = ["terraform destroy"]
+
+[profile]
+default = "work"
+
+[profile.work]
+model = "w"
+"#;
+        let incoming = r#"model = "b"
+theme = "light"
+dangerous_pattern = ["terraform destroy", "kubectl delete"]
+redact_pattern = "sk-[a-z]+"
+
+[profile]
+default = "home"
+
+[profile.work]
+model = "other"
+theme = "dark"
+context = [
+    "Deploys go through ./deploy.sh",
+]
+
+[profile.home]
+model = "h"
+"#;
+        let merged = merge_settings_file(Path::new("config.toml"), existing, incoming).unwrap();
+        let (pairs, warnings) = parse_toml_settings(&merged);
+        assert!(warnings.is_empty(), "{warnings:?}");
+        let values = |key: &str| -> Vec<&str> { pairs.iter().filter(|(k, _)| k == key).map(|(_, v)| v.as_str()).collect() };
+        // Existing values win; new top-level keys stay top-level
+        assert_eq!(values("model"), ["a"]);
+        assert_eq!(values("theme"), ["light"]);
+        assert_eq!(values("profile"), ["work"]);
+        assert_eq!(values("dangerous_pattern"), ["terraform destroy", "kubectl delete"]);
+        assert_eq!(values("redact_pattern"), ["sk-[a-z]+"]);
+        // Profiles merge key by key, and new ones come along whole
+        assert_eq!(values("profile.work.model"), ["w"]);
+        assert_eq!(values("profile.work.theme"), ["dark"]);
+        assert_eq!(values("profile.work.context"), ["Deploys go through ./deploy.sh"]);
+        assert_eq!(values("profile.home.model"), ["h"]);
+
+        let err = merge_settings_file(Path::new("config.toml"), existing, "model = ").unwrap_err();
+        assert!(err.contains("the imported one isn't valid TOML"), "{err}");
+        assert_eq!(merge_settings_file(Path::new("aliases"), "ll=ls -l\n", "la=ls -a\n").unwrap(), "ll=ls -l\nla=ls -a\n");
+    }
+
+    #[test]
+    fn parse_subcommand_recognizes_settings_commands() {
+        let parts: Vec<String> = ["export-settings", "b.tar.gz", "--include-sessions"]
+            .iter().map(|s| s.to_string()).collect();
+        assert!(matches!(
+            parse_subcommand(&parts).unwrap(),
+            Some(Subcommand::ExportSettings { include_sessions: true, .. })
+        ));
+        let parts = vec!["import-settings".to_string()];
+        assert!(parse_subcommand(&parts).is_err());
+        let parts = vec!["list".to_string(), "files".to_string()];
+        assert!(parse_subcommand(&parts).unwrap().is_none());
+    }
+
+    #[test]
+    fn parse_subcommand_models_takes_one_query_word() {
+        let parts = |s: &str| s.split_whitespace().map(String::from).collect::<Vec<_>>();
+        assert!(matches!(parse_subcommand(&parts("models")).unwrap(), Some(Subcommand::Models { query }) if query.is_empty()));
+        assert!(matches!(parse_subcommand(&parts("models llama")).unwrap(), Some(Subcommand::Models { query }) if query == "llama"));
+        assert!(parse_subcommand(&parts("models of german cars")).unwrap().is_none());
+        assert!(matches!(parse_subcommand(&parts("profiles")).unwrap(), Some(Subcommand::Profiles)));
+        assert!(parse_subcommand(&parts("profiles of famous people")).unwrap().is_none());
+    }
+
+    // --- settings resolution ---
+
+    // xorshift; good enough to shuffle layers without pulling in a crate
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    fn random_value(rng: &mut Rng, key: &str) -> String {
+        match key {
+            "theme" => ["dark", "light"][rng.below(2)].to_string(),
+            "provider" => ["openai", "ollama", "anthropic"][rng.below(3)].to_string(),
+            "paste_guard" | "prompt_git" | "root_ok" | "redact" | "env_bootstrap" | "persistent_shell" | "auto_fix"
+            | "auto_run" | "auto_context" | "structured" | "tools" => {
+                ["on", "off"][rng.below(2)].to_string()
+            }
+            "session_max_turns" | "session_max_bytes" | "max_auto_commands" | "transcript_output_lines"
+            | "max_output_bytes" => {
+                rng.below(50).to_string()
+            }
+            "tokenizer" => ["cl100k", "heuristic"][rng.below(2)].to_string(),
+            "on_hangup" => ["forward", "detach"][rng.below(2)].to_string(),
+            "pager" => ["off", "auto", "always"][rng.below(3)].to_string(),
+            "retry_attempts" | "max_tokens" | "auto_fix_attempts" => (1 + rng.below(5)).to_string(),
+            "temperature" | "top_p" | "warn_cost" => format!("0.{}", 1 + rng.below(9)),
+            key if COLOR_KEYS.contains(&key) => ["red", "#ff8800", "38;5;245"][rng.below(3)].to_string(),
+            _ => format!("v{}", rng.below(4)),
+        }
+    }
+
+    #[test]
+    fn resolve_precedence_holds_for_random_layers() {
+        let mut rng = Rng(0x5eed_1234_abcd_0001);
+        for _ in 0..500 {
+            let mut layers = Vec::new();
+            for origin in ORIGIN_PRECEDENCE {
+                if rng.below(4) == 0 {
+                    continue;
+                }
+                let mut values = Vec::new();
+                for _ in 0..rng.below(8) {
+                    let key = SETTING_KEYS[rng.below(SETTING_KEYS.len())];
+                    values.push((key.to_string(), random_value(&mut rng, key)));
+                }
+                layers.push(SettingsLayer { origin, values });
+            }
+            // Input order must not matter
+            for i in (1..layers.len()).rev() {
+                layers.swap(i, rng.below(i + 1));
+            }
+            let settings = Settings::resolve(&layers);
+
+            for key in SETTING_KEYS {
+                // Every value for the key, lowest precedence first, last line last
+                let mut by_precedence: Vec<&SettingsLayer> = layers.iter().collect();
+                by_precedence.sort_by_key(|l| l.origin);
+                let all: Vec<(Origin, &String)> = by_precedence
+                    .iter()
+                    .flat_map(|l| l.values.iter().filter(|(k, _)| k == key).map(move |(_, v)| (l.origin, v)))
+                    .collect();
+                let resolved = settings.values.get(*key).cloned().unwrap_or_default();
+                if REPEATABLE_CONFIG_KEYS.contains(key) {
+                    // Union of every source, each value once, first appearance first
+                    let mut expected: Vec<&String> = Vec::new();
+                    for (_, v) in &all {
+                        if !expected.contains(v) {
+                            expected.push(v);
+                        }
+                    }
+                    assert_eq!(resolved.iter().map(|s| &s.value).collect::<Vec<_>>(), expected);
+                } else {
+                    let expected = all.last().map(|(origin, v)| Setting { value: v.to_string(), origin: *origin });
+                    assert_eq!(resolved.first().cloned(), expected, "key {key}");
+                    assert!(resolved.len() <= 1);
+                    // No source outranks the winner
+                    if let Some(winner) = resolved.first() {
+                        assert!(all.iter().all(|(origin, _)| *origin <= winner.origin));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn resolve_skips_invalid_values_and_notes_conflicts() {
+        let settings = Settings::resolve(&[
+            SettingsLayer {
+                origin: Origin::Flag,
+                values: vec![("model".to_string(), "flag/model".to_string())],
+            },
+            SettingsLayer::defaults(),
+            SettingsLayer::from_text(Origin::Global, "theme=purple\nmodel=global/model\nunknown=1\n"),
+        ]);
+        assert_eq!(settings.model(), "flag/model");
+        assert_eq!(settings.get("model").unwrap().origin, Origin::Flag);
+        assert_eq!(settings.get("theme").unwrap().origin, Origin::Default);
+        assert!(!settings.values.contains_key("unknown"));
+        assert!(settings.notes.iter().any(|n| n.contains("theme=purple")));
+        assert!(settings.notes.iter().any(|n| n.contains("overrides global value 'global/model'")));
+    }
+
+    #[test]
+    fn config_reads_only_what_the_file_set() {
+        let settings = Settings::resolve(&[
+            SettingsLayer::defaults(),
+            SettingsLayer::from_text(Origin::Global, "theme=light\nsession_max_turns=0\ndangerous_pattern=kubectl delete\n"),
+        ]);
+        let config = Config::from_settings(&settings);
+        assert_eq!(config.theme, Some(ThemeMode::Light));
+        assert_eq!(config.model, None);
+        assert_eq!(config.session_max_turns, Some(1));
+        assert_eq!(config.dangerous_patterns, ["kubectl delete"]);
+        assert_eq!(config.max_auto_commands, None);
+    }
+
+    #[test]
+    fn model_comes_from_config_and_falls_back_to_default() {
+        let resolve = |text| Settings::resolve(&[SettingsLayer::defaults(), SettingsLayer::from_text(Origin::Global, text)]);
+        // Older files only had a theme line
+        let legacy = resolve("theme=dark\n");
+        assert_eq!(legacy.model(), DEFAULT_MODEL);
+        assert_eq!(Config::from_settings(&legacy).model, None);
+        let saved = resolve("theme=dark\nmodel=openai/gpt-4o-mini\n");
+        assert_eq!(saved.model(), "openai/gpt-4o-mini");
+        assert_eq!(Config::from_settings(&saved).model.as_deref(), Some("openai/gpt-4o-mini"));
+    }
+
+    #[test]
+    fn ollama_is_picked_by_provider_or_model_prefix() {
+        let resolve = |text| Settings::resolve(&[SettingsLayer::defaults(), SettingsLayer::from_text(Origin::Global, text)]);
+        let hosted = resolve("model=openai/gpt-4o-mini\n");
+        assert_eq!(hosted.provider(), Provider::OpenAi);
+        assert_eq!(hosted.api_url(), DEFAULT_API_URL);
+
+        for text in ["provider=ollama\nmodel=llama3.1\n", "model=ollama/llama3.1\n"] {
+            let local = resolve(text);
+            assert_eq!(local.provider(), Provider::Ollama, "{text}");
+            assert_eq!(local.api_url(), OLLAMA_API_URL, "{text}");
+        }
+        // A set api_url wins, e.g. Ollama on another machine
+        let remote = resolve("provider=ollama\napi_url=http://gpu-box:11434/api/chat\n");
+        assert_eq!(remote.api_url(), "http://gpu-box:11434/api/chat");
+        assert_eq!(resolve("provider=anthropic\n").api_url(), ANTHROPIC_API_URL);
+        assert!(!valid_setting("provider", "bedrock"));
+        assert_eq!(Config::from_settings(&resolve("provider=ollama\n")).to_toml().unwrap(), "provider = \"ollama\"\n");
+    }
+
+    #[test]
+    fn a_provider_set_on_purpose_keeps_anthropic_models() {
+        let resolve = |text| Settings::resolve(&[SettingsLayer::defaults(), SettingsLayer::from_text(Origin::Global, text)]);
+        // Whether or not ANTHROPIC_API_KEY is set
+        let chosen = resolve("provider=openai\nmodel=anthropic/claude-3.5-sonnet\n");
+        assert_eq!(chosen.chosen_provider(), Some(Provider::OpenAi));
+        assert_eq!(chosen.provider(), Provider::OpenAi);
+        assert_eq!(resolve("").chosen_provider(), None);
+    }
+
+    #[test]
+    fn fallback_models_are_a_trimmed_comma_list() {
+        let resolve = |text| Settings::resolve(&[SettingsLayer::defaults(), SettingsLayer::from_text(Origin::Global, text)]);
+        assert!(resolve("").fallback_models().is_empty());
+        let settings = resolve("fallback_models=a/free, b/free ,c\n");
+        assert_eq!(settings.fallback_models(), ["a/free", "b/free", "c"]);
+        assert!(!valid_setting("fallback_models", "a,,b"));
+        assert!(!valid_setting("fallback_models", "a b"));
+        assert_eq!(
+            Config::from_settings(&resolve("fallback_models=a,b\n")).to_toml().unwrap(),
+            "fallback_models = \"a,b\"\n"
+        );
+    }
+
+    #[test]
+    fn parse_subcommand_recognizes_only_exact_config_show() {
+        let parts = |s: &str| s.split_whitespace().map(str::to_string).collect::<Vec<_>>();
+        assert!(matches!(parse_subcommand(&parts("config show")).unwrap(), Some(Subcommand::ConfigShow { origins: false })));
+        assert!(matches!(
+            parse_subcommand(&parts("config show --origins")).unwrap(),
+            Some(Subcommand::ConfigShow { origins: true })
+        ));
+        assert!(parse_subcommand(&parts("config show me my ssh config")).unwrap().is_none());
+    }
+
+    // --- profiles ---
+
+    const PROFILES_TOML: &str = "model = \"global/model\"\n\n[profile]\ndefault = \"sh\"\n\n\
+                                 [profile.jq]\nmodel = \"jq/model\"\ntemperature = 0.1\n\
+                                 extra_prompt = \"Answer with a single jq filter\"\ncontext = [\"a\", \"b\"]\n\n\
+                                 [profile.sh]\nmax_tokens = 300\n";
+
+    fn profile_layers(flags: &[(&str, &str)]) -> Vec<SettingsLayer> {
+        let (pairs, warnings) = parse_toml_settings(PROFILES_TOML);
+        assert!(warnings.is_empty(), "{warnings:?}");
+        let flags = flags.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        vec![
+            SettingsLayer::defaults(),
+            SettingsLayer { origin: Origin::Global, values: pairs },
+            SettingsLayer { origin: Origin::Flag, values: flags },
+        ]
+    }
+
+    #[test]
+    fn profile_tables_come_back_as_prefixed_keys() {
+        let (pairs, warnings) = parse_toml_settings(PROFILES_TOML);
+        assert!(warnings.is_empty(), "{warnings:?}");
+        let keys: Vec<&str> = pairs.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(
+            keys,
+            [
+                "model",
+                "profile",
+                "profile.jq.model",
+                "profile.jq.temperature",
+                "profile.jq.extra_prompt",
+                "profile.jq.context",
+                "profile.jq.context",
+                "profile.sh.max_tokens",
+            ]
+        );
+        assert_eq!(profile_key("profile.jq.model"), Some(("jq", "model")));
+        assert_eq!(profile_key("profile.jq.profile"), None);
+        assert_eq!(profile_key("profile.jq.modle"), None);
+        let (pairs, warnings) = parse_toml_settings("[profile]\njq = \"x\"\n");
+        assert!(pairs.is_empty());
+        assert!(warnings[0].contains("[profile.jq]"), "{warnings:?}");
+        // A lone top-level profile still names the default
+        let (pairs, _) = parse_toml_settings("profile = \"jq\"\n");
+        assert_eq!(pairs, [("profile".to_string(), "jq".to_string())]);
+    }
+
+    #[test]
+    fn profile_sits_between_the_global_config_and_flags() {
+        let (settings, unknown) = Settings::resolve_with_profile(profile_layers(&[("profile", "jq")]));
+        assert_eq!(unknown, None);
+        assert_eq!(settings.profile(), Some("jq"));
+        assert_eq!(settings.model(), "jq/model");
+        assert_eq!(settings.get("model").unwrap().origin, Origin::Profile);
+        assert_eq!(settings.temperature(), Some(0.1));
+        assert_eq!(settings.extra_prompt().as_deref(), Some("Answer with a single jq filter"));
+        assert_eq!(settings.context().as_deref(), Some("a\nb"));
+
+        let (flagged, _) = Settings::resolve_with_profile(profile_layers(&[("profile", "jq"), ("model", "flag/model")]));
+        assert_eq!(flagged.model(), "flag/model");
+        // [profile] default = "sh" in the config file is the default
+        let (default, _) = Settings::resolve_with_profile(profile_layers(&[]));
+        assert_eq!((default.profile(), default.model().as_str(), default.max_tokens()), (Some("sh"), "global/model", Some(300)));
+        assert_eq!(default.extra_prompt(), None);
+    }
+
+    #[test]
+    fn unknown_profiles_name_the_ones_there_are() {
+        let (settings, unknown) = Settings::resolve_with_profile(profile_layers(&[("profile", "awk")]));
+        assert_eq!(unknown.as_deref(), Some("Unknown profile 'awk'; available: jq, sh (ignored)"));
+        assert_eq!((settings.profile(), settings.model().as_str()), (None, "global/model"));
+        let none = Settings::resolve(&[SettingsLayer::defaults()]);
+        assert!(none.check_profile("jq").unwrap_err().contains("none are defined"));
+        assert!(none.profiles().is_empty());
+    }
+
+    #[test]
+    fn saving_the_config_keeps_its_profiles() {
+        let toml = config_from_toml(PROFILES_TOML).to_toml().unwrap();
+        assert_eq!(
+            toml,
+            "model = \"global/model\"\n\n[profile]\ndefault = \"sh\"\n\n\
+             [profile.jq]\nmodel = \"jq/model\"\ntemperature = 0.1\ncontext = [\"a\", \"b\"]\n\
+             extra_prompt = \"Answer with a single jq filter\"\n\n[profile.sh]\nmax_tokens = 300\n"
+        );
+        assert_eq!(config_from_toml(&toml).to_toml().unwrap(), toml);
+        assert_eq!(toml::from_str::<Config>(&toml).unwrap(), config_from_toml(&toml));
+    }
+
+    #[test]
+    fn project_files_cannot_define_profiles() {
+        let dir = project_dir("profiles");
+        fs::write(dir.join(".ask.toml"), "model = \"p\"\n[profile.jq]\nmodel = \"x\"\n").unwrap();
+        let (layer, warnings) = SettingsLayer::project(&dir.join(".ask.toml"));
+        assert_eq!(layer.values, [("model".to_string(), "p".to_string())]);
+        assert!(warnings[0].contains("profile.jq.model is ignored"), "{warnings:?}");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // --- project settings ---
+
+    fn project_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("ask-project-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("src/deep")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn find_project_config_takes_the_nearest_file_and_skips_directories() {
+        let dir = project_dir("find");
+        assert_eq!(find_project_config(&dir.join("src/deep")).filter(|p| p.starts_with(&dir)), None);
+        fs::write(dir.join(".ask.toml"), "model = \"a\"\n").unwrap();
+        assert_eq!(find_project_config(&dir.join("src/deep")), Some(dir.join(".ask.toml")));
+        fs::write(dir.join("src/.ask"), "model=b\n").unwrap();
+        assert_eq!(find_project_config(&dir.join("src/deep")), Some(dir.join("src/.ask")));
+        // Like ~/.ask, the data directory
+        fs::create_dir_all(dir.join("src/deep/.ask")).unwrap();
+        assert_eq!(find_project_config(&dir.join("src/deep")), Some(dir.join("src/.ask")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_toml_settings_reads_scalars_arrays_and_comments() {
+        let text = "# infra\nmodel = \"cheap/model\" # fine here\nauto_run = false\nmax_tokens = 1_024\n\
+                    context = 'C:\\tmp has # in it'\ndangerous_pattern = [\n  \"terraform apply*\", # plans are fine\n  \"kubectl delete *\",\n]\n";
+        let (pairs, warnings) = parse_toml_settings(text);
+        let pairs: Vec<(&str, &str)> = pairs.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        assert_eq!(
+            pairs,
+            [
+                ("model", "cheap/model"),
+                ("auto_run", "false"),
+                ("max_tokens", "1024"),
+                ("context", "C:\\tmp has # in it"),
+                ("dangerous_pattern", "terraform apply*"),
+                ("dangerous_pattern", "kubectl delete *"),
+            ]
+        );
+        assert!(warnings.is_empty(), "{warnings:?}");
+    }
+
+    #[test]
+    fn parse_toml_settings_warns_instead_of_failing() {
+        let text = "theme = \"light\"\nmodel = [\"a\", \"b\"]\nwhen = 1979-05-27\n[server]\nmodel = \"x\"\n";
+        let (pairs, warnings) = parse_toml_settings(text);
+        assert_eq!(pairs, [("theme".to_string(), "light".to_string())]);
+        assert_eq!(warnings.len(), 3, "{warnings:?}");
+        assert!(warnings[0].contains("takes a single value"));
+        assert!(warnings[1].starts_with("when: expected a string"), "{warnings:?}");
+        assert!(warnings[2].contains("[server]"));
+
+        // Not TOML at all: one warning with the line, and nothing read
+        let (pairs, warnings) = parse_toml_settings("theme = \"light\"\nmodel = gpt\n");
+        assert!(pairs.is_empty());
+        assert_eq!(warnings.len(), 1, "{warnings:?}");
+        assert!(warnings[0].starts_with("line 2: "), "{warnings:?}");
+    }
+
+    #[test]
+    fn settings_files_warn_about_unknown_keys_in_either_format() {
+        let dir = project_dir("unknown");
+        fs::write(dir.join("config.toml"), "model = \"a\"\nmodle = \"b\"\n").unwrap();
+        fs::write(dir.join("config"), "model=a\nmodle=b\n").unwrap();
+        for name in ["config.toml", "config"] {
+            let (layer, warnings) = read_settings_file(&dir.join(name), Origin::Global);
+            assert_eq!(layer.values, [("model".to_string(), "a".to_string())]);
+            assert_eq!(warnings, [format!("{}: unknown key modle (ignored)", dir.join(name).display())]);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bad_colors_warn_with_their_key_and_good_ones_survive_a_save() {
+        let dir = project_dir("colors");
+        let path = dir.join("config.toml");
+        fs::write(&path, "helper_color = \"38;5;245\"\ncommand_color = \"#ff8800\"\nprompt_color = \"purple\"\n").unwrap();
+        let (layer, warnings) = read_settings_file(&path, Origin::Global);
+        assert_eq!(warnings.len(), 1, "{warnings:?}");
+        assert!(warnings[0].contains("prompt_color: unknown color 'purple'"), "{warnings:?}");
+
+        let settings = Settings::resolve(&[SettingsLayer::defaults(), layer]);
+        let colors = settings.theme_colors();
+        assert_eq!(colors, [("helper_color", "\u{001b}[38;5;245m".to_string()), ("command_color", "\u{001b}[38;2;255;136;0m".to_string())]);
+
+        // /theme --save rewrites the file; the colors must come back out
+        let toml = Config::from_settings(&settings).to_toml().unwrap();
+        assert!(toml.contains("helper_color = \"38;5;245\"\ncommand_color = \"#ff8800\"\n"), "{toml}");
+        assert_eq!(config_from_toml(&toml).to_toml().unwrap(), toml);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn config_from_toml(text: &str) -> Config {
+        let (pairs, warnings) = parse_toml_settings(text);
+        assert!(warnings.is_empty(), "{warnings:?}");
+        Config::from_settings(&Settings::resolve(&[
+            SettingsLayer::defaults(),
+            SettingsLayer { origin: Origin::Global, values: pairs },
+        ]))
+    }
+
+    #[test]
+    fn default_config_saves_as_an_empty_file() {
+        // No theme line either, so the terminal's background still decides
+        let toml = Config::default().to_toml().unwrap();
+        assert_eq!(toml, "");
+        assert_eq!(config_from_toml(&toml).to_toml().unwrap(), toml);
+    }
+
+    #[test]
+    fn partial_config_round_trips_through_toml() {
+        let text = "theme = \"light\"\nmodel = \"a/b\"\npaste_guard = false\nmax_auto_commands = 2\n\
+                    dangerous_pattern = [\"terraform destroy\", \"say \\\"hi\\\"\"]\ncontext = [\"Use make\"]\n\
+                    audit_log = \"~/audit.log\"\ntemperature = 0.2\n";
+        let toml = config_from_toml(text).to_toml().unwrap();
+        assert_eq!(config_from_toml(&toml).to_toml().unwrap(), toml);
+        let config = config_from_toml(&toml);
+        assert_eq!(config.model.as_deref(), Some("a/b"));
+        assert_eq!(config.paste_guard, Some(false));
+        assert_eq!(config.dangerous_patterns, ["terraform destroy", "say \"hi\""]);
+        assert_eq!(config.audit_log.as_deref(), Some("~/audit.log"));
+        assert_eq!(config.temperature, Some(0.2));
+    }
+
+    #[test]
+    fn legacy_config_migrates_to_toml_without_losing_settings() {
+        let legacy = "theme=light\nmodel=a/b\nroot_ok=true\nredact=off\ndangerous_pattern=rm *\ndangerous_pattern=dd *\n\
+                      persistent_shell=on\nauto_fix_attempts=3\nmax_tokens=500\n";
+        let settings = Settings::resolve(&[SettingsLayer::defaults(), SettingsLayer::from_text(Origin::Global, legacy)]);
+        let toml = Config::from_settings(&settings).to_toml().unwrap();
+        assert_eq!(
+            toml,
+            "theme = \"light\"\nmodel = \"a/b\"\ndangerous_pattern = [\"rm *\", \"dd *\"]\nredact = false\n\
+             persistent_shell = true\nauto_fix_attempts = 3\nroot_ok = true\nmax_tokens = 500\n"
+        );
+        let migrated = config_from_toml(&toml);
+        assert_eq!((migrated.root_ok, migrated.redact, migrated.persistent_shell), (Some(true), Some(false), Some(true)));
+        assert_eq!(migrated.to_toml().unwrap(), toml);
+    }
+
+    #[test]
+    fn project_files_cannot_set_locked_keys_or_read_outside_the_project() {
+        let dir = project_dir("locked");
+        fs::write(dir.join("CONVENTIONS.md"), "Use make, not cargo directly.\n").unwrap();
+        fs::write(
+            dir.join(".ask"),
+            "model=cheap/model\napi_url=http://evil.example/v1\nredact=off\ncontext=@CONVENTIONS.md\ncontext=@../../etc/passwd\n\
+             warning_color=8\n",
+        )
+        .unwrap();
+        let (layer, warnings) = SettingsLayer::project(&dir.join(".ask"));
+        let keys: Vec<&str> = layer.values.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, ["model", "context"]);
+        assert_eq!(layer.values[1].1, format!("@{}", dir.canonicalize().unwrap().join("CONVENTIONS.md").display()));
+        assert_eq!(warnings.len(), 4);
+        assert!(warnings[0].contains("api_url is ignored"));
+        assert!(warnings[2].contains("@../../etc/passwd"));
+        assert!(warnings[3].contains("warning_color is ignored"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn project_settings_sit_between_flags_and_the_global_config() {
+        let dir = project_dir("layers");
+        fs::write(dir.join(".ask"), "model=project/model\nmax_auto_commands=0\nauto_run=off\n").unwrap();
+        let settings = Settings::load(Vec::new(), &dir.join("src"));
+        assert_eq!(settings.project, Some(dir.join(".ask")));
+        assert_eq!(settings.get("model").unwrap().origin, Origin::Workspace);
+        assert_eq!(settings.model(), "project/model");
+        assert_eq!(settings.max_auto_commands(), 0);
+        assert!(!settings.auto_run());
+        let flagged = Settings::load(vec![("model".to_string(), "flag/model".to_string())], &dir);
+        assert_eq!(flagged.model(), "flag/model");
+        assert_eq!(flagged.for_dir(&env::temp_dir()).model(), "flag/model");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn context_lines_and_files_reach_the_command_prompt_only() {
+        let dir = project_dir("context");
+        fs::write(dir.join("notes.md"), "Deploys go through ./deploy.sh\n").unwrap();
+        let text = format!("context=Terraform lives in infra/\ncontext=@{}\n", dir.join("notes.md").display());
+        let settings = Settings::resolve(&[SettingsLayer::defaults(), SettingsLayer::from_text(Origin::Global, &text)]);
+        let context = settings.context().unwrap();
+        assert_eq!(context, "Terraform lives in infra/\nDeploys go through ./deploy.sh");
+
+        let mut request = retry_settings(DEFAULT_API_URL.to_string(), 1);
+        request.context = Some(context);
+        let action = build_messages("list files", &request, &[], None);
+        let action = &action.last().unwrap().content;
+        assert!(action.contains("**Project context:**\nTerraform lives in infra/\nDeploys go through ./deploy.sh\n\n**User request:** list files"));
+        let chat = build_messages("thanks", &request, &[], None);
+        assert!(!chat.last().unwrap().content.contains("Project context"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // --- data directory ---
+
+    // Root can write almost anywhere, but never under /proc
+    const UNWRITABLE: &str = "/proc/self/ask-test";
+
+    #[test]
+    fn data_dir_without_home_falls_back_to_temp_and_warns() {
+        let temp = env::temp_dir().join(format!("ask-data-temp-{}", std::process::id()));
+        let data = DataDir::resolve(None, None, |_| None, temp.clone());
+        assert_eq!(data, DataDir { path: temp.clone(), source: TEMP_DATA_DIR, writable: true });
+        assert!(data.warning().unwrap().contains("no home directory"));
+        let _ = fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn data_dir_refuses_a_temp_dir_it_does_not_own_privately() {
+        use std::os::unix::fs::PermissionsExt;
+        let temp = env::temp_dir().join(format!("ask-data-open-{}", std::process::id()));
+        fs::create_dir_all(&temp).unwrap();
+        fs::set_permissions(&temp, fs::Permissions::from_mode(0o755)).unwrap();
+        let data = DataDir::resolve(None, None, |_| None, temp.clone());
+        assert!(!data.writable);
+        assert!(private_dir(&temp).unwrap_err().to_string().contains("0700"));
+
+        // Someone else's link to a directory of ours is no better
+        fs::set_permissions(&temp, fs::Permissions::from_mode(0o700)).unwrap();
+        let link = env::temp_dir().join(format!("ask-data-link-{}", std::process::id()));
+        let _ = fs::remove_file(&link);
+        std::os::unix::fs::symlink(&temp, &link).unwrap();
+        assert!(private_dir(&link).is_err());
+        assert!(private_dir(&temp).is_ok());
+        let _ = fs::remove_file(&link);
+        let _ = fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn probing_for_a_data_dir_creates_only_the_one_picked() {
+        let root = env::temp_dir().join(format!("ask-data-probe-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        assert!(dir_is_writable(&root.join("a/b")));
+        assert!(!root.join("a").exists());
+        let var = |name: &str| (name == "XDG_DATA_HOME").then(|| root.join("xdg").display().to_string());
+        let data = DataDir::resolve(None, Some(root.join("home")), var, root.join("tmp"));
+        assert_eq!(data.path, root.join("home/.ask"));
+        assert!(data.path.is_dir());
+        assert!(!root.join("xdg").exists() && !root.join("tmp").exists());
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn data_dir_skips_unwritable_home_for_xdg() {
+        let xdg = env::temp_dir().join(format!("ask-data-xdg-{}", std::process::id()));
+        let var = |name: &str| (name == "XDG_DATA_HOME").then(|| xdg.display().to_string());
+        let data = DataDir::resolve(None, Some(PathBuf::from(UNWRITABLE)), var, PathBuf::from(UNWRITABLE));
+        assert_eq!(data.path, xdg.join("ask"));
+        assert_eq!(data.source, "XDG_DATA_HOME");
+        assert_eq!(data.warning(), None);
+        let _ = fs::remove_dir_all(&xdg);
+    }
+
+    #[test]
+    fn data_dir_flag_and_env_are_taken_as_given() {
+        let home = env::temp_dir().join(format!("ask-data-home-{}", std::process::id()));
+        let flag = Path::new(UNWRITABLE);
+        let env_var = |name: &str| (name == "ASK_DATA_DIR").then(|| "/tmp/from-env".to_string());
+
+        let data = DataDir::resolve(Some(flag), Some(home.clone()), env_var, env::temp_dir());
+        assert_eq!((data.path.as_path(), data.source, data.writable), (flag, "--data-dir", false));
+        assert!(data.warning().unwrap().contains("not writable"));
+
+        let data = DataDir::resolve(None, Some(home.clone()), env_var, env::temp_dir());
+        assert_eq!((data.path, data.source), (PathBuf::from("/tmp/from-env"), "ASK_DATA_DIR"));
+
+        let data = DataDir::resolve(None, Some(home.clone()), |_| Some(String::new()), env::temp_dir());
+        assert_eq!(data.path, home.join(".ask"));
+        let _ = fs::remove_dir_all(&home);
+    }
+
+    #[test]
+    fn data_dir_with_nothing_writable_keeps_home_read_only() {
+        let home = PathBuf::from(UNWRITABLE);
+        let data = DataDir::resolve(None, Some(home.clone()), |_| None, PathBuf::from(UNWRITABLE).join("tmp"));
+        assert_eq!(data, DataDir { path: home.join(".ask"), source: "home directory", writable: false });
+    }
+
+    #[test]
+    fn read_only_data_dir_degrades_writes() {
+        let dir = PathBuf::from(UNWRITABLE);
+        assert!(!dir_is_writable(&dir));
+        assert!(Notes { dir: dir.join("notes") }.append("kept in memory", 0).is_err());
+        // Saving warns instead of failing the visit
+        let mut recent = RecentDirs::with_path(Some(dir.join("recent_dirs")));
+        recent.visit(&env::temp_dir());
+        assert_eq!(recent.dirs, [env::temp_dir()]);
+    }
+}
@@ -0,0 +1,3679 @@
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use serde_json::json;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+use crate::api::*;
+use crate::cli::*;
+use crate::config::*;
+use crate::history::*;
+use crate::repl::*;
+use crate::safety::*;
+use crate::theme::*;
+
+/// Reads one keypress from the terminal without waiting for Enter. Falls
+/// back to a whole line when there is no terminal to put in raw mode.
+pub(crate) fn read_key() -> Result<String, io::Error> {
+    let Ok(tty) = fs::OpenOptions::new().read(true).open("/dev/tty") else {
+        return read_confirmation_line();
+    };
+    let fd = tty.as_raw_fd();
+    let mut saved = unsafe { std::mem::zeroed::<libc::termios>() };
+    if unsafe { libc::tcgetattr(fd, &mut saved) } != 0 {
+        return read_confirmation_line();
+    }
+    let mut raw = saved;
+    raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+    raw.c_cc[libc::VMIN] = 1;
+    raw.c_cc[libc::VTIME] = 0;
+    unsafe {
+        libc::tcflush(fd, libc::TCIFLUSH);
+        libc::tcsetattr(fd, libc::TCSANOW, &raw);
+    }
+    let mut byte = [0u8; 1];
+    let result = (&tty).read(&mut byte);
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &saved) };
+    match result? {
+        0 => Err(confirmation_eof()),
+        _ => Ok((byte[0] as char).to_string()),
+    }
+}
+
+/// Reads a line from the terminal without echoing it, for secrets. Falls
+/// back to an ordinary line when there is no terminal. Ctrl-C is caught
+/// for the whole read, so echo is always turned back on.
+pub(crate) fn read_hidden_line() -> Result<String, io::Error> {
+    let modes = TerminalGuard::capture();
+    let Some(mut quiet) = modes.saved else {
+        return read_confirmation_line();
+    };
+    let sigint = CatchSigint::new();
+    quiet.c_lflag &= !libc::ECHO;
+    unsafe { libc::tcsetattr(io::stdin().as_raw_fd(), libc::TCSANOW, &quiet) };
+    let result = read_confirmation_line();
+    modes.restore();
+    // Enter wasn't echoed either
+    let _ = writeln!(prompt_writer());
+    match sigint.caught() {
+        true => Err(confirmation_interrupted()),
+        false => result,
+    }
+}
+
+pub fn process_prompt_with_context(
+    prompt: &str,
+    settings: &RequestSettings,
+    theme: &Theme,
+    history: &[ConversationContext],
+    piped_data: Option<&str>,
+    policy: &SafetyPolicy,
+    runner: &mut dyn CommandRunner,
+) -> Result<PromptResult, Box<dyn std::error::Error>> {
+    // `@file` references are read first, so a bad one costs no request
+    let attached = attach_files(prompt, settings.attachment_budget(), &settings.redaction)?;
+    report_attachments(&attached, theme);
+    let prompt = attached.message.as_str();
+    let (response, model) = ask_model(prompt, settings, theme, history, piped_data, policy)?;
+    let suggested = take_model_notes(&response, &Notes::open(), theme);
+    let mut result = PromptResult {
+        turn: ConversationContext {
+            prompt: attached.visible.clone(),
+            commands: Vec::new(),
+            outputs: Vec::new(),
+            exit_codes: vec![],
+            request: Some(TurnRequest {
+                model,
+                api_url: settings.endpoint.url.clone(),
+                response,
+            }),
+            pinned: false,
+            forgotten: false,
+        },
+        runs: Vec::new(),
+        failed: None,
+    };
+    if policy.print_only {
+        print_response(&suggested)?;
+        return Ok(result);
+    }
+    if policy.copy_only {
+        copy_response(&suggested, theme)?;
+        return Ok(result);
+    }
+
+    let mut outcome = execute_response(&suggested, theme, policy, runner);
+    let mut attempt = 0;
+    loop {
+        let failed = match outcome {
+            Ok(ran) => {
+                result.add_runs(ran);
+                return Ok(result);
+            }
+            Err(err) => err.downcast::<TurnFailed>()?,
+        };
+        result.add_failure(&failed);
+        // --yes never asks, so a failure stops the rest there
+        if failed.has_rest()
+            && policy.can_prompt
+            && !policy.auto_yes
+            && runner.keep_going(failed.failed_command(), failed.failure.status, theme)?
+        {
+            outcome = execute_response(&failed.rest, theme, policy, runner);
+            continue;
+        }
+        if attempt == settings.fix_attempts {
+            result.failed = Some(*failed);
+            return Ok(result);
+        }
+        attempt += 1;
+        eprintln!("Command failed: {failed}");
+        println!(
+            "{}",
+            theme.helper_text(&format!("Asking for a fix (attempt {attempt} of {})...", settings.fix_attempts))
+        );
+
+        // The turn so far, failure included, is the context for the repair
+        let mut context = history.to_vec();
+        context.push(result.turn.clone());
+        let (fix, _) = ask_model(&fix_request(prompt, &failed), settings, theme, &context, piped_data, policy)?;
+        let suggested = take_model_notes(&fix, &Notes::open(), theme);
+        if let Some(request) = &mut result.turn.request {
+            request.response.extend(fix);
+        }
+        outcome = execute_response(&suggested, theme, policy, runner);
+    }
+}
+
+/// Names the files a prompt took along, and which had to be cut to fit.
+pub(crate) fn report_attachments(attached: &AttachedPrompt, theme: &Theme) {
+    if attached.files.is_empty() {
+        return;
+    }
+    let names: Vec<String> = attached
+        .files
+        .iter()
+        .map(|(label, cut)| if *cut { format!("{label} (cut to fit)") } else { label.clone() })
+        .collect();
+    eprintln!("{}", theme.helper_text(&format!("Attached {}", names.join(", "))));
+}
+
+/// What one prompt led to: the turn kept in history, and for `--output
+/// json` how each command that ran ended.
+pub struct PromptResult {
+    pub turn: ConversationContext,
+    pub(crate) runs: Vec<CommandRun>,
+    // The last command failed and no repair was left to try
+    pub(crate) failed: Option<TurnFailed>,
+}
+
+/// A command that ran, with its exit code (None when a signal or Ctrl-C
+/// stopped it) and its output as it printed it.
+pub(crate) struct CommandRun {
+    pub(crate) command: String,
+    pub(crate) exit_code: Option<i32>,
+    pub(crate) output: String,
+}
+
+impl PromptResult {
+    pub(crate) fn add_runs(&mut self, ran: Executed) {
+        self.push_runs(&ran.commands, &ran.outputs, &ran.exit_codes);
+        self.turn.exit_codes.extend(ran.exit_codes);
+        self.turn.commands.extend(ran.commands);
+        self.turn.outputs.extend(ran.outputs);
+    }
+
+    pub(crate) fn add_failure(&mut self, failed: &TurnFailed) {
+        // Every command but the last ran fine
+        self.push_runs(&failed.commands, &failed.outputs, &failed.exit_codes);
+        self.runs.push(CommandRun {
+            command: failed.failed_command().to_string(),
+            exit_code: failed.failure.status.code(),
+            output: failed.failure.output.clone(),
+        });
+        record_failure(&mut self.turn, failed);
+    }
+
+    pub(crate) fn push_runs(&mut self, commands: &[String], outputs: &[String], exit_codes: &[Option<i32>]) {
+        for ((command, output), exit_code) in commands.iter().zip(outputs).zip(exit_codes) {
+            self.runs.push(CommandRun { command: command.clone(), exit_code: *exit_code, output: output.clone() });
+        }
+    }
+
+    /// What single-prompt mode exits with: the last command's status as a
+    /// shell reports it, or 0 when nothing ran.
+    pub(crate) fn exit_code(&self) -> i32 {
+        match (&self.failed, self.runs.last()) {
+            (Some(failed), _) => shell_exit_code(failed.failure.status),
+            (None, Some(run)) => run.exit_code.unwrap_or(128 + libc::SIGINT),
+            (None, None) => 0,
+        }
+    }
+
+    /// The turn, or the failure that ended it.
+    pub(crate) fn into_turn(self) -> Result<ConversationContext, Box<dyn std::error::Error>> {
+        match self.failed {
+            Some(failed) => Err(failed.into()),
+            None => Ok(self.turn),
+        }
+    }
+
+    /// `--output json`: the prompt, the model's reply line by line, and
+    /// each command that ran.
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        let request = self.turn.request.as_ref();
+        let lines: Vec<serde_json::Value> = request
+            .map(|request| request.response.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .map(|line| match line.strip_prefix('#') {
+                Some(comment) => json!({ "type": "comment", "text": comment.trim() }),
+                None => json!({ "type": "command", "text": line }),
+            })
+            .collect();
+        let executed: Vec<serde_json::Value> = self
+            .runs
+            .iter()
+            .map(|run| json!({ "command": run.command, "exit_code": run.exit_code, "output": run.output }))
+            .collect();
+        json!({
+            "schema_version": SCHEMA_VERSION,
+            "prompt": self.turn.prompt,
+            "model": request.map(|request| request.model.as_str()),
+            "lines": lines,
+            "executed": executed,
+        })
+    }
+}
+
+/// Adds the commands of a failed attempt to `turn`, the failure's output
+/// and status included.
+pub(crate) fn record_failure(turn: &mut ConversationContext, failed: &TurnFailed) {
+    turn.commands.extend(failed.commands.iter().cloned());
+    turn.exit_codes.extend(failed.exit_codes.iter().copied());
+    turn.exit_codes.push(failed.failure.status.code());
+    turn.outputs.extend(failed.outputs.iter().cloned());
+    turn.outputs.push(failed.failure.output.clone());
+}
+
+/// The follow-up asking for a repair. The failed command's output is already
+/// in the context, framed like any other command output.
+pub(crate) fn fix_request(prompt: &str, failed: &TurnFailed) -> String {
+    format!(
+        "The command `{}` failed ({}); its output is in the last command-output block. \
+         Reply with a corrected command that still does what I asked: {prompt}",
+        failed.failed_command(),
+        failed.failure.status
+    )
+}
+
+/// `--print`: the raw command lines go to stdout, one per line, so the
+/// result can be captured with `$(ask -p ...)`; commentary goes to stderr.
+/// Fails when the model suggested no command at all.
+pub(crate) fn print_response(response: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut printed = 0;
+    for line in response {
+        if line.starts_with('#') {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+            printed += 1;
+        }
+    }
+    if printed == 0 {
+        return Err("The model suggested no command to print".into());
+    }
+    Ok(())
+}
+
+/// Puts the first suggested command on the clipboard for --copy. Commentary
+/// goes to stderr, as with --print.
+pub(crate) fn copy_response(response: &[String], theme: &Theme) -> Result<(), Box<dyn std::error::Error>> {
+    let (comments, commands): (Vec<&String>, Vec<&String>) = response.iter().partition(|line| line.starts_with('#'));
+    for comment in comments {
+        eprintln!("{}", comment);
+    }
+    let Some(first) = commands.first() else {
+        return Err("The model suggested no command to copy".into());
+    };
+    copy_to_clipboard(first).map_err(|err| format!("Couldn't copy the command: {err}"))?;
+    println!("{} {}", theme.prompt_text("copied>"), theme.command_text(&display_command(first)));
+    if commands.len() > 1 {
+        eprintln!("{}", theme.helper_text(&format!("{} more command(s) were not copied", commands.len() - 1)));
+    }
+    Ok(())
+}
+
+/// What execute_response ran: each command, its output as history keeps
+/// it, and the exit code it ended with (None when a signal or Ctrl-C
+/// stopped it).
+#[derive(Debug, Default)]
+pub struct Executed {
+    pub commands: Vec<String>,
+    pub outputs: Vec<String>,
+    pub exit_codes: Vec<Option<i32>>,
+}
+
+/// Shows commentary and walks each suggested command through confirmation,
+/// returning what actually ran, its output and how it ended.
+pub fn execute_response(
+    commands: &[String],
+    theme: &Theme,
+    policy: &SafetyPolicy,
+    runner: &mut dyn CommandRunner,
+) -> Result<Executed, Box<dyn std::error::Error>> {
+    // Check if all lines are conversational (start with #)
+    let all_conversational = commands.iter().all(|cmd| cmd.starts_with('#'));
+
+    let mut ran = Executed::default();
+
+    // If it's purely conversational, we still want to track it in history
+    if all_conversational {
+        let answer: String = commands
+            .iter()
+            .map(|line| format!("{}\n\n", theme.helper_text(line.trim_start_matches('#').trim())))
+            .collect();
+        page_output(&answer);
+        // Return empty commands but indicate success for conversation tracking
+        return Ok(Executed::default());
+    }
+
+    // Without a terminal there is nobody to say no, so an empty read must not
+    // count as Yes. Show what would have run and stop unless --yes covers it.
+    let needs_human = commands
+        .iter()
+        .any(|c| !c.starts_with('#') && !policy.runs_unattended(c));
+    if needs_human && !policy.can_prompt {
+        for command in commands.iter().filter(|c| !c.starts_with('#')) {
+            println!("{}", command);
+        }
+        return Err(NeedsConfirmation.into());
+    }
+
+    let plan = plan_commands(commands);
+    let mut step = 0;
+    // Commands run without a prompt this turn, capped by max_auto_commands
+    let mut unattended = 0;
+    // Set by "all": the rest of the turn runs without asking, except
+    // commands that look dangerous
+    let mut approve_rest = false;
+    for (index, command) in commands.iter().enumerate() {
+        if command.starts_with('#') {
+            println!(
+                "{}\n",
+                theme.helper_text(command.trim_start_matches('#').trim())
+            );
+            continue;
+        }
+        let pending = &plan[step];
+        let rest = &commands[index + 1..];
+        step += 1;
+        debug_log(2, &format!("safety: '{command}' is {}", policy.explain(command)));
+
+        if policy.runs_unattended(command) && unattended == policy.max_auto {
+            let remaining: Vec<&String> = commands[index..].iter().filter(|c| !c.starts_with('#')).collect();
+            println!(
+                "{}",
+                theme.warning_text(&format!(
+                    "Ran {} command(s) without asking (max_auto_commands={}); {} not run yet:",
+                    unattended,
+                    policy.max_auto,
+                    remaining.len()
+                ))
+            );
+            for rest in &remaining {
+                println!("  {}", theme.command_text(rest));
+            }
+            if !policy.can_prompt {
+                return Err(AutoLimitReached {
+                    ran: unattended,
+                    remaining: remaining.len(),
+                }
+                .into());
+            }
+            println!("{}", theme.helper_text("Confirm the rest one at a time."));
+            unattended += 1;
+        }
+
+        let response = if policy.runs_unattended(command) && unattended < policy.max_auto {
+            unattended += 1;
+            print_clobber_warnings(&mut io::stdout(), command, theme)?;
+            println!("{} {}", theme.prompt_text("run>"), theme.command_text(&display_command(command)));
+            ConfirmResponse::Yes
+        } else if approve_rest && !policy.is_dangerous(command) {
+            print_clobber_warnings(&mut io::stdout(), command, theme)?;
+            println!("{} {}", theme.prompt_text("run>"), theme.command_text(&display_command(command)));
+            ConfirmResponse::Yes
+        } else {
+            runner.confirm(command, theme, policy)?
+        };
+        approve_rest |= matches!(response, ConfirmResponse::All);
+        match response {
+            ConfirmResponse::Yes | ConfirmResponse::All => {
+                ran.commands.push(command.clone());
+                run_step(runner, command, &mut ran, rest)?;
+            }
+            ConfirmResponse::No => {
+                println!("Command execution cancelled");
+                return Ok(ran);
+            }
+            ConfirmResponse::Skip => {
+                println!("Skipping command: {}", theme.command_text(command));
+                continue;
+            }
+            // confirm() already copied it and said so
+            ConfirmResponse::Copy => continue,
+            ConfirmResponse::Edit(edited) => {
+                ran.commands.push(edited.clone());
+                run_step(runner, &edited, &mut ran, rest)?;
+            }
+            ConfirmResponse::Instruct(custom_command) => {
+                let mut detour_output = String::new();
+                if !custom_command.is_empty() {
+                    println!("Running custom command: {}", theme.command_text(&custom_command));
+                    detour_output = runner.run(&custom_command)?.history_output();
+                }
+                // After running custom command, continue with the original flow.
+                // The original may have scrolled away, so say where we were.
+                println!("\nReturning to original command:");
+                for line in detour_context(pending, &detour_output) {
+                    println!("{}", theme.helper_text(&line));
+                }
+                let response = runner.confirm(command, theme, policy)?;
+                approve_rest |= matches!(response, ConfirmResponse::All);
+                match response {
+                    ConfirmResponse::Yes | ConfirmResponse::All => {
+                        ran.commands.push(command.clone());
+                        run_step(runner, command, &mut ran, rest)?;
+                    }
+                    ConfirmResponse::No => {
+                        println!("Command execution cancelled");
+                        return Ok(ran);
+                    }
+                    ConfirmResponse::Skip => {
+                        println!("Skipping command: {}", theme.command_text(command));
+                        continue;
+                    }
+                    ConfirmResponse::Copy => continue,
+                    ConfirmResponse::Edit(edited) => {
+                        ran.commands.push(edited.clone());
+                        run_step(runner, &edited, &mut ran, rest)?;
+                    }
+                    ConfirmResponse::Instruct(_) => {
+                        // Don't allow nested instruct for simplicity
+                        println!("Nested instruct not allowed. Skipping command.");
+                        continue;
+                    }
+                }
+            }
+        }
+
+        // Ctrl-C stops the turn, keeping what ran so far
+        if ran.outputs.last().is_some_and(|output| was_interrupted(output)) {
+            println!("{}", theme.helper_text("Command interrupted; the rest of this turn was not run."));
+            break;
+        }
+    }
+
+    Ok(ran)
+}
+
+/// Runs one confirmed command of the turn (already in `ran.commands`) and
+/// adds its output and exit code to `ran`. A non-zero exit becomes
+/// TurnFailed, carrying what ran so far and the reply lines after it
+/// (`rest`).
+pub(crate) fn run_step(
+    runner: &mut dyn CommandRunner,
+    command: &str,
+    ran: &mut Executed,
+    rest: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let output = runner.run(command)?;
+    if let Some(failure) = output.failure() {
+        return Err(TurnFailed {
+            commands: ran.commands.clone(),
+            outputs: ran.outputs.clone(),
+            exit_codes: ran.exit_codes.clone(),
+            failure,
+            rest: rest.to_vec(),
+        }
+        .into());
+    }
+    ran.outputs.push(output.history_output());
+    ran.exit_codes.push(output.exit_code());
+    Ok(())
+}
+
+/// A command from the model's response, with where it sits in the turn
+/// and the `#` line that explained it, if one came right before it.
+#[derive(Debug, PartialEq)]
+pub(crate) struct PendingCommand {
+    pub(crate) command: String,
+    pub(crate) explanation: Option<String>,
+    pub(crate) step: usize,
+    pub(crate) total: usize,
+}
+
+pub(crate) fn plan_commands(response: &[String]) -> Vec<PendingCommand> {
+    let total = response.iter().filter(|line| !line.starts_with('#')).count();
+    let mut plan = Vec::new();
+    let mut explanation = None;
+    for line in response {
+        if let Some(comment) = line.strip_prefix('#') {
+            explanation = Some(comment.trim().to_string());
+            continue;
+        }
+        plan.push(PendingCommand {
+            command: line.clone(),
+            explanation: explanation.take(),
+            step: plan.len() + 1,
+            total,
+        });
+    }
+    plan
+}
+
+// Lines of detour output repeated when returning to the original command
+pub(crate) const DETOUR_CONTEXT_LINES: usize = 5;
+
+/// What to show before re-asking about a command after an instruct detour:
+/// the step, the model's explanation, and the tail of the detour's output.
+pub(crate) fn detour_context(pending: &PendingCommand, detour_output: &str) -> Vec<String> {
+    let mut lines = vec![format!("Step {}/{}", pending.step, pending.total)];
+    if let Some(explanation) = &pending.explanation {
+        lines.push(explanation.clone());
+    }
+    let output: Vec<&str> = detour_output.lines().filter(|l| !l.trim().is_empty()).collect();
+    if !output.is_empty() {
+        lines.push("Last output:".to_string());
+        let start = output.len().saturating_sub(DETOUR_CONTEXT_LINES);
+        lines.extend(output[start..].iter().map(|l| format!("  {}", l)));
+    }
+    lines
+}
+
+pub(crate) const SPINNER_FRAMES: &[char] = &['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+pub(crate) const SPINNER_TICK: Duration = Duration::from_millis(100);
+
+/// Animated "waiting for the model" indicator with elapsed seconds. Runs
+/// on its own thread and wipes its line when dropped, so every exit path
+/// (including `?`) leaves a clean line for whatever prints next. Does
+/// nothing when stdout isn't a terminal.
+pub(crate) struct Spinner {
+    pub(crate) stop: Option<std::sync::mpsc::Sender<()>>,
+    pub(crate) handle: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Spinner {
+    pub(crate) fn start(theme: &Theme) -> Self {
+        if unsafe { libc_isatty(io::stdout().as_raw_fd()) } != 1 {
+            return Self { stop: None, handle: None };
+        }
+        let color = theme.prompt_color.to_string();
+        let reset = if color.is_empty() { "" } else { RESET };
+        let (stop, stopped) = std::sync::mpsc::channel::<()>();
+        let handle = std::thread::spawn(move || {
+            let started = Instant::now();
+            for frame in SPINNER_FRAMES.iter().cycle() {
+                print!("\r{}{} {}s{}", color, frame, started.elapsed().as_secs(), reset);
+                let _ = io::stdout().flush();
+                // A message or a dropped sender both mean stop
+                if !matches!(
+                    stopped.recv_timeout(SPINNER_TICK),
+                    Err(std::sync::mpsc::RecvTimeoutError::Timeout)
+                ) {
+                    break;
+                }
+            }
+            print!("\r\u{001b}[2K");
+            let _ = io::stdout().flush();
+        });
+        Self {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Spinner {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Returned when --yes hit max_auto_commands in one turn and no terminal is
+/// attached to confirm the rest.
+#[derive(Debug)]
+pub(crate) struct AutoLimitReached {
+    pub(crate) ran: usize,
+    pub(crate) remaining: usize,
+}
+
+impl std::fmt::Display for AutoLimitReached {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Stopped after {} unattended command(s); {} left unrun. Raise the limit with --max-auto N \
+             or run interactively to confirm them.",
+            self.ran, self.remaining
+        )
+    }
+}
+
+impl std::error::Error for AutoLimitReached {}
+
+/// Returned when commands need confirmation but no terminal is attached.
+#[derive(Debug)]
+pub(crate) struct NeedsConfirmation;
+
+impl std::fmt::Display for NeedsConfirmation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "No terminal to confirm commands (stdin is not one, and /dev/tty can't be opened); nothing was run. \
+             Re-run with --yes to execute them or --print to only print them \
+             (dangerous commands always need a terminal)."
+        )
+    }
+}
+
+impl std::error::Error for NeedsConfirmation {}
+
+/// True when someone can answer a confirmation prompt: either stdin is a
+/// terminal or the process still has a controlling TTY.
+pub(crate) fn human_available() -> bool {
+    !stdin_is_piped() || fs::OpenOptions::new().read(true).open("/dev/tty").is_ok()
+}
+
+pub(crate) fn confirm(command: &str, theme: &Theme, policy: &SafetyPolicy) -> Result<ConfirmResponse, io::Error> {
+    confirm_with(command, theme, policy, &mut prompt_writer(), &mut read_confirmation_line, &mut |command| {
+        edit_command(command, theme)
+    })
+}
+
+/// The confirmation prompt, reading answers with `read` and edits with
+/// `edit`. An edit is checked again: one that is (or still is) dangerous
+/// is shown and asked about like any dangerous command, so it needs a
+/// typed `yes` too.
+pub(crate) fn confirm_with(
+    original: &str,
+    theme: &Theme,
+    policy: &SafetyPolicy,
+    out: &mut dyn Write,
+    read: &mut dyn FnMut() -> Result<String, io::Error>,
+    edit: &mut dyn FnMut(&str) -> Result<Option<String>, io::Error>,
+) -> Result<ConfirmResponse, io::Error> {
+    let mut edited: Option<String> = None;
+    print_clobber_warnings(out, original, theme)?;
+    loop {
+        let command = edited.as_deref().unwrap_or(original);
+        let dangerous = policy.is_dangerous(command);
+        if dangerous {
+            write!(
+                out,
+                "{} {}?  [yes/n/s/i/e/c]  ",
+                theme.warning_text("danger>"),
+                theme.warning_text(&display_command(command))
+            )?;
+        } else {
+            write!(
+                out,
+                "{} {}?  [Y/n/s/a/i/e/c]  ",
+                theme.prompt_text("run>"),
+                theme.command_text(&display_command(command))
+            )?;
+        }
+        out.flush()?;
+
+        let input = match read() {
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {
+                writeln!(out)?;
+                return Ok(ConfirmResponse::No);
+            }
+            result => result?,
+        };
+
+        match parse_confirmation_choice(&input) {
+            Some(ConfirmChoice::Yes) if dangerous && normalize_confirmation_input(&input) != "yes" => {
+                writeln!(out, "This command looks dangerous. Type the full word 'yes' to run it.")?;
+            }
+            Some(ConfirmChoice::Yes) => return Ok(edited.map_or(ConfirmResponse::Yes, ConfirmResponse::Edit)),
+            Some(ConfirmChoice::All) if dangerous => {
+                writeln!(out, "'all' doesn't cover dangerous commands. Type the full word 'yes' to run this one.")?;
+            }
+            Some(ConfirmChoice::All) => return Ok(ConfirmResponse::All),
+            Some(ConfirmChoice::No) => return Ok(ConfirmResponse::No),
+            Some(ConfirmChoice::Skip) => return Ok(ConfirmResponse::Skip),
+            Some(ConfirmChoice::Instruct) => {
+                write!(out, "{} ", theme.prompt_text("enter>"))?;
+                out.flush()?;
+                let custom_command = match read() {
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => {
+                        writeln!(out)?;
+                        return Ok(ConfirmResponse::No);
+                    }
+                    result => result?,
+                };
+                return Ok(ConfirmResponse::Instruct(custom_command.trim().to_string()));
+            }
+            Some(ConfirmChoice::Copy) => match copy_to_clipboard(command) {
+                Ok(()) => {
+                    writeln!(out, "{}", theme.helper_text("Copied to the clipboard; not run."))?;
+                    return Ok(ConfirmResponse::Copy);
+                }
+                // Ask again so the command can still be run or skipped
+                Err(err) => writeln!(out, "{}", theme.warning_text(&format!("Couldn't copy the command: {err}")))?,
+            },
+            Some(ConfirmChoice::Edit) => {
+                // Ctrl-C or an emptied line falls back to the Y/n/s/a/i/e/c prompt
+                if let Some(text) = edit(command)?.map(attach_if_marked) {
+                    if !policy.is_dangerous(&text) {
+                        return Ok(ConfirmResponse::Edit(text));
+                    }
+                    print_clobber_warnings(out, &text, theme)?;
+                    edited = Some(text);
+                }
+            }
+            None => {
+                writeln!(out, "Invalid response. Please use Y(es), n(o), s(kip), a(ll), i(nstruct), e(dit), or c(opy).")?;
+            }
+        }
+    }
+}
+
+/// A `>` redirection that truncates its target when the shell sets it up.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Clobber {
+    pub(crate) target: String,
+    // The target is also read earlier in the same command line
+    pub(crate) self_clobber: bool,
+}
+
+/// Finds truncating redirections (`>`, `N>`, `&>`, `>|`) in a shell command.
+/// Appends (`>>`), fd duplication (`2>&1`), and `/dev/null` are ignored.
+/// Quotes and backslashes are honored when reading words and targets.
+pub(crate) fn find_clobbers(cmd: &str) -> Vec<Clobber> {
+    let mut clobbers = Vec::new();
+    let mut inputs: Vec<String> = Vec::new();
+    let mut chars = cmd.chars().peekable();
+    let mut word = String::new();
+    let mut quoted = false;
+
+    // Reads one shell word, stopping at whitespace or an operator.
+    fn read_word(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let mut word = String::new();
+        while let Some(&c) = chars.peek() {
+            match c {
+                c if c.is_whitespace() || matches!(c, '|' | ';' | '&' | '<' | '>') => break,
+                '\'' | '"' => {
+                    chars.next();
+                    while let Some(q) = chars.next() {
+                        if q == c {
+                            break;
+                        }
+                        if q == '\\' && c == '"' && let Some(escaped) = chars.next() {
+                            word.push(escaped);
+                            continue;
+                        }
+                        word.push(q);
+                    }
+                }
+                '\\' => {
+                    chars.next();
+                    if let Some(escaped) = chars.next() {
+                        word.push(escaped);
+                    }
+                }
+                _ => {
+                    word.push(c);
+                    chars.next();
+                }
+            }
+        }
+        word
+    }
+
+    let same_file = |a: &str, b: &str| a.trim_start_matches("./") == b.trim_start_matches("./");
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            '\'' | '"' | '\\' => {
+                word.push_str(&read_word(&mut chars));
+                quoted = true;
+            }
+            '>' => {
+                chars.next();
+                // `2>` names the fd being redirected; it is not a word
+                let fd_prefix = !quoted && !word.is_empty() && word.chars().all(|d| d.is_ascii_digit());
+                if !fd_prefix && !word.is_empty() {
+                    inputs.push(std::mem::take(&mut word));
+                }
+                word.clear();
+                quoted = false;
+                match chars.peek() {
+                    Some('>') | Some('&') => {
+                        chars.next();
+                        read_word(&mut chars);
+                        continue;
+                    }
+                    Some('|') => {
+                        chars.next();
+                    }
+                    _ => {}
+                }
+                let target = read_word(&mut chars);
+                if target.is_empty() || target == "/dev/null" {
+                    continue;
+                }
+                let self_clobber = inputs.iter().any(|input| same_file(input, &target));
+                clobbers.push(Clobber { target, self_clobber });
+            }
+            '<' => {
+                chars.next();
+                if !word.is_empty() {
+                    inputs.push(std::mem::take(&mut word));
+                }
+                quoted = false;
+                // `<<` heredocs and `<(...)` are not file reads
+                if chars.next_if(|&n| n == '<' || n == '(').is_none() {
+                    inputs.push(read_word(&mut chars));
+                }
+            }
+            '&' if !quoted && word.is_empty() && chars.clone().nth(1) == Some('>') => {
+                // `&>` sends stdout and stderr to the target; handled by the `>` arm
+                chars.next();
+            }
+            c if c.is_whitespace() || matches!(c, '|' | ';' | '&') => {
+                chars.next();
+                if !word.is_empty() {
+                    inputs.push(std::mem::take(&mut word));
+                }
+                quoted = false;
+            }
+            _ => {
+                word.push_str(&read_word(&mut chars));
+            }
+        }
+    }
+    clobbers
+}
+
+/// Warns before the prompt when a redirection will wipe an existing file.
+pub(crate) fn print_clobber_warnings(out: &mut dyn Write, command: &str, theme: &Theme) -> Result<(), io::Error> {
+    for clobber in find_clobbers(command) {
+        let path = match clobber.target.strip_prefix("~/") {
+            Some(rest) => dirs::home_dir().map(|home| home.join(rest)),
+            None => Some(PathBuf::from(&clobber.target)),
+        };
+        let Some(size) = path.and_then(|p| fs::metadata(p).ok()).filter(|m| m.is_file()).map(|m| m.len()) else {
+            continue;
+        };
+        if clobber.self_clobber {
+            writeln!(
+                out,
+                "{}",
+                theme.warning_text(&format!(
+                    "warning: {} is read by this command but `>` empties it first; the data will be lost.",
+                    clobber.target
+                ))
+            )?;
+            writeln!(
+                out,
+                "{}",
+                theme.helper_text(&format!(
+                    "Pipe into `sponge {}` (moreutils) or write to a temp file and mv it over.",
+                    clobber.target
+                ))
+            )?;
+        } else {
+            writeln!(
+                out,
+                "{}",
+                theme.helper_text(&format!(
+                    "note: will overwrite existing {} file {}",
+                    format_size(size),
+                    clobber.target
+                ))
+            )?;
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn format_size(bytes: u64) -> String {
+    match bytes {
+        b if b < 1024 => format!("{b}B"),
+        b if b < 1024 * 1024 => format!("{}KB", b.div_ceil(1024)),
+        b => format!("{:.1}MB", b as f64 / (1024.0 * 1024.0)),
+    }
+}
+
+/// An edited command starting with `!` runs attached to the terminal, as
+/// `raw:` would make it.
+pub(crate) fn attach_if_marked(edited: String) -> String {
+    match edited.strip_prefix('!') {
+        Some(rest) => format!("{RAW_PREFIX} {}", rest.trim_start()),
+        None => edited,
+    }
+}
+
+/// Opens the suggested command in a line editor pre-filled with its text.
+/// Returns `None` when the user cancels or clears the line.
+pub(crate) fn edit_command(command: &str, theme: &Theme) -> Result<Option<String>, io::Error> {
+    if stdin_is_piped() {
+        // stdin belongs to someone else; take a replacement line from the TTY
+        let mut out = prompt_writer();
+        write!(out, "{} ", theme.prompt_text("edit>"))?;
+        out.flush()?;
+        let line = read_confirmation_line()?;
+        let edited = line.trim();
+        return Ok((!edited.is_empty()).then(|| edited.to_string()));
+    }
+    let mut editor = DefaultEditor::new().map_err(io::Error::other)?;
+    let prompt = format!("{} ", theme.prompt_text("edit>"));
+    match editor.readline_with_initial(&prompt, (command, "")) {
+        Ok(line) => {
+            let edited = line.trim();
+            Ok((!edited.is_empty()).then(|| edited.to_string()))
+        }
+        Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => Ok(None),
+        Err(err) => Err(io::Error::other(err)),
+    }
+}
+
+/// Where interactive prompts go: stdout normally, stderr when stdout is
+/// being captured so prompts don't end up in someone's pipeline.
+pub(crate) fn prompt_writer() -> Box<dyn Write> {
+    if unsafe { libc_isatty(io::stdout().as_raw_fd()) } == 1 {
+        Box::new(io::stdout())
+    } else {
+        Box::new(io::stderr())
+    }
+}
+
+pub(crate) fn parse_confirmation_choice(input: &str) -> Option<ConfirmChoice> {
+    let trimmed = normalize_confirmation_input(input);
+
+    match trimmed.as_str() {
+        "" | "y" | "yes" => Some(ConfirmChoice::Yes),
+        "a" | "all" => Some(ConfirmChoice::All),
+        "n" | "no" => Some(ConfirmChoice::No),
+        "s" | "skip" => Some(ConfirmChoice::Skip),
+        "i" | "instruct" => Some(ConfirmChoice::Instruct),
+        "e" | "edit" => Some(ConfirmChoice::Edit),
+        "c" | "copy" => Some(ConfirmChoice::Copy),
+        _ => None,
+    }
+}
+
+pub(crate) fn read_confirmation_line() -> Result<String, io::Error> {
+    let mut input = String::new();
+
+    // Prefer reading from controlling TTY so confirmations still work
+    // when stdin is redirected or line editing is active.
+    match fs::OpenOptions::new().read(true).open("/dev/tty") {
+        Ok(mut tty) => {
+            // Flush any stale input left in the TTY buffer (e.g. from rustyline)
+            // so we only read the user's fresh response.
+            let fd = tty.as_raw_fd();
+            unsafe { libc::tcflush(fd, libc::TCIFLUSH); }
+
+            // Ctrl-C cancels the question instead of ending the session. The
+            // signal may land on another thread, so the flag is polled.
+            let sigint = CatchSigint::new();
+
+            // Read byte-by-byte and accept both \r and \n as line terminators.
+            // After rustyline restores the terminal, ICRNL may not be set,
+            // causing Enter to send \r instead of \n — which read_line() ignores.
+            let mut byte = [0u8; 1];
+            loop {
+                if sigint.caught() {
+                    return Err(confirmation_interrupted());
+                }
+                if !has_pending_input(fd, Duration::from_millis(50)) {
+                    continue;
+                }
+                match tty.read(&mut byte) {
+                    Ok(0) if input.is_empty() => return Err(confirmation_eof()),
+                    Ok(0) => break, // EOF
+                    Ok(_) => {
+                        if byte[0] == b'\n' || byte[0] == b'\r' {
+                            break;
+                        }
+                        input.push(byte[0] as char);
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Err(_) => {
+            if io::stdin().read_line(&mut input)? == 0 {
+                return Err(confirmation_eof());
+            }
+        }
+    }
+
+    Ok(input)
+}
+
+/// Ctrl-C while waiting for an answer.
+pub(crate) fn confirmation_interrupted() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "cancelled with Ctrl-C; nothing was run")
+}
+
+/// End of input while waiting for an answer. Treated as an error rather
+/// than an empty line, which would otherwise default to Yes.
+pub(crate) fn confirmation_eof() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "input closed while waiting for confirmation; nothing was run")
+}
+
+pub(crate) fn normalize_confirmation_input(input: &str) -> String {
+    let mut cleaned = String::new();
+    let mut chars = input.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        if ch == '\u{1b}' {
+            // Strip ANSI escape sequences that can leak into terminal input.
+            if matches!(chars.peek(), Some('[')) {
+                chars.next();
+                for seq_char in chars.by_ref() {
+                    if ('@'..='~').contains(&seq_char) {
+                        break;
+                    }
+                }
+            }
+            continue;
+        }
+
+        if !ch.is_control() {
+            cleaned.push(ch);
+        }
+    }
+
+    cleaned.trim().to_lowercase()
+}
+
+/// What a turn does outside the process: asking whether a command may run,
+/// then running it. `Shell` is the real terminal and shell; tests swap in a
+/// runner that answers and records instead.
+pub trait CommandRunner {
+    fn confirm(&mut self, command: &str, theme: &Theme, policy: &SafetyPolicy) -> Result<ConfirmResponse, io::Error>;
+    fn run(&mut self, command: &str) -> Result<CommandOutput, Box<dyn std::error::Error>>;
+    /// Whether to run the rest of the reply after `command` exited with
+    /// `status`.
+    fn keep_going(&mut self, command: &str, status: ExitStatus, theme: &Theme) -> Result<bool, io::Error>;
+}
+
+/// Asks at the terminal and runs commands in the user's shell.
+pub struct Shell;
+
+impl CommandRunner for Shell {
+    fn confirm(&mut self, command: &str, theme: &Theme, policy: &SafetyPolicy) -> Result<ConfirmResponse, io::Error> {
+        confirm(command, theme, policy)
+    }
+
+    fn run(&mut self, command: &str) -> Result<CommandOutput, Box<dyn std::error::Error>> {
+        run_command_with_output(command)
+    }
+
+    fn keep_going(&mut self, command: &str, status: ExitStatus, theme: &Theme) -> Result<bool, io::Error> {
+        let mut out = prompt_writer();
+        write!(
+            out,
+            "{} ",
+            theme.prompt_text(&format!("'{command}' failed ({status}); continue with the rest? [y/N]"))
+        )?;
+        out.flush()?;
+        let answer = match read_confirmation_line() {
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {
+                writeln!(out)?;
+                return Ok(false);
+            }
+            result => result?,
+        };
+        Ok(matches!(normalize_confirmation_input(&answer).as_str(), "y" | "yes"))
+    }
+}
+
+pub(crate) fn run_command_with_output(command: &str) -> Result<CommandOutput, Box<dyn std::error::Error>> {
+    run_command_to(command, false)
+}
+
+/// What a command printed and how it ended. A non-zero status is not an
+/// error here; the caller decides what a failure means.
+#[derive(Debug, Clone)]
+pub struct CommandOutput {
+    pub stdout: String,
+    pub stderr: String,
+    pub status: ExitStatus,
+    // Ctrl-C stopped it, which doesn't count as failing
+    pub interrupted: bool,
+}
+
+impl CommandOutput {
+    /// A command that printed `stdout` and exited 0.
+    pub fn success(stdout: impl Into<String>) -> Self {
+        Self::exited(stdout, 0)
+    }
+
+    /// A command that printed `stdout` and exited with `code`.
+    pub fn exited(stdout: impl Into<String>, code: i32) -> Self {
+        CommandOutput {
+            stdout: stdout.into(),
+            stderr: String::new(),
+            status: std::os::unix::process::ExitStatusExt::from_raw((code & 0xff) << 8),
+            interrupted: false,
+        }
+    }
+
+    pub(crate) fn from_bytes(status: ExitStatus, stdout: &[u8], stderr: &[u8], interrupted: bool) -> Self {
+        CommandOutput {
+            stdout: String::from_utf8_lossy(stdout).to_string(),
+            stderr: String::from_utf8_lossy(stderr).to_string(),
+            status,
+            interrupted,
+        }
+    }
+
+    /// The exit code, or None when a signal or Ctrl-C stopped the command.
+    pub fn exit_code(&self) -> Option<i32> {
+        if self.interrupted { None } else { self.status.code() }
+    }
+
+    pub(crate) fn failure(&self) -> Option<CommandFailed> {
+        (!self.interrupted && !self.status.success()).then(|| CommandFailed {
+            status: self.status,
+            output: self.combined(),
+        })
+    }
+
+    /// Stdout then stderr, the way history has always kept them.
+    pub(crate) fn combined(&self) -> String {
+        let mut result = self.stdout.clone();
+        if !self.stderr.is_empty() {
+            result.push('\n');
+            result.push_str(&self.stderr);
+        }
+        result
+    }
+
+    /// The output kept in history, marked when Ctrl-C cut it short. How it
+    /// ended is kept apart, in the turn's exit_codes.
+    pub(crate) fn history_output(&self) -> String {
+        let mut result = self.combined();
+        if self.interrupted {
+            if !result.is_empty() && !result.ends_with('\n') {
+                result.push('\n');
+            }
+            result.push_str(INTERRUPTED);
+        }
+        result
+    }
+}
+
+// Set once at startup from SafetyPolicy::audit_log
+pub(crate) static AUDIT_LOG: std::sync::OnceLock<PathBuf> = std::sync::OnceLock::new();
+
+/// Appends `timestamp<TAB>uid<TAB>cwd<TAB>status<TAB>command` to the audit
+/// log, if one is configured. Failing to write never stops the command.
+pub(crate) fn audit_command(command: &str, status: &std::process::ExitStatus) {
+    audit_line(command, &status.code().map_or("signal".to_string(), |c| c.to_string()));
+}
+
+pub(crate) fn audit_line(command: &str, status: &str) {
+    let Some(path) = AUDIT_LOG.get() else {
+        return;
+    };
+    let stamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let cwd = env::current_dir().map(|p| p.display().to_string()).unwrap_or_default();
+    let line = format!(
+        "{}\t{}\t{}\t{}\t{}\n",
+        stamp,
+        unsafe { libc::geteuid() },
+        cwd,
+        status,
+        command.replace('\n', "\\n")
+    );
+    if let Some(dir) = path.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    let written = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(line.as_bytes()));
+    if let Err(err) = written {
+        // After a hangup stderr may be gone, and eprintln! would panic
+        let _ = writeln!(io::stderr(), "Warning: could not write audit log {}: {err}", path.display());
+    }
+}
+
+pub(crate) fn running_as_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// /var/log/ask when it can be created, otherwise the data directory.
+pub(crate) fn root_audit_path() -> PathBuf {
+    let system = PathBuf::from(ROOT_AUDIT_LOG);
+    match system.parent().map(fs::create_dir_all) {
+        Some(Ok(())) => system,
+        _ => data_dir().join("audit.log"),
+    }
+}
+
+// Locale variables copied from the login shell when ask's own environment
+// lacks them
+pub(crate) const BOOTSTRAP_LOCALE_VARS: &[&str] = &["LANG", "LANGUAGE", "LC_ALL", "LC_CTYPE", "LC_MESSAGES", "LC_COLLATE"];
+// A login shell that takes longer than this (slow rc files, a prompt
+// waiting for input) is abandoned and commands run with ask's environment
+pub(crate) const BOOTSTRAP_TIMEOUT: Duration = Duration::from_secs(2);
+// Brackets the `env` dump so rc-file banners around it are ignored
+pub(crate) const BOOTSTRAP_MARKER: &str = "__ask_env_bootstrap__";
+
+// Set at startup from env_bootstrap=; the capture itself waits for the
+// first command so prompts that run nothing never pay for it
+pub(crate) static ENV_BOOTSTRAP: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+pub(crate) static SHELL_ENV: std::sync::OnceLock<ShellEnv> = std::sync::OnceLock::new();
+
+/// PATH and locale as the user's login shell sets them up, captured once
+/// per session (`$SHELL -lic env`) and applied to every command ask runs.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ShellEnv {
+    pub(crate) path: Option<String>,
+    pub(crate) locale: Vec<(String, String)>,
+    // Why nothing was captured, for `ask doctor`
+    pub(crate) error: Option<String>,
+}
+
+impl ShellEnv {
+    pub(crate) fn capture(shell: &str) -> Self {
+        match run_login_shell(shell, BOOTSTRAP_TIMEOUT) {
+            Ok(output) => parse_bootstrap_env(&output),
+            Err(error) => Self {
+                error: Some(error),
+                ..Self::default()
+            },
+        }
+    }
+
+    /// The variables to set on a child: PATH is ask's own PATH with the
+    /// login shell's extra entries appended, so an activated virtualenv
+    /// still wins; locale variables only fill gaps.
+    pub(crate) fn overrides(&self, current: impl Fn(&str) -> Option<String>) -> Vec<(String, String)> {
+        let mut vars = Vec::new();
+        if let Some(login_path) = &self.path {
+            let path = match current("PATH") {
+                Some(own) => merge_path(&own, login_path),
+                None => login_path.clone(),
+            };
+            vars.push(("PATH".to_string(), path));
+        }
+        for (name, value) in &self.locale {
+            if current(name).is_none() {
+                vars.push((name.clone(), value.clone()));
+            }
+        }
+        vars
+    }
+}
+
+/// `own` followed by the entries of `extra` it doesn't already have.
+pub(crate) fn merge_path(own: &str, extra: &str) -> String {
+    let mut entries: Vec<&str> = own.split(':').filter(|e| !e.is_empty()).collect();
+    for entry in extra.split(':') {
+        if !entry.is_empty() && !entries.contains(&entry) {
+            entries.push(entry);
+        }
+    }
+    entries.join(":")
+}
+
+/// Runs `env` in a login, interactive shell and returns what it printed,
+/// giving up after `timeout`.
+pub(crate) fn run_login_shell(shell: &str, timeout: Duration) -> Result<String, String> {
+    let script = format!("echo {BOOTSTRAP_MARKER}; env; echo {BOOTSTRAP_MARKER}");
+    let mut child = Command::new(shell)
+        .arg("-lic")
+        .arg(script)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("could not start {shell}: {e}"))?;
+    // Read on a thread so a chatty rc file can't fill the pipe and stall
+    let reader = child.stdout.take().map(|mut pipe| {
+        std::thread::spawn(move || {
+            let mut output = String::new();
+            let _ = pipe.read_to_string(&mut output);
+            output
+        })
+    });
+
+    let start = Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(_)) => break,
+            Ok(None) if start.elapsed() < timeout => std::thread::sleep(Duration::from_millis(10)),
+            Ok(None) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("{shell} took longer than {}s to start", timeout.as_secs()));
+            }
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+    Ok(reader.and_then(|h| h.join().ok()).unwrap_or_default())
+}
+
+/// What `command` prints on stdout, or None when it can't start, exits
+/// non-zero, or is still running after `timeout` (it is killed then).
+pub(crate) fn output_within(command: &mut Command, timeout: Duration) -> Option<String> {
+    let output = capture_within(command.stderr(Stdio::null()), timeout)?;
+    output.status.success().then_some(output.stdout)
+}
+
+/// Runs `command` with its output captured and nothing shown, or None when
+/// it can't start or is still running after `timeout` (it is killed then).
+pub(crate) fn capture_within(command: &mut Command, timeout: Duration) -> Option<CommandOutput> {
+    let mut child = command.stdin(Stdio::null()).stdout(Stdio::piped()).spawn().ok()?;
+    // Read on threads so a long listing can't fill a pipe and stall it
+    let stdout = child.stdout.take().map(read_pipe);
+    let stderr = child.stderr.take().map(read_pipe);
+    let start = Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if start.elapsed() < timeout => std::thread::sleep(Duration::from_millis(5)),
+            _ => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return None;
+            }
+        }
+    };
+    let join = |reader: Option<std::thread::JoinHandle<Vec<u8>>>| reader.and_then(|h| h.join().ok()).unwrap_or_default();
+    Some(CommandOutput::from_bytes(status, &join(stdout), &join(stderr), false))
+}
+
+fn read_pipe(mut pipe: impl Read + Send + 'static) -> std::thread::JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut output = Vec::new();
+        let _ = pipe.read_to_end(&mut output);
+        output
+    })
+}
+
+// How long a command the model asks to look at may run
+pub(crate) const TOOL_COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Runs a command for the run_readonly_command tool in the user's shell,
+/// capturing what it prints. Refused unless it is read-only, and never
+/// given the terminal.
+pub(crate) fn run_readonly_command(command: &str) -> Result<CommandOutput, String> {
+    readonly_command_verdict(command)?;
+    let mut child = Command::new(user_shell());
+    if let Some(shell_env) = session_shell_env() {
+        child.envs(shell_env.overrides(|name| env::var(name).ok()));
+    }
+    child.arg("-c").arg(command).stderr(Stdio::piped());
+    capture_within(&mut child, TOOL_COMMAND_TIMEOUT)
+        .ok_or_else(|| format!("it did not finish within {}s", TOOL_COMMAND_TIMEOUT.as_secs()))
+}
+
+// Clipboard writers, tried in order until one is installed and works:
+// macOS, then Wayland, then X11
+pub(crate) const CLIPBOARD_COMMANDS: &[(&str, &[&str])] =
+    &[("pbcopy", &[]), ("wl-copy", &[]), ("xclip", &["-selection", "clipboard"])];
+
+/// What goes on the clipboard for `command`: no color codes and no
+/// trailing newline, so pasting it doesn't run it straight away.
+pub(crate) fn clipboard_text(command: &str) -> String {
+    strip_ansi(command).trim_end_matches(['\n', '\r']).to_string()
+}
+
+/// Puts `command` on the system clipboard with the first of
+/// CLIPBOARD_COMMANDS that works.
+pub(crate) fn copy_to_clipboard(command: &str) -> Result<(), String> {
+    let text = clipboard_text(command);
+    let mut failure = None;
+    for (program, args) in CLIPBOARD_COMMANDS {
+        // wl-copy and xclip stay behind to serve the selection, so their
+        // output is not waited on
+        let spawned = Command::new(program)
+            .args(*args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => {
+                failure = Some(format!("{program}: {err}"));
+                continue;
+            }
+        };
+        if let Some(mut stdin) = child.stdin.take() {
+            let _ = stdin.write_all(text.as_bytes());
+        }
+        match child.wait() {
+            Ok(status) if status.success() => return Ok(()),
+            Ok(status) => failure = Some(format!("{program} failed ({status})")),
+            Err(err) => failure = Some(format!("{program}: {err}")),
+        }
+    }
+    Err(failure.unwrap_or_else(|| "no clipboard tool found (install pbcopy, wl-copy or xclip)".to_string()))
+}
+
+// Entries of `ls -la` that auto_context keeps
+pub(crate) const AUTO_CONTEXT_ENTRIES: usize = 40;
+// How long each auto_context command may take
+pub(crate) const AUTO_CONTEXT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A look at `dir` for auto_context: its path, the first
+/// AUTO_CONTEXT_ENTRIES of `ls -la`, and `git status --short` inside a
+/// work tree. Commands that fail or hang are left out.
+pub(crate) fn environment_snapshot(dir: &Path) -> String {
+    let mut text = format!("Working directory: {}\n", dir.display());
+    let mut ls = Command::new("ls");
+    ls.arg("-la").env("LC_ALL", "C").current_dir(dir);
+    if let Some(listing) = output_within(&mut ls, AUTO_CONTEXT_TIMEOUT) {
+        let entries: Vec<&str> = listing.lines().filter(|line| !line.starts_with("total ")).collect();
+        text.push_str("$ ls -la\n");
+        for entry in entries.iter().take(AUTO_CONTEXT_ENTRIES) {
+            text.push_str(&format!("{entry}\n"));
+        }
+        if entries.len() > AUTO_CONTEXT_ENTRIES {
+            text.push_str(&format!("({} more entries)\n", entries.len() - AUTO_CONTEXT_ENTRIES));
+        }
+    }
+    let mut git = Command::new("git");
+    git.args(["--no-optional-locks", "status", "--short"]).current_dir(dir);
+    if find_git_dir(dir).is_some()
+        && let Some(status) = output_within(&mut git, AUTO_CONTEXT_TIMEOUT)
+    {
+        text.push_str("$ git status --short\n");
+        text.push_str(if status.trim().is_empty() { "(no changes)\n" } else { &status });
+    }
+    text
+}
+
+/// Picks PATH and the locale variables out of the marked `env` dump. Only
+/// `NAME=value` lines count, so banners and prompts printed by rc files
+/// (even between the markers) are skipped.
+pub(crate) fn parse_bootstrap_env(output: &str) -> ShellEnv {
+    // Whole lines only: bash exports the script, marker included, as
+    // BASH_EXECUTION_STRING
+    let dump: Vec<&str> = output
+        .lines()
+        .skip_while(|line| *line != BOOTSTRAP_MARKER)
+        .skip(1)
+        .take_while(|line| *line != BOOTSTRAP_MARKER)
+        .collect();
+    if dump.is_empty() {
+        return ShellEnv {
+            error: Some("the login shell did not print its environment".to_string()),
+            ..ShellEnv::default()
+        };
+    }
+
+    let mut env = ShellEnv::default();
+    for line in dump {
+        let Some((name, value)) = line.split_once('=') else {
+            continue;
+        };
+        let valid_name = name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_name {
+            continue;
+        }
+        if name == "PATH" {
+            env.path = Some(value.to_string());
+        } else if BOOTSTRAP_LOCALE_VARS.contains(&name) && !value.is_empty() {
+            env.locale.push((name.to_string(), value.to_string()));
+        }
+    }
+    env
+}
+
+pub(crate) fn user_shell() -> String {
+    env::var("SHELL").unwrap_or_else(|_| "/bin/zsh".to_string())
+}
+
+/// The login-shell environment for this session, captured on first use.
+/// None when env_bootstrap=off.
+pub(crate) fn session_shell_env() -> Option<&'static ShellEnv> {
+    if !ENV_BOOTSTRAP.load(std::sync::atomic::Ordering::Relaxed) {
+        return None;
+    }
+    Some(SHELL_ENV.get_or_init(|| ShellEnv::capture(&user_shell())))
+}
+
+// Commands started so far, so anything cached about the working tree
+// (such as the prompt's git status) knows when to look again
+pub(crate) static COMMANDS_RUN: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+pub(crate) fn commands_run() -> usize {
+    COMMANDS_RUN.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+/// Runs `command`, streaming its output live and returning it for history.
+/// With `stdout_to_stderr` the child's stdout is echoed on stderr, leaving our
+/// stdout free for machine-readable results.
+pub(crate) fn run_command_to(command: &str, stdout_to_stderr: bool) -> Result<CommandOutput, Box<dyn std::error::Error>> {
+    COMMANDS_RUN.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    if let Some(command) = attached_command(command) {
+        return run_attached(command, stdout_to_stderr);
+    }
+    let echo = std::sync::Arc::new(LiveEcho::for_terminal(stdout_to_stderr));
+    let output = match run_in_persistent_shell(command, &echo) {
+        Some(result) => result?,
+        None => run_in_fresh_shell(command, &echo)?,
+    };
+    // History gets all of it either way; only the screen was spared
+    if echo.held() && !output.interrupted && !run_pager(&output.combined()) {
+        print!("{}", output.combined());
+    }
+    Ok(output)
+}
+
+// Marks a command to run attached to the terminal: `raw: make menuconfig`
+pub(crate) const RAW_PREFIX: &str = "raw:";
+// What history keeps for a command that had the terminal to itself
+pub(crate) const ATTACHED_OUTPUT: &str = "(interactive command, output not captured)";
+
+// Full-screen programs and remote sessions that always want the terminal
+pub(crate) const TTY_PROGRAMS: &[&str] = &[
+    "vim", "vi", "nvim", "nano", "emacs", "pico", "micro", "less", "more", "most", "man", "top", "htop", "btop",
+    "atop", "tmux", "screen", "watch", "mc", "ranger", "nnn", "tig", "fzf", "mosh", "telnet", "ftp", "sftp",
+];
+// Interpreters and shells: interactive when given nothing to run
+pub(crate) const REPL_PROGRAMS: &[&str] =
+    &["python", "python3", "node", "irb", "ghci", "lua", "bash", "sh", "zsh", "fish"];
+// Database clients: interactive unless given a query to run
+pub(crate) const DB_CLIENTS: &[&str] = &["psql", "mysql", "sqlite3", "redis-cli", "mongosh"];
+
+// Set at startup from tty_command
+pub(crate) static TTY_COMMANDS: std::sync::OnceLock<Vec<String>> = std::sync::OnceLock::new();
+
+/// The command to run attached to the terminal, if it needs one: forced
+/// with `raw:`, or recognized by needs_terminal.
+pub(crate) fn attached_command(command: &str) -> Option<&str> {
+    if let Some(rest) = command.strip_prefix(RAW_PREFIX) {
+        return Some(rest.trim_start());
+    }
+    let extra = TTY_COMMANDS.get().map_or(&[][..], Vec::as_slice);
+    needs_terminal(command, extra).then_some(command)
+}
+
+/// Whether some program in `command` wants a terminal rather than pipes:
+/// an editor, pager or monitor, an ssh login, a REPL with nothing to run,
+/// a git command that opens an editor, or one of `extra`.
+pub(crate) fn needs_terminal(command: &str, extra: &[String]) -> bool {
+    let Some(tokens) = shell_tokens(command) else {
+        return false;
+    };
+    let mut stages: Vec<(Vec<String>, bool)> = vec![(Vec::new(), false)];
+    for token in tokens {
+        match token {
+            ShellToken::Word(word) => stages.last_mut().unwrap().0.push(word),
+            // Input from a file or heredoc isn't the keyboard
+            ShellToken::Op("<") => stages.last_mut().unwrap().1 = true,
+            ShellToken::Op(">" | ">>") => {}
+            ShellToken::Op(_) => stages.push((Vec::new(), false)),
+        }
+    }
+    stages.iter().any(|(words, redirected)| {
+        let words: Vec<&str> = words
+            .iter()
+            .map(String::as_str)
+            .skip_while(|w| matches!(*w, "sudo" | "env" | "time" | "nohup" | "exec") || is_assignment(w))
+            .collect();
+        let Some((program, args)) = words.split_first() else {
+            return false;
+        };
+        let program = program.rsplit('/').next().unwrap_or(program);
+        let positional = || args.iter().filter(|a| !a.starts_with('-')).count();
+        let has = |flags: &[&str]| args.iter().any(|a| flags.contains(a));
+        match program {
+            _ if TTY_PROGRAMS.contains(&program) || extra.iter().any(|e| e == program) => true,
+            "ssh" => !has(&["-T", "-N", "-f"]) && ssh_remote_command(args).is_none(),
+            "git" => match args.first().copied() {
+                Some("commit") => !args.iter().any(|a| {
+                    // -m, -F, -C, or a cluster ending in one such as -am
+                    let short = !a.starts_with("--") && a.starts_with('-') && a.ends_with(['m', 'F', 'C']);
+                    short || a.starts_with("--message") || a.starts_with("--file") || *a == "--no-edit"
+                }),
+                Some("rebase") => has(&["-i", "--interactive"]),
+                Some("add" | "checkout" | "reset" | "stash") => has(&["-p", "--patch", "-i", "--interactive"]),
+                _ => false,
+            },
+            _ if REPL_PROGRAMS.contains(&program) => !redirected && positional() == 0 && !has(&["-c", "-e", "-m"]),
+            _ if DB_CLIENTS.contains(&program) => {
+                !redirected && positional() <= 1 && !has(&["-c", "-e", "--command", "--execute", "-f", "--file"])
+            }
+            _ => false,
+        }
+    })
+}
+
+pub(crate) fn is_assignment(word: &str) -> bool {
+    word.split_once('=').is_some_and(|(name, _)| !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+}
+
+/// The command ssh runs on the remote host, if there is one after the
+/// destination. Options that take a value are skipped with it.
+pub(crate) fn ssh_remote_command<'a>(args: &[&'a str]) -> Option<&'a str> {
+    let mut words = args.iter();
+    let mut destination = false;
+    while let Some(word) = words.next() {
+        if let Some(option) = word.strip_prefix('-') {
+            if option.len() == 1 && "bcDEeFIiJLlmOoPpQRSWw".contains(option) {
+                words.next();
+            }
+        } else if destination {
+            return Some(word);
+        } else {
+            destination = true;
+        }
+    }
+    None
+}
+
+/// Runs `command` with the terminal all to itself, for editors, pagers,
+/// monitors and remote logins. Nothing is captured: history gets a note
+/// and the exit code. Terminal modes are put back afterwards, whatever
+/// state the program left them in.
+pub(crate) fn run_attached(command: &str, stdout_to_stderr: bool) -> Result<CommandOutput, Box<dyn std::error::Error>> {
+    let mut child = Command::new(user_shell());
+    if let Some(shell_env) = session_shell_env() {
+        child.envs(shell_env.overrides(|name| env::var(name).ok()));
+    }
+    child.arg("-c").arg(command);
+    // Stdin may be carrying --stdin-commands; the keyboard is on the tty
+    if unsafe { libc_isatty(io::stdin().as_raw_fd()) } != 1
+        && let Ok(tty) = fs::File::open("/dev/tty")
+    {
+        child.stdin(tty);
+    }
+    if stdout_to_stderr {
+        use std::os::fd::AsFd;
+        child.stdout(io::stderr().as_fd().try_clone_to_owned()?);
+    }
+    io::stdout().flush()?;
+    let modes = TerminalGuard::capture();
+    // Ctrl-C is for the program, not for ask
+    let sigint = CatchSigint::new();
+    let status = child.status();
+    modes.restore();
+    let status = status?;
+    audit_command(command, &status);
+    let killed = std::os::unix::process::ExitStatusExt::signal(&status) == Some(libc::SIGINT);
+    Ok(CommandOutput {
+        stdout: ATTACHED_OUTPUT.to_string(),
+        stderr: String::new(),
+        status,
+        interrupted: killed || (sigint.caught() && !status.success()),
+    })
+}
+
+pub(crate) fn run_in_fresh_shell(
+    command: &str,
+    echo: &std::sync::Arc<LiveEcho>,
+) -> Result<CommandOutput, Box<dyn std::error::Error>> {
+    let shell = user_shell();
+    let mut child = Command::new(&shell);
+    if let Some(shell_env) = session_shell_env() {
+        child.envs(shell_env.overrides(|name| env::var(name).ok()));
+    }
+    let mode = hangup_mode();
+    if mode == HangupMode::Detach {
+        detach_on_hangup(&mut child);
+    }
+    let mut child = child
+        .arg("-c")
+        .arg(command)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // The child shares our process group, so Ctrl-C reaches it directly;
+    // catching SIGINT here keeps the REPL alive while the child dies.
+    let sigint = CatchSigint::new();
+
+    // Stream both pipes as lines arrive, keeping a copy for history
+    let stdout = child.stdout.take().map(|pipe| {
+        let echo = echo.clone();
+        std::thread::spawn(move || stream_lines(pipe, echo.stdout_sink(), &echo))
+    });
+    let stderr = child.stderr.take().map(|pipe| {
+        let echo = echo.clone();
+        std::thread::spawn(move || stream_lines(pipe, io::stderr(), &echo))
+    });
+    let status = match wait_for_child(&mut child, &HANGUP_CAUGHT, mode, HANGUP_GRACE)? {
+        ChildEnd::Exited(status) => status,
+        // The output threads are left behind: a detached child still holds
+        // the pipes, and there is no terminal to show them on anyway
+        ChildEnd::HungUp { orphan } => {
+            let status = match orphan {
+                Some(pid) => {
+                    record_orphan(pid, command);
+                    format!("interrupted by hangup, left running as pid {pid}")
+                }
+                None => "interrupted by hangup".to_string(),
+            };
+            audit_line(command, &status);
+            return Err(HungUp { command: command.to_string(), orphan }.into());
+        }
+    };
+    audit_command(command, &status);
+    let stdout = stdout.and_then(|h| h.join().ok()).unwrap_or_default();
+    let stderr = stderr.and_then(|h| h.join().ok()).unwrap_or_default();
+    let killed = std::os::unix::process::ExitStatusExt::signal(&status) == Some(libc::SIGINT);
+    let interrupted = killed || (sigint.caught() && !status.success());
+    Ok(CommandOutput::from_bytes(status, &stdout, &stderr, interrupted))
+}
+
+/// A command that ran and exited non-zero, with what it printed.
+#[derive(Debug)]
+pub(crate) struct CommandFailed {
+    pub(crate) status: std::process::ExitStatus,
+    pub(crate) output: String,
+}
+
+impl std::fmt::Display for CommandFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Command exited with status {}", self.status)
+    }
+}
+
+impl std::error::Error for CommandFailed {}
+
+/// A command's status as a shell reports it in `$?`: its exit code, or 128
+/// plus the signal that stopped it.
+pub(crate) fn shell_exit_code(status: ExitStatus) -> i32 {
+    match (status.code(), std::os::unix::process::ExitStatusExt::signal(&status)) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1,
+    }
+}
+
+/// A confirmed command from the model's reply failed. Carries the turn up
+/// to and including it, so --auto-fix can record the failure and ask for
+/// a repair.
+#[derive(Debug)]
+pub(crate) struct TurnFailed {
+    // What ran, ending with the command that failed
+    pub(crate) commands: Vec<String>,
+    // Output and exit code of each command before it
+    pub(crate) outputs: Vec<String>,
+    pub(crate) exit_codes: Vec<Option<i32>>,
+    pub(crate) failure: CommandFailed,
+    // The reply's lines after the failed command, not run
+    pub(crate) rest: Vec<String>,
+}
+
+impl std::fmt::Display for TurnFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.failure.fmt(f)
+    }
+}
+
+impl std::error::Error for TurnFailed {}
+
+impl TurnFailed {
+    pub(crate) fn failed_command(&self) -> &str {
+        self.commands.last().map_or("", String::as_str)
+    }
+
+    /// Whether the reply had commands left when this one failed.
+    pub(crate) fn has_rest(&self) -> bool {
+        self.rest.iter().any(|line| !line.starts_with('#'))
+    }
+}
+
+pub(crate) fn was_interrupted(output: &str) -> bool {
+    output.ends_with(INTERRUPTED)
+}
+
+// The interactive session's long-lived shell when persistent_shell=on;
+// None runs each command in a fresh `$SHELL -c`
+pub(crate) static PERSISTENT_SHELL: std::sync::Mutex<Option<PersistentShell>> = std::sync::Mutex::new(None);
+
+/// One shell process that every command is fed to, so `cd`, `export`, and
+/// aliases carry over from one command to the next. After each command a
+/// marker line on both pipes carries the exit status and working directory.
+pub(crate) struct PersistentShell {
+    pub(crate) child: std::process::Child,
+    pub(crate) stdin: std::process::ChildStdin,
+    pub(crate) lines: std::sync::mpsc::Receiver<(ShellPipe, Vec<u8>)>,
+    pub(crate) marker: String,
+    // Where commands read input from; our stdin pipe is the shell's script
+    pub(crate) input: &'static str,
+    pub(crate) shell: String,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum ShellPipe {
+    Stdout,
+    Stderr,
+}
+
+/// What a command left behind in the persistent shell.
+pub(crate) struct ShellRun {
+    pub(crate) status: std::process::ExitStatus,
+    pub(crate) stdout: Vec<u8>,
+    pub(crate) stderr: Vec<u8>,
+    pub(crate) cwd: Option<PathBuf>,
+}
+
+impl PersistentShell {
+    pub(crate) fn start(shell: &str) -> io::Result<Self> {
+        let mut command = Command::new(shell);
+        if let Some(shell_env) = session_shell_env() {
+            command.envs(shell_env.overrides(|name| env::var(name).ok()));
+        }
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let (sender, lines) = std::sync::mpsc::channel();
+        let pipes: [(ShellPipe, Option<Box<dyn Read + Send>>); 2] = [
+            (ShellPipe::Stdout, child.stdout.take().map(|p| Box::new(p) as Box<dyn Read + Send>)),
+            (ShellPipe::Stderr, child.stderr.take().map(|p| Box::new(p) as Box<dyn Read + Send>)),
+        ];
+        for (pipe, reader) in pipes {
+            let (Some(reader), sender) = (reader, sender.clone()) else {
+                continue;
+            };
+            std::thread::spawn(move || {
+                let mut reader = io::BufReader::new(reader);
+                loop {
+                    let mut line = Vec::new();
+                    match reader.read_until(b'\n', &mut line) {
+                        Ok(0) | Err(_) => break,
+                        Ok(_) if sender.send((pipe, line)).is_err() => break,
+                        Ok(_) => {}
+                    }
+                }
+            });
+        }
+        let stdin = child.stdin.take().ok_or_else(|| io::Error::other("no stdin for the shell"))?;
+        let stamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        let mut shell = Self {
+            child,
+            stdin,
+            lines,
+            marker: format!("__ask_done_{}_{}", std::process::id(), stamp),
+            input: if fs::File::open("/dev/tty").is_ok() { "/dev/tty" } else { "/dev/null" },
+            shell: shell.to_string(),
+        };
+        // A trap (not an ignore) keeps Ctrl-C from killing the shell while
+        // commands still get the default SIGINT behaviour; bash needs
+        // expand_aliases for aliases defined by earlier commands.
+        writeln!(shell.stdin, "trap : INT; shopt -s expand_aliases 2>/dev/null")?;
+        Ok(shell)
+    }
+
+    /// Runs `command` in the shell from ask's working directory, streaming
+    /// its output. An error means the shell itself is gone.
+    pub(crate) fn run(&mut self, command: &str, echo: &LiveEcho) -> Result<ShellRun, String> {
+        // Some shells (dash, bash --posix) exit on a syntax error inside
+        // eval, so broken commands are caught by a parse-only run first
+        if let Ok(check) = Command::new(&self.shell).arg("-n").arg("-c").arg(command).output()
+            && !check.status.success()
+        {
+            let _ = io::stderr().write_all(&check.stderr);
+            return Ok(ShellRun {
+                status: check.status,
+                stdout: Vec::new(),
+                stderr: check.stderr,
+                cwd: None,
+            });
+        }
+        let cwd = env::current_dir().map(|p| p.display().to_string()).unwrap_or_default();
+        // eval of one quoted string can't leave the shell waiting for the
+        // rest of an unbalanced quote or heredoc
+        let script = format!(
+            "cd -- {} 2>/dev/null; eval {} <{}; printf '%s %s %s\\n' {m} \"$?\" \"$PWD\"; printf '%s\\n' {m} >&2\n",
+            shell_quote(&cwd),
+            shell_quote(command),
+            self.input,
+            m = self.marker,
+        );
+        let died = |_| "the persistent shell exited".to_string();
+        self.stdin.write_all(script.as_bytes()).map_err(died)?;
+        self.stdin.flush().map_err(died)?;
+
+        let mut run = ShellRun {
+            status: std::os::unix::process::ExitStatusExt::from_raw(0),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            cwd: None,
+        };
+        let (mut stdout, mut stderr) = (CapturedOutput::new(max_output_bytes()), CapturedOutput::new(max_output_bytes()));
+        let (mut stdout_done, mut stderr_done) = (false, false);
+        while !(stdout_done && stderr_done) {
+            forward_sigint(self.child.id());
+            let (pipe, line) = match self.lines.recv_timeout(CHILD_POLL) {
+                Ok(next) => next,
+                Err(std::sync::mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
+                    return Err("the persistent shell exited".to_string());
+                }
+            };
+            let (content, rest) = match find_bytes(&line, self.marker.as_bytes()) {
+                Some(at) => (&line[..at], Some(&line[at + self.marker.len()..])),
+                None => (&line[..], None),
+            };
+            let mut sink: Box<dyn Write> = match pipe {
+                ShellPipe::Stdout => echo.stdout_sink(),
+                ShellPipe::Stderr => Box::new(io::stderr()),
+            };
+            if !content.is_empty() {
+                echo.write(&mut sink, content);
+            }
+            match pipe {
+                ShellPipe::Stdout => stdout.push(content),
+                ShellPipe::Stderr => stderr.push(content),
+            }
+
+            let Some(rest) = rest else {
+                continue;
+            };
+            if pipe == ShellPipe::Stderr {
+                stderr_done = true;
+                continue;
+            }
+            stdout_done = true;
+            let rest = String::from_utf8_lossy(rest);
+            let (code, cwd) = rest.trim_start().trim_end_matches('\n').split_once(' ').unwrap_or(("1", ""));
+            let code = code.parse::<i32>().unwrap_or(1);
+            run.status = std::os::unix::process::ExitStatusExt::from_raw((code & 0xff) << 8);
+            run.cwd = (!cwd.is_empty()).then(|| PathBuf::from(cwd));
+        }
+        run.stdout = stdout.finish();
+        run.stderr = stderr.finish();
+        Ok(run)
+    }
+}
+
+impl Drop for PersistentShell {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Single-quotes `text` for the shell.
+pub(crate) fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+pub(crate) fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// Starts the persistent shell for an interactive session.
+pub(crate) fn start_persistent_shell(theme: &Theme) {
+    match PersistentShell::start(&user_shell()) {
+        Ok(shell) => {
+            if let Ok(mut slot) = PERSISTENT_SHELL.lock() {
+                *slot = Some(shell);
+            }
+        }
+        Err(err) => eprintln!(
+            "{}",
+            theme.helper_text(&format!("Could not start a persistent shell ({err}); each command gets a fresh one."))
+        ),
+    }
+}
+
+/// Runs `command` in the persistent shell if there is one, following its
+/// `cd`s. None means no persistent shell is running.
+pub(crate) fn run_in_persistent_shell(
+    command: &str,
+    echo: &LiveEcho,
+) -> Option<Result<CommandOutput, Box<dyn std::error::Error>>> {
+    let mut slot = PERSISTENT_SHELL.lock().ok()?;
+    let shell = slot.as_mut()?;
+    let sigint = CatchSigint::new();
+    match shell.run(command, echo) {
+        Ok(run) => {
+            audit_command(command, &run.status);
+            if let Some(cwd) = &run.cwd
+                && env::current_dir().ok().as_ref() != Some(cwd)
+            {
+                let _ = env::set_current_dir(cwd);
+            }
+            let interrupted = sigint.caught() && !run.status.success();
+            Some(Ok(CommandOutput::from_bytes(run.status, &run.stdout, &run.stderr, interrupted)))
+        }
+        Err(err) => {
+            // Later commands fall back to a fresh shell each
+            *slot = None;
+            eprintln!("Warning: {err}; later commands run in a fresh shell each.");
+            Some(Err(err.into()))
+        }
+    }
+}
+
+/// How much of a running command's output is shown as it arrives, and
+/// where. Once a screenful has gone by the rest is held back, to be paged
+/// when the command ends, so a flood doesn't wipe the scrollback.
+pub(crate) struct LiveEcho {
+    // Stdout is echoed on stderr, leaving ours for machine-readable results
+    pub(crate) to_stderr: bool,
+    // Lines still to show; stdout and stderr share them
+    pub(crate) remaining: std::sync::atomic::AtomicUsize,
+    pub(crate) held: std::sync::atomic::AtomicBool,
+}
+
+impl LiveEcho {
+    /// Everything is shown.
+    pub(crate) fn all(to_stderr: bool) -> Self {
+        Self::lines(usize::MAX, to_stderr)
+    }
+
+    pub(crate) fn lines(lines: usize, to_stderr: bool) -> Self {
+        LiveEcho {
+            to_stderr,
+            remaining: std::sync::atomic::AtomicUsize::new(lines),
+            held: std::sync::atomic::AtomicBool::new(false),
+        }
+    }
+
+    /// As much as the pager setting lets through: all of it unless output
+    /// is going to a terminal that can page.
+    pub(crate) fn for_terminal(to_stderr: bool) -> Self {
+        let rows = terminal_rows();
+        match pager_mode() {
+            _ if to_stderr || rows == 0 => Self::all(to_stderr),
+            PagerMode::Off => Self::all(to_stderr),
+            // Room for the line that says the rest is coming
+            PagerMode::Auto => Self::lines(rows.saturating_sub(2), to_stderr),
+            PagerMode::Always => Self::lines(0, to_stderr),
+        }
+    }
+
+    pub(crate) fn stdout_sink(&self) -> Box<dyn Write + Send> {
+        if self.to_stderr { Box::new(io::stderr()) } else { Box::new(io::stdout()) }
+    }
+
+    /// Shows `line` on `sink` while there is room. The first line held back
+    /// says where the rest went.
+    pub(crate) fn write(&self, sink: &mut dyn Write, line: &[u8]) {
+        use std::sync::atomic::Ordering::Relaxed;
+        if self.remaining.fetch_update(Relaxed, Relaxed, |left| left.checked_sub(1)).is_ok() {
+            let _ = sink.write_all(line);
+            let _ = sink.flush();
+        } else if !self.held.swap(true, Relaxed) {
+            let _ = writeln!(sink, "{OUTPUT_HELD}");
+            let _ = sink.flush();
+        }
+    }
+
+    pub(crate) fn held(&self) -> bool {
+        self.held.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+// Shown in place of output held back for the pager
+pub(crate) const OUTPUT_HELD: &str = "… more output; it opens in the pager when the command finishes (pager=off to turn this off)";
+
+/// Copies `pipe` to `sink` line by line, as far as `echo` allows, and
+/// returns what it read as history keeps it (see CapturedOutput).
+pub(crate) fn stream_lines(pipe: impl Read, mut sink: impl Write, echo: &LiveEcho) -> Vec<u8> {
+    let mut reader = io::BufReader::new(pipe);
+    let mut captured = CapturedOutput::new(max_output_bytes());
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        // A line with no end in sight comes in pieces, so it can't fill memory
+        match reader.by_ref().take(MAX_LINE_PIECE).read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {
+                echo.write(&mut sink, &line);
+                captured.push(&line);
+            }
+        }
+    }
+    captured.finish()
+}
+
+// Default for max_output_bytes: what one stream of a command's output may
+// keep in memory and history
+pub(crate) const DEFAULT_MAX_OUTPUT_BYTES: usize = 256 * 1024;
+// Longest piece of a single line read at once
+pub(crate) const MAX_LINE_PIECE: u64 = 64 * 1024;
+
+// Set at startup from max_output_bytes; 0 keeps everything
+pub(crate) static MAX_OUTPUT_BYTES: std::sync::atomic::AtomicUsize =
+    std::sync::atomic::AtomicUsize::new(DEFAULT_MAX_OUTPUT_BYTES);
+
+pub(crate) fn max_output_bytes() -> usize {
+    MAX_OUTPUT_BYTES.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+/// One stream of a command's output, kept within `limit` bytes: the first
+/// half and the last half, with a marker saying how much was left out in
+/// between. The terminal still sees all of it as it streams.
+pub(crate) struct CapturedOutput {
+    pub(crate) limit: usize,
+    pub(crate) head: Vec<u8>,
+    pub(crate) tail: std::collections::VecDeque<u8>,
+    // Set once something didn't fit in the head
+    pub(crate) head_full: bool,
+    pub(crate) omitted: usize,
+}
+
+impl CapturedOutput {
+    pub(crate) fn new(limit: usize) -> Self {
+        CapturedOutput {
+            limit,
+            head: Vec::new(),
+            tail: std::collections::VecDeque::new(),
+            head_full: false,
+            omitted: 0,
+        }
+    }
+
+    pub(crate) fn push(&mut self, bytes: &[u8]) {
+        let half = self.limit / 2;
+        if self.limit == 0 || (!self.head_full && self.head.len() + bytes.len() <= half) {
+            self.head.extend_from_slice(bytes);
+            return;
+        }
+        self.head_full = true;
+        self.tail.extend(bytes);
+        let excess = self.tail.len().saturating_sub(self.limit - half);
+        self.tail.drain(..excess);
+        self.omitted += excess;
+    }
+
+    pub(crate) fn finish(mut self) -> Vec<u8> {
+        if self.omitted > 0 {
+            // Start the tail on a whole line when there is one
+            let cut = self.tail.iter().position(|&b| b == b'\n').map_or(0, |at| at + 1);
+            if cut < self.tail.len() {
+                self.tail.drain(..cut);
+                self.omitted += cut;
+            }
+            debug_log(
+                1,
+                &format!(
+                    "output: kept {} of {} bytes (max_output_bytes={})",
+                    self.head.len() + self.tail.len(),
+                    self.head.len() + self.tail.len() + self.omitted,
+                    self.limit
+                ),
+            );
+            if !self.head.is_empty() && !self.head.ends_with(b"\n") {
+                self.head.push(b'\n');
+            }
+            self.head.extend_from_slice(format!("… {} bytes omitted …\n", self.omitted).as_bytes());
+        }
+        self.head.extend(self.tail);
+        self.head
+    }
+}
+
+// Ends the output of a command stopped with Ctrl-C
+pub(crate) const INTERRUPTED: &str = "(interrupted)";
+
+// Set by the handler CatchSigint installs
+pub(crate) static COMMAND_SIGINT: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+// ...when another process sent it with kill(2): the terminal's Ctrl-C
+// already reached the whole process group, this one still has to
+pub(crate) static COMMAND_SIGINT_SENT: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+pub(crate) extern "C" fn note_command_sigint(_: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    COMMAND_SIGINT.store(true, std::sync::atomic::Ordering::Relaxed);
+    let sender = if info.is_null() { 0 } else { unsafe { (*info).si_pid() } };
+    // Our own forwarded signal comes back to us too
+    if sender != 0 && sender != unsafe { libc::getpid() } {
+        COMMAND_SIGINT_SENT.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+/// Catches SIGINT in this process until dropped, then restores the
+/// previous disposition, so Ctrl-C stops the command or question at hand
+/// rather than the session.
+pub(crate) struct CatchSigint {
+    pub(crate) previous: libc::sigaction,
+}
+
+impl CatchSigint {
+    pub(crate) fn new() -> Self {
+        COMMAND_SIGINT.store(false, std::sync::atomic::Ordering::Relaxed);
+        COMMAND_SIGINT_SENT.store(false, std::sync::atomic::Ordering::Relaxed);
+        let handler = note_command_sigint as extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void);
+        let mut action = unsafe { std::mem::zeroed::<libc::sigaction>() };
+        action.sa_sigaction = handler as libc::sighandler_t;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+        let mut previous = unsafe { std::mem::zeroed::<libc::sigaction>() };
+        unsafe {
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(libc::SIGINT, &action, &mut previous);
+        }
+        Self { previous }
+    }
+
+    pub(crate) fn caught(&self) -> bool {
+        COMMAND_SIGINT.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+impl Drop for CatchSigint {
+    fn drop(&mut self) {
+        unsafe { libc::sigaction(libc::SIGINT, &self.previous, std::ptr::null_mut()) };
+    }
+}
+
+/// Passes a SIGINT sent to ask by another process on to the process group
+/// of the command `pid`, as a Ctrl-C at the terminal would have been.
+pub(crate) fn forward_sigint(pid: u32) {
+    if COMMAND_SIGINT_SENT.swap(false, std::sync::atomic::Ordering::Relaxed) {
+        unsafe { libc::killpg(libc::getpgid(pid as libc::pid_t), libc::SIGINT) };
+    }
+}
+
+/// What happens to a running command when the terminal goes away
+/// (on_hangup=forward|detach).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum HangupMode {
+    // Pass SIGHUP on and give the command HANGUP_GRACE to stop
+    Forward,
+    // The command ignores SIGHUP and keeps running; its pid is recorded
+    Detach,
+}
+
+// How long a command has to exit after a forwarded SIGHUP before it is
+// recorded as left running
+pub(crate) const HANGUP_GRACE: Duration = Duration::from_secs(3);
+// Polling interval while a command runs
+pub(crate) const CHILD_POLL: Duration = Duration::from_millis(20);
+
+pub(crate) static HANGUP_CAUGHT: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+pub(crate) static HANGUP_MODE: std::sync::OnceLock<HangupMode> = std::sync::OnceLock::new();
+
+pub(crate) extern "C" fn note_sighup(_: libc::c_int) {
+    HANGUP_CAUGHT.store(true, std::sync::atomic::Ordering::Relaxed);
+}
+
+/// Interactive mode outlives a closed terminal long enough to deal with
+/// the running command and save the session.
+pub(crate) fn catch_hangup() {
+    let handler = note_sighup as extern "C" fn(libc::c_int);
+    unsafe { libc::signal(libc::SIGHUP, handler as libc::sighandler_t) };
+}
+
+pub(crate) fn hung_up() -> bool {
+    HANGUP_CAUGHT.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+pub(crate) fn hangup_mode() -> HangupMode {
+    *HANGUP_MODE.get().unwrap_or(&HangupMode::Forward)
+}
+
+/// nohup for a command about to be spawned. SIGPIPE is ignored too, so
+/// writing to the pipes ask leaves behind fails instead of killing it.
+pub(crate) fn detach_on_hangup(command: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    unsafe {
+        command.pre_exec(|| {
+            libc::signal(libc::SIGHUP, libc::SIG_IGN);
+            libc::signal(libc::SIGPIPE, libc::SIG_IGN);
+            Ok(())
+        });
+    }
+}
+
+/// How a command's wait ended.
+#[derive(Debug, PartialEq)]
+pub(crate) enum ChildEnd {
+    Exited(std::process::ExitStatus),
+    // The terminal closed; `orphan` is the pid if it is still running
+    HungUp { orphan: Option<u32> },
+}
+
+/// Waits for `child`, watching `hangup`. After a hangup in Forward mode
+/// the child gets SIGHUP and `grace` to exit; in Detach mode, or if it
+/// outlasts the grace period, it is left running.
+pub(crate) fn wait_for_child(
+    child: &mut std::process::Child,
+    hangup: &std::sync::atomic::AtomicBool,
+    mode: HangupMode,
+    grace: Duration,
+) -> io::Result<ChildEnd> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(ChildEnd::Exited(status));
+        }
+        if hangup.load(std::sync::atomic::Ordering::Relaxed) {
+            break;
+        }
+        forward_sigint(child.id());
+        std::thread::sleep(CHILD_POLL);
+    }
+    if mode == HangupMode::Forward {
+        unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGHUP) };
+        let deadline = Instant::now() + grace;
+        while Instant::now() < deadline {
+            if child.try_wait()?.is_some() {
+                return Ok(ChildEnd::HungUp { orphan: None });
+            }
+            std::thread::sleep(CHILD_POLL);
+        }
+    }
+    Ok(ChildEnd::HungUp { orphan: Some(child.id()) })
+}
+
+/// A command cut short because the terminal closed.
+#[derive(Debug)]
+pub(crate) struct HungUp {
+    pub(crate) command: String,
+    pub(crate) orphan: Option<u32>,
+}
+
+impl std::fmt::Display for HungUp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "interrupted by hangup: {}", self.command)
+    }
+}
+
+impl std::error::Error for HungUp {}
+
+impl HungUp {
+    /// The interrupted turn, as the recovery file keeps it.
+    pub(crate) fn turn(&self, prompt: &str) -> ConversationContext {
+        let outcome = match self.orphan {
+            Some(pid) => format!("(interrupted by hangup; left running as pid {pid})"),
+            None => "(interrupted by hangup)".to_string(),
+        };
+        ConversationContext {
+            prompt: prompt.to_string(),
+            commands: vec![self.command.clone()],
+            outputs: vec![outcome],
+            exit_codes: vec![None],
+            request: None,
+            pinned: false,
+            forgotten: false,
+        }
+    }
+}
+
+// One `time<TAB>pid<TAB>cwd<TAB>command` line per command left running
+pub(crate) const ORPHANS_FILE: &str = "orphans";
+// What the next interactive start should mention, then delete
+pub(crate) const HANGUP_NOTICE_FILE: &str = "hangup";
+
+pub(crate) fn record_orphan(pid: u32, command: &str) {
+    let cwd = env::current_dir().map(|p| p.display().to_string()).unwrap_or_default();
+    let command = command.replace('\n', "\\n");
+    append_line(&data_dir().join(ORPHANS_FILE), &format!("{}\t{pid}\t{cwd}\t{command}", unix_now()));
+    append_line(&data_dir().join(HANGUP_NOTICE_FILE), &format!("orphan\t{pid}\t{command}"));
+}
+
+/// Best effort: nobody is left to tell when this fails.
+pub(crate) fn append_line(path: &Path, line: &str) {
+    if let Some(dir) = path.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    let _ = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{line}"));
+}
+
+/// Saves the session after the terminal closed, for the next start to
+/// point at. Prints nothing: there is no terminal.
+pub(crate) fn save_after_hangup(history: &[ConversationContext], spill: &SessionSpill) {
+    let full = spill.full_history(history).unwrap_or_else(|_| history.to_vec());
+    if let Ok(path) = save_recovery_file(&full) {
+        append_line(&data_dir().join(HANGUP_NOTICE_FILE), &format!("recovery\t{}", path.display()));
+    }
+}
+
+/// Tells the user what the last hangup left behind, once.
+pub(crate) fn hangup_notice(contents: &str, alive: impl Fn(u32) -> bool) -> Vec<String> {
+    let mut lines = Vec::new();
+    for line in contents.lines() {
+        match line.split('\t').collect::<Vec<_>>()[..] {
+            ["recovery", path] => lines.push(format!("  The session was saved to {path} (view it with: less {path})")),
+            ["orphan", pid, command] => {
+                let state = match pid.parse() {
+                    Ok(pid) if alive(pid) => "still running",
+                    _ => "finished since",
+                };
+                lines.push(format!("  pid {pid} was left running ({state}): {command}"));
+            }
+            _ => {}
+        }
+    }
+    if !lines.is_empty() {
+        lines.insert(0, "The terminal closed during the last session.".to_string());
+        lines.push(format!("  Commands left running are listed in {}", data_dir().join(ORPHANS_FILE).display()));
+    }
+    lines
+}
+
+pub(crate) fn report_last_hangup(theme: &Theme) {
+    let path = data_dir().join(HANGUP_NOTICE_FILE);
+    let Ok(contents) = fs::read_to_string(&path) else {
+        return;
+    };
+    let _ = fs::remove_file(&path);
+    let alive = |pid: u32| unsafe { libc::kill(pid as libc::pid_t, 0) } == 0;
+    for line in hangup_notice(&contents, alive) {
+        println!("{}", theme.warning_text(&line));
+    }
+}
+
+// Code fence tags whose contents are commands; other blocks are only shown
+pub(crate) const SHELL_FENCE_TAGS: &[&str] = &["", "bash", "sh", "zsh", "fish", "shell", "console", "terminal"];
+
+/// Splits a model response into comment lines and commands. A command may
+/// span lines: trailing `\` continuations, heredoc bodies up to their
+/// terminator, and newlines inside an open quote all stay with the line
+/// that started them. Single-line `a && b` chains are split into steps.
+///
+/// Models often answer in markdown despite the prompt. Shell code fences
+/// hold the commands (and everything outside them is then prose), list
+/// numbering and bullets are dropped, a line that is just `` `cmd` `` is
+/// unwrapped, and prose becomes `# ` comments instead of being run.
+pub(crate) fn parse_commands(content: &str) -> Vec<String> {
+    let mut commands = Vec::new();
+    let mut current: Option<String> = None;
+    let mut heredoc: Option<(String, bool)> = None;
+    // Some(true) inside a shell fence, Some(false) inside any other fence
+    let mut fence: Option<bool> = None;
+    let fenced = content.lines().any(|line| line.trim().strip_prefix("```").is_some_and(is_shell_fence));
+
+    for raw in content.lines().flat_map(split_closing_fence) {
+        if let Some((terminator, strip_tabs)) = &heredoc {
+            let body = current.get_or_insert_with(String::new);
+            body.push('\n');
+            body.push_str(raw);
+            let candidate = if *strip_tabs { raw.trim_start_matches('\t') } else { raw };
+            if candidate.trim_end() == terminator {
+                heredoc = None;
+                commands.extend(current.take());
+            }
+            continue;
+        }
+
+        let mut line = raw.trim();
+        if let Some(tag) = line.strip_prefix("```") {
+            match tag.strip_suffix("```") {
+                // ```ls -la``` on one line
+                Some(inline) if current.is_none() && !inline.trim().is_empty() => line = inline.trim(),
+                _ => {
+                    fence = if fence.is_some() { None } else { Some(is_shell_fence(tag)) };
+                    continue;
+                }
+            }
+        }
+        let text = match current.take() {
+            Some(mut open) => {
+                open.push('\n');
+                open.push_str(line);
+                open
+            }
+            None if line.is_empty() => continue,
+            None if line.starts_with('#') => {
+                commands.push(line.to_string());
+                continue;
+            }
+            None => match fence {
+                Some(true) => line.strip_prefix("$ ").unwrap_or(line).to_string(),
+                Some(false) => {
+                    commands.push(format!("# {line}"));
+                    continue;
+                }
+                None => match reply_line(line, fenced) {
+                    ReplyLine::Command(command) => command.to_string(),
+                    ReplyLine::Labelled(label, command) => {
+                        commands.push(format!("# {label}"));
+                        command.to_string()
+                    }
+                    ReplyLine::Prose(prose) => {
+                        commands.push(format!("# {prose}"));
+                        continue;
+                    }
+                },
+            },
+        };
+
+        let scan = scan_shell(&text);
+        if let Some(opened) = scan.heredoc {
+            heredoc = Some(opened);
+            current = Some(text);
+        } else if scan.open_quote || (line.ends_with('\\') && !line.ends_with("\\\\")) {
+            current = Some(text);
+        } else if text.contains('\n') {
+            commands.push(text);
+        } else {
+            commands.extend(split_and_chain(&text));
+        }
+    }
+    // An unterminated heredoc or quote still runs as one piece
+    if let Some(rest) = current.filter(|c| !c.trim().is_empty()) {
+        commands.push(rest);
+    }
+    commands.retain(|c| !c.is_empty());
+    commands
+}
+
+pub(crate) struct ShellScan {
+    pub(crate) open_quote: bool,
+    // Terminator of the first heredoc, and whether `<<-` strips leading tabs
+    pub(crate) heredoc: Option<(String, bool)>,
+}
+
+/// Tracks quoting through `text` and notes any heredoc it opens.
+pub(crate) fn scan_shell(text: &str) -> ShellScan {
+    let mut chars = text.chars().peekable();
+    let mut quote: Option<char> = None;
+    let mut heredoc = None;
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') => quote = None,
+            (Some('\''), _) => {}
+            (_, '\\') => {
+                chars.next();
+            }
+            (Some('"'), '"') => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '<') if chars.peek() == Some(&'<') => {
+                chars.next();
+                if chars.next_if_eq(&'<').is_some() {
+                    continue; // <<< here-string, not a heredoc
+                }
+                let strip_tabs = chars.next_if_eq(&'-').is_some();
+                while chars.next_if(|c| c.is_whitespace()).is_some() {}
+                let mut word = String::new();
+                while let Some(&n) = chars.peek() {
+                    if n.is_whitespace() || matches!(n, ';' | '|' | '&' | '>' | '<' | ')') {
+                        break;
+                    }
+                    if n != '\'' && n != '"' && n != '\\' {
+                        word.push(n);
+                    }
+                    chars.next();
+                }
+                if heredoc.is_none() && !word.is_empty() {
+                    heredoc = Some((word, strip_tabs));
+                }
+            }
+            _ => {}
+        }
+    }
+    ShellScan {
+        open_quote: quote.is_some(),
+        heredoc,
+    }
+}
+
+/// What a line outside any code fence turned out to be.
+#[derive(Debug, PartialEq)]
+pub(crate) enum ReplyLine<'a> {
+    Command(&'a str),
+    // "Update Homebrew: `brew update`" is a description and a command
+    Labelled(&'a str, &'a str),
+    Prose(&'a str),
+}
+
+/// Sorts one line of a reply outside code fences. With `fenced` set the
+/// reply keeps its commands in fences, so only inline code is a command.
+pub(crate) fn reply_line(line: &str, fenced: bool) -> ReplyLine<'_> {
+    let item = strip_list_marker(line);
+    let text = item.strip_prefix("$ ").unwrap_or(item);
+    if let Some(inner) = text.strip_prefix('`').and_then(|t| t.strip_suffix('`'))
+        && !inner.trim().is_empty()
+        && !inner.contains('`')
+    {
+        return ReplyLine::Command(inner.trim());
+    }
+    // "Label: `cmd`" or "`cmd` - what it does"
+    if let [before, code, after] = text.splitn(4, '`').collect::<Vec<_>>()[..]
+        && !code.trim().is_empty()
+    {
+        let (before, after) = (before.trim(), after.trim());
+        if after.is_empty() && before.len() > 1 && before.ends_with(':') {
+            return ReplyLine::Labelled(before.trim_end_matches(':'), code.trim());
+        }
+        if before.is_empty() && after.starts_with(['-', '\u{2014}', ':']) {
+            let label = after.trim_start_matches(['-', '\u{2014}', ':']).trim();
+            if !label.is_empty() {
+                return ReplyLine::Labelled(label, code.trim());
+            }
+        }
+    }
+    if fenced || looks_like_prose(text) {
+        ReplyLine::Prose(text)
+    } else {
+        ReplyLine::Command(text)
+    }
+}
+
+/// Drops "1. ", "2) ", "- ", "* " or "+ " from the start of a list item.
+pub(crate) fn strip_list_marker(line: &str) -> &str {
+    let digits = line.len() - line.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let rest = &line[digits..];
+    let rest = if digits > 0 { rest.strip_prefix(['.', ')']) } else { rest.strip_prefix(['-', '*', '+']) };
+    match rest {
+        Some(rest) if rest.starts_with(' ') => rest.trim_start(),
+        _ => line,
+    }
+}
+
+/// A sentence or a heading rather than a command: "Here's how:",
+/// "Note: this deletes files", "This lists every file.", "Use `grep` here"
+pub(crate) fn looks_like_prose(text: &str) -> bool {
+    let first = text.split_whitespace().next().unwrap_or("");
+    text.ends_with(':')
+        || (first.len() > 1 && first.ends_with(':'))
+        || (text.starts_with(|c: char| c.is_ascii_uppercase())
+            && text.split_whitespace().count() >= 3
+            && (text.ends_with(['.', '!', '?']) || text.contains('`')))
+}
+
+pub(crate) fn is_shell_fence(tag: &str) -> bool {
+    SHELL_FENCE_TAGS.contains(&tag.trim().to_ascii_lowercase().as_str())
+}
+
+/// Splits `ls -la```` into the command and the fence that closes after it.
+pub(crate) fn split_closing_fence(raw: &str) -> Vec<&str> {
+    let line = raw.trim_end();
+    match line.strip_suffix("```") {
+        Some(command) if !line.trim_start().starts_with("```") => vec![command, "```"],
+        _ => vec![raw],
+    }
+}
+
+/// Splits `a && b` into steps, leaving `&&` inside quotes alone.
+pub(crate) fn split_and_chain(line: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    let bytes = line.as_bytes();
+    for (i, c) in line.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match (quote, c) {
+            (Some('\''), '\'') => quote = None,
+            (Some('\''), _) => {}
+            (_, '\\') => escaped = true,
+            (Some('"'), '"') => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '&') if i >= start && bytes.get(i + 1) == Some(&b'&') => {
+                parts.push(line[start..i].trim().to_string());
+                start = i + 2;
+            }
+            _ => {}
+        }
+    }
+    parts.push(line[start..].trim().to_string());
+    parts.retain(|p| !p.is_empty());
+    parts
+}
+
+/// Multi-line commands are shown with their continuation lines indented.
+pub(crate) fn display_command(command: &str) -> String {
+    command.replace('\n', "\n    ")
+}
+
+pub enum ConfirmResponse {
+    Yes,
+    // Yes, and run the rest of the batch without asking (dangerous ones still ask)
+    All,
+    No,
+    Skip,
+    Instruct(String),
+    Edit(String),
+    // Put on the clipboard instead of running; the turn moves on
+    Copy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ConfirmChoice {
+    Yes,
+    All,
+    No,
+    Skip,
+    Instruct,
+    Edit,
+    Copy,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::*;
+    use crate::test_support::*;
+
+
+    #[test]
+    fn normalize_confirmation_input_strips_ansi_sequences() {
+        let input = "\u{1b}[?2004lyes\u{1b}[?2004h\n";
+        assert_eq!(normalize_confirmation_input(input), "yes");
+    }
+
+    #[test]
+    fn normalize_confirmation_input_keeps_valid_option() {
+        assert_eq!(normalize_confirmation_input("  s  \r\n"), "s");
+    }
+
+    #[test]
+    fn parse_confirmation_choice_treats_escaped_yes_as_yes() {
+        let input = "\u{1b}[?2004ly\u{1b}[?2004h\n";
+        assert_eq!(parse_confirmation_choice(input), Some(ConfirmChoice::Yes));
+    }
+
+    #[test]
+    fn parse_confirmation_choice_supports_all_options() {
+        assert_eq!(parse_confirmation_choice("n"), Some(ConfirmChoice::No));
+        assert_eq!(parse_confirmation_choice("skip"), Some(ConfirmChoice::Skip));
+        assert_eq!(parse_confirmation_choice("a"), Some(ConfirmChoice::All));
+        assert_eq!(parse_confirmation_choice("all"), Some(ConfirmChoice::All));
+        assert_eq!(parse_confirmation_choice("i"), Some(ConfirmChoice::Instruct));
+        assert_eq!(parse_confirmation_choice("e"), Some(ConfirmChoice::Edit));
+        assert_eq!(parse_confirmation_choice("edit"), Some(ConfirmChoice::Edit));
+        assert_eq!(parse_confirmation_choice("c"), Some(ConfirmChoice::Copy));
+        assert_eq!(parse_confirmation_choice("copy"), Some(ConfirmChoice::Copy));
+        assert_eq!(parse_confirmation_choice("maybe"), None);
+    }
+
+    /// Runs the confirmation prompt over scripted answers and edits.
+    fn confirm_scripted(command: &str, answers: &[&str], edits: &[&str]) -> (ConfirmResponse, String) {
+        let policy = SafetyPolicy::new(false, &[], DEFAULT_MAX_AUTO_COMMANDS);
+        let mut answers = answers.iter().map(|a| a.to_string());
+        let mut edits = edits.iter().map(|e| Some(e.to_string()));
+        let mut out = Vec::new();
+        let response = confirm_with(
+            command,
+            &Theme::plain(),
+            &policy,
+            &mut out,
+            &mut || Ok(answers.next().expect("no answer scripted")),
+            &mut |_| Ok(edits.next().expect("no edit scripted")),
+        )
+        .unwrap();
+        (response, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn edits_that_are_dangerous_still_need_a_typed_yes() {
+        // e then Enter keeps the dangerous command; y alone isn't enough
+        let (response, out) = confirm_scripted("rm -rf build", &["e", "y", "yes"], &["rm -rf build"]);
+        assert!(matches!(response, ConfirmResponse::Edit(ref edited) if edited == "rm -rf build"));
+        assert!(out.contains("Type the full word 'yes'"), "{out}");
+        // An edit that turns a safe command dangerous asks again
+        let (response, out) = confirm_scripted("ls build", &["e", "y", "n"], &["rm -rf build"]);
+        assert!(matches!(response, ConfirmResponse::No));
+        assert!(out.contains("danger> rm -rf build?"), "{out}");
+        let (response, out) = confirm_scripted("ls build", &["e", "a", "yes"], &["rm -rf build"]);
+        assert!(matches!(response, ConfirmResponse::Edit(ref edited) if edited == "rm -rf build"));
+        assert!(out.contains("'all' doesn't cover dangerous commands"), "{out}");
+        // Safe edits run as before
+        let (response, _) = confirm_scripted("ls build", &["e"], &["ls -la build"]);
+        assert!(matches!(response, ConfirmResponse::Edit(ref edited) if edited == "ls -la build"));
+    }
+
+    /// Confirms through confirm_with with typed answers, and records what
+    /// it would have run.
+    struct TypingUser {
+        answers: std::collections::VecDeque<&'static str>,
+        out: Vec<u8>,
+        ran: Vec<String>,
+    }
+
+    impl CommandRunner for TypingUser {
+        fn confirm(&mut self, command: &str, theme: &Theme, policy: &SafetyPolicy) -> Result<ConfirmResponse, io::Error> {
+            let answers = &mut self.answers;
+            confirm_with(
+                command,
+                theme,
+                policy,
+                &mut self.out,
+                &mut || Ok(answers.pop_front().expect("no answer scripted").to_string()),
+                &mut |_| panic!("nothing to edit"),
+            )
+        }
+
+        fn run(&mut self, command: &str) -> Result<CommandOutput, Box<dyn std::error::Error>> {
+            self.ran.push(command.to_string());
+            Ok(CommandOutput::success(""))
+        }
+
+        fn keep_going(&mut self, command: &str, _: ExitStatus, _: &Theme) -> Result<bool, io::Error> {
+            panic!("{command} can't fail");
+        }
+    }
+
+    #[test]
+    fn all_still_leaves_a_later_dangerous_command_to_a_typed_yes() {
+        let response: Vec<String> = ["ls", "pwd", "rm -rf build", "date"].map(String::from).to_vec();
+        let mut policy = SafetyPolicy::new(false, &[], DEFAULT_MAX_AUTO_COMMANDS);
+        policy.can_prompt = true;
+        // `a` at ls; at rm neither `a` nor `y` will do, only `yes`
+        let mut user = TypingUser { answers: ["a", "a", "y", "yes"].into(), out: Vec::new(), ran: Vec::new() };
+        let ran = execute_response(&response, &Theme::plain(), &policy, &mut user).unwrap();
+        assert_eq!(ran.commands, ["ls", "pwd", "rm -rf build", "date"]);
+        assert!(user.answers.is_empty());
+        let out = String::from_utf8(user.out).unwrap();
+        assert!(out.contains("danger> rm -rf build?"), "{out}");
+        assert!(out.contains("'all' doesn't cover dangerous commands") && out.contains("Type the full word 'yes'"), "{out}");
+        assert!(!out.contains("pwd") && !out.contains("date"), "{out}");
+
+        // Anything but yes leaves it unrun
+        let mut user = TypingUser { answers: ["a", "n"].into(), out: Vec::new(), ran: Vec::new() };
+        let ran = execute_response(&response, &Theme::plain(), &policy, &mut user).unwrap();
+        assert_eq!(ran.commands, ["ls", "pwd"]);
+        assert_eq!(user.ran, ["ls", "pwd"]);
+    }
+
+    #[test]
+    fn parse_commands_splits_chained_commands() {
+        let input = "mkdir myproject && cd myproject && git init";
+        let commands = super::parse_commands(input);
+        assert_eq!(commands, vec!["mkdir myproject", "cd myproject", "git init"]);
+    }
+
+    #[test]
+    fn parse_commands_preserves_comment_lines() {
+        let input = "# This will create a directory && init git\nmkdir foo && cd foo";
+        let commands = super::parse_commands(input);
+        assert_eq!(commands, vec![
+            "# This will create a directory && init git",
+            "mkdir foo",
+            "cd foo",
+        ]);
+    }
+
+    #[test]
+    fn parse_commands_strips_code_fences() {
+        let input = "```bash\nls -la\n```";
+        assert_eq!(parse_commands(input), vec!["ls -la"]);
+    }
+
+    #[test]
+    fn parse_commands_keeps_heredoc_bodies_with_their_command() {
+        let input = "```bash\ncat <<EOF > notes.txt\nfirst line\n\n  indented && not split\nEOF\nwc -l notes.txt\n```";
+        assert_eq!(
+            parse_commands(input),
+            vec!["cat <<EOF > notes.txt\nfirst line\n\n  indented && not split\nEOF", "wc -l notes.txt"]
+        );
+    }
+
+    #[test]
+    fn parse_commands_handles_quoted_and_dash_heredocs() {
+        let input = "cat <<'END' | sort\nb $HOME\na\nEND\nsh <<-DONE\n\techo hi\n\tDONE\necho after";
+        assert_eq!(
+            parse_commands(input),
+            vec!["cat <<'END' | sort\nb $HOME\na\nEND", "sh <<-DONE\n\techo hi\n\tDONE", "echo after"]
+        );
+        // <<< is a here-string and takes no body
+        assert_eq!(parse_commands("grep x <<< \"$v\"\nls"), vec!["grep x <<< \"$v\"", "ls"]);
+    }
+
+    #[test]
+    fn parse_commands_joins_backslash_continuations() {
+        let input = "find . -name '*.log' \\\n  -mtime +7 \\\n  -delete\npwd";
+        assert_eq!(parse_commands(input), vec!["find . -name '*.log' \\\n-mtime +7 \\\n-delete", "pwd"]);
+    }
+
+    #[test]
+    fn parse_commands_keeps_quoted_newlines_together() {
+        let input = "git commit -m \"Fix parser\n\nHandles heredocs && quotes\"\ngit log -1";
+        assert_eq!(
+            parse_commands(input),
+            vec!["git commit -m \"Fix parser\n\nHandles heredocs && quotes\"", "git log -1"]
+        );
+        assert_eq!(parse_commands("echo 'a && b' && ls"), vec!["echo 'a && b'", "ls"]);
+    }
+
+    #[test]
+    fn display_command_indents_continuation_lines() {
+        assert_eq!(display_command("cat <<EOF\nhi\nEOF"), "cat <<EOF\n    hi\n    EOF");
+        assert_eq!(display_command("ls"), "ls");
+    }
+
+    #[test]
+    fn parse_commands_filters_blank_lines_and_trims() {
+        let input = "  ls -la  \n\n   \npwd";
+        assert_eq!(parse_commands(input), vec!["ls -la", "pwd"]);
+    }
+
+    // Replies seen from real models that ignored the "no code blocks" rule
+    const MODEL_REPLY_FIXTURES: &[(&str, &[&str])] = &[
+        (
+            "Here's how to find large files:\n\n```bash\nfind . -size +100M\n```\n\nThis searches recursively.",
+            &["# Here's how to find large files:", "find . -size +100M", "# This searches recursively."],
+        ),
+        ("1. `brew update`\n2. `brew upgrade`", &["brew update", "brew upgrade"]),
+        (
+            "1. Update Homebrew: `brew update`\n2. Upgrade packages: `brew upgrade`",
+            &["# Update Homebrew", "brew update", "# Upgrade packages", "brew upgrade"],
+        ),
+        ("- `du -sh *` - shows folder sizes", &["# shows folder sizes", "du -sh *"]),
+        ("`lsof -i :3000`", &["lsof -i :3000"]),
+        ("1. `mkdir build && cd build`", &["mkdir build", "cd build"]),
+        ("```console\n$ git stash\n$ git pull --rebase\n```", &["git stash", "git pull --rebase"]),
+        (
+            "```bash\ndf -h\n```\nExample output:\n```text\nFilesystem  Size\n/dev/disk1  500G\n```",
+            &["df -h", "# Example output:", "# Filesystem  Size", "# /dev/disk1  500G"],
+        ),
+        ("```ls -la```", &["ls -la"]),
+        ("```sh\nnpm install\nnpm test```", &["npm install", "npm test"]),
+        ("```bash\n# Update first\nbrew update\n```", &["# Update first", "brew update"]),
+        ("Note: this needs sudo\nsudo lsof -i :80", &["# Note: this needs sudo", "sudo lsof -i :80"]),
+        ("* ps aux | grep node\n* kill -9 1234", &["ps aux | grep node", "kill -9 1234"]),
+        ("mkdir build && cd build\ncmake ..", &["mkdir build", "cd build", "cmake .."]),
+    ];
+
+    #[test]
+    fn parse_commands_handles_markdown_replies() {
+        for (reply, expected) in MODEL_REPLY_FIXTURES {
+            assert_eq!(parse_commands(reply), *expected, "reply: {reply:?}");
+        }
+    }
+
+    #[test]
+    fn reply_lines_outside_fences_are_sorted_into_commands_and_prose() {
+        assert_eq!(reply_line("10) echo `date`", false), ReplyLine::Command("echo `date`"));
+        assert_eq!(reply_line("This lists every file.", false), ReplyLine::Prose("This lists every file."));
+        assert_eq!(reply_line("ls -la", true), ReplyLine::Prose("ls -la"));
+        assert_eq!(reply_line("Use `grep` to search", false), ReplyLine::Prose("Use `grep` to search"));
+        assert_eq!(strip_list_marker("-rf"), "-rf");
+        assert_eq!(strip_list_marker("3.14"), "3.14");
+    }
+
+    // --- parse_confirmation_choice edge cases ---
+
+    #[test]
+    fn confirmation_empty_input_defaults_to_yes() {
+        assert_eq!(parse_confirmation_choice(""), Some(ConfirmChoice::Yes));
+    }
+
+    #[test]
+    fn confirmation_is_case_insensitive_and_trims() {
+        assert_eq!(parse_confirmation_choice("YES"), Some(ConfirmChoice::Yes));
+        assert_eq!(parse_confirmation_choice("  No  "), Some(ConfirmChoice::No));
+    }
+
+    // --- output streaming ---
+
+    #[test]
+    fn stream_lines_forwards_and_captures_everything() {
+        let input: &[u8] = b"one\ntwo\nno newline";
+        let mut sink = Vec::new();
+        let captured = stream_lines(input, &mut sink, &LiveEcho::all(false));
+        assert_eq!(captured, input);
+        assert_eq!(sink, input);
+    }
+
+    // --- commands that need the terminal ---
+
+    #[test]
+    fn editors_monitors_and_logins_need_the_terminal() {
+        let tty = |command| needs_terminal(command, &[]);
+        for command in [
+            "vim config.yaml",
+            "sudo nano /etc/hosts",
+            "top",
+            "/usr/bin/htop -d 5",
+            "ssh deploy@web1",
+            "ssh -p 2222 -i ~/.ssh/id host",
+            "git commit",
+            "git rebase -i HEAD~3",
+            "git add -p",
+            "python3",
+            "psql mydb",
+            "cd /var/log && less syslog",
+            "ps aux | less",
+            "EDITOR=vim git commit --amend",
+        ] {
+            assert!(tty(command), "{command}");
+        }
+        for command in [
+            "ls -la",
+            "ssh host uptime",
+            "ssh -p 2222 host 'df -h'",
+            "ssh -T git@github.com",
+            "git commit -m 'fix'",
+            "git commit -am 'fix'",
+            "git commit --message=fix",
+            "git rebase main",
+            "python3 script.py",
+            "python3 -c 'print(1)'",
+            "bash < setup.sh",
+            "psql -c 'select 1' mydb",
+            "sqlite3 app.db 'select 1'",
+            "echo vim",
+            "grep top notes.txt",
+            "echo 'unterminated",
+        ] {
+            assert!(!tty(command), "{command}");
+        }
+        assert!(needs_terminal("k9s --context prod", &["k9s".to_string()]));
+    }
+
+    #[test]
+    fn raw_marker_and_bang_edit_force_the_terminal() {
+        assert_eq!(attached_command("raw: make menuconfig"), Some("make menuconfig"));
+        assert_eq!(attached_command("vim notes.md"), Some("vim notes.md"));
+        assert_eq!(attached_command("make"), None);
+        assert_eq!(attach_if_marked("!./installer".to_string()), "raw: ./installer");
+        assert_eq!(attach_if_marked("./installer".to_string()), "./installer");
+        assert_eq!(ssh_remote_command(&["-o", "BatchMode=yes", "host", "ls"]), Some("ls"));
+        assert_eq!(ssh_remote_command(&["-v", "host"]), None);
+    }
+
+    #[test]
+    fn attached_commands_keep_their_exit_code_but_not_their_output() {
+        let output = run_command_with_output("raw: echo hi >/dev/null; exit 3").unwrap();
+        assert_eq!(output.exit_code(), Some(3));
+        assert_eq!(output.history_output(), ATTACHED_OUTPUT);
+        let output = run_command_with_output("raw: true").unwrap();
+        assert_eq!(output.history_output(), ATTACHED_OUTPUT);
+    }
+
+    #[test]
+    fn environment_snapshot_lists_the_first_entries_of_the_directory() {
+        let dir = env::temp_dir().join(format!("ask-snapshot-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for i in 0..45 {
+            fs::write(dir.join(format!("file{i:02}")), "").unwrap();
+        }
+        let snapshot = environment_snapshot(&dir);
+        assert!(snapshot.starts_with(&format!("Working directory: {}\n$ ls -la\n", dir.display())), "{snapshot}");
+        assert!(snapshot.contains("file37") && !snapshot.contains("file38"), "{snapshot}");
+        // 45 files plus . and ..
+        assert!(snapshot.ends_with("(7 more entries)\n"), "{snapshot}");
+        assert!(!snapshot.contains("git status"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn clipboard_text_drops_color_codes_and_trailing_newlines() {
+        assert_eq!(clipboard_text("\u{1b}[1;32mls -la\u{1b}[0m\n"), "ls -la");
+        assert_eq!(clipboard_text("cat <<EOF\nhello\nEOF\r\n\n"), "cat <<EOF\nhello\nEOF");
+        assert_eq!(clipboard_text("  echo hi  "), "  echo hi  ");
+    }
+
+    #[test]
+    fn copy_response_needs_a_command() {
+        assert_eq!(
+            copy_response(&["# just chatting".to_string()], &Theme::plain()).unwrap_err().to_string(),
+            "The model suggested no command to copy"
+        );
+    }
+
+    #[test]
+    fn output_within_gives_up_on_slow_and_failing_commands() {
+        assert_eq!(output_within(Command::new("echo").arg("hi"), Duration::from_secs(5)).as_deref(), Some("hi\n"));
+        assert_eq!(output_within(&mut Command::new("false"), Duration::from_secs(5)), None);
+        assert_eq!(output_within(Command::new("sleep").arg("5"), Duration::from_millis(50)), None);
+    }
+
+    #[test]
+    fn captured_output_keeps_head_and_tail_within_the_limit() {
+        let mut captured = CapturedOutput::new(40);
+        for i in 0..100 {
+            captured.push(format!("line {i:02}\n").as_bytes());
+        }
+        let kept = String::from_utf8(captured.finish()).unwrap();
+        // 20 bytes each side, on whole lines: 2 lines up front, 2 at the end
+        assert_eq!(kept, "line 00\nline 01\n… 768 bytes omitted …\nline 98\nline 99\n");
+
+        let mut small = CapturedOutput::new(40);
+        small.push(b"short\n");
+        assert_eq!(small.finish(), b"short\n");
+        let mut unlimited = CapturedOutput::new(0);
+        unlimited.push(&[b'x'; 1000]);
+        assert_eq!(unlimited.finish().len(), 1000);
+    }
+
+    #[test]
+    fn long_output_streams_whole_but_is_stored_capped() {
+        let input = "x".repeat(100) + "\n";
+        let input = input.repeat(DEFAULT_MAX_OUTPUT_BYTES / 50);
+        let mut sink = Vec::new();
+        let captured = stream_lines(input.as_bytes(), &mut sink, &LiveEcho::all(false));
+        assert_eq!(sink.len(), input.len());
+        assert!(captured.len() <= DEFAULT_MAX_OUTPUT_BYTES + 40);
+        assert!(String::from_utf8_lossy(&captured).contains("bytes omitted"));
+    }
+
+    #[test]
+    fn output_past_a_screenful_is_held_back_but_still_captured() {
+        let input: &[u8] = b"one\ntwo\nthree\nfour\n";
+        let echo = LiveEcho::lines(2, false);
+        let mut sink = Vec::new();
+        let captured = stream_lines(input, &mut sink, &echo);
+        assert_eq!(captured, input);
+        assert_eq!(String::from_utf8(sink).unwrap(), format!("one\ntwo\n{OUTPUT_HELD}\n"));
+        assert!(echo.held());
+
+        let echo = LiveEcho::lines(4, false);
+        stream_lines(input, &mut Vec::new(), &echo);
+        assert!(!echo.held());
+        // Not a terminal: nothing to page, so everything is shown
+        assert!(!LiveEcho::for_terminal(false).held() && LiveEcho::for_terminal(true).to_stderr);
+    }
+
+    #[test]
+    fn pager_setting_defaults_to_auto_and_rejects_unknown_modes() {
+        let resolve = |text| Settings::resolve(&[SettingsLayer::defaults(), SettingsLayer::from_text(Origin::Global, text)]);
+        assert_eq!(resolve("").pager_mode(), PagerMode::Auto);
+        assert_eq!(resolve("pager=always").pager_mode(), PagerMode::Always);
+        assert_eq!(resolve("pager=off").pager_mode(), PagerMode::Off);
+        assert_eq!(resolve("pager=sometimes").pager_mode(), PagerMode::Auto);
+        // Outside interactive mode nothing pages
+        assert_eq!(pager_mode(), PagerMode::Off);
+    }
+
+    #[test]
+    fn run_command_with_output_captures_stdout_and_stderr() {
+        let output = run_command_with_output("echo out; echo err 1>&2").expect("command runs");
+        assert_eq!((output.stdout.as_str(), output.stderr.as_str()), ("out\n", "err\n"));
+        assert_eq!(output.history_output(), "out\n\nerr\n");
+    }
+
+    #[test]
+    fn run_command_with_output_reports_failure_status() {
+        let output = run_command_with_output("echo oops >&2; exit 3").expect("a failure is not an error");
+        assert_eq!(output.exit_code(), Some(3));
+        assert_eq!(shell_exit_code(output.status), 3);
+        assert_eq!(output.history_output(), "\noops\n");
+        assert!(output.failure().is_some());
+        assert!(CommandOutput::success("fine").failure().is_none());
+    }
+
+    // Regression: a command killed by Ctrl-C failed the whole turn, so what
+    // it printed never reached history.
+    #[test]
+    fn interrupted_command_keeps_its_output_with_a_marker() {
+        let output = run_command_with_output("echo partial; kill -INT $$").expect("an interrupt is not a failure");
+        assert!(output.interrupted && output.failure().is_none());
+        assert_eq!((output.exit_code(), shell_exit_code(output.status)), (None, 130));
+        assert_eq!(output.history_output(), "partial\n(interrupted)");
+        assert!(was_interrupted(&output.history_output()));
+        assert_eq!(result_status(&Ok(output)), "failed");
+        let mut output = CommandOutput::success("");
+        output.interrupted = true;
+        assert_eq!(output.history_output(), "(interrupted)");
+        output.stdout = "out".to_string();
+        output.stderr = "err".to_string();
+        assert_eq!(output.history_output(), "out\nerr\n(interrupted)");
+    }
+
+    #[test]
+    fn interrupted_command_ends_the_turn() {
+        let theme = Theme::from_mode(ThemeMode::Dark);
+        let mut policy = SafetyPolicy::new(true, &[], 5);
+        policy.can_prompt = false;
+        let response = vec!["echo a; kill -INT $$".to_string(), "echo b".to_string()];
+        let Executed { commands: ran, outputs, .. } = execute_response(&response, &theme, &policy, &mut Shell).unwrap();
+        assert_eq!(ran, ["echo a; kill -INT $$"]);
+        assert_eq!(outputs, ["a\n(interrupted)"]);
+    }
+
+    // --- environment bootstrap ---
+
+    #[test]
+    fn bootstrap_env_ignores_banners_and_other_vars() {
+        let output = format!(
+            "Welcome back!\nPATH=/fake/from/banner\n{m}\nLAST LOGIN: today\nPATH=/opt/homebrew/bin:/usr/bin\n\
+             LANG=en_US.UTF-8\nLC_ALL=\nSECRET=x\nnot a var=1\n{m}\nbye\n",
+            m = BOOTSTRAP_MARKER
+        );
+        let env = parse_bootstrap_env(&output);
+        assert_eq!(env.path.as_deref(), Some("/opt/homebrew/bin:/usr/bin"));
+        assert_eq!(env.locale, vec![("LANG".to_string(), "en_US.UTF-8".to_string())]);
+        assert_eq!(env.error, None);
+
+        assert!(parse_bootstrap_env("PATH=/usr/bin\n").error.is_some());
+    }
+
+    #[test]
+    fn bootstrap_env_appends_path_and_fills_missing_locale() {
+        let env = ShellEnv {
+            path: Some("/opt/homebrew/bin:/usr/bin:/bin".to_string()),
+            locale: vec![
+                ("LANG".to_string(), "en_US.UTF-8".to_string()),
+                ("LC_ALL".to_string(), "en_US.UTF-8".to_string()),
+            ],
+            error: None,
+        };
+        let current = |name: &str| match name {
+            "PATH" => Some("/venv/bin:/usr/bin:/bin".to_string()),
+            "LC_ALL" => Some("C".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            env.overrides(current),
+            vec![
+                ("PATH".to_string(), "/venv/bin:/usr/bin:/bin:/opt/homebrew/bin".to_string()),
+                ("LANG".to_string(), "en_US.UTF-8".to_string()),
+            ]
+        );
+        assert!(ShellEnv::default().overrides(current).is_empty());
+    }
+
+    #[test]
+    fn bootstrap_env_captures_from_a_real_shell() {
+        let env = ShellEnv::capture("/bin/sh");
+        assert_eq!(env.error, None);
+        assert!(env.path.is_some_and(|p| !p.is_empty()));
+    }
+
+    #[test]
+    fn bootstrap_env_gives_up_on_a_slow_shell() {
+        let dir = env::temp_dir().join(format!("ask-bootstrap-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let shell = dir.join("slow-shell");
+        fs::write(&shell, "#!/bin/sh\nsleep 5\n").unwrap();
+        fs::set_permissions(&shell, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+        let start = Instant::now();
+        let result = run_login_shell(shell.to_str().unwrap(), Duration::from_millis(200));
+        assert!(result.unwrap_err().contains("longer than"));
+        assert!(start.elapsed() < Duration::from_secs(3));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    // --- persistent shell ---
+
+    #[test]
+    fn persistent_shell_keeps_directory_exports_and_aliases() {
+        let mut shell = PersistentShell::start("/bin/sh").unwrap();
+        let run = shell.run("cd /tmp && export ASK_TEST_FOO=bar && alias hi='echo hello'", &LiveEcho::all(true)).unwrap();
+        assert!(run.status.success());
+        assert_eq!(run.cwd.as_deref(), Some(Path::new("/tmp")));
+
+        let run = shell.run("echo \"$ASK_TEST_FOO\"; hi", &LiveEcho::all(true)).unwrap();
+        assert_eq!(String::from_utf8_lossy(&run.stdout), "bar\nhello\n");
+    }
+
+    #[test]
+    fn persistent_shell_reports_status_and_partial_lines() {
+        let mut shell = PersistentShell::start("/bin/sh").unwrap();
+        let run = shell.run("printf 'no newline'; echo oops >&2; (exit 3)", &LiveEcho::all(true)).unwrap();
+        assert_eq!(run.status.code(), Some(3));
+        assert_eq!(run.stdout, b"no newline");
+        assert_eq!(run.stderr, b"oops\n");
+        assert!(CommandOutput::from_bytes(run.status, &run.stdout, &run.stderr, false).failure().is_some());
+
+        // An unbalanced quote is a syntax error, not a hang
+        let run = shell.run("echo 'unterminated", &LiveEcho::all(true)).unwrap();
+        assert!(!run.status.success());
+        let run = shell.run("echo still here", &LiveEcho::all(true)).unwrap();
+        assert_eq!(run.stdout, b"still here\n");
+    }
+
+    #[test]
+    fn persistent_shell_exit_is_reported() {
+        let mut shell = PersistentShell::start("/bin/sh").unwrap();
+        assert!(shell.run("exit", &LiveEcho::all(true)).is_err());
+    }
+
+    #[test]
+    fn shell_quote_survives_single_quotes() {
+        let output = Command::new("/bin/sh")
+            .arg("-c")
+            .arg(format!("printf %s {}", shell_quote("it's a 'test'")))
+            .output()
+            .unwrap();
+        assert_eq!(output.stdout, b"it's a 'test'");
+    }
+
+    // --- redirection clobbering ---
+
+    #[test]
+    fn find_clobbers_table() {
+        let clobber = |target: &str, self_clobber| Clobber { target: target.to_string(), self_clobber };
+        let cases: Vec<(&str, Vec<Clobber>)> = vec![
+            ("sort data.txt > data.txt", vec![clobber("data.txt", true)]),
+            ("sort data.txt>data.txt", vec![clobber("data.txt", true)]),
+            ("cat ./notes.md | grep x > notes.md", vec![clobber("notes.md", true)]),
+            ("tr a-z A-Z < in.txt > in.txt", vec![clobber("in.txt", true)]),
+            ("echo hi > important.conf", vec![clobber("important.conf", false)]),
+            ("echo hi >| out.txt", vec![clobber("out.txt", false)]),
+            ("echo hi >> log.txt", vec![]),
+            ("make 2> errors.log", vec![clobber("errors.log", false)]),
+            ("make > build.log 2>&1", vec![clobber("build.log", false)]),
+            ("make &> all.log", vec![clobber("all.log", false)]),
+            ("find . -name x 2>/dev/null", vec![]),
+            ("ls > /dev/null", vec![]),
+            ("echo 'a > b' > \"my file.txt\"", vec![clobber("my file.txt", false)]),
+            ("sed 's/x/y/' \"my file.txt\" > 'my file.txt'", vec![clobber("my file.txt", true)]),
+            ("echo \"2\" > two", vec![clobber("two", false)]),
+            ("grep -c foo report.csv", vec![]),
+            ("cat <<EOF > out.txt", vec![clobber("out.txt", false)]),
+        ];
+        for (cmd, expected) in cases {
+            assert_eq!(find_clobbers(cmd), expected, "{cmd}");
+        }
+    }
+
+    #[test]
+    fn format_size_uses_readable_units() {
+        assert_eq!(format_size(512), "512B");
+        assert_eq!(format_size(14 * 1024), "14KB");
+        assert_eq!(format_size(3 * 1024 * 1024 / 2), "1.5MB");
+    }
+
+    // --- instruct detours ---
+
+    #[test]
+    fn plan_commands_attaches_the_preceding_comment() {
+        let response: Vec<String> = ["# Find big files", "du -sh *", "sort -h", "# Then clean up", "rm -i big.iso"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let plan = plan_commands(&response);
+        assert_eq!(plan.len(), 3);
+        assert_eq!((plan[0].step, plan[0].total), (1, 3));
+        assert_eq!(plan[0].explanation.as_deref(), Some("Find big files"));
+        assert_eq!(plan[1].explanation, None);
+        assert_eq!(plan[2].explanation.as_deref(), Some("Then clean up"));
+    }
+
+    #[test]
+    fn detour_context_shows_step_explanation_and_output_tail() {
+        let pending = PendingCommand {
+            command: "rm -i big.iso".to_string(),
+            explanation: Some("Then clean up".to_string()),
+            step: 3,
+            total: 3,
+        };
+        let output: String = (1..=20).map(|i| format!("line {i}\n")).collect();
+        let lines = detour_context(&pending, &output);
+        assert_eq!(lines[..3], ["Step 3/3", "Then clean up", "Last output:"]);
+        assert_eq!(lines.len(), 3 + DETOUR_CONTEXT_LINES);
+        assert_eq!(lines.last().unwrap(), "  line 20");
+        assert_eq!(detour_context(&pending, ""), ["Step 3/3", "Then clean up"]);
+    }
+
+    // --- max_auto_commands ---
+
+    #[test]
+    fn yes_stops_at_max_auto_without_a_terminal() {
+        let theme = Theme::from_mode(ThemeMode::Dark);
+        let mut policy = SafetyPolicy::new(true, &[], 3);
+        policy.can_prompt = false;
+        let dir = env::temp_dir().join(format!("ask-max-auto-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let marker = dir.join("ran");
+        let response: Vec<String> = std::iter::once("# several steps".to_string())
+            .chain((0..7).map(|i| format!("echo {i} >> {}", marker.display())))
+            .collect();
+
+        let err = execute_response(&response, &theme, &policy, &mut Shell).unwrap_err();
+        let limit = err.downcast_ref::<AutoLimitReached>().expect("AutoLimitReached");
+        assert_eq!((limit.ran, limit.remaining), (3, 4));
+        assert_eq!(fs::read_to_string(&marker).unwrap(), "0\n1\n2\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn yes_under_max_auto_runs_everything() {
+        let theme = Theme::from_mode(ThemeMode::Dark);
+        let mut policy = SafetyPolicy::new(true, &[], 5);
+        policy.can_prompt = false;
+        let response = vec!["echo a".to_string(), "echo b".to_string()];
+        let Executed { commands: ran, outputs, .. } = execute_response(&response, &theme, &policy, &mut Shell).unwrap();
+        assert_eq!(ran, response);
+        assert_eq!(outputs.len(), 2);
+    }
+
+    // --- auto-fix ---
+
+    fn fix_policy() -> SafetyPolicy {
+        let mut policy = SafetyPolicy::new(true, &[], DEFAULT_MAX_AUTO_COMMANDS);
+        policy.can_prompt = false;
+        policy
+    }
+
+    #[test]
+    fn failed_command_is_repaired_and_both_are_recorded() {
+        let (url, served) = fake_api(vec![
+            completion("echo start\necho missing >&2; exit 3"),
+            completion("# That file is elsewhere.\necho fixed"),
+        ]);
+        let mut settings = retry_settings(url, 1);
+        settings.fix_attempts = 2;
+        let result = process_prompt_with_context("show the file", &settings, &Theme::plain(), &[], None, &fix_policy(), &mut Shell).unwrap();
+        let codes: Vec<Option<i32>> = result.runs.iter().map(|run| run.exit_code).collect();
+        assert_eq!(codes, [Some(0), Some(3), Some(0)]);
+        assert!(result.runs[1].output.ends_with("missing\n") && !result.runs[1].output.contains("failed:"));
+        let turn = result.into_turn().unwrap();
+        assert_eq!(served.lock().unwrap().len(), 2);
+        assert_eq!(turn.commands, ["echo start", "echo missing >&2; exit 3", "echo fixed"]);
+        assert_eq!(turn.outputs[0], "start\n");
+        assert_eq!(turn.outputs[1], "\nmissing\n");
+        assert_eq!(turn.outputs[2], "fixed\n");
+        assert_eq!(turn.exit_codes, codes);
+        let response = &turn.request.as_ref().unwrap().response;
+        assert_eq!(response.last().map(String::as_str), Some("echo fixed"));
+        assert!(response.contains(&"echo start".to_string()));
+    }
+
+    #[test]
+    fn repairs_stop_at_auto_fix_attempts() {
+        let (url, served) = fake_api(vec![completion("exit 1"), completion("exit 2"), completion("exit 3")]);
+        let mut settings = retry_settings(url, 1);
+        settings.fix_attempts = 1;
+        let Err(err) = process_prompt_with_context("do it", &settings, &Theme::plain(), &[], None, &fix_policy(), &mut Shell)
+            .and_then(PromptResult::into_turn)
+        else {
+            panic!("the turn should fail");
+        };
+        let failed = err.downcast_ref::<TurnFailed>().expect("TurnFailed");
+        assert_eq!(failed.failed_command(), "exit 2");
+        assert_eq!(served.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn auto_fix_is_off_by_default() {
+        let (url, served) = fake_api(vec![completion("exit 1"), completion("true")]);
+        let settings = retry_settings(url, 1);
+        let Err(err) = process_prompt_with_context("do it", &settings, &Theme::plain(), &[], None, &fix_policy(), &mut Shell)
+            .and_then(PromptResult::into_turn)
+        else {
+            panic!("the turn should fail");
+        };
+        assert_eq!(err.to_string(), "Command exited with status exit status: 1");
+        assert_eq!(served.lock().unwrap().len(), 1);
+
+        let resolve = |text| Settings::resolve(&[SettingsLayer::defaults(), SettingsLayer::from_text(Origin::Global, text)]);
+        assert_eq!(resolve("").fix_attempts(), 0);
+        assert_eq!(resolve("auto_fix=on").fix_attempts(), DEFAULT_AUTO_FIX_ATTEMPTS);
+        assert_eq!(resolve("auto_fix=on\nauto_fix_attempts=5").fix_attempts(), 5);
+        assert_eq!(resolve("auto_fix_attempts=5").fix_attempts(), 0);
+    }
+
+    #[test]
+    fn fix_request_names_the_command_and_the_original_ask() {
+        let failed = TurnFailed {
+            commands: vec!["ls".to_string(), "cat nope".to_string()],
+            outputs: vec!["a\n".to_string()],
+            exit_codes: vec![Some(0)],
+            failure: CommandFailed {
+                status: std::os::unix::process::ExitStatusExt::from_raw(1 << 8),
+                output: "cat: nope: No such file\n".to_string(),
+            },
+            rest: vec!["# Then count them".to_string()],
+        };
+        assert!(!failed.has_rest());
+        let request = fix_request("print the notes", &failed);
+        assert!(request.contains("`cat nope` failed (exit status: 1)"));
+        assert!(request.ends_with("still does what I asked: print the notes"));
+        assert_eq!(route_prompt(&request).kind, PromptKind::Action);
+        // The turn keeps the output as printed, and the status apart
+        let mut turn = output_turn("");
+        turn.commands.clear();
+        turn.outputs.clear();
+        record_failure(&mut turn, &failed);
+        assert_eq!(turn.outputs, ["a\n", "cat: nope: No such file\n"]);
+        assert_eq!(turn.exit_codes, [Some(0), Some(1)]);
+        assert_eq!(turn.last_command().unwrap().status, "exit 1");
+    }
+
+    // --- JSON output ---
+
+    #[test]
+    fn prompt_result_json_lists_the_reply_and_each_run() {
+        let (url, _served) = fake_api(vec![completion("# Say hello first.\necho hi\necho oops; exit 4\necho never")]);
+        let settings = retry_settings(url, 1);
+        let result = process_prompt_with_context("greet", &settings, &Theme::plain(), &[], None, &fix_policy(), &mut Shell).unwrap();
+        let value = result.to_json();
+        assert_eq!(value["prompt"], "greet");
+        assert_eq!(value["model"], json!(settings.model));
+        assert_eq!(
+            value["lines"],
+            json!([
+                { "type": "comment", "text": "Say hello first." },
+                { "type": "command", "text": "echo hi" },
+                { "type": "command", "text": "echo oops; exit 4" },
+                { "type": "command", "text": "echo never" },
+            ])
+        );
+        assert_eq!(
+            value["executed"],
+            json!([
+                { "command": "echo hi", "exit_code": 0, "output": "hi\n" },
+                { "command": "echo oops; exit 4", "exit_code": 4, "output": "oops\n" },
+            ])
+        );
+        // Single-prompt mode exits the way the failed command did
+        assert_eq!(result.exit_code(), 4);
+        let err = result.into_turn().err().expect("the failure ends the turn");
+        assert_eq!(report_error(err.as_ref()), 4);
+        assert_eq!(error_code(err.as_ref()), "command_failed");
+    }
+
+    #[test]
+    fn prompt_result_json_names_the_fallback_that_answered() {
+        let busy = http_response("503 Service Unavailable", "Retry-After: 0\r\n", "no capacity");
+        let (url, _served) = fake_api(vec![busy, completion("# Hello from the backup")]);
+        let settings = RequestSettings { fallback_models: vec!["free/backup".to_string()], ..retry_settings(url, 1) };
+        let result = process_prompt_with_context("hi", &settings, &Theme::plain(), &[], None, &fix_policy(), &mut Shell).unwrap();
+        assert_eq!(result.turn.request.as_ref().unwrap().model, "free/backup");
+        assert_eq!(result.to_json()["model"], "free/backup");
+    }
+
+    #[test]
+    fn errors_get_machine_readable_codes() {
+        let api = ApiFailure { message: "API error 401: no".to_string(), retryable: false, retry_after: None };
+        assert_eq!(api.to_string(), "API error 401: no");
+        let cases: Vec<(Box<dyn std::error::Error>, &str)> = vec![
+            (NeedsConfirmation.into(), "needs_confirmation"),
+            (AutoLimitReached { ran: 5, remaining: 1 }.into(), "auto_limit_reached"),
+            (api.into(), "api_error"),
+            (missing_key_message("OPENROUTER_ASK_API_KEY").into(), "error"),
+        ];
+        for (err, code) in cases {
+            assert_eq!(error_code(err.as_ref()), code, "{err}");
+        }
+    }
+
+    // --- print mode ---
+
+    #[test]
+    fn print_response_needs_at_least_one_command() {
+        assert!(print_response(&["# just chatting".to_string()]).is_err());
+        assert!(print_response(&[]).is_err());
+        assert!(print_response(&["# lists ports".to_string(), "lsof -i".to_string()]).is_ok());
+    }
+
+    // --- confirmation without a terminal ---
+
+    #[test]
+    fn confirmation_eof_is_not_an_empty_answer() {
+        let err = confirmation_eof();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        // An empty line would parse as Yes; EOF must never reach that path.
+        assert_eq!(parse_confirmation_choice(""), Some(ConfirmChoice::Yes));
+    }
+
+    #[test]
+    fn needs_confirmation_is_recognised_for_exit_code() {
+        let err: Box<dyn std::error::Error> = NeedsConfirmation.into();
+        assert!(err.is::<NeedsConfirmation>());
+        assert!(err.to_string().contains("--yes"));
+    }
+
+    // --- spinner ---
+
+    #[test]
+    fn spinner_stops_promptly_when_dropped() {
+        let theme = Theme::from_mode(ThemeMode::Dark);
+        let started = Instant::now();
+        drop(Spinner::start(&theme));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    // --- terminal hangup ---
+
+    #[test]
+    fn wait_for_child_returns_exit_status_without_a_hangup() {
+        let hangup = std::sync::atomic::AtomicBool::new(false);
+        let mut child = Command::new("/bin/sh").arg("-c").arg("exit 3").spawn().unwrap();
+        let end = wait_for_child(&mut child, &hangup, HangupMode::Forward, HANGUP_GRACE).unwrap();
+        assert!(matches!(end, ChildEnd::Exited(status) if status.code() == Some(3)));
+    }
+
+    #[test]
+    fn forwarded_hangup_lets_the_command_stop_cleanly() {
+        let mut pty = Pty::open();
+        let mut child = pty.spawn("trap 'echo got-hup; exit 7' HUP; echo ready; while :; do sleep 0.05; done", false);
+        pty.wait_for("ready");
+        let hangup = std::sync::atomic::AtomicBool::new(true);
+        let end = wait_for_child(&mut child, &hangup, HangupMode::Forward, Duration::from_secs(5)).unwrap();
+        assert_eq!(end, ChildEnd::HungUp { orphan: None });
+        pty.wait_for("got-hup");
+        assert_eq!(child.wait().unwrap().code(), Some(7));
+    }
+
+    #[test]
+    fn forwarded_hangup_gives_up_after_the_grace_period() {
+        let mut pty = Pty::open();
+        let mut child = pty.spawn("trap '' HUP; echo ready; sleep 5", false);
+        pty.wait_for("ready");
+        let hangup = std::sync::atomic::AtomicBool::new(true);
+        let end = wait_for_child(&mut child, &hangup, HangupMode::Forward, Duration::from_millis(200)).unwrap();
+        assert_eq!(end, ChildEnd::HungUp { orphan: Some(child.id()) });
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+
+    #[test]
+    fn closing_the_terminal_kills_a_command_unless_detached() {
+        let mut pty = Pty::open();
+        let mut attached = pty.spawn("echo ready; sleep 5", false);
+        pty.wait_for("ready");
+        pty.close();
+        let hangup = std::sync::atomic::AtomicBool::new(false);
+        let end = wait_for_child(&mut attached, &hangup, HangupMode::Forward, HANGUP_GRACE).unwrap();
+        assert!(matches!(end, ChildEnd::Exited(status) if !status.success()), "{end:?}");
+
+        let mut pty = Pty::open();
+        let mut detached = pty.spawn("echo ready; sleep 5", true);
+        pty.wait_for("ready");
+        pty.close();
+        let hangup = std::sync::atomic::AtomicBool::new(true);
+        let end = wait_for_child(&mut detached, &hangup, HangupMode::Detach, HANGUP_GRACE).unwrap();
+        assert_eq!(end, ChildEnd::HungUp { orphan: Some(detached.id()) });
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(detached.try_wait().unwrap().is_none(), "the detached command should still be running");
+        let _ = detached.kill();
+        let _ = detached.wait();
+    }
+
+    #[test]
+    fn hung_up_turn_records_the_interrupted_command() {
+        let turn = HungUp { command: "rsync -a src/ dst/".to_string(), orphan: Some(42) }.turn("copy it");
+        assert_eq!(turn.prompt, "copy it");
+        assert_eq!(turn.commands, ["rsync -a src/ dst/"]);
+        assert_eq!(turn.outputs, ["(interrupted by hangup; left running as pid 42)"]);
+    }
+
+    #[test]
+    fn hangup_notice_lists_recovery_and_orphans() {
+        let contents = "recovery\t/home/me/.ask/recovery/session-1.txt\norphan\t41\tmake all\norphan\t42\trsync -a a b\njunk\n";
+        let lines = hangup_notice(contents, |pid| pid == 42);
+        assert_eq!(lines[0], "The terminal closed during the last session.");
+        assert!(lines[1].contains("saved to /home/me/.ask/recovery/session-1.txt"));
+        assert_eq!(lines[2], "  pid 41 was left running (finished since): make all");
+        assert_eq!(lines[3], "  pid 42 was left running (still running): rsync -a a b");
+        assert!(lines[4].ends_with(ORPHANS_FILE));
+        assert!(hangup_notice("", |_| true).is_empty());
+    }
+}
@@ -0,0 +1,1717 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+use crate::api::*;
+use crate::cli::*;
+use crate::config::*;
+use crate::exec::*;
+use crate::repl::*;
+use crate::safety::*;
+use crate::theme::*;
+
+pub(crate) fn save_recovery_file(history: &[ConversationContext]) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let dir = data_dir().join("recovery");
+    fs::create_dir_all(&dir)?;
+    let stamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let path = dir.join(format!("session-{stamp}.txt"));
+
+    let mut contents = String::new();
+    for (i, ctx) in history.iter().enumerate() {
+        contents.push_str(&format_turn(i + 1, ctx, None, &Theme::plain()));
+        contents.push('\n');
+    }
+    fs::write(&path, contents)?;
+    Ok(path)
+}
+
+/// One turn the way `history` lists it and the recovery file stores it,
+/// numbered from 1 like `history rerun`: each command with how it ended,
+/// then its output. `output_lines` caps each output; None keeps all of it.
+pub(crate) fn format_turn(number: usize, ctx: &ConversationContext, output_lines: Option<usize>, theme: &Theme) -> String {
+    let label = format!("[{number}]");
+    let indent = " ".repeat(label.len() + 1);
+    let pin = match ctx {
+        ctx if ctx.forgotten => format!(" {}", theme.hint_text("(forgotten)")),
+        ctx if ctx.pinned => format!(" {}", theme.helper_text("(pinned)")),
+        _ => String::new(),
+    };
+    let mut text = format!("{}{pin} User: {}\n", theme.helper_text(&label), ctx.prompt);
+    let push_output = |text: &mut String, output: &str| {
+        let lines: Vec<&str> = output.trim_end().lines().collect();
+        let shown = output_lines.unwrap_or(lines.len()).min(lines.len());
+        if shown == 0 {
+            return;
+        }
+        text.push_str(&format!("{indent}Output: {}\n", lines[..shown].join(&format!("\n{indent}        "))));
+        if shown < lines.len() {
+            text.push_str(&format!("{indent}        {}\n", theme.hint_text(&format!("... ({} more lines)", lines.len() - shown))));
+        }
+    };
+    for (i, cmd) in ctx.commands.iter().enumerate() {
+        let Some(output) = ctx.outputs.get(i) else {
+            text.push_str(&format!("{indent}Command: {}\n", theme.command_text(cmd)));
+            continue;
+        };
+        let (output, status) = ctx.command_result(i).unwrap_or((output, String::new()));
+        let status = if status == "exit 0" { theme.hint_text(&status) } else { theme.warning_text(&status) };
+        text.push_str(&format!("{indent}Command: {} ({status})\n", theme.command_text(cmd)));
+        push_output(&mut text, output);
+    }
+    // A turn cut short may have output without a command
+    for output in ctx.outputs.iter().skip(ctx.commands.len()) {
+        push_output(&mut text, output);
+    }
+    text
+}
+
+/// A turn's last command, with all it printed and how it ended.
+pub(crate) struct LastCommand<'a> {
+    pub(crate) command: &'a str,
+    pub(crate) output: &'a str,
+    // As command_result puts it: `exit 0`, `exit 3`, `interrupted`, ...
+    pub(crate) status: String,
+}
+
+impl ConversationContext {
+    /// `/forget`: drops what the turn ran and printed but keeps its place,
+    /// so the turns after it keep their numbers.
+    pub(crate) fn forget(&mut self) {
+        self.commands.clear();
+        self.outputs.clear();
+        self.exit_codes.clear();
+        self.request = None;
+        self.pinned = false;
+        self.forgotten = true;
+    }
+
+    /// The last command the turn ran, if it ran any.
+    pub(crate) fn last_command(&self) -> Option<LastCommand<'_>> {
+        let index = self.commands.len().checked_sub(1)?;
+        let (output, status) = self.command_result(index)?;
+        Some(LastCommand { command: &self.commands[index], output, status })
+    }
+
+    /// What the turn's `index`th command printed and how it ended: `exit 0`,
+    /// `exit 3`, `interrupted` or `killed by a signal`, from its recorded
+    /// exit code.
+    pub(crate) fn command_result(&self, index: usize) -> Option<(&str, String)> {
+        let output = self.outputs.get(index)?;
+        let status = match self.exit_codes.get(index) {
+            _ if was_interrupted(output) => "interrupted".to_string(),
+            Some(Some(code)) => format!("exit {code}"),
+            Some(None) => "killed by a signal".to_string(),
+            // Built-ins, which can't fail, keep no exit codes
+            None => "exit 0".to_string(),
+        };
+        Some((output, status))
+    }
+}
+
+// Output lines per command kept in a saved transcript; 0 keeps them all
+pub(crate) const DEFAULT_TRANSCRIPT_OUTPUT_LINES: usize = 50;
+
+/// What `/save` was asked to do.
+#[derive(Debug, PartialEq)]
+pub(crate) struct SaveCommand {
+    // None: ~/.ask/sessions/<timestamp>.md
+    pub(crate) path: Option<PathBuf>,
+    // `/save!` or `--force`: replace an existing file
+    pub(crate) force: bool,
+}
+
+pub(crate) fn parse_save_command(input: &str) -> Option<Result<SaveCommand, String>> {
+    let mut words = input.split_whitespace();
+    let mut force = match words.next()? {
+        "/save" => false,
+        "/save!" => true,
+        _ => return None,
+    };
+    let mut path = None;
+    for word in words {
+        match word {
+            "--force" => force = true,
+            _ if path.is_none() => path = Some(expand_path(word)),
+            _ => return Some(Err("Usage: /save [PATH] (/save! PATH overwrites)".to_string())),
+        }
+    }
+    Some(Ok(SaveCommand { path, force }))
+}
+
+/// ~/.ask/sessions/2026-03-14-093015.md for a session saved at `now`.
+pub(crate) fn default_transcript_path(now: i64) -> PathBuf {
+    let (date, time) = local_time(now);
+    data_dir()
+        .join("sessions")
+        .join(format!("{date}-{}{:02}.md", time.replace(':', ""), now.rem_euclid(60)))
+}
+
+/// The conversation as markdown: each prompt a heading, then what ran and
+/// what it printed in fenced blocks. Each output keeps `output_lines`
+/// lines (0 keeps all of it), and terminal escapes are dropped.
+pub(crate) fn render_transcript(history: &[ConversationContext], output_lines: usize, now: i64) -> String {
+    let (date, time) = local_time(now);
+    let mut text = format!("# ask session, {date} {time}\n");
+    for (i, ctx) in history.iter().enumerate().filter(|(_, ctx)| !ctx.forgotten) {
+        let prompt = strip_ansi(&ctx.prompt);
+        let mut prompt_lines = prompt.lines();
+        text.push_str(&format!("\n## {}. {}\n", i + 1, prompt_lines.next().unwrap_or_default()));
+        let rest: Vec<&str> = prompt_lines.collect();
+        if !rest.is_empty() {
+            text.push_str(&fenced("text", &rest.join("\n")));
+        }
+        for command in &ctx.commands {
+            text.push_str(&fenced("sh", &strip_ansi(command)));
+        }
+        for output in &ctx.outputs {
+            let output = strip_ansi(output);
+            let lines: Vec<&str> = output.trim_end().lines().collect();
+            if lines.is_empty() {
+                continue;
+            }
+            let shown = if output_lines == 0 { lines.len() } else { output_lines.min(lines.len()) };
+            let mut body = lines[..shown].join("\n");
+            if shown < lines.len() {
+                body.push_str(&format!("\n... ({} more lines)", lines.len() - shown));
+            }
+            text.push_str(&fenced("text", &body));
+        }
+    }
+    text
+}
+
+/// A fenced code block whose fence is longer than any backtick run inside.
+pub(crate) fn fenced(lang: &str, body: &str) -> String {
+    let longest = body.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest.max(2) + 1);
+    format!("\n{fence}{lang}\n{body}\n{fence}\n")
+}
+
+/// Drops CSI (`ESC [ ... m`), OSC (`ESC ] ... BEL`) and other two-byte
+/// escape sequences, as colored command output leaves behind.
+pub(crate) fn strip_ansi(text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(ch) = chars.next() {
+        if ch != '\u{1b}' {
+            plain.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\u{7}' || (c == '\u{1b}' && chars.next_if_eq(&'\\').is_some()) {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    plain
+}
+
+/// Writes a transcript, creating its directory. An existing file is only
+/// replaced when `force` is set.
+pub(crate) fn write_transcript(path: &Path, contents: &str, force: bool) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    let mut file = match options.open(path) {
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+            return Err(format!("{} already exists (/save! or --force overwrites it)", path.display()).into());
+        }
+        result => result?,
+    };
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+// Turns `history` shows without an argument
+pub(crate) const HISTORY_PAGE: usize = 20;
+// Output lines per command in the listing
+pub(crate) const HISTORY_OUTPUT_LINES: usize = 1;
+
+// Turns before the first `task` boundary
+pub(crate) const UNTITLED_TASK: &str = "(untitled)";
+
+/// Where a named task starts: turns from `start` on belong to it, up to
+/// the next boundary.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct TaskBoundary {
+    pub(crate) name: String,
+    pub(crate) start: usize,
+}
+
+/// The `task` boundaries of a session. They only scope what prompts see;
+/// no turn is ever moved or dropped, so `task merge` undoes one exactly.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct TaskLog {
+    pub(crate) boundaries: Vec<TaskBoundary>,
+}
+
+impl TaskLog {
+    /// Starts task `name` at turn index `at`. A task nothing was asked in
+    /// yet is renamed rather than left behind empty.
+    pub(crate) fn start(&mut self, name: &str, at: usize) {
+        if let Some(last) = self.boundaries.last_mut()
+            && last.start == at
+        {
+            last.name = name.to_string();
+            return;
+        }
+        self.boundaries.push(TaskBoundary { name: name.to_string(), start: at });
+    }
+
+    /// Removes the latest boundary, returning it.
+    pub(crate) fn merge(&mut self) -> Option<TaskBoundary> {
+        self.boundaries.pop()
+    }
+
+    /// Each task as (name, first turn index, end index) over the first
+    /// `len` turns. The untitled task is left out once it is empty and a
+    /// named one follows; the last entry is always the current task.
+    pub(crate) fn spans(&self, len: usize) -> Vec<(&str, usize, usize)> {
+        let named: Vec<&TaskBoundary> = self.boundaries.iter().filter(|b| b.start <= len).collect();
+        let mut spans = Vec::new();
+        let first = named.first().map_or(len, |b| b.start);
+        if first > 0 || named.is_empty() {
+            spans.push((UNTITLED_TASK, 0, first));
+        }
+        for (i, boundary) in named.iter().enumerate() {
+            let end = named.get(i + 1).map_or(len, |next| next.start);
+            spans.push((boundary.name.as_str(), boundary.start, end));
+        }
+        spans
+    }
+
+    /// The turns a prompt after `history` gets: the current task, with the
+    /// one before it when `widen` is set. Earlier tasks are named in a
+    /// leading one-line turn, the way accepted notes are, so they are
+    /// budgeted and compacted like the rest.
+    pub(crate) fn scope(&self, history: &[ConversationContext], widen: bool) -> Vec<ConversationContext> {
+        let spans = self.spans(history.len());
+        let current = spans.len() - 1;
+        let start = spans[if widen { current.saturating_sub(1) } else { current }].1;
+        let earlier: Vec<String> = spans
+            .iter()
+            .rev()
+            .filter(|(_, first, end)| *end <= start && end > first)
+            .map(|(name, first, end)| format!("{name} ({} turn(s))", end - first))
+            .collect();
+        let mut scoped = Vec::new();
+        if !earlier.is_empty() {
+            scoped.push(ConversationContext {
+                prompt: "task".to_string(),
+                commands: vec!["task".to_string()],
+                outputs: vec![format!(
+                    "Earlier tasks in this session, most recent first, left out of this conversation: {}",
+                    earlier.join(", ")
+                )],
+                exit_codes: vec![],
+                request: None,
+                pinned: false,
+                forgotten: false,
+            });
+        }
+        // Pins outlive their task
+        scoped.extend(history[..start].iter().filter(|ctx| ctx.pinned).cloned());
+        scoped.extend_from_slice(&history[start..]);
+        scoped
+    }
+
+    /// The task containing turn index `index`, when any are named.
+    pub(crate) fn name_at(&self, index: usize) -> Option<&str> {
+        if self.boundaries.is_empty() {
+            return None;
+        }
+        let named = self.boundaries.iter().rev().find(|b| b.start <= index);
+        Some(named.map_or(UNTITLED_TASK, |b| b.name.as_str()))
+    }
+
+    /// The `task` listing for a session of `len` turns.
+    pub(crate) fn render(&self, len: usize) -> String {
+        let spans = self.spans(len);
+        let mut text = String::new();
+        for (i, (name, first, end)) in spans.iter().enumerate() {
+            let current = if i + 1 == spans.len() { " (current)" } else { "" };
+            text.push_str(&format!("{:>3}. {name}: {} turn(s){current}\n", i + 1, end - first));
+        }
+        text
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum TaskCommand {
+    List,
+    Start(String),
+    Previous,
+    Merge,
+}
+
+/// Parses `task`, `task NAME`, `task previous`, and `task merge`.
+pub(crate) fn parse_task_command(input: &str) -> Option<TaskCommand> {
+    let rest = input.strip_prefix("task")?;
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    Some(match rest.trim() {
+        "" => TaskCommand::List,
+        "previous" => TaskCommand::Previous,
+        "merge" => TaskCommand::Merge,
+        name => TaskCommand::Start(name.to_string()),
+    })
+}
+
+/// Which turns `history` lists.
+#[derive(Debug, PartialEq)]
+pub(crate) enum HistoryRange {
+    // `history` or `history N`: the latest N turns
+    Recent(usize),
+    // `history all`
+    All,
+    // `history A..B`: turns A through B, numbered from 1
+    Span(usize, usize),
+    // `history full N`: turn N with all of its output
+    Full(usize),
+}
+
+pub(crate) fn parse_history_listing(input: &str) -> Option<Result<HistoryRange, String>> {
+    let mut words = input.split_whitespace();
+    if !matches!(words.next(), Some("history" | "/history")) {
+        return None;
+    }
+    let usage = "Usage: history [N|all|A..B|full N]".to_string();
+    let range = match (words.next(), words.next()) {
+        (None, _) => HistoryRange::Recent(HISTORY_PAGE),
+        // `history rerun` is parse_history_rerun's
+        (Some("rerun"), _) => return None,
+        (Some("all"), None) => HistoryRange::All,
+        (Some("full"), Some(n)) => match (n.parse::<usize>(), words.next()) {
+            (Ok(n), None) if n >= 1 => HistoryRange::Full(n),
+            _ => return Some(Err(usage)),
+        },
+        (Some(arg), None) => match arg.split_once("..") {
+            Some((a, b)) => match (a.parse::<usize>(), b.parse::<usize>()) {
+                (Ok(a), Ok(b)) if a >= 1 && a <= b => HistoryRange::Span(a, b),
+                _ => return Some(Err(usage)),
+            },
+            None => match arg.parse::<usize>() {
+                Ok(n) if n >= 1 => HistoryRange::Recent(n),
+                _ => return Some(Err(usage)),
+            },
+        },
+        _ => return Some(Err(usage)),
+    };
+    Some(Ok(range))
+}
+
+/// What `/forget`, `/pin` and `/unpin` do to the turn they name.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum TurnAction {
+    Forget,
+    Pin,
+    Unpin,
+}
+
+/// `/forget N`, `/pin N` or `/unpin N`, where N is a turn number as
+/// `history` shows it or `last`. Returns the action and the turn's index.
+pub(crate) fn parse_turn_action(input: &str, turns: usize) -> Option<Result<(TurnAction, usize), String>> {
+    let mut words = input.split_whitespace();
+    let (action, name) = match words.next()? {
+        "/forget" => (TurnAction::Forget, "/forget"),
+        "/pin" => (TurnAction::Pin, "/pin"),
+        "/unpin" => (TurnAction::Unpin, "/unpin"),
+        _ => return None,
+    };
+    let number = match (words.next(), words.next()) {
+        (Some("last"), None) => turns,
+        (Some(n), None) => match n.parse::<usize>() {
+            Ok(n) if n >= 1 => n,
+            _ => return Some(Err(format!("Usage: {name} N|last"))),
+        },
+        _ => return Some(Err(format!("Usage: {name} N|last"))),
+    };
+    if number == 0 || number > turns {
+        return Some(Err(match turns {
+            0 => "No turns yet in this session.".to_string(),
+            _ => format!("No turn {number} (session has {turns}; see history)"),
+        }));
+    }
+    Some(Ok((action, number - 1)))
+}
+
+/// The listing for `range` of a session with `history.len()` turns. Turn
+/// numbers are absolute, so they match `history rerun` on every page.
+/// Once tasks are named, each task's turns come under its name.
+pub(crate) fn render_history(history: &[ConversationContext], range: &HistoryRange, tasks: &TaskLog, theme: &Theme) -> String {
+    let total = history.len();
+    if total == 0 {
+        return "No turns yet in this session.\n".to_string();
+    }
+    let (start, end) = match *range {
+        HistoryRange::Recent(n) => (total.saturating_sub(n), total),
+        HistoryRange::All => (0, total),
+        HistoryRange::Span(a, b) => (a - 1, b.min(total)),
+        HistoryRange::Full(n) => (n - 1, n.min(total)),
+    };
+    let output_lines = match range {
+        HistoryRange::Full(_) => None,
+        _ => Some(HISTORY_OUTPUT_LINES),
+    };
+    if start >= end {
+        return format!("No turns in that range (session has {total}).\n");
+    }
+    let mut text = String::new();
+    for (i, ctx) in history.iter().enumerate().take(end).skip(start) {
+        if let Some(name) = tasks.name_at(i)
+            && (i == start || tasks.boundaries.iter().any(|b| b.start == i))
+        {
+            text.push_str(&format!("── task: {name} ──\n"));
+        }
+        text.push_str(&format_turn(i + 1, ctx, output_lines, theme));
+    }
+    if start > 0 && matches!(range, HistoryRange::Recent(_)) {
+        text.push_str(&format!(
+            "(… {start} earlier turns — `history {}` or `history all`)\n",
+            (end - start + 30).min(total)
+        ));
+    }
+    text
+}
+
+/// When long output goes through $PAGER (pager=off|auto|always).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum PagerMode {
+    Off,
+    // Only output taller than the terminal
+    Auto,
+    Always,
+}
+
+impl PagerMode {
+    pub(crate) fn from_str(value: &str) -> Option<Self> {
+        match value {
+            "off" => Some(PagerMode::Off),
+            "auto" => Some(PagerMode::Auto),
+            "always" => Some(PagerMode::Always),
+            _ => None,
+        }
+    }
+}
+
+// Set when interactive mode starts; other modes never page
+pub(crate) static PAGER_MODE: std::sync::OnceLock<PagerMode> = std::sync::OnceLock::new();
+
+pub(crate) fn pager_mode() -> PagerMode {
+    *PAGER_MODE.get().unwrap_or(&PagerMode::Off)
+}
+
+/// The terminal's height, or 0 when stdout isn't a terminal.
+pub(crate) fn terminal_rows() -> usize {
+    let fd = io::stdout().as_raw_fd();
+    let mut size = unsafe { std::mem::zeroed::<libc::winsize>() };
+    if unsafe { libc_isatty(fd) } == 1 && unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) } == 0 {
+        size.ws_row as usize
+    } else {
+        0
+    }
+}
+
+/// Prints `text`, through $PAGER (default `less -FRX`) when stdout is a
+/// terminal too short to show it all, or always with pager=always. Falls
+/// back to printing directly if the pager can't run.
+pub(crate) fn page_output(text: &str) {
+    let rows = match pager_mode() {
+        PagerMode::Off => 0,
+        PagerMode::Auto | PagerMode::Always => terminal_rows(),
+    };
+    if rows == 0 || (pager_mode() == PagerMode::Auto && text.lines().count() < rows) || !run_pager(text) {
+        print!("{text}");
+    }
+}
+
+/// Shows `text` in $PAGER and waits for the user to quit it. False when
+/// the pager couldn't run.
+pub(crate) fn run_pager(text: &str) -> bool {
+    let pager = env::var("PAGER").ok().filter(|p| !p.trim().is_empty()).unwrap_or_else(|| "less -FRX".to_string());
+    let _ = io::stdout().flush();
+    // Ctrl-C belongs to the pager, not to ask
+    let _sigint = CatchSigint::new();
+    Command::new("/bin/sh")
+        .arg("-c")
+        .arg(&pager)
+        .stdin(Stdio::piped())
+        .spawn()
+        .and_then(|mut child| {
+            if let Some(mut stdin) = child.stdin.take() {
+                // The user may quit the pager before reading it all
+                let _ = stdin.write_all(text.as_bytes());
+            }
+            child.wait()
+        })
+        .is_ok()
+}
+
+/// Caps how much of a session stays in memory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct SessionLimits {
+    pub(crate) max_turns: usize,
+    pub(crate) max_bytes: usize,
+}
+
+pub(crate) const DEFAULT_SESSION_LIMITS: SessionLimits = SessionLimits {
+    max_turns: 200,
+    max_bytes: 16 * 1024 * 1024,
+};
+
+// Spilled turns keep this much of each output in memory. It is longer than
+// the 200-byte cut in ConversationContext::messages, so compaction renders
+// stubs identically.
+pub(crate) const STUB_OUTPUT_PREVIEW: usize = 256;
+
+/// What a spilled turn keeps of `output`: the start of what the model is
+/// sent for it (the JSON summary when there is one), and the interrupted
+/// marker, so ConversationContext::messages renders the stub like the
+/// full turn.
+pub(crate) fn stub_output(output: &str) -> String {
+    let body = output.strip_suffix(INTERRUPTED).unwrap_or(output).trim_end();
+    let summary = compact_json_output(body);
+    let mut stub = truncate_at_char_boundary(summary.as_deref().unwrap_or(body), STUB_OUTPUT_PREVIEW).to_string();
+    if was_interrupted(output) {
+        if !stub.is_empty() {
+            stub.push('\n');
+        }
+        stub.push_str(INTERRUPTED);
+    }
+    stub
+}
+
+/// Where a spilled turn lives in the spill file.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SpillRef {
+    pub(crate) offset: u64,
+    pub(crate) len: u64,
+}
+
+/// Moves old turns out of memory into a scratch file, leaving stubs behind
+/// (prompt, commands, and a short output preview). Compaction only reads the
+/// recent tail, so it never notices; anything that needs the full record
+/// goes through `full_turn` / `full_history`.
+pub(crate) struct SessionSpill {
+    pub(crate) limits: SessionLimits,
+    pub(crate) path: PathBuf,
+    pub(crate) file: Option<fs::File>,
+    pub(crate) spilled: std::collections::BTreeMap<usize, SpillRef>,
+    pub(crate) end: u64,
+}
+
+impl SessionSpill {
+    /// The spill file goes in a private temp directory with a random name,
+    /// made on the first spill.
+    pub(crate) fn new(limits: SessionLimits) -> Self {
+        Self::with_path(limits, PathBuf::new())
+    }
+
+    pub(crate) fn with_path(limits: SessionLimits, path: PathBuf) -> Self {
+        Self {
+            limits,
+            path,
+            file: None,
+            spilled: std::collections::BTreeMap::new(),
+            end: 0,
+        }
+    }
+
+    /// Spills the oldest in-memory turns until both limits hold again.
+    /// The most recent turn always stays resident.
+    pub(crate) fn enforce(&mut self, history: &mut [ConversationContext]) -> io::Result<()> {
+        let mut resident: Vec<usize> = (0..history.len())
+            .filter(|i| !self.spilled.contains_key(i))
+            .collect();
+        let mut resident_bytes: usize = resident.iter().map(|&i| turn_bytes(&history[i])).sum();
+
+        while resident.len() > 1
+            && (resident.len() > self.limits.max_turns || resident_bytes > self.limits.max_bytes)
+        {
+            let index = resident.remove(0);
+            resident_bytes -= turn_bytes(&history[index]);
+            self.spill(index, &mut history[index])?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn spill(&mut self, index: usize, ctx: &mut ConversationContext) -> io::Result<()> {
+        if self.file.is_none() {
+            use std::os::unix::fs::OpenOptionsExt;
+            // Outputs can hold secrets: a directory and file of this user's alone
+            match self.path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => private_dir(dir)?,
+                _ => self.path = staging_dir("ask-session")?.join("spill.jsonl"),
+            }
+            self.file = Some(
+                fs::OpenOptions::new()
+                    .create_new(true)
+                    .mode(0o600)
+                    .read(true)
+                    .write(true)
+                    .open(&self.path)?,
+            );
+            self.end = 0;
+        }
+        let Some(file) = self.file.as_mut() else {
+            return Ok(());
+        };
+
+        let mut record = session_record(ctx).map_err(io::Error::other)?;
+        record.push(b'\n');
+        io::Seek::seek(file, io::SeekFrom::Start(self.end))?;
+        file.write_all(&record)?;
+        self.spilled.insert(index, SpillRef { offset: self.end, len: record.len() as u64 });
+        self.end += record.len() as u64;
+
+        ctx.outputs = ctx.outputs.iter().map(|output| stub_output(output)).collect();
+        Ok(())
+    }
+
+    /// The complete turn at `index`, read back from disk if it was spilled.
+    /// A pin set since then lives on the resident stub.
+    pub(crate) fn full_turn(&self, history: &[ConversationContext], index: usize) -> io::Result<ConversationContext> {
+        let Some(spill_ref) = self.spilled.get(&index) else {
+            return Ok(history[index].clone());
+        };
+        let mut file = fs::File::open(&self.path)?;
+        let mut record = vec![0u8; spill_ref.len as usize];
+        io::Seek::seek(&mut file, io::SeekFrom::Start(spill_ref.offset))?;
+        file.read_exact(&mut record)?;
+        let mut turn = parse_session_record(&record).map_err(io::Error::other)?;
+        turn.pinned = history[index].pinned;
+        Ok(turn)
+    }
+
+    /// Turn index `index` was forgotten: its record is no longer looked up.
+    pub(crate) fn forget(&mut self, index: usize) {
+        self.spilled.remove(&index);
+    }
+
+    pub(crate) fn full_history(&self, history: &[ConversationContext]) -> io::Result<Vec<ConversationContext>> {
+        (0..history.len()).map(|i| self.full_turn(history, i)).collect()
+    }
+
+    /// Forgets every spilled turn (the session history was reset).
+    pub(crate) fn clear(&mut self) {
+        self.spilled.clear();
+        self.file = None;
+        self.end = 0;
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl Drop for SessionSpill {
+    fn drop(&mut self) {
+        self.clear();
+        if let Some(dir) = self.path.parent() {
+            let _ = fs::remove_dir(dir);
+        }
+    }
+}
+
+/// Payload size of a turn, used against the session byte budget.
+pub(crate) fn turn_bytes(ctx: &ConversationContext) -> usize {
+    ctx.prompt.len()
+        + ctx.commands.iter().map(String::len).sum::<usize>()
+        + ctx.outputs.iter().map(String::len).sum::<usize>()
+        + ctx.request.as_ref().map_or(0, |r| r.response.iter().map(String::len).sum())
+}
+
+// Tokens set aside for the note about dropped turns
+pub(crate) const OMITTED_NOTE_TOKENS: usize = 100;
+
+/// What compact_history sends: the note about turns left out, then each
+/// turn that fit, in order.
+pub(crate) struct CompactedHistory {
+    pub(crate) note: Option<ChatMessage>,
+    // Whether the turn is pinned, and its messages
+    pub(crate) turns: Vec<(bool, Vec<ChatMessage>)>,
+}
+
+impl CompactedHistory {
+    pub(crate) fn messages(self) -> Vec<ChatMessage> {
+        self.note.into_iter().chain(self.turns.into_iter().flat_map(|(_, messages)| messages)).collect()
+    }
+}
+
+/// The earlier turns as they go into a request, as role-tagged messages.
+pub(crate) fn compact_history(history: &[ConversationContext], redaction: &Redaction, budget: usize) -> Vec<ChatMessage> {
+    compact_turns(history, redaction, budget).messages()
+}
+
+/// Fits whole turns into `budget` tokens: pinned turns first, newest
+/// first, then the most recent of the rest. Older turns are dropped; when
+/// any are, a system note up front lists what they asked so follow-ups
+/// still have some thread to pull on.
+pub(crate) fn compact_turns(history: &[ConversationContext], redaction: &Redaction, budget: usize) -> CompactedHistory {
+    let fit = |budget: usize| {
+        let mut total = 0;
+        let mut kept: Vec<Option<Vec<ChatMessage>>> = vec![None; history.len()];
+        for pinned in [true, false] {
+            for (i, ctx) in history.iter().enumerate().rev().filter(|(_, ctx)| ctx.pinned == pinned) {
+                let messages = ctx.messages(redaction);
+                let tokens: usize = messages.iter().map(|m| estimate_tokens(&m.content)).sum();
+                if total + tokens > budget {
+                    // A pin too big to fit leaves room for the other pins,
+                    // but recent turns stop at the first gap
+                    if pinned {
+                        continue;
+                    }
+                    break;
+                }
+                total += tokens;
+                kept[i] = Some(messages);
+            }
+        }
+        kept
+    };
+
+    let mut kept = fit(budget);
+    let mut note = None;
+    if kept.iter().any(Option::is_none) {
+        kept = fit(budget.saturating_sub(OMITTED_NOTE_TOKENS));
+        let omitted: Vec<&ConversationContext> = history.iter().zip(&kept).filter(|(_, k)| k.is_none()).map(|(ctx, _)| ctx).collect();
+        let mut text = format!(
+            "{} earlier interaction(s) in this session were omitted for length. They asked:",
+            omitted.len()
+        );
+        for ctx in omitted.iter().rev() {
+            let line = format!("\n- {}", truncate_at_char_boundary(&ctx.prompt, 80));
+            if estimate_tokens(&text) + estimate_tokens(&line) > OMITTED_NOTE_TOKENS {
+                text.push_str("\n- ...");
+                break;
+            }
+            text.push_str(&line);
+        }
+        note = Some(ChatMessage::new(Role::System, text));
+    }
+
+    // Chronological order, pinned turns in their place
+    let turns = history
+        .iter()
+        .zip(kept)
+        .filter_map(|(ctx, messages)| messages.map(|messages| (ctx.pinned, messages)))
+        .collect();
+    CompactedHistory { note, turns }
+}
+
+// Version of the machine-readable formats ask writes: the --stdin-commands
+// result lines, spilled session turns, and the export-settings manifest.
+// Bump it when a field is renamed, removed, or changes meaning; a new
+// optional field does not need a bump. Readers accept this version and the
+// one before it (migrating the old shape forward) and refuse newer ones.
+//   1: records written before schema_version existed (no version field;
+//      session turns may lack `request`, manifests only carry `format=`)
+//   2: every record carries a top-level schema_version
+pub(crate) const SCHEMA_VERSION: u32 = 2;
+
+/// Validates a schema version read back from disk. `what` names the source
+/// for the error message.
+pub(crate) fn check_schema_version(found: u32, what: &str) -> Result<u32, String> {
+    if found > SCHEMA_VERSION {
+        return Err(format!(
+            "{what} has schema version {found}, but ask {} reads up to version {SCHEMA_VERSION}; upgrade ask to read it",
+            env!("CARGO_PKG_VERSION")
+        ));
+    }
+    if found + 1 < SCHEMA_VERSION {
+        return Err(format!(
+            "{what} has schema version {found}, which ask {} no longer reads",
+            env!("CARGO_PKG_VERSION")
+        ));
+    }
+    Ok(found)
+}
+
+/// Serializes a turn for the spill file, tagged with the schema version.
+pub(crate) fn session_record(ctx: &ConversationContext) -> serde_json::Result<Vec<u8>> {
+    let mut record = serde_json::to_value(ctx)?;
+    record["schema_version"] = json!(SCHEMA_VERSION);
+    serde_json::to_vec(&record)
+}
+
+/// Reads a turn written by `session_record`, migrating version 1 records.
+pub(crate) fn parse_session_record(bytes: &[u8]) -> Result<ConversationContext, String> {
+    let mut record: serde_json::Value =
+        serde_json::from_slice(bytes).map_err(|e| format!("Invalid session record: {e}"))?;
+    let version = match record.get("schema_version") {
+        None => 1,
+        Some(v) => v
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or("Invalid session record: schema_version is not a number")?,
+    };
+    if check_schema_version(version, "Session record")? == 1 && record.get("request").is_none() {
+        // Version 1 turns predate replay metadata
+        record["request"] = serde_json::Value::Null;
+    }
+    serde_json::from_value(record).map_err(|e| format!("Invalid session record: {e}"))
+}
+
+// The session --resume picks up without a name
+pub(crate) const LAST_SESSION: &str = "last";
+// How often a running session is saved, so a crash loses little
+pub(crate) const SESSION_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// An interactive session kept for --resume in sessions/<name>.json:
+/// `{"schema_version": N, "turns": [record, ...], "tasks": [boundary, ...]}`
+/// with each turn as `session_record` writes it. Saves from before tasks
+/// have no "tasks".
+pub(crate) struct SavedSession {
+    pub(crate) path: PathBuf,
+}
+
+impl SavedSession {
+    pub(crate) fn named(name: &str) -> Result<Self, String> {
+        Self::in_dir(&data_dir().join("sessions"), name)
+    }
+
+    pub(crate) fn in_dir(dir: &Path, name: &str) -> Result<Self, String> {
+        let valid = !name.is_empty()
+            && !name.starts_with('.')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !valid {
+            return Err(format!("Invalid session name '{name}' (use letters, digits, '-', '_' and '.')"));
+        }
+        Ok(Self { path: dir.join(format!("{name}.json")) })
+    }
+
+    /// The saved turns and tasks; None when nothing was saved under this name.
+    pub(crate) fn load(&self) -> Result<Option<(Vec<ConversationContext>, TaskLog)>, String> {
+        match fs::read(&self.path) {
+            Ok(bytes) => parse_session_document(&bytes).map(Some),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    /// Replaces the saved session, through a temporary file so a crash
+    /// mid-write leaves the previous save intact.
+    pub(crate) fn save(&self, history: &[ConversationContext], tasks: &TaskLog) -> Result<(), Box<dyn std::error::Error>> {
+        let mut turns = Vec::with_capacity(history.len());
+        for ctx in history {
+            turns.push(serde_json::from_slice::<serde_json::Value>(&session_record(ctx)?)?);
+        }
+        let document = json!({ "schema_version": SCHEMA_VERSION, "turns": turns, "tasks": tasks.boundaries });
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let partial = self.path.with_extension("json.partial");
+        fs::write(&partial, serde_json::to_vec(&document)?)?;
+        fs::rename(&partial, &self.path)?;
+        Ok(())
+    }
+
+    pub(crate) fn delete(&self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+pub(crate) fn parse_session_document(bytes: &[u8]) -> Result<(Vec<ConversationContext>, TaskLog), String> {
+    let document: serde_json::Value =
+        serde_json::from_slice(bytes).map_err(|e| format!("Invalid saved session: {e}"))?;
+    let version = document
+        .get("schema_version")
+        .and_then(serde_json::Value::as_u64)
+        .and_then(|v| u32::try_from(v).ok())
+        .ok_or("Invalid saved session: no schema_version")?;
+    check_schema_version(version, "The saved session")?;
+    let turns = document
+        .get("turns")
+        .and_then(serde_json::Value::as_array)
+        .ok_or("Invalid saved session: no turns")?;
+    let turns = turns
+        .iter()
+        .map(|turn| parse_session_record(turn.to_string().as_bytes()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut boundaries: Vec<TaskBoundary> = match document.get("tasks") {
+        None => Vec::new(),
+        Some(tasks) => serde_json::from_value(tasks.clone()).map_err(|e| format!("Invalid saved session: {e}"))?,
+    };
+    // Out-of-order or out-of-range boundaries would scope nonsense
+    let mut last = 0;
+    boundaries.retain(|b| {
+        let keep = b.start >= last && b.start <= turns.len();
+        last = last.max(b.start);
+        keep
+    });
+    Ok((turns, TaskLog { boundaries }))
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ConversationContext {
+    pub prompt: String,
+    pub commands: Vec<String>,
+    pub outputs: Vec<String>,
+    // Each command's exit code, recorded as it finished; None when a signal
+    // or Ctrl-C stopped it. Empty for built-ins such as `cd`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exit_codes: Vec<Option<i32>>,
+    // None for built-ins and direct commands that never reached the model
+    #[serde(default)]
+    pub request: Option<TurnRequest>,
+    // `/pin`: kept in the context ahead of newer turns when it is compacted
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pinned: bool,
+    // `/forget`: kept only so later turns keep their numbers; never sent
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub forgotten: bool,
+}
+
+/// What the model was asked and what it suggested, so a turn can be replayed.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TurnRequest {
+    pub model: String,
+    pub api_url: String,
+    pub response: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+
+    // --- multibyte truncation ---
+
+    #[test]
+    fn truncate_at_char_boundary_backs_off_inside_a_character() {
+        // 199 ASCII bytes, then a 4-byte emoji straddling the 200-byte mark
+        let text = format!("{}😀tail", "a".repeat(199));
+        let cut = truncate_at_char_boundary(&text, 200);
+        assert_eq!(cut, "a".repeat(199));
+        assert_eq!(truncate_at_char_boundary(&text, 203), format!("{}😀", "a".repeat(199)));
+    }
+
+    #[test]
+    fn truncate_at_char_boundary_leaves_short_text_alone() {
+        assert_eq!(truncate_at_char_boundary("héllo", 200), "héllo");
+        assert_eq!(truncate_at_char_boundary("", 0), "");
+    }
+
+    #[test]
+    fn compact_history_handles_multibyte_output_at_every_offset() {
+        // Shift each sample across the 200-byte boundary so every byte
+        // position inside the character gets hit at least once.
+        for sample in ["😀", "漢字", "e\u{301}", "👩‍💻"] {
+            for pad in 195..=200 {
+                let output = format!("{}{}", "x".repeat(pad), sample.repeat(20));
+                let history = vec![ConversationContext {
+                    prompt: "ls".to_string(),
+                    commands: vec!["ls".to_string()],
+                    outputs: vec![output],
+                    exit_codes: vec![],
+                    request: None,
+                    pinned: false,
+                    forgotten: false,
+                }];
+                let out = compact_history(&history, &redaction(), MAX_CONTEXT_TOKENS);
+                assert!(out.iter().any(|m| m.content.contains("... (truncated)")));
+            }
+        }
+    }
+
+    #[test]
+    fn estimate_total_context_size_counts_multibyte_output_by_token() {
+        // 漢 is two cl100k tokens, where bytes/4 would have said under one
+        let history = vec![ConversationContext {
+            prompt: String::new(),
+            commands: vec![],
+            outputs: vec!["漢".repeat(400)],
+            exit_codes: vec![],
+            request: None,
+            pinned: false,
+            forgotten: false,
+        }];
+        assert_eq!(estimate_total_context_size(&history), 800);
+    }
+
+    // --- compact_history ---
+
+    #[test]
+    fn compact_history_empty_sends_no_messages() {
+        assert!(compact_history(&[], &redaction(), MAX_CONTEXT_TOKENS).is_empty());
+    }
+
+    #[test]
+    fn compact_history_keeps_chronological_order_for_small_history() {
+        let history = vec![
+            ConversationContext {
+                prompt: "first-prompt".to_string(),
+                commands: vec!["ls".to_string()],
+                outputs: vec![],
+                exit_codes: vec![],
+                request: None,
+                pinned: false,
+                forgotten: false,
+            },
+            ConversationContext {
+                prompt: "second-prompt".to_string(),
+                commands: vec!["pwd".to_string()],
+                outputs: vec![],
+                exit_codes: vec![],
+                request: None,
+                pinned: false,
+                forgotten: false,
+            },
+        ];
+        let out = compact_history(&history, &redaction(), MAX_CONTEXT_TOKENS);
+        let roles: Vec<Role> = out.iter().map(|m| m.role).collect();
+        assert_eq!(roles, [Role::User, Role::Assistant, Role::User, Role::Assistant]);
+        assert_eq!(out[0].content, "first-prompt");
+        assert_eq!(out[2].content, "second-prompt");
+    }
+
+    #[test]
+    fn compact_history_truncates_when_over_token_budget() {
+        let history: Vec<ConversationContext> = (0..40)
+            .map(|_| ConversationContext {
+                prompt: " p".repeat(250), // 250 tokens
+                commands: vec![],
+                outputs: vec![],
+                exit_codes: vec![],
+                request: None,
+                pinned: false,
+                forgotten: false,
+            })
+            .collect();
+        let out = compact_history(&history, &redaction(), MAX_CONTEXT_TOKENS);
+        assert_eq!(out[0].role, Role::System, "expected a note about dropped turns");
+        assert!(out[0].content.starts_with("29 earlier interaction(s)"), "{}", out[0].content);
+        // Whole turns only: every prompt that survives is intact
+        assert!(out[1..].iter().all(|m| m.content.len() == 500));
+        let tokens: usize = out.iter().map(|m| estimate_tokens(&m.content)).sum();
+        assert!(tokens <= MAX_CONTEXT_TOKENS, "compacted output must respect budget");
+    }
+
+    /// `count` turns of about 250 tokens each, prompts starting "N ".
+    fn long_turns(count: usize) -> Vec<ConversationContext> {
+        (0..count)
+            .map(|i| ConversationContext {
+                prompt: format!("{i} {}", " p".repeat(249)),
+                commands: vec![],
+                outputs: vec![],
+                exit_codes: vec![],
+                request: None,
+                pinned: false,
+                forgotten: false,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn compact_history_keeps_pinned_turns_however_old() {
+        let mut history = long_turns(40);
+        history[0].pinned = true;
+        history[5].pinned = true;
+        let out = compact_history(&history, &redaction(), MAX_CONTEXT_TOKENS);
+        // The pins take their budget first; in order, ahead of the recent turns
+        assert!(out[1].content.starts_with("0 "), "{}", out[1].content);
+        assert!(out[2].content.starts_with("5 "), "{}", out[2].content);
+        assert!(out[3..].iter().all(|m| m.content.split(' ').next().unwrap().parse::<usize>().unwrap() > 30));
+        assert!(out[0].content.starts_with("29 earlier interaction(s)"), "{}", out[0].content);
+        assert!(!out[0].content.contains("\n- 0 ") && !out[0].content.contains("\n- 5 "));
+        let tokens: usize = out.iter().map(|m| estimate_tokens(&m.content)).sum();
+        assert!(tokens <= MAX_CONTEXT_TOKENS);
+    }
+
+    #[test]
+    fn compact_history_skips_a_pin_too_big_to_fit_but_keeps_the_rest() {
+        let mut history = long_turns(40);
+        history[0].pinned = true;
+        history[1].pinned = true;
+        history[1].prompt = format!("1 {}", " p".repeat(MAX_CONTEXT_TOKENS));
+        let out = compact_history(&history, &redaction(), MAX_CONTEXT_TOKENS);
+        assert!(out[1].content.starts_with("0 "), "{}", out[1].content);
+        assert!(!out.iter().any(|m| m.content.starts_with("1 ")));
+        // Without pins it is plain recency again
+        history[0].pinned = false;
+        history[1].pinned = false;
+        let out = compact_history(&history, &redaction(), MAX_CONTEXT_TOKENS);
+        assert!(out[1].content.starts_with("29 "), "{}", out[1].content);
+    }
+
+    #[test]
+    fn turn_messages_keep_the_assistant_wording_and_output() {
+        let ctx = ConversationContext {
+            prompt: "how big is this folder".to_string(),
+            commands: vec!["du -sh .".to_string()],
+            outputs: vec!["4.0K\t.\n".to_string()],
+            exit_codes: vec![],
+            request: Some(TurnRequest {
+                model: "m".to_string(),
+                api_url: DEFAULT_API_URL.to_string(),
+                response: vec!["# Checking the folder size".to_string(), "du -sh .".to_string()],
+            }),
+            pinned: false,
+            forgotten: false,
+        };
+        let messages = ctx.messages(&redaction());
+        assert_eq!(messages[1], ChatMessage::new(Role::Assistant, "# Checking the folder size\ndu -sh ."));
+        assert_eq!(messages[2].role, Role::Tool);
+        assert!(messages[2].content.contains("$ du -sh .\n4.0K\t."));
+    }
+
+    #[test]
+    fn render_messages_alternates_roles() {
+        let messages = vec![
+            ChatMessage::new(Role::User, "list files"),
+            ChatMessage::new(Role::Assistant, "ls"),
+            ChatMessage::new(Role::Tool, "Output of the commands that ran:\n$ ls\na.txt"),
+            ChatMessage::new(Role::User, "what did we just do?"),
+        ];
+        let rendered = render_messages(&messages);
+        let roles: Vec<&str> = rendered.iter().map(|m| m["role"].as_str().unwrap()).collect();
+        assert_eq!(roles, ["user", "assistant", "user"]);
+        let last = rendered[2]["content"].as_str().unwrap();
+        assert!(last.starts_with("Output of the commands that ran:") && last.ends_with("what did we just do?"));
+    }
+
+    // --- transcripts ---
+
+    #[test]
+    fn parse_save_command_forms() {
+        let save = |path: Option<&str>, force| Some(Ok(SaveCommand { path: path.map(PathBuf::from), force }));
+        assert_eq!(parse_save_command("/save"), save(None, false));
+        assert_eq!(parse_save_command("/save!"), save(None, true));
+        assert_eq!(parse_save_command("/save notes/today.md"), save(Some("notes/today.md"), false));
+        assert_eq!(parse_save_command("/save! a.md"), save(Some("a.md"), true));
+        assert_eq!(parse_save_command("/save --force a.md"), save(Some("a.md"), true));
+        assert_eq!(parse_save_command("/save a.md --force"), save(Some("a.md"), true));
+        assert!(matches!(parse_save_command("/save a.md b.md"), Some(Err(_))));
+        assert_eq!(parse_save_command("/saved"), None);
+        assert_eq!(parse_save_command("save the file"), None);
+    }
+
+    #[test]
+    fn transcript_is_markdown_with_trimmed_plain_output() {
+        let history = vec![
+            ConversationContext {
+                prompt: "list big files".to_string(),
+                commands: vec!["du -sh * | sort -h".to_string()],
+                outputs: vec!["\u{1b}[31m4.0K\u{1b}[0m\ta\n8.0K\tb\n12K\tc\n".to_string()],
+                exit_codes: vec![],
+                request: None,
+                pinned: false,
+                forgotten: false,
+            },
+            ConversationContext {
+                prompt: "pasted\nblock".to_string(),
+                commands: vec!["echo '```'".to_string()],
+                outputs: vec![String::new()],
+                exit_codes: vec![],
+                request: None,
+                pinned: false,
+                forgotten: false,
+            },
+        ];
+        let text = render_transcript(&history, 2, 0);
+        let (date, time) = local_time(0);
+        assert_eq!(
+            text,
+            format!(
+                "# ask session, {date} {time}\n\
+                 \n## 1. list big files\n\
+                 \n```sh\ndu -sh * | sort -h\n```\n\
+                 \n```text\n4.0K\ta\n8.0K\tb\n... (1 more lines)\n```\n\
+                 \n## 2. pasted\n\
+                 \n```text\nblock\n```\n\
+                 \n````sh\necho '```'\n````\n"
+            )
+        );
+        assert!(render_transcript(&history, 0, 0).contains("12K\tc\n```"));
+    }
+
+    #[test]
+    fn strip_ansi_drops_csi_and_osc_sequences() {
+        assert_eq!(strip_ansi("\u{1b}[1;32mok\u{1b}[0m done"), "ok done");
+        assert_eq!(strip_ansi("\u{1b}]0;title\u{7}text"), "text");
+        assert_eq!(strip_ansi("\u{1b}]8;;http://x\u{1b}\\link\u{1b}]8;;\u{1b}\\"), "link");
+        assert_eq!(strip_ansi("plain ünïcode"), "plain ünïcode");
+    }
+
+    #[test]
+    fn write_transcript_creates_dirs_and_refuses_to_overwrite() {
+        let dir = env::temp_dir().join(format!("ask-transcript-test-{}", std::process::id()));
+        let path = dir.join("nested").join("s.md");
+        write_transcript(&path, "one", false).unwrap();
+        let err = write_transcript(&path, "two", false).unwrap_err();
+        assert!(err.to_string().contains("already exists"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "one");
+        write_transcript(&path, "three", true).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "three");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn default_transcript_path_is_in_the_sessions_dir() {
+        let path = default_transcript_path(59);
+        let (date, time) = local_time(59);
+        assert_eq!(path, data_dir().join("sessions").join(format!("{date}-{}59.md", time.replace(':', ""))));
+    }
+
+    // --- tasks ---
+
+    fn tasks(boundaries: &[(&str, usize)]) -> TaskLog {
+        TaskLog {
+            boundaries: boundaries.iter().map(|(name, start)| TaskBoundary { name: name.to_string(), start: *start }).collect(),
+        }
+    }
+
+    fn prompts(turns: &[ConversationContext]) -> Vec<&str> {
+        turns.iter().map(|t| t.prompt.as_str()).collect()
+    }
+
+    #[test]
+    fn parse_task_command_forms() {
+        assert_eq!(parse_task_command("task"), Some(TaskCommand::List));
+        assert_eq!(parse_task_command("task  debug the container "), Some(TaskCommand::Start("debug the container".to_string())));
+        assert_eq!(parse_task_command("task previous"), Some(TaskCommand::Previous));
+        assert_eq!(parse_task_command("task merge"), Some(TaskCommand::Merge));
+        assert_eq!(parse_task_command("tasks"), None);
+        assert_eq!(parse_task_command("list my tasks"), None);
+    }
+
+    #[test]
+    fn task_scope_sends_the_current_task_and_names_earlier_ones() {
+        let history: Vec<ConversationContext> = ["a1", "a2", "b1", "c1", "c2"].iter().map(|p| turn(p, None)).collect();
+        // No tasks: everything, untouched
+        assert_eq!(prompts(&TaskLog::default().scope(&history, false)), ["a1", "a2", "b1", "c1", "c2"]);
+
+        let log = tasks(&[("b", 2), ("c", 3)]);
+        let scoped = log.scope(&history, false);
+        assert_eq!(prompts(&scoped), ["task", "c1", "c2"]);
+        assert_eq!(
+            scoped[0].outputs[0],
+            "Earlier tasks in this session, most recent first, left out of this conversation: b (1 turn(s)), (untitled) (2 turn(s))"
+        );
+        // `task previous` adds the task before
+        assert_eq!(prompts(&log.scope(&history, true)), ["task", "b1", "c1", "c2"]);
+        // A task just started sees nothing but the summary
+        assert_eq!(prompts(&tasks(&[("d", 5)]).scope(&history, false)), ["task"]);
+    }
+
+    #[test]
+    fn task_start_renames_an_empty_task_and_merge_undoes_a_boundary() {
+        let mut log = TaskLog::default();
+        log.start("typo", 2);
+        log.start("dns", 2);
+        assert_eq!(log, tasks(&[("dns", 2)]));
+        log.start("script", 4);
+        assert_eq!(log.merge().map(|b| b.name), Some("script".to_string()));
+        assert_eq!(log, tasks(&[("dns", 2)]));
+        assert_eq!(log.render(4), "  1. (untitled): 2 turn(s)\n  2. dns: 2 turn(s) (current)\n");
+        // Nothing before the first task: no untitled entry
+        assert_eq!(tasks(&[("dns", 0)]).render(1), "  1. dns: 1 turn(s) (current)\n");
+    }
+
+    #[test]
+    fn parse_turn_action_forms() {
+        assert_eq!(parse_turn_action("/forget 2", 3), Some(Ok((TurnAction::Forget, 1))));
+        assert_eq!(parse_turn_action("/forget last", 3), Some(Ok((TurnAction::Forget, 2))));
+        assert_eq!(parse_turn_action("/pin 1", 3), Some(Ok((TurnAction::Pin, 0))));
+        assert_eq!(parse_turn_action("/unpin last", 3), Some(Ok((TurnAction::Unpin, 2))));
+        assert_eq!(parse_turn_action("/forget 4", 3), Some(Err("No turn 4 (session has 3; see history)".to_string())));
+        assert_eq!(parse_turn_action("/pin last", 0), Some(Err("No turns yet in this session.".to_string())));
+        for bad in ["/forget", "/forget 0", "/pin x", "/pin 1 2"] {
+            assert!(matches!(parse_turn_action(bad, 3), Some(Err(_))), "{bad}");
+        }
+        assert_eq!(parse_turn_action("/pinned 1", 3), None);
+        assert_eq!(parse_turn_action("forget 1", 3), None);
+    }
+
+    #[test]
+    fn forgotten_turns_keep_their_number_and_are_never_sent() {
+        let mut history: Vec<ConversationContext> = ["a1", "b1", "b2"].iter().map(|p| output_turn(p)).collect();
+        history[1].pinned = true;
+        history[1].forget();
+        let listing = render_history(&history, &HistoryRange::All, &tasks(&[("b", 1)]), &Theme::plain());
+        assert_eq!(
+            listing,
+            "── task: (untitled) ──\n[1] User: show the readme\n    Command: cat README (exit 0)\n    Output: a1\n\
+             ── task: b ──\n[2] (forgotten) User: show the readme\n\
+             [3] User: show the readme\n    Command: cat README (exit 0)\n    Output: b2\n"
+        );
+        let sent = compact_history(&history, &redaction(), 3000);
+        assert!(sent.iter().all(|m| !m.content.contains("b1")), "{sent:?}");
+        assert_eq!(sent.len(), 6);
+        assert!(!render_transcript(&history, 0, 0).contains("## 2."));
+    }
+
+    #[test]
+    fn pinned_turns_outlive_their_task() {
+        let mut history: Vec<ConversationContext> = ["setup", "a2", "b1"].iter().map(|p| turn(p, None)).collect();
+        history[0].pinned = true;
+        assert_eq!(prompts(&tasks(&[("b", 2)]).scope(&history, false)), ["task", "setup", "b1"]);
+        let listing = render_history(&history, &HistoryRange::Span(1, 1), &TaskLog::default(), &Theme::plain());
+        assert_eq!(listing, "[1] (pinned) User: setup\n");
+    }
+
+    #[test]
+    fn history_groups_turns_by_task() {
+        let history: Vec<ConversationContext> = ["a1", "b1", "b2"].iter().map(|p| turn(p, None)).collect();
+        let listing = render_history(&history, &HistoryRange::All, &tasks(&[("b", 1)]), &Theme::plain());
+        assert_eq!(
+            listing,
+            "── task: (untitled) ──\n[1] User: a1\n── task: b ──\n[2] User: b1\n[3] User: b2\n"
+        );
+        // A page starting mid-task still says which task it is
+        let page = render_history(&history, &HistoryRange::Span(3, 3), &tasks(&[("b", 1)]), &Theme::plain());
+        assert!(page.starts_with("── task: b ──\n[3]"), "{page}");
+    }
+
+    #[test]
+    fn reconstruct_context_stays_within_the_turns_task() {
+        let history = vec![turn("first", Some("a")), turn("second", Some("a")), turn("third", Some("b"))];
+        let log = tasks(&[("later", 1)]);
+        let context = reconstruct_context(&history, &log, 3, &redaction(), MAX_CONTEXT_TOKENS);
+        assert!(context.iter().any(|m| m.content == "second"));
+        assert!(!context.iter().any(|m| m.content == "first"));
+        // A boundary after the turn doesn't change its replay
+        let later = tasks(&[("after", 3)]);
+        assert_eq!(reconstruct_context(&history, &later, 3, &redaction(), MAX_CONTEXT_TOKENS), reconstruct_context(&history, &TaskLog::default(), 3, &redaction(), MAX_CONTEXT_TOKENS));
+    }
+
+    #[test]
+    fn parse_history_rerun_reads_turn_and_model() {
+        let rerun = parse_history_rerun("history rerun 7 --model x/y").unwrap().unwrap();
+        assert_eq!(rerun.turn, 7);
+        assert_eq!(rerun.model.as_deref(), Some("x/y"));
+        let rerun = parse_history_rerun("history rerun 2").unwrap().unwrap();
+        assert!(rerun.model.is_none());
+    }
+
+    #[test]
+    fn parse_history_rerun_rejects_bad_arguments_and_ignores_other_input() {
+        assert!(parse_history_rerun("history rerun").unwrap().is_err());
+        assert!(parse_history_rerun("history rerun 0").unwrap().is_err());
+        assert!(parse_history_rerun("history rerun 3 --model").unwrap().is_err());
+        assert!(parse_history_rerun("history").is_none());
+        assert!(parse_history_rerun("show my shell history").is_none());
+    }
+
+    #[test]
+    fn reconstruct_context_uses_only_earlier_turns() {
+        let history = vec![turn("first", Some("a")), turn("second", Some("a")), turn("third", Some("b"))];
+        let context = reconstruct_context(&history, &TaskLog::default(), 3, &redaction(), MAX_CONTEXT_TOKENS);
+        assert_eq!(context, compact_history(&history[..2], &redaction(), MAX_CONTEXT_TOKENS));
+        assert!(context.iter().any(|m| m.content == "second"));
+        assert!(!context.iter().any(|m| m.content == "third"));
+        // Deterministic: replaying twice gives the same context
+        assert_eq!(context, reconstruct_context(&history, &TaskLog::default(), 3, &redaction(), MAX_CONTEXT_TOKENS));
+        assert_eq!(reconstruct_context(&history, &TaskLog::default(), 1, &redaction(), MAX_CONTEXT_TOKENS), compact_history(&[], &redaction(), MAX_CONTEXT_TOKENS));
+    }
+
+    #[test]
+    fn conversation_context_roundtrips_through_serde() {
+        let original = turn("list files", Some("meta-llama/llama-3.3-70b-instruct"));
+        let json = serde_json::to_string(&original).unwrap();
+        let restored: ConversationContext = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.request, original.request);
+        // Older records without a request still load
+        let legacy: ConversationContext =
+            serde_json::from_str(r#"{"prompt":"ls","commands":["ls"],"outputs":[]}"#).unwrap();
+        assert!(legacy.request.is_none());
+    }
+
+    #[test]
+    fn side_by_side_aligns_shared_commands() {
+        let left: Vec<String> = ["cd src", "ls -la", "pwd"].iter().map(|s| s.to_string()).collect();
+        let right: Vec<String> = ["cd src", "ls -lah", "pwd"].iter().map(|s| s.to_string()).collect();
+        let rows = side_by_side(&left, &right);
+        assert_eq!(rows[0], (' ', "cd src", "cd src"));
+        assert_eq!(rows.last().unwrap(), &(' ', "pwd", "pwd"));
+        assert_eq!(rows.iter().filter(|r| r.0 == '≠').count(), 2);
+    }
+
+    #[test]
+    fn pad_column_truncates_and_pads_by_characters() {
+        assert_eq!(pad_column("ab", 4), "ab  ");
+        assert_eq!(pad_column("abcdef", 4), "abc…");
+        assert_eq!(pad_column("漢字", 3).chars().count(), 3);
+    }
+
+    // --- session spill ---
+
+    fn big_turn(i: usize) -> ConversationContext {
+        ConversationContext {
+            prompt: format!("prompt {i}"),
+            commands: vec![format!("cat file{i}.log")],
+            outputs: vec![format!("{i}: {}", "log line ".repeat(1000))],
+            exit_codes: vec![],
+            request: None,
+            pinned: false,
+            forgotten: false,
+        }
+    }
+
+    fn spill_in(name: &str, limits: SessionLimits) -> SessionSpill {
+        let path = env::temp_dir()
+            .join(format!("ask-spill-test-{}-{name}", std::process::id()))
+            .join("spill.jsonl");
+        SessionSpill::with_path(limits, path)
+    }
+
+    #[test]
+    fn session_spill_bounds_memory_over_a_long_session() {
+        let limits = SessionLimits { max_turns: 50, max_bytes: 200 * 1024 };
+        let mut spill = spill_in("long", limits);
+        // Every allocation on this thread is counted, spill bookkeeping included
+        let (history, peak) = peak_heap_during(|| {
+            let mut history = Vec::new();
+            for i in 0..1000 {
+                history.push(big_turn(i));
+                spill.enforce(&mut history).unwrap();
+            }
+            history
+        });
+        // Unbounded, 1000 turns of ~9 KB would hold ~9 MB. With spilling the
+        // heap holds the byte budget plus one small stub per turn.
+        assert!(peak < limits.max_bytes + 1000 * 1024, "peak {peak} bytes");
+        assert!(history.iter().rev().skip(50).all(|ctx| ctx.outputs[0].len() <= STUB_OUTPUT_PREVIEW));
+
+        // Old turns come back in full
+        assert_eq!(spill.full_turn(&history, 0).unwrap().outputs, big_turn(0).outputs);
+        assert_eq!(spill.full_turn(&history, 999).unwrap().outputs, big_turn(999).outputs);
+    }
+
+    #[test]
+    fn session_spill_does_not_change_compaction() {
+        let limits = SessionLimits { max_turns: 5, max_bytes: usize::MAX };
+        let mut spill = spill_in("compact", limits);
+        let original: Vec<ConversationContext> = (0..40).map(big_turn).collect();
+        let mut history = original.clone();
+        spill.enforce(&mut history).unwrap();
+        assert_eq!(compact_history(&history, &redaction(), MAX_CONTEXT_TOKENS), compact_history(&original, &redaction(), MAX_CONTEXT_TOKENS));
+        assert_eq!(spill.full_history(&history).unwrap().len(), 40);
+
+        // Big JSON is summarized, and an interrupted run says so, stub or not
+        let mut json_turn = big_turn(40);
+        let items: Vec<String> = (0..200).map(|i| format!(r#"{{"id":{i},"name":"pod-{i}"}}"#)).collect();
+        json_turn.outputs = vec![format!("[{}]", items.join(",")), format!("{}\n{INTERRUPTED}", "partial ".repeat(100))];
+        json_turn.commands.push("tail -f app.log".to_string());
+        let original: Vec<ConversationContext> = vec![json_turn, big_turn(41)];
+        let mut history = original.clone();
+        let mut spill = spill_in("compact-json", SessionLimits { max_turns: 1, max_bytes: usize::MAX });
+        spill.enforce(&mut history).unwrap();
+        assert!(history[0].outputs[0].len() <= STUB_OUTPUT_PREVIEW);
+        let compacted = compact_history(&history, &redaction(), MAX_CONTEXT_TOKENS);
+        assert_eq!(compacted, compact_history(&original, &redaction(), MAX_CONTEXT_TOKENS));
+        let tool = compacted.iter().find(|m| m.role == Role::Tool).unwrap();
+        assert!(tool.content.contains("JSON array of 200 items"), "{}", tool.content);
+        assert!(tool.content.contains("(interrupted)"), "{}", tool.content);
+        assert_eq!(spill.full_turn(&history, 0).unwrap().outputs, original[0].outputs);
+    }
+
+    #[test]
+    fn session_spill_file_is_private_and_never_an_existing_one() {
+        use std::os::unix::fs::PermissionsExt;
+        let limits = SessionLimits { max_turns: 1, max_bytes: usize::MAX };
+        let mut spill = SessionSpill::new(limits);
+        let mut history: Vec<ConversationContext> = (0..2).map(big_turn).collect();
+        spill.enforce(&mut history).unwrap();
+        let dir = spill.path.parent().unwrap().to_path_buf();
+        assert!(dir.file_name().unwrap().to_string_lossy().starts_with("ask-session-"));
+        assert_eq!(fs::metadata(&dir).unwrap().permissions().mode() & 0o777, 0o700);
+        assert_eq!(fs::metadata(&spill.path).unwrap().permissions().mode() & 0o777, 0o600);
+        drop(spill);
+        assert!(!dir.exists());
+
+        // A file planted at a fixed path is not written through
+        let mut planted = spill_in("planted", limits);
+        fs::create_dir_all(planted.path.parent().unwrap()).unwrap();
+        fs::set_permissions(planted.path.parent().unwrap(), fs::Permissions::from_mode(0o700)).unwrap();
+        fs::write(&planted.path, "someone else's").unwrap();
+        let mut history: Vec<ConversationContext> = (0..2).map(big_turn).collect();
+        assert_eq!(planted.enforce(&mut history).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        planted.clear();
+    }
+
+    #[test]
+    fn session_spill_clear_forgets_spilled_turns() {
+        let limits = SessionLimits { max_turns: 1, max_bytes: usize::MAX };
+        let mut spill = spill_in("clear", limits);
+        let mut history: Vec<ConversationContext> = (0..3).map(big_turn).collect();
+        spill.enforce(&mut history).unwrap();
+        spill.clear();
+        history.clear();
+        history.push(big_turn(7));
+        assert_eq!(spill.full_turn(&history, 0).unwrap().prompt, "prompt 7");
+    }
+
+    #[test]
+    fn session_spill_forget_keeps_later_turns_in_place_and_keeps_pins() {
+        let limits = SessionLimits { max_turns: 1, max_bytes: usize::MAX };
+        let mut spill = spill_in("forget", limits);
+        let mut history: Vec<ConversationContext> = (0..4).map(big_turn).collect();
+        spill.enforce(&mut history).unwrap();
+        history[1].forget();
+        spill.forget(1);
+        let full = spill.full_history(&history).unwrap();
+        let prompts: Vec<&str> = full.iter().map(|t| t.prompt.as_str()).collect();
+        assert_eq!(prompts, ["prompt 0", "prompt 1", "prompt 2", "prompt 3"]);
+        assert!(full[1].forgotten && full[1].outputs.is_empty());
+        assert_eq!(full[2].outputs, big_turn(2).outputs);
+        // Pinning a spilled turn sticks when it is read back
+        history[0].pinned = true;
+        assert!(spill.full_turn(&history, 0).unwrap().pinned);
+        spill.clear();
+    }
+
+    // --- schema versions ---
+
+    #[test]
+    fn session_records_round_trip_with_schema_version() {
+        let mut ctx = big_turn(3);
+        ctx.request = Some(TurnRequest {
+            model: "m".to_string(),
+            api_url: "u".to_string(),
+            response: vec!["ls".to_string()],
+        });
+        let record = session_record(&ctx).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&record).unwrap();
+        assert_eq!(value["schema_version"], json!(SCHEMA_VERSION));
+        let back = parse_session_record(&record).unwrap();
+        assert_eq!(back.outputs, ctx.outputs);
+        assert_eq!(back.request, ctx.request);
+        // Only pinned turns carry the flag
+        assert!(value.get("pinned").is_none());
+        ctx.pinned = true;
+        assert!(parse_session_record(&session_record(&ctx).unwrap()).unwrap().pinned);
+    }
+
+    #[test]
+    fn version_one_session_fixture_migrates_forward() {
+        let fixture = include_str!("../tests/fixtures/session-v1.jsonl");
+        let turns: Vec<ConversationContext> =
+            fixture.lines().map(|line| parse_session_record(line.as_bytes()).unwrap()).collect();
+        assert_eq!(turns.len(), 2);
+        assert_eq!(turns[0].commands, vec!["ls -la"]);
+        assert_eq!(turns[0].request, None);
+        assert_eq!(turns[1].request.as_ref().unwrap().response, vec!["du -sh ."]);
+
+        // Re-saved turns come back identical under the current version
+        for turn in &turns {
+            let again = parse_session_record(&session_record(turn).unwrap()).unwrap();
+            assert_eq!(again.prompt, turn.prompt);
+            assert_eq!(again.request, turn.request);
+        }
+    }
+
+    #[test]
+    fn future_schema_versions_are_rejected() {
+        let record = format!(r#"{{"schema_version":{},"prompt":"p","commands":[],"outputs":[]}}"#, SCHEMA_VERSION + 1);
+        let err = parse_session_record(record.as_bytes()).err().unwrap();
+        assert!(err.contains(&format!("schema version {}", SCHEMA_VERSION + 1)), "{err}");
+        assert!(err.contains(env!("CARGO_PKG_VERSION")), "{err}");
+
+        let manifest = format!("schema_version={}\nformat=1\n", SCHEMA_VERSION + 1);
+        assert!(manifest_schema_version(&manifest).is_err());
+        assert!(manifest_schema_version("schema_version=soon\n").is_err());
+    }
+
+    #[test]
+    fn version_one_manifest_fixture_is_accepted() {
+        let fixture = include_str!("../tests/fixtures/manifest-v1");
+        assert_eq!(manifest_schema_version(fixture), Ok(1));
+        let current = format!("schema_version={SCHEMA_VERSION}\nformat={BUNDLE_FORMAT}\n");
+        assert_eq!(manifest_schema_version(&current), Ok(SCHEMA_VERSION));
+    }
+
+    // --- saved sessions ---
+
+    #[test]
+    fn saved_session_round_trips_and_deletes() {
+        let dir = env::temp_dir().join(format!("ask-saved-session-{}", std::process::id()));
+        let session = SavedSession::in_dir(&dir, "work").unwrap();
+        assert_eq!(session.path, dir.join("work.json"));
+        assert!(session.load().unwrap().is_none());
+
+        let mut turns = vec![big_turn(1), big_turn(2)];
+        turns[1].request = Some(TurnRequest {
+            model: "m".to_string(),
+            api_url: "u".to_string(),
+            response: vec!["ls".to_string()],
+        });
+        let tasks = TaskLog { boundaries: vec![TaskBoundary { name: "dns".to_string(), start: 1 }] };
+        session.save(&turns, &tasks).unwrap();
+        let (back, back_tasks) = session.load().unwrap().unwrap();
+        assert_eq!(back_tasks, tasks);
+        assert_eq!(back.len(), 2);
+        assert_eq!(back[0].outputs, turns[0].outputs);
+        assert_eq!(back[1].request, turns[1].request);
+        assert!(!dir.join("work.json.partial").exists());
+
+        session.delete();
+        assert!(session.load().unwrap().is_none());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn saved_session_keeps_only_sensible_task_boundaries() {
+        let doc = format!(
+            r#"{{"schema_version":{SCHEMA_VERSION},"turns":[{{"prompt":"a","commands":[],"outputs":[]}}],
+                "tasks":[{{"name":"x","start":1}},{{"name":"back","start":0}},{{"name":"far","start":9}}]}}"#
+        );
+        let (_, tasks) = parse_session_document(doc.as_bytes()).unwrap();
+        assert_eq!(tasks.boundaries, vec![TaskBoundary { name: "x".to_string(), start: 1 }]);
+    }
+
+    #[test]
+    fn saved_session_names_stay_in_the_sessions_dir() {
+        for bad in ["", "../x", "a/b", ".hidden", "two words"] {
+            assert!(SavedSession::in_dir(Path::new("/s"), bad).is_err(), "{bad:?}");
+        }
+        assert!(SavedSession::in_dir(Path::new("/s"), "client-2.v1_a").is_ok());
+    }
+
+    #[test]
+    fn saved_session_from_another_version_is_refused() {
+        let newer = format!(r#"{{"schema_version":{},"turns":[]}}"#, SCHEMA_VERSION + 1);
+        assert!(parse_session_document(newer.as_bytes()).err().unwrap().contains("upgrade ask"));
+        assert!(parse_session_document(br#"{"turns":[]}"#).is_err());
+        assert!(parse_session_document(b"not json").is_err());
+        let current = format!(r#"{{"schema_version":{SCHEMA_VERSION},"turns":[{{"prompt":"p","commands":[],"outputs":[]}}]}}"#);
+        assert_eq!(parse_session_document(current.as_bytes()).unwrap().0[0].prompt, "p");
+    }
+
+    #[test]
+    fn unreadable_session_is_set_aside_on_resume() {
+        let dir = env::temp_dir().join(format!("ask-resume-bad-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let session = SavedSession::in_dir(&dir, LAST_SESSION).unwrap();
+        fs::write(&session.path, format!(r#"{{"schema_version":{},"turns":[]}}"#, SCHEMA_VERSION + 1)).unwrap();
+        assert!(resume_session(&session, &Theme::plain()).0.is_empty());
+        assert!(!session.path.exists());
+        assert!(dir.join("last.json.unreadable").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,22 @@
+//! ask: a command-line assistant that turns requests into shell commands.
+//!
+//! The binary in main.rs only wires [`cli::run`] to the process exit code;
+//! everything else lives here so it can be tested without a terminal, a
+//! network connection or a shell.
+
+pub mod api;
+pub mod auth;
+pub mod cli;
+pub mod commit;
+pub mod completions;
+pub mod config;
+pub mod exec;
+pub mod history;
+pub mod repl;
+pub mod safety;
+pub mod theme;
+
+#[cfg(test)]
+mod test_server;
+#[cfg(test)]
+mod test_support;
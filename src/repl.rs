@@ -0,0 +1,3236 @@
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::{Hinter, HistoryHinter};
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant, SystemTime};
+use crate::api::*;
+use crate::cli::*;
+use crate::config::*;
+use crate::exec::*;
+use crate::history::*;
+use crate::safety::*;
+use crate::theme::*;
+
+/// Returns true when every line in the burst arrived within `max_gap` of the
+/// previous one — far faster than anyone types a line and presses Enter.
+pub(crate) fn looks_like_paste(arrivals: &[Instant], max_gap: Duration) -> bool {
+    arrivals.len() > 1
+        && arrivals
+            .windows(2)
+            .all(|pair| pair[1].saturating_duration_since(pair[0]) <= max_gap)
+}
+
+/// Tab completion for the interactive prompt: built-in commands at the
+/// start of the line, otherwise files and directories relative to the
+/// current directory. Also hints the latest matching history entry.
+pub(crate) struct PromptHelper {
+    pub(crate) files: FilenameCompleter,
+    pub(crate) hints: HistoryHinter,
+    pub(crate) theme: Theme,
+}
+
+pub(crate) type PromptEditor = Editor<PromptHelper, DefaultHistory>;
+
+impl PromptHelper {
+    pub(crate) fn new(theme: &Theme) -> Self {
+        PromptHelper { files: FilenameCompleter::new(), hints: HistoryHinter::new(), theme: theme.clone() }
+    }
+}
+
+impl Completer for PromptHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let (start, mut paths) = self.files.complete_path(line, pos)?;
+        drop_hidden(&line[start..pos], &mut paths);
+        let word = line[..pos].trim_start();
+        let mut builtins = builtin_completions(word);
+        if builtins.is_empty() {
+            return Ok((start, paths));
+        }
+        let word_start = pos - word.len();
+        if start == word_start {
+            builtins.extend(paths);
+        }
+        Ok((word_start, builtins))
+    }
+}
+
+impl Hinter for PromptHelper {
+    type Hint = String;
+
+    // Without color a hint can't be told apart from typed text
+    fn hint(&self, line: &str, pos: usize, ctx: &Context<'_>) -> Option<String> {
+        if self.theme.hint_color.is_empty() {
+            return None;
+        }
+        // Pasted blocks are history entries too, but can't be shown inline
+        self.hints.hint(line, pos, ctx).filter(|hint| !hint.contains('\n'))
+    }
+}
+
+impl Highlighter for PromptHelper {
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        Cow::Owned(self.theme.hint_text(hint))
+    }
+}
+
+impl Validator for PromptHelper {}
+
+impl Helper for PromptHelper {}
+
+/// Built-in commands starting with `word`, offered only while it is the
+/// first word on the line.
+pub(crate) fn builtin_completions(word: &str) -> Vec<Pair> {
+    if word.is_empty() || word.contains(char::is_whitespace) {
+        return Vec::new();
+    }
+    let mut names: Vec<&str> = BUILTINS
+        .iter()
+        .flat_map(|builtin| builtin.names.iter().copied())
+        .filter(|name| name.starts_with(word))
+        .collect();
+    names.sort_unstable();
+    names.dedup();
+    names
+        .into_iter()
+        .map(|name| Pair { display: name.to_string(), replacement: name.to_string() })
+        .collect()
+}
+
+/// Hides dotfiles from path completions unless the typed name already
+/// starts with a dot, the way shells do.
+pub(crate) fn drop_hidden(typed: &str, paths: &mut Vec<Pair>) {
+    let name = typed.rsplit('/').next().unwrap_or("").trim_start_matches(['"', '\'']);
+    if !name.starts_with('.') {
+        paths.retain(|pair| !pair.display.starts_with('.'));
+    }
+}
+
+/// Gathers any lines that arrive right behind `first` and reports whether
+/// they look like a paste rather than typing. Each is read at `prompt`, so
+/// a line still being typed shows up the way a fresh prompt would.
+pub(crate) fn collect_paste_burst(rl: &mut PromptEditor, first: &str, prompt: &str) -> (Vec<String>, bool) {
+    let mut lines: Vec<String> = first.lines().map(str::to_string).collect();
+    let mut arrivals = vec![Instant::now()];
+    let bracketed = first.trim().contains('\n');
+    while !bracketed && stdin_has_pending_input(PASTE_LINE_GAP) {
+        match rl.readline(prompt) {
+            Ok(next) => {
+                arrivals.push(Instant::now());
+                lines.push(next);
+            }
+            Err(_) => break,
+        }
+    }
+    (lines, bracketed || looks_like_paste(&arrivals, PASTE_LINE_GAP))
+}
+
+/// Waits up to `timeout` for more bytes on stdin without consuming them.
+pub(crate) fn stdin_has_pending_input(timeout: Duration) -> bool {
+    has_pending_input(io::stdin().as_raw_fd(), timeout)
+}
+
+pub(crate) fn has_pending_input(fd: libc::c_int, timeout: Duration) -> bool {
+    let mut fds = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let timeout_ms = timeout.as_millis().min(i32::MAX as u128) as libc::c_int;
+    unsafe { libc::poll(&mut fds, 1, timeout_ms) > 0 }
+}
+
+/// Shows a pasted block and asks once what to do with the whole thing.
+/// Lines the safety policy calls dangerous are marked, and then running
+/// the block takes the full word `yes`, as a dangerous command would.
+pub(crate) fn confirm_paste(
+    lines: &[String],
+    theme: &Theme,
+    policy: &SafetyPolicy,
+    out: &mut dyn Write,
+    read: &mut dyn FnMut() -> Result<String, io::Error>,
+) -> Result<PasteChoice, io::Error> {
+    writeln!(out, "{}", theme.helper_text(&format!("Pasted {} lines:", lines.len())))?;
+    let mut dangerous = false;
+    for line in lines {
+        if policy.is_dangerous(line) {
+            dangerous = true;
+            writeln!(out, "  {} {}", theme.warning_text("danger>"), theme.warning_text(line))?;
+        } else {
+            writeln!(out, "  {}", theme.command_text(line))?;
+        }
+    }
+    loop {
+        let options = if dangerous { "[yes/N/s]" } else { "[y/N/s]" };
+        write!(
+            out,
+            "{} ",
+            theme.prompt_text(&format!("Run as one block? {options} (s = handle each line normally)"))
+        )?;
+        out.flush()?;
+
+        let input = match read() {
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {
+                writeln!(out)?;
+                return Ok(PasteChoice::Discard);
+            }
+            result => result?,
+        };
+        match normalize_confirmation_input(&input).as_str() {
+            "y" if dangerous => {
+                writeln!(out, "This block has dangerous lines. Type the full word 'yes' to run it.")?;
+            }
+            "y" | "yes" => return Ok(PasteChoice::RunBlock),
+            "" | "n" | "no" => return Ok(PasteChoice::Discard),
+            "s" | "skip" => return Ok(PasteChoice::Dispatch),
+            _ => writeln!(out, "Invalid response. Please use y(es), n(o), or s(kip guard).")?,
+        }
+    }
+}
+
+/// Current directory for the prompt - folder name, or ~ for home.
+pub(crate) fn prompt_cwd_display() -> String {
+    if let Ok(cwd) = env::current_dir() {
+        if let Ok(home) = env::var("HOME") {
+            if cwd.to_string_lossy() == home {
+                "~".to_string()
+            } else if let Some(relative) = cwd.to_string_lossy().strip_prefix(&format!("{}/", home)) {
+                format!("~/{}", relative.split('/').next_back().unwrap_or(relative))
+            } else if let Some(name) = cwd.file_name() {
+                name.to_string_lossy().to_string()
+            } else {
+                "/".to_string() // Root directory
+            }
+        } else {
+            cwd.file_name()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_else(|| "/".to_string())
+        }
+    } else {
+        "?".to_string()
+    }
+}
+
+// How long the prompt waits for `git status` before showing the branch alone
+pub(crate) const GIT_STATUS_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// The branch of the work tree around a directory, and whether it has
+/// uncommitted changes.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct GitPrompt {
+    pub(crate) branch: String,
+    pub(crate) dirty: bool,
+}
+
+/// The prompt's git part for `dir`, or None outside a work tree. The
+/// branch comes straight from HEAD; only the dirty check runs git.
+pub(crate) fn git_prompt(dir: &Path) -> Option<GitPrompt> {
+    let git_dir = find_git_dir(dir)?;
+    let branch = head_branch(&fs::read_to_string(git_dir.join("HEAD")).ok()?)?;
+    let dirty = git_dirty(dir, GIT_STATUS_TIMEOUT).unwrap_or(false);
+    Some(GitPrompt { branch, dirty })
+}
+
+/// The nearest `.git` at or above `dir`. Worktrees and submodules have a
+/// `.git` file pointing at the real one.
+pub(crate) fn find_git_dir(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors().find_map(|ancestor| {
+        let git = ancestor.join(".git");
+        if git.is_dir() {
+            return Some(git);
+        }
+        let pointer = fs::read_to_string(&git).ok()?;
+        let target = pointer.trim().strip_prefix("gitdir:")?.trim();
+        Some(ancestor.join(target))
+    })
+}
+
+/// The branch a HEAD file names, or the short hash of a detached HEAD.
+pub(crate) fn head_branch(head: &str) -> Option<String> {
+    let head = head.trim();
+    match head.strip_prefix("ref:") {
+        Some(reference) => {
+            let reference = reference.trim();
+            Some(reference.strip_prefix("refs/heads/").unwrap_or(reference).to_string())
+        }
+        None => head.get(..7).map(str::to_string),
+    }
+}
+
+/// Whether `git status` lists anything in `dir`'s work tree. None when git
+/// fails or takes longer than `timeout`, as it can in enormous repos.
+pub(crate) fn git_dirty(dir: &Path, timeout: Duration) -> Option<bool> {
+    let mut git = Command::new("git");
+    git.args(["--no-optional-locks", "status", "--porcelain"]).current_dir(dir);
+    output_within(&mut git, timeout).map(|status| !status.trim().is_empty())
+}
+
+/// git_prompt results by directory, looked up again once a command has
+/// run, so the prompt doesn't run git before every line.
+#[derive(Default)]
+pub(crate) struct GitPromptCache {
+    pub(crate) entries: HashMap<PathBuf, Option<GitPrompt>>,
+    // commands_run() when the entries were looked up
+    pub(crate) commands_run: usize,
+}
+
+impl GitPromptCache {
+    pub(crate) fn lookup(&mut self, dir: &Path) -> Option<GitPrompt> {
+        let run = commands_run();
+        if run != self.commands_run {
+            self.entries.clear();
+            self.commands_run = run;
+        }
+        self.entries.entry(dir.to_path_buf()).or_insert_with(|| git_prompt(dir)).clone()
+    }
+}
+
+/// `ask [DIR]>`, or `ask [DIR on BRANCH]>` inside a work tree with the
+/// branch in the command color and a `*` after it for uncommitted changes.
+pub(crate) fn prompt_label(dir: &str, git: Option<&GitPrompt>, theme: &Theme) -> String {
+    let Some(git) = git else {
+        return theme.prompt_text(&format!("ask [{}]>", dir));
+    };
+    let dirty = if git.dirty { "*" } else { "" };
+    format!(
+        "{}{}{}",
+        theme.prompt_text(&format!("ask [{} on ", dir)),
+        theme.command_text(&format!("{}{}", git.branch, dirty)),
+        theme.prompt_text("]>")
+    )
+}
+
+/// An interactive built-in handled without the LLM: the words that run
+/// it, how it is called, and what it does. `/help` and `ask --help` are
+/// both generated from these.
+pub(crate) struct Builtin {
+    pub(crate) names: &'static [&'static str],
+    pub(crate) usage: &'static str,
+    pub(crate) about: &'static str,
+}
+
+pub(crate) const BUILTINS: &[Builtin] = &[
+    Builtin {
+        names: &["help", "/help", "/commands"],
+        usage: "/help",
+        about: "List built-in commands, shortcuts and confirmation keys",
+    },
+    Builtin { names: &["exit", "quit"], usage: "exit / quit", about: "Exit interactive mode" },
+    Builtin { names: &["clear"], usage: "clear", about: "Clear screen and reset conversation context" },
+    Builtin { names: &["finder"], usage: "finder [PATH]", about: "Reveal PATH (default: current directory) in Finder" },
+    Builtin { names: &[], usage: "finder last", about: "Reveal the last existing path seen in command output" },
+    Builtin {
+        names: &["/model"],
+        usage: "/model [--save] [MODEL]",
+        about: "Show the current model, or switch (and optionally save) it",
+    },
+    Builtin { names: &["/models"], usage: "/models [QUERY]", about: "Pick a model from the provider's list and switch to it" },
+    Builtin {
+        names: &["/theme"],
+        usage: "/theme [--save] [NAME]",
+        about: "Show the themes, or switch (and optionally save) to NAME",
+    },
+    Builtin { names: &["/profile"], usage: "/profile [NAME]", about: "Show the profiles, or switch to profile NAME" },
+    Builtin { names: &["/usage"], usage: "/usage", about: "Show the tokens and cost of this session's requests" },
+    Builtin { names: &["/context"], usage: "/context", about: "Show exactly what the next prompt sends the model as context" },
+    Builtin { names: &["recent"], usage: "recent / cd!", about: "Switch to a recent directory ('cd =N' picks entry N)" },
+    Builtin {
+        names: &["history", "/history"],
+        usage: "history [N|all|A..B]",
+        about: "List recent turns with how each command ended (the last 20 by default)",
+    },
+    Builtin { names: &[], usage: "history full N", about: "Show turn N with all of its output" },
+    Builtin { names: &["/forget"], usage: "/forget N|last", about: "Drop turn N from the session and the model's context" },
+    Builtin {
+        names: &["/pin", "/unpin"],
+        usage: "/pin N|last",
+        about: "Keep turn N in the model's context however old it gets (/unpin undoes it)",
+    },
+    Builtin {
+        names: &[],
+        usage: "history rerun N [--model MODEL]",
+        about: "Resend turn N (with the context it had) and compare answers",
+    },
+    Builtin { names: &["task"], usage: "task [NAME]", about: "Start a task named NAME; prompts only see its turns (no NAME lists tasks)" },
+    Builtin { names: &[], usage: "task previous", about: "Let the next prompt also see the task before this one" },
+    Builtin { names: &[], usage: "task merge", about: "Undo the latest task boundary, joining it to the task before" },
+    Builtin { names: &["json"], usage: "json", about: "Show the last JSON output, formatted" },
+    Builtin {
+        names: &[],
+        usage: "json path .items[0].name",
+        about: "Print one value from the last JSON output",
+    },
+    Builtin {
+        names: &["/save", "/save!"],
+        usage: "/save [PATH]",
+        about: "Write the session to a markdown file (default ~/.ask/sessions/); /save! overwrites",
+    },
+    Builtin { names: &["note"], usage: "note TEXT", about: "Append a line to today's notes" },
+    Builtin { names: &["notes"], usage: "notes [today|yesterday|week|DATE]", about: "Show notes (DATE is YYYY-MM-DD)" },
+];
+
+pub(crate) const SHORTCUTS: &[Builtin] = &[
+    Builtin { names: &["q"], usage: "q", about: "Exit interactive mode" },
+    Builtin { names: &["."], usage: ".", about: "Show the current directory (pwd)" },
+    Builtin { names: &[".."], usage: "..", about: "Go up one directory (cd ..)" },
+    Builtin { names: &[], usage: "!COMMAND", about: "Run COMMAND as typed: no safety check, no model" },
+    Builtin { names: &[], usage: "!!", about: "Run the most recent command again" },
+    Builtin { names: &[], usage: "?QUESTION", about: "Ask the model, even if the line starts like a command" },
+];
+
+/// The answers to the run-this-command prompt.
+pub(crate) const CONFIRM_KEYS: &[(&str, &str)] = &[
+    ("Y/yes (or Enter)", "Execute the command"),
+    ("n/no", "Cancel execution and exit (in interactive mode, returns to prompt)"),
+    ("s/skip", "Skip this command and continue to the next"),
+    ("a/all", "Execute this command and the rest of the batch without asking (dangerous ones still ask)"),
+    ("i/instruct", "Execute a custom command first, then return to the original"),
+    ("e/edit", "Edit the suggested command before running it"),
+];
+
+/// What a `!` line runs: the rest of the line verbatim, or for `!!` the
+/// most recent command in the session. `None` for other input.
+pub(crate) fn raw_command<'a>(input: &'a str, history: &'a [ConversationContext]) -> Option<Result<&'a str, &'static str>> {
+    let rest = input.strip_prefix('!')?;
+    if rest.trim() == "!" {
+        let last = history.iter().rev().find_map(|turn| turn.commands.last());
+        return Some(last.map(String::as_str).ok_or("No command has run yet"));
+    }
+    match rest.trim() {
+        "" => Some(Err("Usage: !COMMAND, or !! to run the last command again")),
+        command => Some(Ok(command)),
+    }
+}
+
+pub(crate) fn builtin_names() -> impl Iterator<Item = &'static str> {
+    BUILTINS.iter().chain(SHORTCUTS).flat_map(|builtin| builtin.names.iter().copied())
+}
+
+pub(crate) fn is_builtin(input: &str) -> bool {
+    builtin_names().any(|name| name == input)
+}
+
+/// A `/word` that isn't a built-in or a path, so it is a typo rather than
+/// something for the model or the shell.
+pub(crate) fn is_unknown_slash_command(input: &str) -> bool {
+    let word = input.split_whitespace().next().unwrap_or("");
+    word.len() > 1 && word.starts_with('/') && !word[1..].contains('/') && !is_builtin(word) && !Path::new(word).exists()
+}
+
+/// Aligned `usage  description` rows; a usage too long for the column
+/// gets a line of its own.
+pub(crate) fn help_table<'a>(rows: impl IntoIterator<Item = (&'a str, &'a str)>, usage: impl Fn(&str) -> String) -> String {
+    let mut table = String::new();
+    for (name, about) in rows {
+        if name.len() > 16 {
+            table.push_str(&format!("  {}\n{:20}{}\n", usage(name), "", about));
+        } else {
+            table.push_str(&format!("  {}  {}\n", usage(&format!("{name:<16}")), about));
+        }
+    }
+    table
+}
+
+pub(crate) fn builtin_rows(builtins: &[Builtin]) -> impl Iterator<Item = (&'static str, &'static str)> + '_ {
+    builtins.iter().map(|builtin| (builtin.usage, builtin.about))
+}
+
+/// `/help` inside the interactive session.
+pub(crate) fn print_interactive_help(theme: &Theme) {
+    let usage = |text: &str| theme.command_text(text);
+    println!("{}", theme.prompt_text("Built-in commands:"));
+    print!("{}", help_table(builtin_rows(BUILTINS), usage));
+    println!("{}", theme.prompt_text("Shortcuts:"));
+    print!("{}", help_table(builtin_rows(SHORTCUTS), usage));
+    println!("{}", theme.prompt_text("Confirmation keys:"));
+    print!("{}", help_table(CONFIRM_KEYS.iter().copied(), usage));
+    println!("{}", theme.helper_text("Anything else is run directly if it is a common command, or sent to the model."));
+    println!("{}", theme.helper_text("A line starting with ! skips both: it runs as typed, without a safety check."));
+    println!("{}", theme.helper_text("Tab completes built-in commands and file names; Right-arrow accepts a history hint."));
+}
+
+/// Suggests the built-in a single mistyped word was probably meant to be.
+/// Multi-word input is natural language and never triggers a suggestion.
+pub(crate) fn suggest_builtin(input: &str) -> Option<&'static str> {
+    let word = input.trim();
+    if word.is_empty()
+        || word.len() > 12
+        || word.contains(char::is_whitespace)
+        || !word.chars().all(|c| c.is_ascii_alphabetic())
+        || is_builtin(word)
+    {
+        return None;
+    }
+    // Short words get less slack, otherwise almost anything matches
+    // ("hello" is not a typo of "help")
+    let max_distance = |name: &str| if word.len().min(name.len()) <= 4 { 1 } else { 2 };
+    let word = word.to_lowercase();
+
+    builtin_names()
+        .filter(|name| name.len() >= 4) // q, ., .. are too short to guess at
+        .map(|name| (name, edit_distance(&word, name)))
+        .filter(|(name, distance)| *distance <= max_distance(name))
+        .min_by_key(|(_, distance)| *distance)
+        .map(|(name, _)| name)
+}
+
+/// Optimal string alignment distance: Levenshtein plus adjacent transpositions,
+/// so `claer` is one edit away from `clear`.
+pub(crate) fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+/// How an interactive session is set up, from settings and flags.
+pub(crate) struct InteractiveOptions {
+    pub(crate) paste_guard: bool,
+    pub(crate) session_limits: SessionLimits,
+    pub(crate) transcript_lines: usize,
+    // Where the session is saved for --resume
+    pub(crate) session: SavedSession,
+    // Start from what `session` holds
+    pub(crate) resume: bool,
+    // Loaded again when a cd moves into another project
+    pub(crate) config: Settings,
+    // Colors allowed at all; /theme only changes the palette when they are
+    pub(crate) colors: bool,
+}
+
+/// Notices edits to the session's prompt_file between prompts: a new
+/// modification time reloads it, and while the file is missing (or no
+/// longer parses) the built-in prompt is used, with a warning.
+pub(crate) struct PromptFileWatch {
+    pub(crate) path: Option<PathBuf>,
+    pub(crate) modified: Option<SystemTime>,
+}
+
+impl PromptFileWatch {
+    pub(crate) fn new(path: Option<PathBuf>) -> Self {
+        let modified = path.as_deref().and_then(modified_time);
+        Self { path, modified }
+    }
+
+    /// What to switch to when the file at `path` changed since the last
+    /// look: the reloaded prompt, or why the built-in one is used instead.
+    /// A different `path` (after /profile or a cd) is watched from now on;
+    /// the settings were already loaded with it.
+    pub(crate) fn poll(&mut self, path: Option<PathBuf>) -> Option<Result<CustomPrompt, String>> {
+        if path != self.path {
+            *self = Self::new(path);
+            return None;
+        }
+        let path = self.path.as_deref()?;
+        let modified = modified_time(path);
+        if modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+        Some(match modified {
+            None => Err(format!("prompt_file {} is gone; using the built-in prompt until it is back", path.display())),
+            Some(_) => CustomPrompt::load(path).map_err(|err| format!("{err}; using the built-in prompt")),
+        })
+    }
+}
+
+pub(crate) fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+pub(crate) fn run_interactive_mode(
+    settings: &RequestSettings,
+    theme: &Theme,
+    options: InteractiveOptions,
+    policy: &SafetyPolicy,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let InteractiveOptions { paste_guard, session_limits, transcript_lines, session, resume, mut config, colors } = options;
+    let session = &session;
+    // /model and /profile change this copy only, so the switch lasts for
+    // the session (or until a cd into another project)
+    let mut settings = settings.clone();
+    let mut policy = policy.clone();
+    // /theme swaps this for everything printed after it
+    let mut theme = theme.clone();
+    let mut project = config.project.clone();
+    let mut prompt_watch = PromptFileWatch::new(config.prompt_file());
+    print_interactive_banner(&settings.model, config.profile(), &policy, &theme);
+    report_last_hangup(&theme);
+    catch_hangup();
+
+    let rl_config = rustyline::Config::builder()
+        .bracketed_paste(true)
+        .build();
+    let mut rl = PromptEditor::with_config(rl_config)?;
+    rl.set_helper(Some(PromptHelper::new(&theme)));
+    // Missing on first run
+    let history_file = data_dir().join("history");
+    let _ = rl.load_history(&history_file);
+    let (mut history, mut tasks) = if resume { resume_session(session, &theme) } else { (Vec::new(), TaskLog::default()) };
+    // Set by `task previous` for the next prompt only
+    let mut widen = false;
+    let mut saved = (history.len(), Instant::now());
+    // Lines from a paste the user chose to handle one at a time
+    let mut pending: VecDeque<String> = VecDeque::new();
+    // Lines typed ahead behind another; already shown at the prompt
+    let mut typed_ahead: VecDeque<String> = VecDeque::new();
+    let mut boundary = FeatureBoundary::new();
+    let mut spill = SessionSpill::new(session_limits);
+    let mut recent = RecentDirs::load();
+    // prompt_git=off can come from a project, so it is read again on a cd
+    let mut prompt_git = config.prompt_git();
+    let mut git_cache = GitPromptCache::default();
+    if let Some(turn) = offer_earlier_notes(&theme)? {
+        history.push(turn);
+    }
+
+    // The last command output that was JSON, for the `json` built-in, and
+    // how many turns have been looked at for it
+    let mut last_json: Option<serde_json::Value> = None;
+    let mut json_checked = history.len();
+
+    loop {
+        if hung_up() {
+            break;
+        }
+        if let Some((value, bytes)) = history.get(json_checked..).and_then(newest_json_output) {
+            if bytes > JSON_SUMMARY_BYTES {
+                eprintln!("(JSON output, {bytes} bytes: `json` shows it formatted, `json path .key` pulls out a value)");
+            }
+            last_json = Some(value);
+        }
+        if history.len() != saved.0 && saved.1.elapsed() >= SESSION_SAVE_INTERVAL {
+            if let Err(err) = persist_session(session, &history, &tasks, &spill) {
+                eprintln!("Warning: could not save the session: {err}");
+            }
+            saved = (history.len(), Instant::now());
+        }
+        // Keep long sessions from growing without bound
+        if let Err(err) = spill.enforce(&mut history) {
+            eprintln!("Warning: could not spill old turns to disk: {err}");
+        }
+        json_checked = history.len();
+        // A cd into another project brings in its settings
+        if let Ok(cwd) = env::current_dir()
+            && find_project_config(&cwd) != project
+        {
+            let loaded = config.for_dir(&cwd);
+            report_config_files(&loaded, &theme);
+            project = loaded.project.clone();
+            prompt_git = loaded.prompt_git();
+            let model = settings.model.clone();
+            settings = request_settings(&loaded, settings.endpoint.clone(), settings.platform.clone());
+            match safety_policy(&loaded, policy.auto_yes, policy.print_only) {
+                Ok(loaded) => policy = loaded,
+                Err(err) => eprintln!("Warning: {err}"),
+            }
+            if settings.model != model {
+                println!("{}", theme.helper_text(&format!("Model: {}", settings.model)));
+            }
+        }
+        match prompt_watch.poll(config.prompt_file()) {
+            Some(Ok(prompt)) => {
+                settings.custom_prompt = Some(prompt);
+                println!("{}", theme.helper_text("Reloaded prompt_file"));
+            }
+            Some(Err(err)) => {
+                settings.custom_prompt = None;
+                eprintln!("{}", theme.warning_text(&format!("Warning: {err}")));
+            }
+            None => {}
+        }
+
+        let cwd_display = boundary
+            .run("prompt", prompt_cwd_display)
+            .unwrap_or_else(|| "?".to_string());
+
+        let git = match env::current_dir() {
+            Ok(cwd) if prompt_git => boundary.run("git prompt", || git_cache.lookup(&cwd)).flatten(),
+            _ => None,
+        };
+        let prompt = format!("{} ", prompt_label(&cwd_display, git.as_ref(), &theme));
+        let input = if let Some(line) = typed_ahead.pop_front() {
+            line
+        } else if let Some(line) = pending.pop_front() {
+            println!("{}{}", prompt, line);
+            line
+        } else {
+            let line = match rl.readline(&prompt) {
+                Ok(line) => line,
+                // The terminal is gone; nothing can be printed any more
+                Err(_) if hung_up() => break,
+                Err(ReadlineError::Interrupted) => {
+                    // Ctrl-C: cancel current line, continue loop
+                    println!("^C");
+                    continue;
+                }
+                Err(ReadlineError::Eof) => {
+                    // Ctrl-D: exit
+                    println!("Goodbye!");
+                    break;
+                }
+                Err(err) => {
+                    return Err(err.into());
+                }
+            };
+
+            if paste_guard {
+                // Bracketed paste delivers the whole block in one line; without it,
+                // the remaining lines are already waiting on stdin.
+                let (mut lines, pasted) = boundary
+                    .run("paste guard", || collect_paste_burst(&mut rl, &line, &prompt))
+                    .unwrap_or_else(|| (line.lines().map(str::to_string).collect(), true));
+                boundary.report(&history, &spill, &theme);
+                lines.retain(|l| !l.trim().is_empty());
+
+                if pasted && lines.len() > 1 {
+                    match confirm_paste(&lines, &theme, &policy, &mut io::stdout(), &mut read_confirmation_line)? {
+                        PasteChoice::RunBlock if policy.print_only => {
+                            for line in &lines {
+                                println!("{line}");
+                            }
+                        }
+                        PasteChoice::RunBlock => {
+                            let block = lines.join("\n");
+                            let _ = rl.add_history_entry(block.as_str());
+                            let _ = rl.append_history(&history_file);
+                            match run_command_with_output(&block) {
+                                Ok(output) => history.push(direct_turn(&block, &block, &output)),
+                                Err(e) => {
+                                    if let Some(hangup) = e.downcast_ref::<HungUp>() {
+                                        history.push(hangup.turn(&block));
+                                        break;
+                                    }
+                                    eprintln!("Command failed: {}", e);
+                                }
+                            }
+                        }
+                        PasteChoice::Dispatch => pending.extend(lines),
+                        PasteChoice::Discard => println!("Paste discarded"),
+                    }
+                    continue;
+                }
+                // Typed, not pasted: every line is a prompt of its own
+                let mut lines = lines.into_iter();
+                let first = lines.next().unwrap_or_default();
+                typed_ahead.extend(lines);
+                first
+            } else {
+                line
+            }
+        };
+        let input = input.trim();
+
+        if input.is_empty() {
+            continue;
+        }
+
+        // Add to readline history for arrow-key navigation and hints
+        let _ = rl.add_history_entry(input);
+        let _ = rl.append_history(&history_file);
+
+        // `!` runs the line as typed, bypassing the safety check and the model
+        if let Some(command) = raw_command(input, &history) {
+            let command = match command {
+                Ok(command) => command.to_string(),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    continue;
+                }
+            };
+            println!("{} {}", theme.prompt_text("run>"), theme.command_text(&command));
+            match run_command_with_output(&command) {
+                Ok(output) => history.push(direct_turn(input, &command, &output)),
+                Err(e) => {
+                    if let Some(hangup) = e.downcast_ref::<HungUp>() {
+                        history.push(hangup.turn(input));
+                        break;
+                    }
+                    eprintln!("Command failed: {}", e);
+                }
+            }
+            for note in run_analyzers(ANALYZERS, &history, &settings, &mut boundary) {
+                println!("{}", theme.helper_text(&note));
+            }
+            boundary.report(&history, &spill, &theme);
+            continue;
+        }
+
+        // A typo'd built-in shouldn't cost an API round trip
+        let corrected;
+        let input = match suggest_builtin(input) {
+            Some(suggestion) if !is_safe_direct_command(input) => {
+                print!("{} ", theme.prompt_text(&format!("did you mean '{}'? [y/N]", suggestion)));
+                io::stdout().flush()?;
+                let answer = read_confirmation_line()?;
+                if matches!(normalize_confirmation_input(&answer).as_str(), "y" | "yes") {
+                    corrected = suggestion.to_string();
+                    corrected.as_str()
+                } else {
+                    input
+                }
+            }
+            _ => input,
+        };
+
+        // Shortcuts for common commands
+        if input == "q" || input == "exit" || input == "quit" {
+            println!("Goodbye!");
+            break;
+        }
+
+        if matches!(input, "help" | "/help" | "/commands") {
+            print_interactive_help(&theme);
+            continue;
+        }
+
+        if input == "." {
+            // Shortcut for pwd
+            let cwd = env::current_dir()
+                .map(|p| p.display().to_string())
+                .unwrap_or_else(|_| "unknown".to_string());
+            println!("{} {}", theme.prompt_text("run>"), theme.command_text("pwd"));
+            println!("{}", cwd);
+
+            // Add to history
+            history.push(ConversationContext {
+                prompt: "pwd".to_string(),
+                commands: vec!["pwd".to_string()],
+                outputs: vec![cwd],
+                exit_codes: vec![],
+                request: None,
+                pinned: false,
+                forgotten: false,
+            });
+            continue;
+        }
+
+        if input == ".." {
+            // Shortcut for cd ..
+            println!("{} {}", theme.prompt_text("run>"), theme.command_text("cd .."));
+            change_directory(Path::new(".."), "cd ..", &mut history, &mut recent, &theme);
+            continue;
+        }
+
+        if let Some(command) = parse_recent(input) {
+            let current = env::current_dir().unwrap_or_default();
+            let choices = recent.choices(&current);
+            let pick = match command {
+                Ok(_) if choices.is_empty() => {
+                    println!("{}", theme.helper_text("No recent directories yet; they are remembered as you cd around."));
+                    continue;
+                }
+                Ok(RecentCommand::Pick(n)) => n,
+                Ok(RecentCommand::Menu) => {
+                    for (i, dir) in choices.iter().enumerate() {
+                        println!("  {} {}", theme.prompt_text(&format!("{}", i + 1)), theme.command_text(&home_relative(dir)));
+                    }
+                    print!("{} ", theme.prompt_text(&format!("cd to [1-{}, other key cancels]:", choices.len())));
+                    io::stdout().flush()?;
+                    let key = read_key().unwrap_or_default();
+                    println!("{}", key.trim());
+                    match key.trim().parse::<usize>() {
+                        Ok(n) => n,
+                        Err(_) => continue,
+                    }
+                }
+                Err(err) => {
+                    eprintln!("{}", err);
+                    continue;
+                }
+            };
+            match choices.get(pick.wrapping_sub(1)) {
+                Some(dir) => {
+                    let cd = format!("cd {}", dir.display());
+                    println!("{} {}", theme.prompt_text("run>"), theme.command_text(&cd));
+                    change_directory(dir, &cd, &mut history, &mut recent, &theme);
+                }
+                None => eprintln!("No recent directory {} (there are {})", pick, choices.len()),
+            }
+            continue;
+        }
+
+        if let Some(command) = parse_notes_command(input, unix_now()) {
+            let notes = Notes::open();
+            match command {
+                Ok(NotesCommand::Add(text)) => match notes.append(&text, unix_now()) {
+                    Ok(()) => println!("{}", theme.helper_text(&format!("Noted: {text}"))),
+                    Err(err) => eprintln!("Could not save note: {err}"),
+                },
+                Ok(NotesCommand::Show(dates)) => {
+                    let mut shown = 0;
+                    for date in &dates {
+                        let lines = notes.day(date);
+                        if lines.is_empty() {
+                            continue;
+                        }
+                        println!("{}", theme.prompt_text(date));
+                        for line in &lines {
+                            println!("{line}");
+                        }
+                        shown += lines.len();
+                    }
+                    if shown == 0 {
+                        println!("{}", theme.helper_text("No notes for that day; add one with 'note TEXT'."));
+                    }
+                }
+                Err(err) => eprintln!("{}", err),
+            }
+            continue;
+        }
+
+        if input == "clear" {
+            // Clear the screen and reset context
+            Command::new("clear").status()?;
+            history.clear();
+            tasks = TaskLog::default();
+            spill.clear();
+            session.delete();
+            saved = (0, Instant::now());
+            print_interactive_banner(&settings.model, config.profile(), &policy, &theme);
+            continue;
+        }
+
+        if let Some(command) = parse_model_command(input) {
+            match command {
+                Ok(ModelCommand::Show) => {
+                    println!("{}", theme.helper_text(&format!("Model: {}", settings.model)));
+                }
+                Ok(ModelCommand::Switch { model, save }) => {
+                    // The endpoint was picked at startup for one provider
+                    let provider = settings.endpoint.provider;
+                    if config.provider_for(&model) != provider {
+                        eprintln!("{model} runs on another provider; start a new session with: ask --model {model}");
+                        continue;
+                    }
+                    settings.set_model(model);
+                    println!("{}", theme.helper_text(&format!("Model: {}", settings.model)));
+                    if save {
+                        let mut config = Config::load();
+                        config.model = Some(settings.model.clone());
+                        match config.save() {
+                            Ok(()) => println!("{}", theme.helper_text("Saved as the default model")),
+                            Err(err) => eprintln!("Warning: could not save model: {err}"),
+                        }
+                    }
+                }
+                Err(err) => eprintln!("{}", err),
+            }
+            continue;
+        }
+
+        if let Some(command) = parse_theme_command(input) {
+            match command {
+                Ok(ThemeCommand::Show) => {
+                    println!("{}", theme.helper_text(&format!("Themes: {}", THEME_NAMES.join(", "))));
+                }
+                Ok(ThemeCommand::Switch { mode, save }) => {
+                    if colors {
+                        theme = Theme::from_mode(mode).with_colors(&config.theme_colors());
+                        if let Some(helper) = rl.helper_mut() {
+                            helper.theme = theme.clone();
+                        }
+                    }
+                    println!("{}", theme.helper_text(&format!("Theme: {}", mode.as_str())));
+                    if !colors {
+                        println!("Colors are off (NO_COLOR, --no-color or not a terminal), so nothing changes on screen");
+                    }
+                    if save {
+                        let mut config = Config::load();
+                        config.theme = Some(mode);
+                        match config.save() {
+                            Ok(()) => println!("{}", theme.helper_text("Saved as the default theme")),
+                            Err(err) => eprintln!("Warning: could not save theme: {err}"),
+                        }
+                    }
+                }
+                Err(err) => eprintln!("{}", err),
+            }
+            continue;
+        }
+
+        if let Some(name) = parse_profile_command(input) {
+            let Some(name) = name else {
+                let active = config.profile().map_or("none".to_string(), str::to_string);
+                println!("{}", theme.helper_text(&format!("Profile: {active}")));
+                match config.profiles().as_slice() {
+                    [] => println!("{}", theme.helper_text("No profiles. Add a [profile.NAME] table to ~/.ask/config.toml")),
+                    names => println!("{}", theme.helper_text(&format!("Profiles: {}", names.join(", ")))),
+                }
+                continue;
+            };
+            let loaded = match config.with_profile(name, &env::current_dir().unwrap_or_default()) {
+                Ok(loaded) => loaded,
+                Err(err) => {
+                    eprintln!("{err}");
+                    continue;
+                }
+            };
+            // The endpoint was picked at startup for one provider
+            if loaded.provider() != settings.endpoint.provider || loaded.api_url() != settings.endpoint.url {
+                eprintln!("Profile {name} uses another provider or endpoint; start a new session with: ask --profile {name}");
+                continue;
+            }
+            settings = request_settings(&loaded, settings.endpoint.clone(), settings.platform.clone());
+            match safety_policy(&loaded, policy.auto_yes, policy.print_only) {
+                Ok(loaded) => policy = loaded,
+                Err(err) => eprintln!("Warning: {err}"),
+            }
+            config = loaded;
+            println!("{}", theme.helper_text(&format!("Profile: {name} (model: {})", settings.model)));
+            continue;
+        }
+
+        if input == "/usage" {
+            println!("{}", theme.helper_text(&session_usage().summary()));
+            if let Some(line) = MODEL_REQUESTS.lock().ok().and_then(|models| requests_by_model(&models)) {
+                println!("{}", theme.helper_text(&line));
+            }
+            continue;
+        }
+
+        if input == "/context" {
+            // Peeks at `widen` rather than taking it; the next prompt still gets it
+            page_output(&render_context(&settings, &tasks.scope(&history, widen), None, None, &theme));
+            continue;
+        }
+
+        if let Some(query) = parse_models_command(input) {
+            let picked = find_models(&settings, query).and_then(|models| Ok(pick_model(&models, &theme)?.map(|m| m.id.clone())));
+            match picked {
+                Ok(Some(model)) => {
+                    settings.set_model(model);
+                    println!("{}", theme.helper_text(&format!("Model: {} (/model --save {} keeps it)", settings.model, settings.model)));
+                }
+                Ok(None) => {}
+                Err(err) => eprintln!("{}", err),
+            }
+            continue;
+        }
+
+        if let Some(arg) = parse_finder(input) {
+            // Reveal in Finder (or open the containing folder on Linux)
+            let target = match arg {
+                None => env::current_dir().map_err(|e| e.to_string()),
+                Some("last") => last_output_path(&history)
+                    .ok_or_else(|| "No existing path found in recent command output".to_string()),
+                Some(arg) => resolve_finder_path(arg),
+            };
+            match target {
+                Ok(path) => match reveal_command(env::consts::OS, &path, path.is_dir()).status() {
+                    Ok(_) => println!("{}", theme.helper_text(&format!("Revealed {}", path.display()))),
+                    Err(e) => eprintln!("Failed to open Finder: {}", e),
+                },
+                Err(err) => eprintln!("{}", err),
+            }
+            continue;
+        }
+
+        if let Some(command) = parse_save_command(input) {
+            let result: Result<PathBuf, Box<dyn std::error::Error>> = command.map_err(|e| e.into()).and_then(|save| {
+                let full = spill.full_history(&history).unwrap_or_else(|_| history.clone());
+                let path = save.path.unwrap_or_else(|| default_transcript_path(unix_now()));
+                write_transcript(&path, &render_transcript(&full, transcript_lines, unix_now()), save.force)?;
+                Ok(path)
+            });
+            match result {
+                Ok(path) => println!("{}", theme.helper_text(&format!("Saved {} turns to {}", history.len(), path.display()))),
+                Err(err) => eprintln!("Could not save the session: {}", err),
+            }
+            continue;
+        }
+
+        if let Some(command) = parse_json_command(input) {
+            match (command, last_json.clone()) {
+                (Err(err), _) => eprintln!("{}", err),
+                (Ok(_), None) => println!("{}", theme.helper_text("No JSON output yet; run a command that prints JSON first.")),
+                (Ok(JsonCommand::Show), Some(value)) => page_output(&render_json(&value, &theme)),
+                (Ok(JsonCommand::Path(steps)), Some(value)) => match query_json(&value, &steps) {
+                    // Strings print bare, so they can be copied as they are
+                    Ok(serde_json::Value::String(s)) => println!("{s}"),
+                    Ok(found) => page_output(&render_json(found, &theme)),
+                    Err(err) => eprintln!("{}", err),
+                },
+            }
+            continue;
+        }
+
+        if let Some(command) = parse_task_command(input) {
+            match command {
+                TaskCommand::List => print!("{}", tasks.render(history.len())),
+                TaskCommand::Start(name) => {
+                    tasks.start(&name, history.len());
+                    let note = format!("Task: {name}. Prompts now see only its turns; 'task merge' undoes this.");
+                    println!("{}", theme.helper_text(&note));
+                }
+                TaskCommand::Previous => match tasks.spans(history.len()).iter().rev().nth(1) {
+                    Some((name, _, _)) => {
+                        widen = true;
+                        println!("{}", theme.helper_text(&format!("The next prompt also sees task '{name}'.")));
+                    }
+                    None => println!("{}", theme.helper_text("There is no earlier task to include.")),
+                },
+                TaskCommand::Merge => match tasks.merge() {
+                    Some(merged) => {
+                        let into = tasks.spans(history.len()).last().map_or(UNTITLED_TASK, |s| s.0).to_string();
+                        println!("{}", theme.helper_text(&format!("Merged task '{}' into '{into}'.", merged.name)));
+                    }
+                    None => println!("{}", theme.helper_text("No task boundary to merge.")),
+                },
+            }
+            continue;
+        }
+
+        if let Some(range) = parse_history_listing(input) {
+            match range {
+                // Spilled turns only keep a preview in memory
+                Ok(HistoryRange::Full(n)) if n <= history.len() => match spill.full_turn(&history, n - 1) {
+                    Ok(turn) => page_output(&format_turn(n, &turn, None, &theme)),
+                    Err(err) => eprintln!("Could not read turn {n} back: {err}"),
+                },
+                Ok(range) => page_output(&render_history(&history, &range, &tasks, &theme)),
+                Err(err) => eprintln!("{}", err),
+            }
+            continue;
+        }
+
+        if let Some(action) = parse_turn_action(input, history.len()) {
+            match action {
+                Ok((_, index)) if history[index].forgotten => eprintln!("Turn {} was forgotten", index + 1),
+                Ok((TurnAction::Forget, index)) => {
+                    history[index].forget();
+                    spill.forget(index);
+                    let note = format!("Forgot turn {}: {}", index + 1, truncate_at_char_boundary(&history[index].prompt, 60));
+                    println!("{}", theme.helper_text(&note));
+                }
+                Ok((action, index)) => {
+                    history[index].pinned = action == TurnAction::Pin;
+                    let verb = if action == TurnAction::Pin { "Pinned" } else { "Unpinned" };
+                    let note = format!("{verb} turn {}: {}", index + 1, truncate_at_char_boundary(&history[index].prompt, 60));
+                    println!("{}", theme.helper_text(&note));
+                }
+                Err(err) => eprintln!("{}", err),
+            }
+            continue;
+        }
+
+        if let Some(rerun) = parse_history_rerun(input) {
+            let result = rerun
+                .map_err(|e| e.into())
+                .and_then(|rerun| rerun_turn(&mut history, &tasks, &rerun, &settings, &theme, &policy));
+            if let Err(err) = result {
+                eprintln!("Error: {}", err);
+            }
+            println!();
+            continue;
+        }
+
+        if is_unknown_slash_command(input) {
+            eprintln!("Unknown command '{}', try /help", input.split_whitespace().next().unwrap_or(input));
+            continue;
+        }
+
+        // `?` sends the line to the model even if it reads like a command
+        let forced_question = input.starts_with('?');
+        if input == "?" {
+            eprintln!("Usage: ?QUESTION, to ask the model without running anything directly");
+            continue;
+        }
+
+        // Check if it's a safe direct command
+        if verbosity() >= 2 && !forced_question {
+            match direct_command_verdict(input) {
+                Ok(reason) => debug_log(2, &format!("safety: '{input}' runs directly ({reason})")),
+                Err(reason) => debug_log(2, &format!("safety: '{input}' goes to the model ({reason})")),
+            }
+        }
+        if !forced_question && is_safe_direct_command(input) {
+            // Determine the actual command to run
+            let command_to_run = if input.trim() == "ls" {
+                // Special handling for plain 'ls' - convert to 'ls -l' for better info
+                "ls -l".to_string()
+            } else if is_script_execution(input) {
+                script_command(input)
+            } else {
+                input.to_string()
+            };
+
+            // cd only moves us around, so it never needs asking
+            let is_cd = input.trim().starts_with("cd");
+            let command_to_run = if policy.auto_safe || is_cd {
+                println!("{} {}", theme.prompt_text("run>"), theme.command_text(&command_to_run));
+                command_to_run
+            } else {
+                match confirm(&command_to_run, &theme, &policy)? {
+                    ConfirmResponse::Yes | ConfirmResponse::All => command_to_run,
+                    ConfirmResponse::Edit(edited) => edited,
+                    ConfirmResponse::Copy => continue,
+                    _ => {
+                        println!("Skipping command: {}", theme.command_text(&command_to_run));
+                        continue;
+                    }
+                }
+            };
+
+            // Special handling for cd command
+            if is_cd {
+                let arg = input.trim().strip_prefix("cd").unwrap_or_default();
+                match cd_target(arg, recent.previous.as_deref()) {
+                    Ok(path) => change_directory(&path, input, &mut history, &mut recent, &theme),
+                    Err(err) => eprintln!("{}", err),
+                }
+            } else {
+                // Execute other safe commands (including scripts)
+                match run_command_with_output(&command_to_run) {
+                    Ok(output) => {
+                        // Add to history - store what was actually executed,
+                        // failures too so a follow-up can see what went wrong
+                        history.push(direct_turn(input, &command_to_run, &output));
+                    }
+                    Err(e) => {
+                        if let Some(hangup) = e.downcast_ref::<HungUp>() {
+                            history.push(hangup.turn(input));
+                            break;
+                        }
+                        eprintln!("Command failed: {}", e);
+                    }
+                }
+            }
+
+            for note in run_analyzers(ANALYZERS, &history, &settings, &mut boundary) {
+                println!("{}", theme.helper_text(&note));
+            }
+            boundary.report(&history, &spill, &theme);
+
+            continue;
+        }
+
+        let scoped = tasks.scope(&history, std::mem::take(&mut widen));
+        let usage_before = session_usage();
+        match process_prompt_with_context(input, &settings, &theme, &scoped, None, &policy, &mut Shell) {
+            Ok(result) => {
+                // A turn whose command failed is kept too, so "why did that
+                // fail?" has the failure to look at
+                if let Some(failed) = &result.failed {
+                    eprintln!("Error: {}", failed);
+                }
+                history.push(result.turn);
+
+                for note in run_analyzers(ANALYZERS, &history, &settings, &mut boundary) {
+                    println!("{}", theme.helper_text(&note));
+                }
+                boundary.report(&history, &spill, &theme);
+            }
+            Err(err) => {
+                if let Some(hangup) = err.downcast_ref::<HungUp>() {
+                    history.push(hangup.turn(input));
+                    break;
+                }
+                eprintln!("Error: {}", err);
+                // Continue the loop even on error in interactive mode
+            }
+        }
+        let session = session_usage();
+        let turn_usage = session.since(&usage_before);
+        if turn_usage.requests > 0 {
+            println!("{}", theme.helper_text(&turn_usage.line(Some(&session))));
+        }
+
+        println!(); // Add blank line between prompts
+    }
+
+    if hung_up() {
+        save_after_hangup(&history, &spill);
+    }
+    if let Err(err) = persist_session(session, &history, &tasks, &spill)
+        && !hung_up()
+    {
+        eprintln!("Warning: could not save the session: {err}");
+    }
+    Ok(())
+}
+
+/// The turns and tasks to start from with --resume. A session that can't
+/// be read is set aside, so saving this one doesn't overwrite it.
+pub(crate) fn resume_session(session: &SavedSession, theme: &Theme) -> (Vec<ConversationContext>, TaskLog) {
+    match session.load() {
+        Ok(Some((turns, tasks))) => {
+            let note = format!("Resumed {} turns from {}", turns.len(), session.path.display());
+            println!("{}", theme.helper_text(&note));
+            (turns, tasks)
+        }
+        Ok(None) => {
+            println!("{}", theme.helper_text(&format!("No saved session at {}; starting fresh", session.path.display())));
+            (Vec::new(), TaskLog::default())
+        }
+        Err(err) => {
+            let aside = session.path.with_extension("json.unreadable");
+            let kept = match fs::rename(&session.path, &aside) {
+                Ok(()) => format!(" (kept as {})", aside.display()),
+                Err(_) => String::new(),
+            };
+            eprintln!("{}", theme.warning_text(&format!("Could not resume: {err}{kept}. Starting fresh.")));
+            (Vec::new(), TaskLog::default())
+        }
+    }
+}
+
+/// Saves the whole session, spilled turns included. An empty session
+/// leaves the last save alone.
+pub(crate) fn persist_session(
+    session: &SavedSession,
+    history: &[ConversationContext],
+    tasks: &TaskLog,
+    spill: &SessionSpill,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if history.is_empty() {
+        return Ok(());
+    }
+    session.save(&spill.full_history(history)?, tasks)
+}
+
+/// On a day's first session, offers the notes of the last day that has any
+/// as context. Accepted notes become a turn, so they are budgeted and
+/// compacted like any other.
+pub(crate) fn offer_earlier_notes(theme: &Theme) -> Result<Option<ConversationContext>, Box<dyn std::error::Error>> {
+    let now = unix_now();
+    let Some((date, lines)) = Notes::open().unoffered(now) else {
+        return Ok(None);
+    };
+    let day = if date == local_time(now - 86_400).0 {
+        "yesterday's".to_string()
+    } else {
+        format!("{date}'s")
+    };
+    for line in &lines {
+        println!("{}", theme.helper_text(line));
+    }
+    print!("{} ", theme.prompt_text(&format!("Include {day} {} note(s)? [y/N]", lines.len())));
+    io::stdout().flush()?;
+    let answer = read_confirmation_line()?;
+    if !matches!(normalize_confirmation_input(&answer).as_str(), "y" | "yes") {
+        return Ok(None);
+    }
+    let command = format!("notes {date}");
+    Ok(Some(ConversationContext {
+        prompt: command.clone(),
+        commands: vec![command],
+        outputs: vec![lines.join("\n")],
+        exit_codes: vec![],
+        request: None,
+        pinned: false,
+        forgotten: false,
+    }))
+}
+
+/// Moves the session to `path` the way an interactive `cd` does: reports
+/// the new directory, records `input` as a turn, and remembers both the old
+/// and the new directory for `recent`.
+pub(crate) fn change_directory(
+    path: &Path,
+    input: &str,
+    history: &mut Vec<ConversationContext>,
+    recent: &mut RecentDirs,
+    theme: &Theme,
+) {
+    let previous = env::current_dir().ok();
+    match env::set_current_dir(path) {
+        Ok(_) => {
+            let cwd = env::current_dir().unwrap_or_default();
+            println!("{}", theme.helper_text(&format!("Changed directory to: {}", cwd.display())));
+            if let Some(previous) = &previous {
+                recent.visit(previous);
+            }
+            recent.visit(&cwd);
+            recent.previous = previous;
+
+            // Add to history
+            history.push(ConversationContext {
+                prompt: input.to_string(),
+                commands: vec![input.to_string()],
+                outputs: vec![format!("Changed to: {}", cwd.display())],
+                exit_codes: vec![],
+                request: None,
+                pinned: false,
+                forgotten: false,
+            });
+        }
+        Err(e) => {
+            eprintln!("{}", cd_error(path, &e));
+        }
+    }
+}
+
+/// Where `cd ARG` goes: home with no argument, the previous directory for
+/// `-`, and otherwise ARG without the quotes a model sometimes puts around
+/// it, with `~` and variables expanded.
+pub(crate) fn cd_target(arg: &str, previous: Option<&Path>) -> Result<PathBuf, String> {
+    match strip_quotes(arg.trim()) {
+        "" => dirs::home_dir().ok_or_else(|| "cd: HOME not set".to_string()),
+        "-" => previous.map(Path::to_path_buf).ok_or_else(|| "cd: OLDPWD not set".to_string()),
+        path => Ok(expand_path(path)),
+    }
+}
+
+/// A failed cd in the shell's words, whatever the platform's error says.
+pub(crate) fn cd_error(path: &Path, err: &io::Error) -> String {
+    let reason = match err.kind() {
+        io::ErrorKind::NotFound => "no such file or directory",
+        io::ErrorKind::NotADirectory => "not a directory",
+        io::ErrorKind::PermissionDenied => "permission denied",
+        _ => return format!("cd: {}: {err}", path.display()),
+    };
+    format!("cd: {reason}: {}", path.display())
+}
+
+pub(crate) fn print_interactive_banner(model: &str, profile: Option<&str>, policy: &SafetyPolicy, theme: &Theme) {
+    println!("{}", theme.prompt_text("Interactive mode. Type /help for built-in commands and shortcuts"));
+    if policy.auto_safe {
+        println!("{}", theme.helper_text("Common commands and scripts execute directly without confirmation"));
+    }
+    println!("{}", theme.helper_text("Start a line with ! to run it as typed; !! runs the last command again"));
+    println!("{}", theme.helper_text(&format!("Model: {}", model)));
+    if let Some(profile) = profile {
+        println!("{}", theme.helper_text(&format!("Profile: {}", profile)));
+    }
+
+    // Show current directory
+    if let Ok(cwd) = env::current_dir() {
+        println!("{}", theme.helper_text(&format!("📁 {}", cwd.display())));
+    }
+    println!();
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum ModelCommand {
+    Show,
+    Switch { model: String, save: bool },
+}
+
+/// Parses `/model`, `/model NAME`, and `/model --save NAME`. Returns `None`
+/// for other input. Names are checked loosely: anything non-empty without
+/// whitespace, since providers name models freely.
+pub(crate) fn parse_model_command(input: &str) -> Option<Result<ModelCommand, String>> {
+    let rest = input.strip_prefix("/model")?;
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let usage = "Usage: /model [--save] [MODEL]".to_string();
+    let words: Vec<&str> = rest.split_whitespace().collect();
+    let (save, name) = match words.as_slice() {
+        [] => return Some(Ok(ModelCommand::Show)),
+        ["--save", name] => (true, *name),
+        [name] if *name != "--save" => (false, *name),
+        _ => return Some(Err(usage)),
+    };
+    Some(Ok(ModelCommand::Switch {
+        model: name.to_string(),
+        save,
+    }))
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum ThemeCommand {
+    Show,
+    Switch { mode: ThemeMode, save: bool },
+}
+
+/// Parses `/theme`, `/theme NAME`, and `/theme --save NAME`. Returns
+/// `None` for other input.
+pub(crate) fn parse_theme_command(input: &str) -> Option<Result<ThemeCommand, String>> {
+    let rest = input.strip_prefix("/theme")?;
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let words: Vec<&str> = rest.split_whitespace().collect();
+    let (save, name) = match words.as_slice() {
+        [] => return Some(Ok(ThemeCommand::Show)),
+        ["--save", name] => (true, *name),
+        [name] if *name != "--save" => (false, *name),
+        _ => return Some(Err("Usage: /theme [--save] [NAME]".to_string())),
+    };
+    Some(
+        ThemeMode::from_str(name)
+            .map(|mode| ThemeCommand::Switch { mode, save })
+            .ok_or_else(|| format!("Unknown theme '{name}'; pick one of {}", THEME_NAMES.join(", "))),
+    )
+}
+
+/// Parses `/models` and `/models QUERY`, returning the query.
+pub(crate) fn parse_models_command(input: &str) -> Option<&str> {
+    let rest = input.strip_prefix("/models")?;
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    Some(rest.trim())
+}
+
+/// Parses `/profile` and `/profile NAME`, returning the name if given.
+pub(crate) fn parse_profile_command(input: &str) -> Option<Option<&str>> {
+    let rest = input.strip_prefix("/profile")?;
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    Some(Some(rest.trim()).filter(|name| !name.is_empty()))
+}
+
+pub(crate) struct RerunRequest {
+    pub(crate) turn: usize,
+    pub(crate) model: Option<String>,
+}
+
+/// Parses `history rerun N [--model MODEL]`. Returns `None` for anything
+/// that isn't a rerun so other input passes through untouched.
+pub(crate) fn parse_history_rerun(input: &str) -> Option<Result<RerunRequest, String>> {
+    let mut words = input.split_whitespace();
+    if !matches!(words.next(), Some("history" | "/history")) || words.next() != Some("rerun") {
+        return None;
+    }
+    let usage = "Usage: history rerun N [--model MODEL]".to_string();
+    let Some(turn) = words.next().and_then(|n| n.parse::<usize>().ok()).filter(|n| *n > 0) else {
+        return Some(Err(usage));
+    };
+    let model = match (words.next(), words.next(), words.next()) {
+        (None, _, _) => None,
+        (Some("--model"), Some(model), None) => Some(model.to_string()),
+        _ => return Some(Err(usage)),
+    };
+    Some(Ok(RerunRequest { turn, model }))
+}
+
+/// A command typed at the prompt that ran without the model, as history
+/// keeps it. Says so when it failed.
+pub(crate) fn direct_turn(prompt: &str, command: &str, output: &CommandOutput) -> ConversationContext {
+    if let Some(failure) = output.failure() {
+        eprintln!("Command failed: {failure}");
+    }
+    ConversationContext {
+        prompt: prompt.to_string(),
+        commands: vec![command.to_string()],
+        outputs: vec![output.history_output()],
+        exit_codes: vec![output.exit_code()],
+        request: None,
+        pinned: false,
+        forgotten: false,
+    }
+}
+
+/// The context a turn was originally sent with: the earlier turns of its
+/// task, compacted exactly as they were then (compaction is a pure
+/// function of the earlier turns). A one-off `task previous` is not
+/// recorded, so such a turn replays with its own task only.
+pub(crate) fn reconstruct_context(
+    history: &[ConversationContext],
+    tasks: &TaskLog,
+    turn: usize,
+    redaction: &Redaction,
+    budget: usize,
+) -> Vec<ChatMessage> {
+    let earlier = &history[..turn.saturating_sub(1).min(history.len())];
+    compact_history(&tasks.scope(earlier, false), redaction, budget)
+}
+
+/// Resends an earlier turn to a (possibly different) model and shows both
+/// suggestions side by side. Nothing runs unless the user asks, and running
+/// appends a fresh turn instead of rewriting the old one.
+pub(crate) fn rerun_turn(
+    history: &mut Vec<ConversationContext>,
+    tasks: &TaskLog,
+    rerun: &RerunRequest,
+    settings: &RequestSettings,
+    theme: &Theme,
+    policy: &SafetyPolicy,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let index = rerun.turn - 1;
+    let turn = history
+        .get(index)
+        .ok_or_else(|| format!("No turn {} (session has {} turns)", rerun.turn, history.len()))?;
+    let original = turn
+        .request
+        .as_ref()
+        .ok_or_else(|| format!("Turn {} ran directly and never went to the model", rerun.turn))?;
+    let prompt = turn.prompt.clone();
+    let settings = RequestSettings {
+        model: rerun.model.clone().unwrap_or_else(|| settings.model.clone()),
+        ..settings.clone()
+    };
+    let model = settings.model.as_str();
+
+    // query_api compacts the same earlier turns, so the model sees exactly
+    // the context the original request had.
+    let budget = settings.history_budget(&request_message(&prompt, &settings, None).content);
+    let context = reconstruct_context(history, tasks, rerun.turn, &settings.redaction, budget);
+    let scoped = tasks.scope(&history[..index], false);
+    println!(
+        "{}",
+        theme.helper_text(&format!(
+            "Replaying with {} earlier turn(s) of context (~{} tokens) on {}",
+            scoped.len(),
+            context.iter().map(|m| estimate_tokens(&m.content)).sum::<usize>(),
+            model
+        ))
+    );
+    let (response, answered) = ask_model(&prompt, &settings, theme, &scoped, None, policy)?;
+    let model = answered.as_str();
+
+    println!("{}", theme.helper_text(&format!("Turn {}: {}", rerun.turn, prompt)));
+    let width = 40;
+    println!(
+        "  {} │ {}",
+        theme.helper_text(&pad_column(&original.model, width)),
+        theme.helper_text(model)
+    );
+    for (marker, left, right) in side_by_side(&original.response, &response) {
+        println!(
+            "{} {} │ {}",
+            marker,
+            theme.command_text(&pad_column(left, width)),
+            theme.command_text(right)
+        );
+    }
+
+    print!("{} ", theme.prompt_text("Run the new suggestion? [y/N]"));
+    io::stdout().flush()?;
+    let answer = read_confirmation_line()?;
+    if matches!(normalize_confirmation_input(&answer).as_str(), "y" | "yes") {
+        let ran = execute_response(&response, theme, policy, &mut Shell)?;
+        history.push(ConversationContext {
+            prompt,
+            commands: ran.commands,
+            outputs: ran.outputs,
+            exit_codes: ran.exit_codes,
+            request: Some(TurnRequest {
+                model: model.to_string(),
+                api_url: settings.endpoint.url.clone(),
+                response,
+            }),
+            pinned: false,
+            forgotten: false,
+        });
+    }
+    Ok(())
+}
+
+/// Lines up two command lists so shared commands sit on the same row.
+/// Rows that differ are marked with `≠`.
+pub(crate) fn side_by_side<'a>(left: &'a [String], right: &'a [String]) -> Vec<(char, &'a str, &'a str)> {
+    // Longest common subsequence table, filled from the end
+    let mut lcs = vec![vec![0usize; right.len() + 1]; left.len() + 1];
+    for i in (0..left.len()).rev() {
+        for j in (0..right.len()).rev() {
+            lcs[i][j] = if left[i] == right[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut rows = Vec::new();
+    while i < left.len() || j < right.len() {
+        if i < left.len() && j < right.len() && left[i] == right[j] {
+            rows.push((' ', left[i].as_str(), right[j].as_str()));
+            i += 1;
+            j += 1;
+        } else if j < right.len() && (i == left.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            rows.push(('≠', "", right[j].as_str()));
+            j += 1;
+        } else {
+            rows.push(('≠', left[i].as_str(), ""));
+            i += 1;
+        }
+    }
+    rows
+}
+
+/// Truncates to `width` characters, marking the cut with an ellipsis.
+pub(crate) fn fit_column(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        text.to_string()
+    } else {
+        let mut cut: String = text.chars().take(width.saturating_sub(1)).collect();
+        cut.push('…');
+        cut
+    }
+}
+
+pub(crate) fn pad_column(text: &str, width: usize) -> String {
+    let fitted = fit_column(text, width);
+    let padding = width.saturating_sub(fitted.chars().count());
+    format!("{}{}", fitted, " ".repeat(padding))
+}
+
+/// Post-turn hook that inspects the session, as the settings in use would
+/// send it, and may return a note to show.
+pub(crate) type Analyzer = fn(&[ConversationContext], &RequestSettings) -> Option<String>;
+
+pub(crate) const ANALYZERS: &[(&str, Analyzer)] = &[("context size", context_size_note)];
+
+pub(crate) fn context_size_note(history: &[ConversationContext], settings: &RequestSettings) -> Option<String> {
+    let total = estimate_total_context_size(history);
+    let budget = settings.default_history_budget();
+    (total > budget).then(|| {
+        format!("Note: Context is ~{total} tokens, over the {budget}-token budget; older turns are being compacted.")
+    })
+}
+
+/// Runs each analyzer behind the panic boundary; a broken analyzer loses its
+/// note for this turn but never the session.
+pub(crate) fn run_analyzers(
+    analyzers: &[(&str, Analyzer)],
+    history: &[ConversationContext],
+    settings: &RequestSettings,
+    boundary: &mut FeatureBoundary,
+) -> Vec<String> {
+    analyzers
+        .iter()
+        .filter_map(|(name, analyzer)| boundary.run(name, || analyzer(history, settings)).flatten())
+        .collect()
+}
+
+thread_local! {
+    // Set while auxiliary feature code runs so the panic hook stays quiet
+    static IN_FEATURE: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+    static LAST_PANIC: std::cell::RefCell<Option<String>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Catches panics from auxiliary REPL features (prompt decoration, paste
+/// detection, analyzers) so they degrade to an error message instead of
+/// ending the session. Command execution and confirmation are deliberately
+/// NOT run through here — a failure there should stop, not be absorbed.
+pub(crate) struct FeatureBoundary {
+    pub(crate) failures: Vec<(String, String)>,
+    pub(crate) recovery_offered: bool,
+}
+
+impl FeatureBoundary {
+    pub(crate) fn new() -> Self {
+        static HOOK: std::sync::Once = std::sync::Once::new();
+        HOOK.call_once(|| {
+            let default_hook = std::panic::take_hook();
+            std::panic::set_hook(Box::new(move |info| {
+                if IN_FEATURE.with(|flag| flag.get()) {
+                    // Reported later, once the terminal has been restored
+                    LAST_PANIC.with(|last| *last.borrow_mut() = Some(info.to_string()));
+                } else {
+                    default_hook(info);
+                }
+            }));
+        });
+        Self {
+            failures: Vec::new(),
+            recovery_offered: false,
+        }
+    }
+
+    /// Runs `f`, returning `None` (and recording the failure) if it panics.
+    pub(crate) fn run<T>(&mut self, name: &str, f: impl FnOnce() -> T) -> Option<T> {
+        IN_FEATURE.with(|flag| flag.set(true));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _terminal = TerminalGuard::capture();
+            f()
+        }));
+        IN_FEATURE.with(|flag| flag.set(false));
+
+        match result {
+            Ok(value) => Some(value),
+            Err(payload) => {
+                let message = LAST_PANIC
+                    .with(|last| last.borrow_mut().take())
+                    .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown panic".to_string());
+                self.failures.push((name.to_string(), message));
+                None
+            }
+        }
+    }
+
+    /// Prints any recorded failures and, the first time, offers to save the
+    /// session so nothing is lost if the user decides to restart.
+    pub(crate) fn report(&mut self, history: &[ConversationContext], spill: &SessionSpill, theme: &Theme) {
+        if self.failures.is_empty() {
+            return;
+        }
+        for (name, message) in self.failures.drain(..) {
+            eprintln!("Error: internal error in {name} (session continues): {message}");
+        }
+        if self.recovery_offered || history.is_empty() {
+            return;
+        }
+        self.recovery_offered = true;
+
+        print!("{} ", theme.prompt_text("Save this session to a recovery file? [y/N]"));
+        let _ = io::stdout().flush();
+        let answer = read_confirmation_line().unwrap_or_default();
+        if !matches!(normalize_confirmation_input(&answer).as_str(), "y" | "yes") {
+            return;
+        }
+        let full = match spill.full_history(history) {
+            Ok(full) => full,
+            Err(err) => {
+                eprintln!("Could not read spilled turns back: {err}");
+                history.to_vec()
+            }
+        };
+        match save_recovery_file(&full) {
+            Ok(path) => println!("{}", theme.helper_text(&format!("Session saved to {}", path.display()))),
+            Err(err) => eprintln!("Could not save recovery file: {err}"),
+        }
+    }
+}
+
+/// Restores terminal modes and colors if a feature panics mid-render, so the
+/// error message and the next prompt are readable.
+pub(crate) struct TerminalGuard {
+    pub(crate) saved: Option<libc::termios>,
+}
+
+impl TerminalGuard {
+    pub(crate) fn capture() -> Self {
+        let fd = io::stdin().as_raw_fd();
+        let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+        let saved = (unsafe { libc::tcgetattr(fd, &mut termios) } == 0).then_some(termios);
+        Self { saved }
+    }
+
+    /// Puts the captured terminal modes back now.
+    pub(crate) fn restore(&self) {
+        if let Some(termios) = &self.saved {
+            unsafe { libc::tcsetattr(io::stdin().as_raw_fd(), libc::TCSANOW, termios) };
+        }
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        if !std::thread::panicking() {
+            return;
+        }
+        self.restore();
+        if unsafe { libc_isatty(io::stdout().as_raw_fd()) } == 1 {
+            print!("{RESET}");
+            let _ = io::stdout().flush();
+        }
+    }
+}
+
+// JSON output bigger than this is summarized for the model; history and
+// `json` still have all of it
+pub(crate) const JSON_SUMMARY_BYTES: usize = 2048;
+pub(crate) const JSON_SAMPLE_CHARS: usize = 300;
+
+/// Output that is a single JSON document (`kubectl -o json`, `gh api`).
+/// Anything else, including JSON followed by stderr text, is not.
+pub(crate) fn parse_json_output(output: &str) -> Option<serde_json::Value> {
+    let trimmed = output.trim();
+    if !(trimmed.starts_with('{') || trimmed.starts_with('[')) {
+        return None;
+    }
+    serde_json::from_str(trimmed).ok()
+}
+
+/// The newest command output in `turns` that is JSON, with its size.
+pub(crate) fn newest_json_output(turns: &[ConversationContext]) -> Option<(serde_json::Value, usize)> {
+    turns
+        .iter()
+        .rev()
+        .flat_map(|turn| turn.outputs.iter().rev())
+        .find_map(|output| parse_json_output(output).map(|value| (value, output.len())))
+}
+
+/// What the model is sent for a command's `output`: a summary when it is
+/// JSON too big to be worth sending whole, otherwise None.
+pub(crate) fn compact_json_output(output: &str) -> Option<String> {
+    if output.len() <= JSON_SUMMARY_BYTES {
+        return None;
+    }
+    parse_json_output(output).map(|value| json_summary(&value, output.len()))
+}
+
+/// What the model sees instead of a big JSON blob: the top-level shape
+/// and the start of the document.
+pub(crate) fn json_summary(value: &serde_json::Value, bytes: usize) -> String {
+    use serde_json::Value;
+    let shape = |value: &Value| match value {
+        Value::Array(items) => format!("array of {}", items.len()),
+        Value::Object(map) => format!("object with {} keys", map.len()),
+        Value::String(s) if s.chars().count() > 60 => format!("string of {} chars", s.chars().count()),
+        other => other.to_string(),
+    };
+    let mut summary = match value {
+        Value::Object(map) => {
+            let mut lines = format!("JSON object ({bytes} bytes), summarized:\n");
+            for (key, value) in map {
+                lines.push_str(&format!("  {key}: {}\n", shape(value)));
+            }
+            lines
+        }
+        Value::Array(items) => {
+            let mut lines = format!("JSON array of {} items ({bytes} bytes), summarized:\n", items.len());
+            if let Some(Value::Object(first)) = items.first() {
+                let keys: Vec<&str> = first.keys().map(String::as_str).collect();
+                lines.push_str(&format!("  item keys: {}\n", keys.join(", ")));
+            }
+            lines
+        }
+        other => format!("JSON {} ({bytes} bytes)\n", shape(other)),
+    };
+    let compact = value.to_string();
+    let sample: String = compact.chars().take(JSON_SAMPLE_CHARS).collect();
+    let more = if sample.len() < compact.len() { "…" } else { "" };
+    summary.push_str(&format!("sample: {sample}{more}"));
+    summary
+}
+
+/// Pretty-printed JSON with keys, strings and other values in the
+/// theme's colors (none with --no-color).
+pub(crate) fn render_json(value: &serde_json::Value, theme: &Theme) -> String {
+    fn render(value: &serde_json::Value, theme: &Theme, indent: usize, out: &mut String) {
+        use serde_json::Value;
+        let pad = |depth: usize| "  ".repeat(depth);
+        match value {
+            Value::Object(map) if !map.is_empty() => {
+                out.push_str("{\n");
+                for (i, (key, value)) in map.iter().enumerate() {
+                    out.push_str(&pad(indent + 1));
+                    out.push_str(&theme.prompt_text(&Value::String(key.clone()).to_string()));
+                    out.push_str(": ");
+                    render(value, theme, indent + 1, out);
+                    out.push_str(if i + 1 < map.len() { ",\n" } else { "\n" });
+                }
+                out.push_str(&pad(indent));
+                out.push('}');
+            }
+            Value::Array(items) if !items.is_empty() => {
+                out.push_str("[\n");
+                for (i, item) in items.iter().enumerate() {
+                    out.push_str(&pad(indent + 1));
+                    render(item, theme, indent + 1, out);
+                    out.push_str(if i + 1 < items.len() { ",\n" } else { "\n" });
+                }
+                out.push_str(&pad(indent));
+                out.push(']');
+            }
+            Value::String(_) => out.push_str(&theme.command_text(&value.to_string())),
+            Value::Object(_) | Value::Array(_) => out.push_str(&value.to_string()),
+            other => out.push_str(&theme.helper_text(&other.to_string())),
+        }
+    }
+    let mut out = String::new();
+    render(value, theme, 0, &mut out);
+    out.push('\n');
+    out
+}
+
+/// One step of a `json path` expression.
+#[derive(Debug, PartialEq)]
+pub(crate) enum JsonStep {
+    Key(String),
+    // Negative counts back from the end
+    Index(i64),
+}
+
+/// What the user asked of the last JSON output.
+#[derive(Debug, PartialEq)]
+pub(crate) enum JsonCommand {
+    // `json`
+    Show,
+    // `json path .items[0].name`
+    Path(Vec<JsonStep>),
+}
+
+pub(crate) fn parse_json_command(input: &str) -> Option<Result<JsonCommand, String>> {
+    if input == "json" {
+        return Some(Ok(JsonCommand::Show));
+    }
+    let rest = input.strip_prefix("json ")?.trim();
+    let Some(path) = rest.strip_prefix("path") else {
+        return Some(Err("Usage: json, or json path .items[0].name".to_string()));
+    };
+    if !(path.is_empty() || path.starts_with(char::is_whitespace)) {
+        return Some(Err("Usage: json, or json path .items[0].name".to_string()));
+    }
+    Some(parse_json_path(path.trim()).map(JsonCommand::Path))
+}
+
+/// `.a.b[2][-1]`; a bare `.` is the whole document.
+pub(crate) fn parse_json_path(path: &str) -> Result<Vec<JsonStep>, String> {
+    if !(path.starts_with('.') || path.starts_with('[')) {
+        return Err(format!("A path starts with '.' or '[' (e.g. .items[0].name), not '{path}'"));
+    }
+    let mut steps = Vec::new();
+    let mut rest = path;
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('[') {
+            let (index, after) = after.split_once(']').ok_or_else(|| format!("Missing ']' in {path}"))?;
+            let index = index.trim().parse().map_err(|_| format!("'[{index}]' is not an array index"))?;
+            steps.push(JsonStep::Index(index));
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('.') {
+            let end = after.find(['.', '[']).unwrap_or(after.len());
+            if end > 0 {
+                steps.push(JsonStep::Key(after[..end].to_string()));
+            } else if !after.is_empty() && !after.starts_with('[') {
+                return Err(format!("Empty key in {path}"));
+            }
+            rest = &after[end..];
+        } else {
+            return Err(format!("Unexpected '{rest}' in {path}"));
+        }
+    }
+    Ok(steps)
+}
+
+pub(crate) fn query_json<'a>(value: &'a serde_json::Value, steps: &[JsonStep]) -> Result<&'a serde_json::Value, String> {
+    let mut current = value;
+    for step in steps {
+        current = match (step, current) {
+            (JsonStep::Key(key), serde_json::Value::Object(map)) => {
+                map.get(key).ok_or_else(|| format!("No key '{key}'"))?
+            }
+            (JsonStep::Index(index), serde_json::Value::Array(items)) => {
+                let at = if *index < 0 { items.len() as i64 + index } else { *index };
+                usize::try_from(at)
+                    .ok()
+                    .and_then(|at| items.get(at))
+                    .ok_or_else(|| format!("Index {index} is out of range ({} items)", items.len()))?
+            }
+            (JsonStep::Key(key), _) => return Err(format!("Can't take '{key}' of a non-object")),
+            (JsonStep::Index(index), _) => return Err(format!("Can't take [{index}] of a non-array")),
+        };
+    }
+    Ok(current)
+}
+
+/// `finder` with an optional single argument. Anything longer is a question
+/// for the model ("finder is slow, why?"), not a path.
+pub(crate) fn parse_finder(input: &str) -> Option<Option<&str>> {
+    let rest = input.strip_prefix("finder")?;
+    if rest.is_empty() {
+        return Some(None);
+    }
+    if !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let rest = rest.trim();
+    match strip_quotes(rest) {
+        path if path.len() != rest.len() => Some(Some(path)),
+        _ if !rest.contains(char::is_whitespace) => Some(Some(rest)),
+        _ => None,
+    }
+}
+
+/// `text` without one pair of matching quotes around it.
+pub(crate) fn strip_quotes(text: &str) -> &str {
+    text.strip_prefix('"')
+        .and_then(|t| t.strip_suffix('"'))
+        .or_else(|| text.strip_prefix('\'').and_then(|t| t.strip_suffix('\'')))
+        .unwrap_or(text)
+}
+
+/// Expands `~`, `~user` and `$VAR`/`${VAR}` like the shell would. Unset
+/// variables and unknown users are left as written so the error shows what
+/// was typed.
+pub(crate) fn expand_path(arg: &str) -> PathBuf {
+    let mut expanded = String::new();
+    let rest = match arg.strip_prefix('~') {
+        Some(rest) => {
+            let (user, rest) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+            let home = if user.is_empty() { dirs::home_dir() } else { user_home(user) };
+            match home {
+                Some(home) => {
+                    expanded.push_str(&home.display().to_string());
+                    rest
+                }
+                None => arg,
+            }
+        }
+        None => arg,
+    };
+    let mut chars = rest.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            expanded.push(c);
+            continue;
+        }
+        let braced = chars.next_if_eq(&'{').is_some();
+        let mut name = String::new();
+        while let Some(n) = chars.next_if(|n| n.is_ascii_alphanumeric() || *n == '_') {
+            name.push(n);
+        }
+        if braced {
+            chars.next_if_eq(&'}');
+        }
+        match env::var(&name) {
+            Ok(value) if !name.is_empty() => expanded.push_str(&value),
+            _ if braced => expanded.push_str(&format!("${{{}}}", name)),
+            _ => expanded.push_str(&format!("${}", name)),
+        }
+    }
+    PathBuf::from(expanded)
+}
+
+/// The home directory of account `name`, from the password database.
+pub(crate) fn user_home(name: &str) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+    let name = std::ffi::CString::new(name).ok()?;
+    // The entry lives in static storage until the next lookup, so copy it out
+    let entry = unsafe { libc::getpwnam(name.as_ptr()) };
+    if entry.is_null() {
+        return None;
+    }
+    let dir = unsafe { std::ffi::CStr::from_ptr((*entry).pw_dir) };
+    Some(PathBuf::from(std::ffi::OsStr::from_bytes(dir.to_bytes())))
+}
+
+/// Resolves a `finder` argument to an existing path, relative to the cwd.
+/// A missing path suggests the closest name in the same directory.
+pub(crate) fn resolve_finder_path(arg: &str) -> Result<PathBuf, String> {
+    let path = expand_path(arg);
+    if path.exists() {
+        return Ok(path);
+    }
+    let mut message = format!("No such file or directory: {}", arg);
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    if let (Some(name), Ok(entries)) = (path.file_name().and_then(|n| n.to_str()), fs::read_dir(&parent)) {
+        let max_distance = (name.chars().count() / 3).max(1);
+        let closest = entries
+            .filter_map(|e| e.ok()?.file_name().into_string().ok())
+            .map(|candidate| (edit_distance(&name.to_lowercase(), &candidate.to_lowercase()), candidate))
+            .filter(|(distance, _)| *distance <= max_distance)
+            .min();
+        if let Some((_, candidate)) = closest {
+            message.push_str(&format!(" (did you mean {}?)", parent.join(candidate).display()));
+        }
+    }
+    Err(message)
+}
+
+/// The most recent token in earlier command output that names an existing
+/// file or directory, e.g. the screenshot a `find` just turned up.
+pub(crate) fn last_output_path(history: &[ConversationContext]) -> Option<PathBuf> {
+    history
+        .iter()
+        .rev()
+        .flat_map(|ctx| ctx.outputs.iter().rev())
+        .flat_map(|output| output.split_whitespace().rev())
+        .map(|token| {
+            token
+                .trim_start_matches(['(', '"', '\'', '`'])
+                .trim_end_matches(['.', ',', ':', ';', ')', '"', '\'', '`'])
+        })
+        .filter(|token| token.contains('/') || token.starts_with('~') || token.contains('.'))
+        .map(expand_path)
+        .find(|path| path.exists())
+}
+
+/// Builds the command that shows `path` in the file manager. macOS reveals
+/// files with `open -R` so Finder highlights them instead of opening them;
+/// elsewhere the containing directory is opened.
+pub(crate) fn reveal_command(os: &str, path: &Path, is_dir: bool) -> Command {
+    let mut command;
+    if os == "macos" {
+        command = Command::new("open");
+        if !is_dir {
+            command.arg("-R");
+        }
+        command.arg(path);
+    } else {
+        command = Command::new("xdg-open");
+        let dir = if is_dir { Some(path) } else { path.parent() };
+        match dir {
+            Some(dir) if !dir.as_os_str().is_empty() => command.arg(dir),
+            _ => command.arg("."),
+        };
+    }
+    command
+}
+
+// Directories remembered for `recent`; the menu shows up to nine of them
+// (one keypress each) after leaving out the current directory.
+pub(crate) const MAX_RECENT_DIRS: usize = 10;
+
+/// Directories the session has moved between, most recent first, kept in
+/// ~/.ask/recent_dirs across sessions.
+pub(crate) struct RecentDirs {
+    pub(crate) path: Option<PathBuf>,
+    pub(crate) dirs: Vec<PathBuf>,
+    // Where the last cd left from, for `cd -`; starts as the shell's OLDPWD
+    pub(crate) previous: Option<PathBuf>,
+}
+
+impl RecentDirs {
+    pub(crate) fn load() -> Self {
+        Self::with_path(Some(data_dir().join("recent_dirs")))
+    }
+
+    pub(crate) fn with_path(path: Option<PathBuf>) -> Self {
+        let dirs = path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .unwrap_or_default()
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(PathBuf::from)
+            .collect();
+        Self { path, dirs, previous: env::var_os("OLDPWD").map(PathBuf::from) }
+    }
+
+    /// Moves `dir` to the front of the list.
+    pub(crate) fn visit(&mut self, dir: &Path) {
+        if !dir.is_dir() {
+            return;
+        }
+        self.dirs.retain(|d| d != dir);
+        self.dirs.insert(0, dir.to_path_buf());
+        self.dirs.truncate(MAX_RECENT_DIRS);
+        self.save();
+    }
+
+    /// The menu entries: existing directories other than `current`. Entries
+    /// that no longer exist are dropped from the file as they are noticed.
+    pub(crate) fn choices(&mut self, current: &Path) -> Vec<PathBuf> {
+        let before = self.dirs.len();
+        self.dirs.retain(|d| d.is_dir());
+        if self.dirs.len() != before {
+            self.save();
+        }
+        self.dirs.iter().filter(|d| *d != current).take(9).cloned().collect()
+    }
+
+    pub(crate) fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if let Some(dir) = path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        let contents: String = self.dirs.iter().map(|d| format!("{}\n", d.display())).collect();
+        if let Err(err) = fs::write(path, contents) {
+            eprintln!("Warning: could not save recent directories: {err}");
+        }
+    }
+}
+
+/// What the user asked of the recent-directories list.
+#[derive(Debug, PartialEq)]
+pub(crate) enum RecentCommand {
+    // `recent` or `cd!`: show the menu
+    Menu,
+    // `cd =N`: jump straight to menu entry N
+    Pick(usize),
+}
+
+pub(crate) fn parse_recent(input: &str) -> Option<Result<RecentCommand, String>> {
+    if input == "recent" || input == "cd!" {
+        return Some(Ok(RecentCommand::Menu));
+    }
+    let choice = input.strip_prefix("cd =")?;
+    Some(
+        choice
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .filter(|&n| n >= 1)
+            .map(RecentCommand::Pick)
+            .ok_or_else(|| format!("Usage: cd =N (N is a number from 'recent'), got '{choice}'")),
+    )
+}
+
+/// Local date (`YYYY-MM-DD`) and time of day (`HH:MM`) for a Unix time.
+pub(crate) fn local_time(secs: i64) -> (String, String) {
+    let t = secs as libc::time_t;
+    let mut tm = unsafe { std::mem::zeroed::<libc::tm>() };
+    unsafe { libc::localtime_r(&t, &mut tm) };
+    (
+        format!("{:04}-{:02}-{:02}", tm.tm_year + 1900, tm.tm_mon + 1, tm.tm_mday),
+        format!("{:02}:{:02}", tm.tm_hour, tm.tm_min),
+    )
+}
+
+pub(crate) fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+// Earlier days looked at for notes to offer to a new day's first session
+pub(crate) const NOTES_LOOKBACK_DAYS: i64 = 7;
+
+/// Append-only notes, one file per day in ~/.ask/notes/YYYY-MM-DD.md with a
+/// `- HH:MM text` line per note.
+pub(crate) struct Notes {
+    pub(crate) dir: PathBuf,
+}
+
+impl Notes {
+    pub(crate) fn open() -> Self {
+        Self { dir: data_dir().join("notes") }
+    }
+
+    pub(crate) fn append(&self, text: &str, now: i64) -> io::Result<()> {
+        let (date, time) = local_time(now);
+        fs::create_dir_all(&self.dir)?;
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(format!("{date}.md")))?;
+        // One note per line, whatever the text contains
+        let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        writeln!(file, "- {time} {text}")
+    }
+
+    pub(crate) fn day(&self, date: &str) -> Vec<String> {
+        fs::read_to_string(self.dir.join(format!("{date}.md")))
+            .unwrap_or_default()
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// The notes of the latest day before today that has any, once per day:
+    /// only the first session of a day gets them.
+    pub(crate) fn unoffered(&self, now: i64) -> Option<(String, Vec<String>)> {
+        let (today, _) = local_time(now);
+        let marker = self.dir.join(".offered");
+        if fs::read_to_string(&marker).is_ok_and(|day| day.trim() == today) {
+            return None;
+        }
+        if fs::create_dir_all(&self.dir).and_then(|_| fs::write(&marker, &today)).is_err() {
+            return None;
+        }
+        (1..=NOTES_LOOKBACK_DAYS)
+            .map(|days| local_time(now - days * 86_400).0)
+            .filter(|date| *date != today)
+            .find_map(|date| {
+                let notes = self.day(&date);
+                (!notes.is_empty()).then_some((date, notes))
+            })
+    }
+}
+
+/// What the user asked of their notes.
+#[derive(Debug, PartialEq)]
+pub(crate) enum NotesCommand {
+    // `note TEXT`
+    Add(String),
+    // `notes [today|yesterday|week|YYYY-MM-DD]`: the days to show, oldest first
+    Show(Vec<String>),
+}
+
+pub(crate) fn parse_notes_command(input: &str, now: i64) -> Option<Result<NotesCommand, String>> {
+    if let Some(text) = input.strip_prefix("note ") {
+        return Some(Ok(NotesCommand::Add(text.trim().to_string())));
+    }
+    if input == "note" {
+        return Some(Err("Usage: note TEXT".to_string()));
+    }
+    let arg = match input.strip_prefix("notes") {
+        Some("") => "today",
+        Some(rest) if rest.starts_with(' ') => rest.trim(),
+        _ => return None,
+    };
+    let day = |days: i64| local_time(now - days * 86_400).0;
+    let dates = match arg {
+        "today" => vec![day(0)],
+        "yesterday" => vec![day(1)],
+        "week" => {
+            let mut dates: Vec<String> = (0..7).rev().map(day).collect();
+            // A daylight saving change can land two offsets on one date
+            dates.dedup();
+            dates
+        }
+        date if is_iso_date(date) => vec![date.to_string()],
+        other => return Some(Err(format!("Usage: notes [today|yesterday|week|YYYY-MM-DD], got '{other}'"))),
+    };
+    Some(Ok(NotesCommand::Show(dates)))
+}
+
+pub(crate) fn is_iso_date(text: &str) -> bool {
+    let bytes = text.as_bytes();
+    bytes.len() == 10
+        && bytes.iter().enumerate().all(|(i, b)| match i {
+            4 | 7 => *b == b'-',
+            _ => b.is_ascii_digit(),
+        })
+}
+
+// The line a model writes to save a note; intercepted, never run
+pub(crate) const MODEL_NOTE_PREFIX: &str = "#note:";
+
+/// Saves the `#note:` lines of a reply and returns the rest of it.
+pub(crate) fn take_model_notes(response: &[String], notes: &Notes, theme: &Theme) -> Vec<String> {
+    let mut rest = Vec::new();
+    for line in response {
+        let Some(text) = line.strip_prefix(MODEL_NOTE_PREFIX).map(str::trim) else {
+            rest.push(line.clone());
+            continue;
+        };
+        if text.is_empty() {
+            continue;
+        }
+        eprintln!("{}", theme.helper_text(&format!("Noted: {text}")));
+        if let Err(err) = notes.append(text, unix_now()) {
+            eprintln!("Warning: could not save note: {err}");
+        }
+    }
+    rest
+}
+
+/// Shows `path` the way the prompt does: `~` for the home directory.
+pub(crate) fn home_relative(path: &Path) -> String {
+    match dirs::home_dir().and_then(|home| path.strip_prefix(&home).ok().map(Path::to_path_buf)) {
+        Some(rest) if rest.as_os_str().is_empty() => "~".to_string(),
+        Some(rest) => format!("~/{}", rest.display()),
+        None => path.display().to_string(),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum PasteChoice {
+    RunBlock,
+    Dispatch,
+    Discard,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use rustyline::history::History;
+
+    // --- paste guard ---
+
+    #[test]
+    fn looks_like_paste_detects_fast_bursts() {
+        let t0 = Instant::now();
+        let arrivals = [t0, t0 + Duration::from_millis(2), t0 + Duration::from_millis(5)];
+        assert!(looks_like_paste(&arrivals, PASTE_LINE_GAP));
+    }
+
+    #[test]
+    fn looks_like_paste_ignores_typed_lines() {
+        let t0 = Instant::now();
+        let arrivals = [t0, t0 + Duration::from_millis(900)];
+        assert!(!looks_like_paste(&arrivals, PASTE_LINE_GAP));
+    }
+
+    #[test]
+    fn looks_like_paste_needs_more_than_one_line() {
+        assert!(!looks_like_paste(&[], PASTE_LINE_GAP));
+        assert!(!looks_like_paste(&[Instant::now()], PASTE_LINE_GAP));
+    }
+
+    // A single slow gap anywhere in the burst means a human was typing.
+    #[test]
+    fn looks_like_paste_rejects_burst_with_one_slow_gap() {
+        let t0 = Instant::now();
+        let arrivals = [
+            t0,
+            t0 + Duration::from_millis(3),
+            t0 + Duration::from_millis(400),
+            t0 + Duration::from_millis(402),
+        ];
+        assert!(!looks_like_paste(&arrivals, PASTE_LINE_GAP));
+    }
+
+    #[test]
+    fn looks_like_paste_treats_gap_at_threshold_as_paste() {
+        let t0 = Instant::now();
+        assert!(looks_like_paste(&[t0, t0 + PASTE_LINE_GAP], PASTE_LINE_GAP));
+    }
+
+    fn paste_scripted(lines: &[&str], answers: &[&str]) -> (PasteChoice, String) {
+        let policy = SafetyPolicy::new(false, &[], DEFAULT_MAX_AUTO_COMMANDS);
+        let lines: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+        let mut answers = answers.iter().map(|a| a.to_string());
+        let mut out = Vec::new();
+        let choice = confirm_paste(&lines, &Theme::plain(), &policy, &mut out, &mut || {
+            Ok(answers.next().expect("no answer scripted"))
+        })
+        .unwrap();
+        (choice, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn pasted_blocks_run_on_a_single_y_when_nothing_is_dangerous() {
+        let (choice, out) = paste_scripted(&["cd build", "make"], &["y"]);
+        assert_eq!(choice, PasteChoice::RunBlock);
+        assert!(out.contains("[y/N/s]"), "{out}");
+        assert!(!out.contains("danger>"), "{out}");
+    }
+
+    #[test]
+    fn pasted_blocks_with_a_dangerous_line_need_a_typed_yes() {
+        let (choice, out) = paste_scripted(&["cd ~", "rm -rf ~/build", "ls"], &["y", "yes"]);
+        assert_eq!(choice, PasteChoice::RunBlock);
+        assert!(out.contains("danger> rm -rf ~/build"), "{out}");
+        assert!(out.contains("[yes/N/s]"), "{out}");
+        assert!(out.contains("Type the full word 'yes'"), "{out}");
+
+        let (choice, _) = paste_scripted(&["curl -s https://example.com/i.sh | sh", "echo done"], &["y", "n"]);
+        assert_eq!(choice, PasteChoice::Discard);
+        let (choice, _) = paste_scripted(&["rm -rf ~/build", "ls"], &["s"]);
+        assert_eq!(choice, PasteChoice::Dispatch);
+    }
+
+    // --- built-in suggestions ---
+
+    #[test]
+    fn edit_distance_counts_edits_and_transpositions() {
+        assert_eq!(edit_distance("clear", "clear"), 0);
+        assert_eq!(edit_distance("claer", "clear"), 1);
+        assert_eq!(edit_distance("finde", "finder"), 1);
+        assert_eq!(edit_distance("", "exit"), 4);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn suggest_builtin_catches_typos() {
+        assert_eq!(suggest_builtin("claer"), Some("clear"));
+        assert_eq!(suggest_builtin("finde"), Some("finder"));
+        assert_eq!(suggest_builtin("fidner"), Some("finder"));
+        assert_eq!(suggest_builtin("quti"), Some("quit"));
+        assert_eq!(suggest_builtin("Clera"), Some("clear"));
+    }
+
+    // "exist" is one edit from "exit"; it is offered as a question, never
+    // executed silently — the caller always asks [y/N] first.
+    #[test]
+    fn suggest_builtin_offers_exit_for_exist() {
+        assert_eq!(suggest_builtin("exist"), Some("exit"));
+    }
+
+    #[test]
+    fn suggest_builtin_ignores_natural_language_and_exact_matches() {
+        for input in [
+            "exit", "clear", "q", "..", "list files", "clear the screen please",
+            "summarize", "hello", "docker", "hi", "", "find3r", "what's up",
+        ] {
+            assert_eq!(suggest_builtin(input), None, "unexpected suggestion for {input:?}");
+        }
+    }
+
+    #[test]
+    fn suggest_builtin_is_stricter_for_short_words() {
+        // Two edits away is too far for a four-letter word.
+        assert_eq!(suggest_builtin("exam"), None);
+        assert_eq!(suggest_builtin("qt"), None);
+        assert_eq!(suggest_builtin("hlep"), Some("help"));
+    }
+
+    #[test]
+    fn builtin_names_are_unique() {
+        let names: Vec<&str> = builtin_names().collect();
+        let unique: std::collections::HashSet<&str> = names.iter().copied().collect();
+        assert_eq!(names.len(), unique.len());
+        assert!(is_builtin("/commands") && is_builtin("..") && !is_builtin("rerun"));
+    }
+
+    #[test]
+    fn bang_lines_run_as_typed_and_bang_bang_repeats() {
+        // A conversational answer after it runs nothing
+        let answer = ConversationContext {
+            prompt: "thanks".to_string(),
+            commands: vec![],
+            outputs: vec![],
+            exit_codes: vec![],
+            request: None,
+            pinned: false,
+            forgotten: false,
+        };
+        let history = vec![direct_turn("ls", "ls -l", &CommandOutput::success("a\n")), answer];
+        assert_eq!(raw_command("!rm -rf build && make", &history), Some(Ok("rm -rf build && make")));
+        assert_eq!(raw_command("!!", &history), Some(Ok("ls -l")));
+        assert_eq!(raw_command("!! ", &[]), Some(Err("No command has run yet")));
+        assert!(matches!(raw_command("!", &history), Some(Err(_))));
+        assert_eq!(raw_command("ls !x", &history), None);
+    }
+
+    #[test]
+    fn unknown_slash_commands_are_not_sent_on() {
+        assert!(is_unknown_slash_command("/foo"));
+        assert!(is_unknown_slash_command("/modle gpt-4o"));
+        assert!(!is_unknown_slash_command("/help"));
+        assert!(!is_unknown_slash_command("/model x/y"));
+        // Paths are commands, not typos
+        assert!(!is_unknown_slash_command("/tmp"));
+        assert!(!is_unknown_slash_command("/usr/bin/env ls"));
+        assert!(!is_unknown_slash_command("/"));
+        assert!(!is_unknown_slash_command("what is /foo"));
+    }
+
+    #[test]
+    fn help_table_aligns_and_wraps_long_usage() {
+        let table = help_table([("q", "Quit"), ("history rerun N [--model M]", "Resend")], |s: &str| s.to_string());
+        assert_eq!(table, format!("  q                 Quit\n  history rerun N [--model M]\n{:20}Resend\n", ""));
+        // Padding happens before styling, so colors don't skew the columns
+        let theme = Theme::from_mode(ThemeMode::Dark);
+        let styled = help_table(builtin_rows(SHORTCUTS), |s: &str| theme.command_text(s));
+        assert!(styled.contains(&theme.command_text(&format!("{:<16}", ".."))));
+    }
+
+    // --- tab completion ---
+
+    fn complete(line: &str) -> (usize, Vec<String>) {
+        let history = DefaultHistory::new();
+        let (start, pairs) = PromptHelper::new(&Theme::plain()).complete(line, line.len(), &Context::new(&history)).unwrap();
+        (start, pairs.into_iter().map(|pair| pair.replacement).collect())
+    }
+
+    #[test]
+    fn completion_offers_builtins_only_as_the_first_word() {
+        assert_eq!(complete("/mo"), (0, vec!["/model".to_string(), "/models".to_string()]));
+        assert_eq!(complete("  fin"), (2, vec!["finder".to_string()]));
+        assert!(complete("ls fin").1.is_empty());
+        assert!(builtin_completions("").is_empty());
+    }
+
+    #[test]
+    fn completion_hides_dotfiles_unless_the_name_starts_with_a_dot() {
+        let dir = env::temp_dir().join(format!("ask-complete-{}", std::process::id()));
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join(".env"), "").unwrap();
+        fs::write(dir.join("notes.txt"), "").unwrap();
+        let dir_text = dir.display();
+        let (start, mut found) = complete(&format!("cat {dir_text}/"));
+        found.sort();
+        assert_eq!(start, 4);
+        assert_eq!(found, [format!("{dir_text}/notes.txt"), format!("{dir_text}/src/")]);
+        assert_eq!(complete(&format!("cat {dir_text}/.e")).1, [format!("{dir_text}/.env")]);
+        // A path as the first word completes too
+        assert_eq!(complete(&format!("{dir_text}/s")).1, [format!("{dir_text}/src/")]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn hints_suggest_the_latest_matching_history_entry() {
+        let mut history = DefaultHistory::new();
+        for entry in ["git status", "git log --oneline", "ls\nwc -l", "git"] {
+            history.add(entry).unwrap();
+        }
+        let ctx = Context::new(&history);
+        let helper = PromptHelper::new(&Theme::from_mode(ThemeMode::Dark));
+        assert_eq!(helper.hint("git s", 5, &ctx).as_deref(), Some("tatus"));
+        assert_eq!(helper.hint("git ", 4, &ctx).as_deref(), Some("log --oneline"));
+        // Only at the end of the line, and never a multi-line paste
+        assert_eq!(helper.hint("git s", 3, &ctx), None);
+        assert_eq!(helper.hint("ls", 2, &ctx), None);
+        assert_eq!(helper.highlight_hint("tatus"), format!("\u{001b}[2;37mtatus{RESET}"));
+        // Uncolored hints would look like typed text
+        assert_eq!(PromptHelper::new(&Theme::plain()).hint("git s", 5, &ctx), None);
+    }
+
+    // --- finder ---
+
+    #[test]
+    fn parse_finder_takes_one_path_or_nothing() {
+        assert_eq!(parse_finder("finder"), Some(None));
+        assert_eq!(parse_finder("finder ~/Desktop"), Some(Some("~/Desktop")));
+        assert_eq!(parse_finder("finder last"), Some(Some("last")));
+        assert_eq!(parse_finder("finder \"My Files/a b.png\""), Some(Some("My Files/a b.png")));
+        assert_eq!(parse_finder("finder is slow today, why"), None);
+        assert_eq!(parse_finder("finders"), None);
+    }
+
+    #[test]
+    fn expand_path_handles_tilde_and_variables() {
+        let home = dirs::home_dir().unwrap();
+        assert_eq!(expand_path("~/x"), home.join("x"));
+        assert_eq!(expand_path("$HOME/x"), home.join("x"));
+        assert_eq!(expand_path("${HOME}/x"), home.join("x"));
+        assert_eq!(expand_path("~ask-surely-no-such-user/x"), PathBuf::from("~ask-surely-no-such-user/x"));
+        assert_eq!(expand_path("~root/x"), user_home("root").unwrap().join("x"));
+        assert_eq!(expand_path("$ASK_SURELY_UNSET_VAR/x"), PathBuf::from("$ASK_SURELY_UNSET_VAR/x"));
+    }
+
+    #[test]
+    fn head_branch_reads_refs_and_detached_heads() {
+        assert_eq!(head_branch("ref: refs/heads/main\n").as_deref(), Some("main"));
+        assert_eq!(head_branch("ref: refs/heads/feature/login\n").as_deref(), Some("feature/login"));
+        assert_eq!(head_branch("3f2c9a1b7e0d4c5a6b8e9f0a1b2c3d4e5f6a7b8c\n").as_deref(), Some("3f2c9a1"));
+        assert_eq!(head_branch(""), None);
+    }
+
+    #[test]
+    fn git_prompt_finds_the_work_tree_above_the_directory() {
+        let dir = env::temp_dir().join(format!("ask-git-prompt-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("repo/.git")).unwrap();
+        fs::create_dir_all(dir.join("repo/src/deep")).unwrap();
+        fs::write(dir.join("repo/.git/HEAD"), "ref: refs/heads/topic\n").unwrap();
+        // A worktree's .git is a file naming the real one
+        fs::create_dir_all(dir.join("linked")).unwrap();
+        fs::write(dir.join("linked/.git"), "gitdir: ../repo/.git\n").unwrap();
+        assert_eq!(find_git_dir(&dir.join("repo/src/deep")), Some(dir.join("repo/.git")));
+        assert_eq!(find_git_dir(&dir.join("linked")), Some(dir.join("linked/../repo/.git")));
+        assert_eq!(git_prompt(&dir.join("repo/src")).map(|git| git.branch).as_deref(), Some("topic"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn git_dirty_notices_changes_and_the_cache_looks_again_after_a_command() {
+        let dir = env::temp_dir().join(format!("ask-git-dirty-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let init = Command::new("git").arg("init").arg("-q").current_dir(&dir).status();
+        if !init.is_ok_and(|status| status.success()) {
+            return; // no git here
+        }
+        assert_eq!(git_dirty(&dir, Duration::from_secs(5)), Some(false));
+        let mut cache = GitPromptCache::default();
+        assert!(!cache.lookup(&dir).unwrap().dirty);
+        fs::write(dir.join("new.txt"), "x").unwrap();
+        assert_eq!(git_dirty(&dir, Duration::from_secs(5)), Some(true));
+        run_command_with_output("true").unwrap();
+        assert!(cache.lookup(&dir).unwrap().dirty);
+        assert_eq!(git_dirty(&dir, Duration::ZERO), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn prompt_label_shows_the_branch_and_dirty_mark() {
+        let plain = Theme::plain();
+        assert_eq!(prompt_label("Projects", None, &plain), "ask [Projects]>");
+        let git = GitPrompt { branch: "main".to_string(), dirty: true };
+        assert_eq!(prompt_label("myrepo", Some(&git), &plain), "ask [myrepo on main*]>");
+        let clean = GitPrompt { dirty: false, ..git };
+        assert_eq!(prompt_label("myrepo", Some(&clean), &plain), "ask [myrepo on main]>");
+    }
+
+    #[test]
+    fn cd_target_handles_home_previous_and_quotes() {
+        let home = dirs::home_dir().unwrap();
+        let previous = Path::new("/tmp/before");
+        assert_eq!(cd_target("", None).unwrap(), home);
+        assert_eq!(cd_target(" - ", Some(previous)).unwrap(), previous);
+        assert_eq!(cd_target("-", None).unwrap_err(), "cd: OLDPWD not set");
+        assert_eq!(cd_target("\"~/my projects\"", None).unwrap(), home.join("my projects"));
+        assert_eq!(cd_target("'${HOME}/work'", None).unwrap(), home.join("work"));
+        assert_eq!(cd_target("src", None).unwrap(), PathBuf::from("src"));
+    }
+
+    #[test]
+    fn cd_error_uses_the_shell_wording() {
+        let path = Path::new("nope");
+        assert_eq!(cd_error(path, &io::ErrorKind::NotFound.into()), "cd: no such file or directory: nope");
+        assert_eq!(cd_error(path, &io::ErrorKind::NotADirectory.into()), "cd: not a directory: nope");
+        let missing = env::temp_dir().join(format!("ask-cd-missing-{}", std::process::id()));
+        let err = env::set_current_dir(&missing).unwrap_err();
+        assert_eq!(cd_error(&missing, &err), format!("cd: no such file or directory: {}", missing.display()));
+    }
+
+    #[test]
+    fn resolve_finder_path_suggests_close_matches() {
+        let dir = env::temp_dir().join(format!("ask-finder-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("screenshot.png"), "").unwrap();
+        assert_eq!(resolve_finder_path(dir.join("screenshot.png").to_str().unwrap()).unwrap(), dir.join("screenshot.png"));
+        let err = resolve_finder_path(dir.join("screnshot.png").to_str().unwrap()).unwrap_err();
+        assert!(err.contains("did you mean") && err.contains("screenshot.png"), "{err}");
+        let err = resolve_finder_path(dir.join("zzz").to_str().unwrap()).unwrap_err();
+        assert!(!err.contains("did you mean"), "{err}");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn last_output_path_finds_the_newest_existing_path() {
+        let dir = env::temp_dir().join(format!("ask-finder-last-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let shot = dir.join("shot.png");
+        fs::write(&shot, "").unwrap();
+        let mut ctx = turn("where did it go", None);
+        ctx.outputs = vec![format!("Saved to '{}'.\nmissing: /no/such/file.png\n", shot.display())];
+        assert_eq!(last_output_path(&[ctx]), Some(shot));
+        assert_eq!(last_output_path(&[turn("nothing", None)]), None);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn reveal_command_highlights_files_on_macos_and_opens_parent_elsewhere() {
+        let args = |c: &Command| c.get_args().map(|a| a.to_string_lossy().to_string()).collect::<Vec<_>>();
+        let mac = reveal_command("macos", Path::new("/tmp/a.png"), false);
+        assert_eq!(mac.get_program(), "open");
+        assert_eq!(args(&mac), ["-R", "/tmp/a.png"]);
+        assert_eq!(args(&reveal_command("macos", Path::new("/tmp"), true)), ["/tmp"]);
+        let linux = reveal_command("linux", Path::new("/tmp/a.png"), false);
+        assert_eq!(linux.get_program(), "xdg-open");
+        assert_eq!(args(&linux), ["/tmp"]);
+        assert_eq!(args(&reveal_command("linux", Path::new("a.png"), false)), ["."]);
+    }
+
+    // --- recent directories ---
+
+    #[test]
+    fn parse_recent_forms() {
+        assert_eq!(parse_recent("recent"), Some(Ok(RecentCommand::Menu)));
+        assert_eq!(parse_recent("cd!"), Some(Ok(RecentCommand::Menu)));
+        assert_eq!(parse_recent("cd =2"), Some(Ok(RecentCommand::Pick(2))));
+        assert!(matches!(parse_recent("cd =0"), Some(Err(_))));
+        assert!(matches!(parse_recent("cd =x"), Some(Err(_))));
+        assert_eq!(parse_recent("cd src"), None);
+        assert_eq!(parse_recent("recently"), None);
+    }
+
+    #[test]
+    fn recent_dirs_dedupe_cap_and_persist() {
+        let root = env::temp_dir().join(format!("ask-recent-test-{}", std::process::id()));
+        let file = root.join("recent_dirs");
+        let dirs: Vec<PathBuf> = (0..MAX_RECENT_DIRS + 2).map(|i| root.join(format!("d{i}"))).collect();
+        for dir in &dirs {
+            fs::create_dir_all(dir).unwrap();
+        }
+
+        let mut recent = RecentDirs::with_path(Some(file.clone()));
+        for dir in &dirs {
+            recent.visit(dir);
+        }
+        recent.visit(&dirs[5]);
+        recent.visit(&root.join("missing"));
+        assert_eq!(recent.dirs.len(), MAX_RECENT_DIRS);
+        assert_eq!(recent.dirs[0], dirs[5]);
+        assert_eq!(recent.dirs.iter().filter(|d| **d == dirs[5]).count(), 1);
+
+        // The current directory is left out of the menu, which holds nine
+        let reloaded = &mut RecentDirs::with_path(Some(file.clone()));
+        assert_eq!(reloaded.dirs, recent.dirs);
+        let choices = reloaded.choices(&dirs[5]);
+        assert_eq!(choices.len(), 9);
+        assert!(!choices.contains(&dirs[5]));
+
+        // Vanished directories are pruned, on disk too
+        fs::remove_dir(&dirs[11]).unwrap();
+        assert!(!reloaded.choices(&dirs[5]).contains(&dirs[11]));
+        assert!(!fs::read_to_string(&file).unwrap().contains(&dirs[11].display().to_string()));
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn home_relative_uses_tilde() {
+        let home = dirs::home_dir().unwrap();
+        assert_eq!(home_relative(&home), "~");
+        assert_eq!(home_relative(&home.join("src/app")), "~/src/app");
+        assert_eq!(home_relative(Path::new("/definitely/not/home")), "/definitely/not/home");
+    }
+
+    // --- notes ---
+
+    // Noon UTC on 2026-03-10; far enough from midnight for any local zone
+    const NOTES_NOW: i64 = 1_773_144_000;
+
+    #[test]
+    fn parse_notes_command_forms() {
+        let (today, _) = local_time(NOTES_NOW);
+        let day = |date: &str| Some(Ok(NotesCommand::Show(vec![date.to_string()])));
+        assert_eq!(
+            parse_notes_command("note  staging needs the migration first", NOTES_NOW),
+            Some(Ok(NotesCommand::Add("staging needs the migration first".to_string())))
+        );
+        assert!(matches!(parse_notes_command("note", NOTES_NOW), Some(Err(_))));
+        assert_eq!(parse_notes_command("notes", NOTES_NOW), day(&today));
+        assert_eq!(parse_notes_command("notes today", NOTES_NOW), day(&today));
+        assert_eq!(parse_notes_command("notes 2026-01-31", NOTES_NOW), day("2026-01-31"));
+        let Some(Ok(NotesCommand::Show(week))) = parse_notes_command("notes week", NOTES_NOW) else {
+            panic!("notes week should show a range");
+        };
+        assert_eq!((week.len(), week.last()), (7, Some(&today)));
+        assert!(matches!(parse_notes_command("notes 2026-1-31", NOTES_NOW), Some(Err(_))));
+        assert_eq!(parse_notes_command("notepad", NOTES_NOW), None);
+        assert_eq!(parse_notes_command("notes.txt", NOTES_NOW), None);
+    }
+
+    #[test]
+    fn notes_append_per_day_and_are_offered_once() {
+        let dir = env::temp_dir().join(format!("ask-notes-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let notes = Notes { dir: dir.clone() };
+        let yesterday = NOTES_NOW - 86_400;
+        notes.append("staging deploy needs\nthe DB migration first", yesterday).unwrap();
+        notes.append("rotate the API key", yesterday).unwrap();
+
+        let (date, time) = local_time(yesterday);
+        let lines = notes.day(&date);
+        assert_eq!(lines[0], format!("- {time} staging deploy needs the DB migration first"));
+        assert_eq!(lines.len(), 2);
+        assert!(notes.day(&local_time(NOTES_NOW).0).is_empty());
+
+        assert_eq!(notes.unoffered(NOTES_NOW), Some((date, lines)));
+        assert_eq!(notes.unoffered(NOTES_NOW), None, "only the day's first session is asked");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn model_note_lines_are_saved_not_run() {
+        let dir = env::temp_dir().join(format!("ask-model-notes-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let notes = Notes { dir: dir.clone() };
+        let response = vec![
+            "#note: check the backup job tomorrow".to_string(),
+            "# Note: this only lists files".to_string(),
+            "ls".to_string(),
+        ];
+        let rest = take_model_notes(&response, &notes, &Theme::from_mode(ThemeMode::Dark));
+        assert_eq!(rest, response[1..]);
+        let saved = notes.day(&local_time(unix_now()).0);
+        assert!(saved[0].ends_with(" check the backup job tomorrow"), "{saved:?}");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    // --- /model ---
+
+    #[test]
+    fn parse_model_command_shows_switches_and_saves() {
+        assert_eq!(parse_model_command("/model"), Some(Ok(ModelCommand::Show)));
+        assert_eq!(
+            parse_model_command("/model openai/gpt-4o-mini"),
+            Some(Ok(ModelCommand::Switch { model: "openai/gpt-4o-mini".to_string(), save: false }))
+        );
+        assert_eq!(
+            parse_model_command("/model --save x/y"),
+            Some(Ok(ModelCommand::Switch { model: "x/y".to_string(), save: true }))
+        );
+    }
+
+    #[test]
+    fn parse_model_command_rejects_bad_names_and_ignores_other_input() {
+        assert!(parse_model_command("/model two words").unwrap().is_err());
+        assert!(parse_model_command("/model --save").unwrap().is_err());
+        assert!(parse_model_command("/model --save a b").unwrap().is_err());
+        assert_eq!(parse_model_command("/models"), None);
+        assert_eq!(parse_model_command("which model am I using"), None);
+    }
+
+    #[test]
+    fn parse_theme_command_switches_saves_and_rejects_unknown_names() {
+        assert_eq!(parse_theme_command("/theme"), Some(Ok(ThemeCommand::Show)));
+        assert_eq!(
+            parse_theme_command("/theme Solarized"),
+            Some(Ok(ThemeCommand::Switch { mode: ThemeMode::Solarized, save: false }))
+        );
+        assert_eq!(
+            parse_theme_command("/theme --save plain"),
+            Some(Ok(ThemeCommand::Switch { mode: ThemeMode::Plain, save: true }))
+        );
+        assert!(parse_theme_command("/theme neon").unwrap().unwrap_err().contains("high-contrast"));
+        assert!(parse_theme_command("/theme --save").unwrap().is_err());
+        assert_eq!(parse_theme_command("/themes"), None);
+    }
+
+    #[test]
+    fn parse_models_command_takes_an_optional_query() {
+        assert_eq!(parse_models_command("/models"), Some(""));
+        assert_eq!(parse_models_command("/models  llama "), Some("llama"));
+        assert_eq!(parse_models_command("/model llama"), None);
+        assert_eq!(parse_models_command("/modelsx"), None);
+    }
+
+    #[test]
+    fn prompt_file_watch_reloads_on_change_and_falls_back_when_gone() {
+        let path = env::temp_dir().join(format!("ask-prompt-watch-{}.txt", std::process::id()));
+        let touch = |text: &str, secs: u64| {
+            fs::write(&path, text).unwrap();
+            let file = fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)).unwrap();
+        };
+        touch("Use gsed.", 1_000);
+        let mut watch = PromptFileWatch::new(Some(path.clone()));
+        assert_eq!(watch.poll(Some(path.clone())), None);
+
+        touch("Use gsed and gawk.", 2_000);
+        assert_eq!(watch.poll(Some(path.clone())), Some(Ok(CustomPrompt::System("Use gsed and gawk.".to_string()))));
+        assert_eq!(watch.poll(Some(path.clone())), None);
+
+        fs::remove_file(&path).unwrap();
+        assert!(watch.poll(Some(path.clone())).unwrap().unwrap_err().contains("is gone; using the built-in prompt"));
+        assert_eq!(watch.poll(Some(path.clone())), None);
+
+        touch("On {os}, {query}", 3_000);
+        assert_eq!(watch.poll(Some(path.clone())), Some(Ok(CustomPrompt::Template("On {os}, {query}".to_string()))));
+        // Another file (a /profile switch) is only watched from then on
+        assert_eq!(watch.poll(None), None);
+        assert_eq!(watch.path, None);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn parse_profile_command_takes_an_optional_name() {
+        assert_eq!(parse_profile_command("/profile"), Some(None));
+        assert_eq!(parse_profile_command("/profile  jq "), Some(Some("jq")));
+        assert_eq!(parse_profile_command("/profiles"), None);
+        assert_eq!(parse_profile_command("profile jq"), None);
+    }
+
+    // --- JSON output ---
+
+    #[test]
+    fn parse_json_output_takes_one_document_only() {
+        assert!(parse_json_output("  {\"a\": 1}\n").is_some());
+        assert!(parse_json_output("[1, 2]").is_some());
+        for output in ["42", "\"text\"", "{ not json", "{\"a\": 1}\nwarning: deprecated", "", "ok"] {
+            assert_eq!(parse_json_output(output), None, "{output:?}");
+        }
+    }
+
+    #[test]
+    fn big_json_output_is_summarized_for_the_model_and_kept_whole() {
+        let small = "{\"kind\": \"List\"}\n";
+        assert_eq!(compact_json_output(small), None);
+        assert_eq!(compact_json_output(&"plain text ".repeat(300)), None);
+
+        let items: Vec<serde_json::Value> =
+            (0..100).map(|i| serde_json::json!({"name": format!("pod-{i}"), "ready": true})).collect();
+        let big = serde_json::json!({"apiVersion": "v1", "items": items, "kind": "List"}).to_string();
+        let kept = compact_json_output(&big).unwrap();
+        assert!(kept.len() < JSON_SUMMARY_BYTES, "{kept}");
+        assert!(kept.starts_with(&format!("JSON object ({} bytes)", big.len())));
+        assert!(kept.contains("  items: array of 100\n"));
+        assert!(kept.contains("  kind: \"List\"\n"));
+        assert!(kept.contains("sample: {\"apiVersion\"") && kept.ends_with('…'));
+
+        // History holds the output as printed, and `json` sees every item
+        let turns = [output_turn(&big), output_turn("done\n")];
+        let messages = turns[0].messages(&redaction());
+        assert!(messages.last().unwrap().content.contains("JSON object ("));
+        let (last, bytes) = newest_json_output(&turns).unwrap();
+        assert_eq!(bytes, big.len());
+        assert_eq!(query_json(&last, &[JsonStep::Key("items".into()), JsonStep::Index(-1)]).unwrap()["name"], "pod-99");
+        assert!(newest_json_output(&turns[1..]).is_none());
+    }
+
+    #[test]
+    fn json_summary_of_an_array_lists_item_keys() {
+        let value = serde_json::json!([{"id": 1, "title": "x"}, {"id": 2, "title": "y"}]);
+        let summary = json_summary(&value, 5000);
+        assert!(summary.starts_with("JSON array of 2 items (5000 bytes), summarized:\n  item keys: id, title\n"));
+        assert!(summary.ends_with("sample: [{\"id\":1,\"title\":\"x\"},{\"id\":2,\"title\":\"y\"}]"));
+    }
+
+    #[test]
+    fn render_json_matches_pretty_printing_without_color() {
+        let value = serde_json::json!({"a": [1, {"b": null}], "c": "d", "e": {}, "f": []});
+        assert_eq!(render_json(&value, &Theme::plain()), serde_json::to_string_pretty(&value).unwrap() + "\n");
+        let dark = Theme::from_mode(ThemeMode::Dark);
+        assert!(render_json(&value, &dark).contains(&dark.command_text("\"d\"")));
+    }
+
+    #[test]
+    fn parse_json_command_forms() {
+        assert_eq!(parse_json_command("json"), Some(Ok(JsonCommand::Show)));
+        assert_eq!(
+            parse_json_command("json path .items[0].name"),
+            Some(Ok(JsonCommand::Path(vec![
+                JsonStep::Key("items".into()),
+                JsonStep::Index(0),
+                JsonStep::Key("name".into())
+            ])))
+        );
+        assert_eq!(parse_json_command("json path ."), Some(Ok(JsonCommand::Path(vec![]))));
+        assert_eq!(parse_json_command("json path [-1][2]"), Some(Ok(JsonCommand::Path(vec![JsonStep::Index(-1), JsonStep::Index(2)]))));
+        for bad in ["json path", "json path items", "json path .a..b", "json path .a[x]", "json path .a[1", "json pathway", "json .a"] {
+            assert!(matches!(parse_json_command(bad), Some(Err(_))), "{bad}");
+        }
+        assert_eq!(parse_json_command("jsonify this"), None);
+    }
+
+    #[test]
+    fn query_json_walks_keys_and_indexes() {
+        let value = serde_json::json!({"items": [{"name": "a"}, {"name": "b"}], "n": 3});
+        let path = |p: &str| parse_json_path(p).unwrap();
+        assert_eq!(query_json(&value, &path(".items[1].name")).unwrap(), "b");
+        assert_eq!(query_json(&value, &path(".items[-2].name")).unwrap(), "a");
+        assert_eq!(query_json(&value, &path(".")).unwrap(), &value);
+        assert_eq!(query_json(&value, &path(".nope")).unwrap_err(), "No key 'nope'");
+        assert_eq!(query_json(&value, &path(".items[2]")).unwrap_err(), "Index 2 is out of range (2 items)");
+        assert_eq!(query_json(&value, &path(".items[-3]")).unwrap_err(), "Index -3 is out of range (2 items)");
+        assert!(query_json(&value, &path(".n.x")).is_err());
+        assert!(query_json(&value, &path(".n[0]")).is_err());
+    }
+
+    // --- history rerun ---
+
+    #[test]
+    fn parse_history_listing_forms() {
+        assert_eq!(parse_history_listing("history"), Some(Ok(HistoryRange::Recent(HISTORY_PAGE))));
+        assert_eq!(parse_history_listing("history 50"), Some(Ok(HistoryRange::Recent(50))));
+        assert_eq!(parse_history_listing("history all"), Some(Ok(HistoryRange::All)));
+        assert_eq!(parse_history_listing("history 30..60"), Some(Ok(HistoryRange::Span(30, 60))));
+        assert!(matches!(parse_history_listing("history 60..30"), Some(Err(_))));
+        assert!(matches!(parse_history_listing("history 0"), Some(Err(_))));
+        assert!(parse_history_listing("history rerun 3").is_none());
+        assert!(parse_history_listing("historyx").is_none());
+        assert_eq!(parse_history_listing("/history 5"), Some(Ok(HistoryRange::Recent(5))));
+        assert_eq!(parse_history_listing("history full 3"), Some(Ok(HistoryRange::Full(3))));
+        for bad in ["history full", "history full 0", "history full 3 4", "history full x"] {
+            assert!(matches!(parse_history_listing(bad), Some(Err(_))), "{bad}");
+        }
+    }
+
+    #[test]
+    fn history_shows_how_each_command_ended() {
+        let ctx = ConversationContext {
+            prompt: "build it".to_string(),
+            commands: vec!["make".to_string(), "make test".to_string(), "sleep 9".to_string()],
+            outputs: vec![
+                "cc main.c\nok\n".to_string(),
+                "1 failed\n2 passed\n".to_string(),
+                format!("partial\n{INTERRUPTED}"),
+            ],
+            exit_codes: vec![Some(0), Some(2), None],
+            request: None,
+            pinned: false,
+            forgotten: false,
+        };
+        let listing = render_history(std::slice::from_ref(&ctx), &HistoryRange::All, &TaskLog::default(), &Theme::plain());
+        assert_eq!(
+            listing,
+            "[1] User: build it\n\
+             \x20   Command: make (exit 0)\n\
+             \x20   Output: cc main.c\n\
+             \x20           ... (1 more lines)\n\
+             \x20   Command: make test (exit 2)\n\
+             \x20   Output: 1 failed\n\
+             \x20           ... (1 more lines)\n\
+             \x20   Command: sleep 9 (interrupted)\n\
+             \x20   Output: partial\n\
+             \x20           ... (1 more lines)\n"
+        );
+        // `history full N` keeps every line
+        let full = render_history(&[ctx], &HistoryRange::Full(1), &TaskLog::default(), &Theme::plain());
+        assert!(full.contains("Output: 1 failed\n            2 passed\n    Command: sleep 9"), "{full}");
+        // Colored, a failure stands out from a success
+        let dark = Theme::from_mode(ThemeMode::Dark);
+        let failed = ConversationContext {
+            prompt: "x".to_string(),
+            commands: vec!["false".to_string()],
+            outputs: vec![String::new()],
+            exit_codes: vec![Some(1)],
+            request: None,
+            pinned: false,
+            forgotten: false,
+        };
+        let colored = format_turn(1, &failed, None, &dark);
+        assert!(colored.contains(&dark.warning_text("exit 1")), "{colored:?}");
+    }
+
+    #[test]
+    fn history_pages_keep_absolute_turn_numbers() {
+        let history: Vec<ConversationContext> = (1..=150).map(|i| turn(&format!("prompt {i}"), None)).collect();
+        let page = render_history(&history, &HistoryRange::Recent(HISTORY_PAGE), &TaskLog::default(), &Theme::plain());
+        assert_eq!(page.lines().filter(|l| l.starts_with('[')).count(), 20);
+        assert!(page.starts_with("[131] User: prompt 131\n"), "{page}");
+        assert!(page.ends_with("(… 130 earlier turns — `history 50` or `history all`)\n"), "{page}");
+
+        let span = render_history(&history, &HistoryRange::Span(30, 32), &TaskLog::default(), &Theme::plain());
+        assert_eq!(span, "[30] User: prompt 30\n[31] User: prompt 31\n[32] User: prompt 32\n");
+        assert!(!render_history(&history, &HistoryRange::All, &TaskLog::default(), &Theme::plain()).contains("earlier turns"));
+        assert!(render_history(&history, &HistoryRange::Span(151, 160), &TaskLog::default(), &Theme::plain()).starts_with("No turns"));
+    }
+
+    #[test]
+    fn format_turn_caps_output_lines_only_when_asked() {
+        let ctx = ConversationContext {
+            prompt: "disk usage".to_string(),
+            commands: vec!["df -h".to_string()],
+            outputs: vec!["Filesystem Size\n/dev/disk1 500G\n/dev/disk2 1T\n".to_string()],
+            exit_codes: vec![],
+            request: None,
+            pinned: false,
+            forgotten: false,
+        };
+        assert_eq!(
+            format_turn(9, &ctx, Some(1), &Theme::plain()),
+            "[9] User: disk usage\n    Command: df -h (exit 0)\n    Output: Filesystem Size\n            ... (2 more lines)\n"
+        );
+        assert!(format_turn(9, &ctx, None, &Theme::plain()).ends_with("            /dev/disk2 1T\n"));
+    }
+
+    // --- panic boundary ---
+
+    fn panicking_analyzer(_: &[ConversationContext], _: &RequestSettings) -> Option<String> {
+        panic!("analyzer exploded");
+    }
+
+    fn echo_analyzer(history: &[ConversationContext], _: &RequestSettings) -> Option<String> {
+        Some(format!("{} turns", history.len()))
+    }
+
+    #[test]
+    fn failed_direct_commands_are_kept_with_their_status() {
+        let output = run_command_with_output("echo oops >&2; exit 4").unwrap();
+        let turn = direct_turn("run it", "echo oops >&2; exit 4", &output);
+        assert_eq!(turn.exit_codes, [Some(4)]);
+        let last = turn.last_command().unwrap();
+        assert_eq!((last.output.trim(), last.status.as_str()), ("oops", "exit 4"));
+        assert_eq!(direct_turn("ls", "ls", &CommandOutput::success("a\n")).exit_codes, [Some(0)]);
+    }
+
+    #[test]
+    fn panicking_analyzer_does_not_end_the_session() {
+        let mut boundary = FeatureBoundary::new();
+        let analyzers: &[(&str, Analyzer)] = &[("boom", panicking_analyzer), ("echo", echo_analyzer)];
+        let settings = retry_settings(DEFAULT_API_URL.to_string(), 1);
+        let history = vec![ConversationContext {
+            prompt: "ls".to_string(),
+            commands: vec!["ls".to_string()],
+            outputs: vec![],
+            exit_codes: vec![],
+            request: None,
+            pinned: false,
+            forgotten: false,
+        }];
+
+        let notes = run_analyzers(analyzers, &history, &settings, &mut boundary);
+        assert_eq!(notes, vec!["1 turns"]);
+        assert_eq!(boundary.failures.len(), 1);
+        assert_eq!(boundary.failures[0].0, "boom");
+        assert!(boundary.failures[0].1.contains("analyzer exploded"));
+
+        // The next turn still runs every analyzer.
+        boundary.failures.clear();
+        let notes = run_analyzers(analyzers, &history, &settings, &mut boundary);
+        assert_eq!(notes, vec!["1 turns"]);
+    }
+
+    #[test]
+    fn feature_boundary_passes_through_values() {
+        let mut boundary = FeatureBoundary::new();
+        assert_eq!(boundary.run("ok", || 42), Some(42));
+        assert!(boundary.failures.is_empty());
+    }
+}
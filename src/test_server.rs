@@ -0,0 +1,88 @@
+//! A local HTTP server standing in for the model API. The unit tests reach
+//! it through test_support; tests/api.rs includes this file by path.
+
+// Each test crate uses a different part of it
+#![allow(dead_code)]
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+/// One request as the server saw it.
+pub struct Recorded {
+    pub request_line: String,
+    // Header names lowercased
+    pub headers: Vec<(String, String)>,
+    pub body: serde_json::Value,
+}
+
+impl Recorded {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+}
+
+/// Serves one canned HTTP response per connection, in order, and records
+/// every request. Returns the chat completions URL.
+pub fn fake_api(responses: Vec<String>) -> (String, Arc<Mutex<Vec<Recorded>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/v1/chat/completions", listener.local_addr().unwrap());
+    let recorded = Arc::new(Mutex::new(Vec::new()));
+    let log = recorded.clone();
+    std::thread::spawn(move || {
+        for response in responses {
+            let Ok((mut stream, _)) = listener.accept() else { return };
+            // Read the whole request so the client never sees a reset
+            let Some(request) = read_request(&mut stream) else { return };
+            log.lock().unwrap().push(request);
+            let _ = stream.write_all(response.as_bytes());
+        }
+    });
+    (url, recorded)
+}
+
+/// Reads one request off `stream`, or None once the client has hung up
+/// without sending anything. A request cut short is returned as far as it
+/// got.
+pub fn read_request(stream: &mut TcpStream) -> Option<Recorded> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = stream.read(&mut buf).ok()?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+        let text = String::from_utf8_lossy(&request);
+        if let Some(end) = text.find("\r\n\r\n") {
+            let length = text[..end]
+                .lines()
+                .find_map(|l| l.to_ascii_lowercase().strip_prefix("content-length:").map(|v| v.trim().to_string()))
+                .and_then(|v| v.parse::<usize>().ok())
+                .unwrap_or(0);
+            if request.len() >= end + 4 + length {
+                break;
+            }
+        }
+    }
+    if request.is_empty() {
+        return None;
+    }
+    let text = String::from_utf8_lossy(&request);
+    let (head, body) = text.split_once("\r\n\r\n").unwrap_or((&text, ""));
+    let mut lines = head.lines();
+    let request_line = lines.next().unwrap_or_default().to_string();
+    let headers = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(n, v)| (n.trim().to_ascii_lowercase(), v.trim().to_string()))
+        .collect();
+    let body = serde_json::from_str(body).unwrap_or(serde_json::Value::Null);
+    Some(Recorded { request_line, headers, body })
+}
+
+pub fn http_response(status: &str, headers: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n{headers}\r\n{body}",
+        body.len()
+    )
+}
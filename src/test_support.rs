@@ -0,0 +1,201 @@
+//! Helpers shared by the unit tests of several modules.
+
+use crate::api::*;
+use crate::history::*;
+use crate::safety::*;
+use crate::exec::*;
+pub(crate) use crate::test_server::*;
+use serde_json::json;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::fs;
+use std::io::Read;
+use std::os::unix::io::AsRawFd;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+pub(crate) fn redaction() -> Redaction {
+    Redaction::new(true, &[])
+}
+
+pub(crate) fn turn(prompt: &str, model: Option<&str>) -> ConversationContext {
+    ConversationContext {
+        prompt: prompt.to_string(),
+        commands: vec![],
+        outputs: vec![],
+        exit_codes: vec![],
+        request: model.map(|m| TurnRequest {
+            model: m.to_string(),
+            api_url: DEFAULT_API_URL.to_string(),
+            response: vec!["ls".to_string()],
+        }),
+        pinned: false,
+        forgotten: false,
+    }
+}
+
+/// A direct turn where `cat README` printed `output`.
+pub(crate) fn output_turn(output: &str) -> ConversationContext {
+    ConversationContext {
+        prompt: "show the readme".to_string(),
+        commands: vec!["cat README".to_string()],
+        outputs: vec![output.to_string()],
+        exit_codes: vec![],
+        request: None,
+        pinned: false,
+        forgotten: false,
+    }
+}
+
+pub(crate) fn completion(content: &str) -> String {
+    http_response("200 OK", "", &json!({ "choices": [{ "message": { "content": content } }] }).to_string())
+}
+
+pub(crate) fn retry_settings(url: String, max_attempts: u32) -> RequestSettings {
+    RequestSettings {
+        model: "m".to_string(),
+        endpoint: Endpoint::new(url, None),
+        platform: Platform { os: "Linux".to_string(), shell: "Bash".to_string() },
+        redaction: redaction(),
+        temperature: None,
+        top_p: None,
+        max_tokens: None,
+        max_attempts,
+        refusal_patterns: Vec::new(),
+        fix_attempts: 0,
+        context: None,
+        extra_prompt: None,
+        custom_prompt: None,
+        fallback_models: Vec::new(),
+        context_window: None,
+        auto_context: false,
+        structured: false,
+        tools: true,
+    }
+}
+
+/// The system allocator, counting the bytes each thread has live so a
+/// test can measure its own code while other tests run alongside it.
+pub(crate) struct CountingAllocator;
+
+thread_local! {
+    static HEAP_LIVE: Cell<isize> = const { Cell::new(0) };
+    static HEAP_PEAK: Cell<isize> = const { Cell::new(0) };
+}
+
+fn count_heap(delta: isize) {
+    let _ = HEAP_LIVE.try_with(|live| {
+        let now = live.get() + delta;
+        live.set(now);
+        let _ = HEAP_PEAK.try_with(|peak| peak.set(peak.get().max(now)));
+    });
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            count_heap(layout.size() as isize);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        count_heap(-(layout.size() as isize));
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = unsafe { System.realloc(ptr, layout, new_size) };
+        if !new.is_null() {
+            count_heap(new_size as isize - layout.size() as isize);
+        }
+        new
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Runs `f` and returns the most heap it had live on this thread at once,
+/// over what was live before it started.
+pub(crate) fn peak_heap_during<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let start = HEAP_LIVE.with(Cell::get);
+    HEAP_PEAK.with(|peak| peak.set(start));
+    let result = f();
+    let peak = HEAP_PEAK.with(Cell::get);
+    (result, (peak - start).max(0) as usize)
+}
+
+/// A pseudo-terminal standing in for the window ask runs in.
+pub(crate) struct Pty {
+    // The only handle on the master side, so dropping it hangs up
+    pub(crate) master: Option<fs::File>,
+    pub(crate) slave: fs::File,
+    pub(crate) output: String,
+}
+
+impl Pty {
+    pub(crate) fn open() -> Self {
+        use std::os::fd::FromRawFd;
+        use std::os::unix::fs::OpenOptionsExt;
+        // Close-on-exec from the start, or commands spawned by other
+        // tests meanwhile would hold the master open
+        let master = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC) };
+        assert!(master >= 0, "posix_openpt failed");
+        let master = unsafe { fs::File::from_raw_fd(master) };
+        assert_eq!(unsafe { libc::grantpt(master.as_raw_fd()) }, 0);
+        assert_eq!(unsafe { libc::unlockpt(master.as_raw_fd()) }, 0);
+        let name = unsafe { std::ffi::CStr::from_ptr(libc::ptsname(master.as_raw_fd())) };
+        let slave = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(name.to_str().unwrap())
+            .unwrap();
+        Self { master: Some(master), slave, output: String::new() }
+    }
+
+    /// Runs `script` with this terminal as its controlling terminal,
+    /// the way a shell in the window would.
+    pub(crate) fn spawn(&self, script: &str, detach: bool) -> std::process::Child {
+        use std::os::unix::process::CommandExt;
+        let mut command = Command::new("/bin/sh");
+        command
+            .arg("-c")
+            .arg(script)
+            .stdin(self.slave.try_clone().unwrap())
+            .stdout(self.slave.try_clone().unwrap())
+            .stderr(self.slave.try_clone().unwrap());
+        if detach {
+            detach_on_hangup(&mut command);
+        }
+        unsafe {
+            command.pre_exec(|| {
+                libc::setsid();
+                libc::ioctl(0, libc::TIOCSCTTY, 0);
+                Ok(())
+            });
+        }
+        command.spawn().unwrap()
+    }
+
+    pub(crate) fn wait_for(&mut self, text: &str) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let master = self.master.as_mut().unwrap();
+        while !self.output.contains(text) {
+            assert!(Instant::now() < deadline, "never saw {text:?} in {:?}", self.output);
+            let mut poll = libc::pollfd { fd: master.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+            if unsafe { libc::poll(&mut poll, 1, 50) } == 1 {
+                let mut buf = [0u8; 256];
+                let n = master.read(&mut buf).unwrap_or(0);
+                self.output.push_str(&String::from_utf8_lossy(&buf[..n]));
+            }
+        }
+    }
+
+    /// Closes the window: the kernel hangs up the child's session.
+    pub(crate) fn close(&mut self) {
+        self.master = None;
+    }
+}
@@ -0,0 +1,664 @@
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ThemeMode {
+    Light,
+    Dark,
+    Solarized,
+    HighContrast,
+    // No colors, even on a terminal
+    Plain,
+}
+
+impl ThemeMode {
+    pub(crate) fn from_str(value: &str) -> Option<Self> {
+        let value = value.to_lowercase();
+        PALETTES.iter().find(|palette| palette.name == value).map(|palette| palette.mode)
+    }
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        self.palette().name
+    }
+
+    pub(crate) fn palette(&self) -> &'static Palette {
+        PALETTES.iter().find(|palette| palette.mode == *self).unwrap_or(&PALETTES[0])
+    }
+}
+
+/// A built-in theme: the name --theme and /theme take, and its colors.
+pub(crate) struct Palette {
+    pub(crate) mode: ThemeMode,
+    pub(crate) name: &'static str,
+    pub(crate) theme: Theme,
+}
+
+const fn palette(mode: ThemeMode, name: &'static str, codes: [&'static str; 5]) -> Palette {
+    let [helper_color, command_color, prompt_color, warning_color, hint_color] = codes;
+    Palette {
+        mode,
+        name,
+        theme: Theme {
+            helper_color: Cow::Borrowed(helper_color),
+            command_color: Cow::Borrowed(command_color),
+            prompt_color: Cow::Borrowed(prompt_color),
+            warning_color: Cow::Borrowed(warning_color),
+            hint_color: Cow::Borrowed(hint_color),
+        },
+    }
+}
+
+// Helper, command, prompt, warning and hint colors of each theme
+pub(crate) const PALETTES: &[Palette] = &[
+    palette(ThemeMode::Dark, "dark", [
+        "\u{001b}[36;1m",
+        "\u{001b}[93m",
+        "\u{001b}[92m",      // bright green - distinct from regular text
+        "\u{001b}[1;97;41m", // bold bright white on red
+        "\u{001b}[2;37m",    // dim white
+    ]),
+    palette(ThemeMode::Light, "light", [
+        "\u{001b}[35m",
+        "\u{001b}[31m",
+        "\u{001b}[34m",
+        "\u{001b}[1;37;41m", // bold white on red
+        "\u{001b}[90m",      // gray
+    ]),
+    // Solarized accents in 256 colors: cyan, yellow, blue, base3 on red, base01
+    palette(ThemeMode::Solarized, "solarized", [
+        "\u{001b}[38;5;37m",
+        "\u{001b}[38;5;136m",
+        "\u{001b}[38;5;33m",
+        "\u{001b}[1;38;5;230;48;5;160m",
+        "\u{001b}[38;5;240m",
+    ]),
+    // Bold bright colors only; hints are bright cyan rather than dim
+    palette(ThemeMode::HighContrast, "high-contrast", [
+        "\u{001b}[1;97m",
+        "\u{001b}[1;93m",
+        "\u{001b}[1;96m",
+        "\u{001b}[1;97;41m",
+        "\u{001b}[96m",
+    ]),
+    palette(ThemeMode::Plain, "plain", ["", "", "", "", ""]),
+];
+
+// What --theme accepts, for completions
+pub(crate) const THEME_NAMES: [&str; PALETTES.len()] = {
+    let mut names = [""; PALETTES.len()];
+    let mut i = 0;
+    while i < PALETTES.len() {
+        names[i] = PALETTES[i].name;
+        i += 1;
+    }
+    names
+};
+
+/// ANSI colors for each kind of output. Every color is empty in the plain
+/// theme, and text then passes through untouched.
+#[derive(Clone)]
+pub struct Theme {
+    pub(crate) helper_color: Cow<'static, str>,
+    pub(crate) command_color: Cow<'static, str>,
+    pub(crate) prompt_color: Cow<'static, str>,
+    pub(crate) warning_color: Cow<'static, str>,
+    // History suggestions behind the cursor, muted next to typed text
+    pub(crate) hint_color: Cow<'static, str>,
+}
+
+// Config keys that override one color of the theme, in Theme field order
+pub(crate) const COLOR_KEYS: [&str; 5] = ["helper_color", "command_color", "prompt_color", "warning_color", "hint_color"];
+
+// The eight ANSI colors; `bright-` adds 60 and gray is bright black
+const COLOR_NAMES: [&str; 8] = ["black", "red", "green", "yellow", "blue", "magenta", "cyan", "white"];
+
+// Attributes that may come before a color, as in `bold bright-blue`
+const COLOR_ATTRIBUTES: [(&str, u8); 4] = [("bold", 1), ("dim", 2), ("italic", 3), ("underline", 4)];
+
+/// The escape sequence for a `*_color` setting. Takes a color name
+/// (`red`, `bright-blue`, `gray`, optionally after `bold`, `dim`, `italic`
+/// or `underline`), `#rrggbb` for truecolor, or raw SGR parameters such as
+/// `38;5;245` for a 256-color index.
+pub(crate) fn parse_color(spec: &str) -> Result<String, String> {
+    let spec = spec.trim();
+    let params = if let Some(hex) = spec.strip_prefix('#') {
+        if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("'{spec}' is not a #rrggbb color"));
+        }
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap_or_default();
+        format!("38;2;{};{};{}", channel(0), channel(2), channel(4))
+    } else if spec.starts_with(|c: char| c.is_ascii_digit()) {
+        let valid = spec.split(';').all(|part| !part.is_empty() && part.parse::<u8>().is_ok());
+        if !valid {
+            return Err(format!("'{spec}' is not a list of SGR numbers 0-255 such as 38;5;245"));
+        }
+        spec.to_string()
+    } else {
+        let mut codes = Vec::new();
+        let words: Vec<&str> = spec.split_whitespace().collect();
+        let Some((color, attributes)) = words.split_last() else {
+            return Err("the color is empty".to_string());
+        };
+        for attribute in attributes {
+            let lower = attribute.to_lowercase();
+            match COLOR_ATTRIBUTES.iter().find(|(name, _)| *name == lower) {
+                Some((_, code)) => codes.push(code.to_string()),
+                None => return Err(format!("unknown attribute '{attribute}' (try bold, dim, italic or underline)")),
+            }
+        }
+        let lower = color.to_lowercase();
+        let (base, name) = match lower.strip_prefix("bright-") {
+            Some(name) => (90, name),
+            None if lower == "gray" || lower == "grey" => (90, "black"),
+            None => (30, lower.as_str()),
+        };
+        match COLOR_NAMES.iter().position(|known| *known == name) {
+            Some(index) => codes.push((base + index).to_string()),
+            None => return Err(format!("unknown color '{color}' (try a name like bright-blue, #rrggbb or 38;5;N)")),
+        }
+        codes.join(";")
+    };
+    Ok(format!("\u{001b}[{params}m"))
+}
+
+pub(crate) const RESET: &str = "\u{001b}[0m";
+
+impl Theme {
+    pub(crate) fn from_mode(mode: ThemeMode) -> Self {
+        mode.palette().theme.clone()
+    }
+
+    /// This theme with the roles in `colors` (a COLOR_KEYS key and an
+    /// escape sequence from parse_color) recolored.
+    pub(crate) fn with_colors(mut self, colors: &[(&str, String)]) -> Self {
+        for (key, color) in colors {
+            let field = match *key {
+                "helper_color" => &mut self.helper_color,
+                "command_color" => &mut self.command_color,
+                "prompt_color" => &mut self.prompt_color,
+                "warning_color" => &mut self.warning_color,
+                "hint_color" => &mut self.hint_color,
+                _ => continue,
+            };
+            *field = Cow::Owned(color.clone());
+        }
+        self
+    }
+
+    /// No escape sequences at all: for NO_COLOR, --no-color, and output
+    /// that isn't a terminal.
+    pub fn plain() -> Self {
+        Self::from_mode(ThemeMode::Plain)
+    }
+
+    pub(crate) fn paint(color: &str, text: &str) -> String {
+        if color.is_empty() {
+            return text.to_string();
+        }
+        format!("{}{}{}", color, text, RESET)
+    }
+
+    pub(crate) fn helper_text(&self, text: &str) -> String {
+        Self::paint(&self.helper_color, text)
+    }
+
+    pub(crate) fn command_text(&self, text: &str) -> String {
+        Self::paint(&self.command_color, text)
+    }
+
+    pub(crate) fn prompt_text(&self, text: &str) -> String {
+        Self::paint(&self.prompt_color, text)
+    }
+
+    pub(crate) fn warning_text(&self, text: &str) -> String {
+        Self::paint(&self.warning_color, text)
+    }
+
+    pub(crate) fn hint_text(&self, text: &str) -> String {
+        Self::paint(&self.hint_color, text)
+    }
+}
+
+/// The command-line side of the color decision.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct ColorFlags {
+    pub(crate) no_color: bool,
+    pub(crate) force_color: bool,
+    // --json, --print and --stdin-commands output is read by other tools
+    pub(crate) machine_output: bool,
+}
+
+/// NO_COLOR and ASK_FORCE_COLOR as read from the environment.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct ColorEnv<'a> {
+    pub(crate) no_color: Option<&'a str>,
+    pub(crate) force_color: Option<&'a str>,
+}
+
+/// Whether the Theme gets colors, first rule that applies wins:
+/// machine-readable output is always plain, then --no-color, then
+/// --force-color, then ASK_FORCE_COLOR (anything but "" or "0"), then
+/// NO_COLOR (anything but "", see no-color.org), and otherwise colors
+/// only when stdout is a terminal.
+pub(crate) fn color_enabled(flags: ColorFlags, env: ColorEnv, stdout_is_tty: bool) -> bool {
+    if flags.machine_output || flags.no_color {
+        return false;
+    }
+    if flags.force_color || env.force_color.is_some_and(|v| !v.is_empty() && v != "0") {
+        return true;
+    }
+    env.no_color.is_none_or(str::is_empty) && stdout_is_tty
+}
+
+// OSC 11 asks for the background color; the DA1 query after it is one
+// every terminal answers, so its reply marks the end of the answers
+pub(crate) const BACKGROUND_QUERY: &[u8] = b"\x1b]11;?\x1b\\\x1b[c";
+
+// How long the terminal gets to answer before the default is used
+pub(crate) const BACKGROUND_QUERY_TIMEOUT: Duration = Duration::from_millis(150);
+
+/// The theme for a terminal nobody picked one for: light on a light
+/// background and dark otherwise. The terminal is asked first, then
+/// COLORFGBG is read. Keys typed while the terminal answers are put back.
+pub(crate) fn detect_theme_mode() -> ThemeMode {
+    let asked = fs::OpenOptions::new().read(true).write(true).open("/dev/tty").ok().map(|tty| {
+        let answer = query_background(&tty, BACKGROUND_QUERY_TIMEOUT);
+        push_back_input(&tty, &answer.typeahead);
+        answer.mode
+    });
+    asked
+        .flatten()
+        .or_else(|| std::env::var("COLORFGBG").ok().and_then(|value| colorfgbg_mode(&value)))
+        .unwrap_or(ThemeMode::Dark)
+}
+
+/// What the terminal said about its background, and the bytes that came
+/// in alongside the answers: keys the user typed meanwhile.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct BackgroundAnswer {
+    pub(crate) mode: Option<ThemeMode>,
+    pub(crate) typeahead: Vec<u8>,
+}
+
+/// Sends BACKGROUND_QUERY to `tty` and reads the answers with echo off, so
+/// none of them show up on screen. Nothing is sent while typed input is
+/// already waiting, since that input would be read along with the answers.
+pub(crate) fn query_background(tty: &fs::File, timeout: Duration) -> BackgroundAnswer {
+    let fd = tty.as_raw_fd();
+    let mut saved = unsafe { std::mem::zeroed::<libc::termios>() };
+    if unsafe { libc::tcgetattr(fd, &mut saved) } != 0 {
+        return BackgroundAnswer::default();
+    }
+    let mut pending: libc::c_int = 0;
+    if unsafe { libc::ioctl(fd, libc::FIONREAD, &mut pending) } != 0 || pending > 0 {
+        return BackgroundAnswer::default();
+    }
+    let mut raw = saved;
+    raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+    raw.c_cc[libc::VMIN] = 0;
+    raw.c_cc[libc::VTIME] = 0;
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &raw) } != 0 {
+        return BackgroundAnswer::default();
+    }
+
+    let mut received = Vec::new();
+    if (&*tty).write_all(BACKGROUND_QUERY).is_ok() {
+        let deadline = Instant::now() + timeout;
+        while !terminal_replies(&received).1 {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let mut poll = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+            if left.is_zero() || unsafe { libc::poll(&mut poll, 1, left.as_millis() as libc::c_int) } != 1 {
+                break;
+            }
+            let mut buf = [0u8; 256];
+            match (&*tty).read(&mut buf) {
+                Ok(n) if n > 0 => received.extend_from_slice(&buf[..n]),
+                _ => break,
+            }
+        }
+    }
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &saved) };
+    let (mode, _, typeahead) = terminal_replies(&received);
+    BackgroundAnswer { mode, typeahead }
+}
+
+/// Splits what the terminal sent back into the background it reported,
+/// whether the DA1 reply has arrived, and everything else. A reply cut
+/// off by the timeout is dropped rather than passed on as typed input.
+pub(crate) fn terminal_replies(bytes: &[u8]) -> (Option<ThemeMode>, bool, Vec<u8>) {
+    let mut mode = None;
+    let mut answered = false;
+    let mut rest = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let tail = &bytes[i..];
+        if let Some(body) = tail.strip_prefix(b"\x1b]11;") {
+            // Ended by BEL or ST (ESC \)
+            let Some(end) = body.iter().position(|&b| b == 0x07 || b == 0x1b) else { break };
+            mode = std::str::from_utf8(&body[..end]).ok().and_then(background_mode);
+            i += 5 + end + if body[end] == 0x1b { 2 } else { 1 };
+        } else if let Some(body) = tail.strip_prefix(b"\x1b[?") {
+            let Some(end) = body.iter().position(|b| !b.is_ascii_digit() && *b != b';') else { break };
+            if body[end] == b'c' {
+                answered = true;
+                i += 3 + end + 1;
+            } else {
+                rest.push(bytes[i]);
+                i += 1;
+            }
+        } else {
+            rest.push(bytes[i]);
+            i += 1;
+        }
+    }
+    (mode, answered, rest)
+}
+
+/// Light or dark for an OSC 11 color such as `rgb:ffff/ffff/dddd`, by its
+/// relative luminance. Each channel may have one to four hex digits.
+pub(crate) fn background_mode(color: &str) -> Option<ThemeMode> {
+    let channels = color.strip_prefix("rgb:").or_else(|| color.strip_prefix("rgba:"))?;
+    let levels: Vec<f64> = channels
+        .split('/')
+        .take(3)
+        .map(|hex| {
+            let value = u32::from_str_radix(hex, 16).ok().filter(|_| (1..=4).contains(&hex.len()))?;
+            Some(f64::from(value) / f64::from((1u32 << (4 * hex.len())) - 1))
+        })
+        .collect::<Option<_>>()?;
+    let [r, g, b] = levels[..] else { return None };
+    let luminance = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    Some(if luminance > 0.5 { ThemeMode::Light } else { ThemeMode::Dark })
+}
+
+/// COLORFGBG, as rxvt and Konsole set it: `FG;BG` or `FG;default;BG`.
+/// Backgrounds 7 (white) and 9-15 (the bright colors but black) are light.
+pub(crate) fn colorfgbg_mode(value: &str) -> Option<ThemeMode> {
+    match value.rsplit(';').next()?.trim().parse::<u8>().ok()? {
+        7 | 9..=15 => Some(ThemeMode::Light),
+        0..=6 | 8 => Some(ThemeMode::Dark),
+        _ => None,
+    }
+}
+
+/// Puts typed-ahead keys back in the terminal's input, so the prompt that
+/// reads next sees them. Kernels without TIOCSTI lose them.
+pub(crate) fn push_back_input(tty: &fs::File, bytes: &[u8]) {
+    for byte in bytes {
+        if unsafe { libc::ioctl(tty.as_raw_fd(), libc::TIOCSTI, byte as *const u8) } != 0 {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::*;
+    use crate::test_support::*;
+
+    // --- ThemeMode ---
+
+    #[test]
+    fn theme_mode_parses_case_insensitively_and_rejects_unknown() {
+        assert!(matches!(ThemeMode::from_str("light"), Some(ThemeMode::Light)));
+        assert!(matches!(ThemeMode::from_str("DARK"), Some(ThemeMode::Dark)));
+        assert!(ThemeMode::from_str("blue").is_none());
+    }
+
+    #[test]
+    fn theme_mode_str_roundtrips() {
+        for palette in PALETTES {
+            assert_eq!(ThemeMode::from_str(palette.name), Some(palette.mode));
+            assert_eq!(palette.mode.as_str(), palette.name);
+        }
+        assert_eq!(THEME_NAMES, ["dark", "light", "solarized", "high-contrast", "plain"]);
+    }
+
+    #[test]
+    fn every_palette_but_plain_colors_every_role() {
+        for palette in PALETTES.iter().filter(|p| p.mode != ThemeMode::Plain) {
+            let theme = &palette.theme;
+            let colors = [&theme.helper_color, &theme.command_color, &theme.prompt_color, &theme.warning_color, &theme.hint_color];
+            assert!(colors.iter().all(|c| c.starts_with("\u{001b}[") && c.ends_with('m')), "{}", palette.name);
+        }
+        assert!(Theme::from_mode(ThemeMode::Plain).command_text("ls") == "ls");
+    }
+
+    #[test]
+    fn theme_wraps_text_with_color_and_reset() {
+        let theme = Theme::from_mode(ThemeMode::Dark);
+        let painted = theme.helper_text("hello");
+        assert!(painted.contains("hello"));
+        assert!(painted.ends_with(RESET));
+        assert!(painted.starts_with(&*theme.helper_color));
+    }
+
+    // --- User colors ---
+
+    #[test]
+    fn parse_color_takes_names_sgr_and_hex() {
+        assert_eq!(parse_color("red").unwrap(), "\u{001b}[31m");
+        assert_eq!(parse_color("bright-blue").unwrap(), "\u{001b}[94m");
+        assert_eq!(parse_color("Gray").unwrap(), "\u{001b}[90m");
+        assert_eq!(parse_color("bold bright-white").unwrap(), "\u{001b}[1;97m");
+        assert_eq!(parse_color("38;5;245").unwrap(), "\u{001b}[38;5;245m");
+        assert_eq!(parse_color("1;97;41").unwrap(), "\u{001b}[1;97;41m");
+        assert_eq!(parse_color("#ff8800").unwrap(), "\u{001b}[38;2;255;136;0m");
+        assert_eq!(parse_color(" #FF8800 ").unwrap(), "\u{001b}[38;2;255;136;0m");
+    }
+
+    #[test]
+    fn parse_color_rejects_bad_specs() {
+        for spec in ["", "purple", "bright-", "blink red", "#ff88", "#gg8800", "38;5;256", "38;;5", "38;5;"] {
+            assert!(parse_color(spec).is_err(), "{spec:?}");
+        }
+        assert!(parse_color("purple").unwrap_err().contains("'purple'"));
+        // Nothing that could end the escape sequence early gets through
+        assert!(parse_color("31m\u{001b}[0").is_err());
+    }
+
+    #[test]
+    fn user_colors_layer_over_the_palette_and_still_reset() {
+        let theme = Theme::from_mode(ThemeMode::Light)
+            .with_colors(&[("command_color", parse_color("#ff8800").unwrap()), ("bogus", "x".to_string())]);
+        let painted = theme.command_text("ls");
+        assert_eq!(painted, format!("\u{001b}[38;2;255;136;0mls{RESET}"));
+        // Roles without an override keep the base theme's color
+        assert_eq!(theme.helper_color, Theme::from_mode(ThemeMode::Light).helper_color);
+    }
+
+    #[test]
+    fn plain_theme_emits_no_escape_sequences() {
+        let theme = Theme::plain();
+        let painted = [
+            theme.helper_text("hello"),
+            theme.command_text("rm -rf build"),
+            theme.prompt_text("Execute? [y/N/e/i/s]"),
+            theme.warning_text("Running as root"),
+        ];
+        assert_eq!(painted[1], "rm -rf build");
+        assert!(painted.iter().all(|text| !text.contains('\u{001b}')));
+
+        // The confirmation preview goes through the theme too
+        let mut out = Vec::new();
+        print_clobber_warnings(&mut out, "echo hi > /etc/passwd", &theme).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("/etc/passwd") && !out.contains('\u{001b}'), "{out:?}");
+    }
+
+    #[test]
+    fn color_is_off_for_flag_env_or_pipe() {
+        let env = |no_color| ColorEnv { no_color, force_color: None };
+        let flags = |no_color| ColorFlags { no_color, ..Default::default() };
+        assert!(color_enabled(flags(false), env(None), true));
+        assert!(!color_enabled(flags(true), env(None), true));
+        assert!(!color_enabled(flags(false), env(Some("1")), true));
+        // An empty NO_COLOR doesn't count
+        assert!(color_enabled(flags(false), env(Some("")), true));
+        assert!(!color_enabled(flags(false), env(None), false));
+    }
+
+    #[test]
+    fn color_decision_truth_table() {
+        let envs = [None, Some(""), Some("0"), Some("1")];
+        for machine_output in [false, true] {
+            for no_color in [false, true] {
+                for force_color in [false, true] {
+                    for no_color_env in envs {
+                        for force_color_env in envs {
+                            for tty in [false, true] {
+                                let flags = ColorFlags { no_color, force_color, machine_output };
+                                let env = ColorEnv { no_color: no_color_env, force_color: force_color_env };
+                                let forced_by_env = matches!(force_color_env, Some("1"));
+                                let off_by_env = matches!(no_color_env, Some("0" | "1"));
+                                let expected = !machine_output
+                                    && !no_color
+                                    && (force_color || forced_by_env || (!off_by_env && tty));
+                                assert_eq!(
+                                    color_enabled(flags, env, tty),
+                                    expected,
+                                    "machine={machine_output} --no-color={no_color} --force-color={force_color} \
+                                     NO_COLOR={no_color_env:?} ASK_FORCE_COLOR={force_color_env:?} tty={tty}"
+                                );
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // --- Background detection ---
+
+    #[test]
+    fn background_colors_map_to_light_or_dark() {
+        assert_eq!(background_mode("rgb:ffff/ffff/ffff"), Some(ThemeMode::Light));
+        assert_eq!(background_mode("rgb:0000/0000/0000"), Some(ThemeMode::Dark));
+        // Solarized light and dark, with two-digit channels
+        assert_eq!(background_mode("rgb:fd/f6/e3"), Some(ThemeMode::Light));
+        assert_eq!(background_mode("rgb:00/2b/36"), Some(ThemeMode::Dark));
+        assert_eq!(background_mode("rgba:ffff/ffff/ffff/ffff"), Some(ThemeMode::Light));
+        for color in ["", "rgb:ffff/ffff", "rgb:fffff/0/0", "rgb:zz/00/00", "#ffffff"] {
+            assert_eq!(background_mode(color), None, "{color:?}");
+        }
+    }
+
+    #[test]
+    fn colorfgbg_reads_the_background_field() {
+        assert_eq!(colorfgbg_mode("15;0"), Some(ThemeMode::Dark));
+        assert_eq!(colorfgbg_mode("0;15"), Some(ThemeMode::Light));
+        assert_eq!(colorfgbg_mode("0;default;7"), Some(ThemeMode::Light));
+        assert_eq!(colorfgbg_mode("7;8"), Some(ThemeMode::Dark));
+        assert_eq!(colorfgbg_mode("15;default"), None);
+        assert_eq!(colorfgbg_mode(""), None);
+    }
+
+    #[test]
+    fn replies_are_split_from_typed_keys() {
+        let bytes = b"l\x1b]11;rgb:ffff/ffff/ffff\x1b\\s\x1b[A\x1b[?62;22c";
+        let (mode, answered, rest) = terminal_replies(bytes);
+        assert_eq!(mode, Some(ThemeMode::Light));
+        assert!(answered);
+        // Arrow keys are typed input too
+        assert_eq!(rest, b"ls\x1b[A");
+
+        let (mode, answered, rest) = terminal_replies(b"\x1b]11;rgb:0000/0000/0000\x07\x1b[?1;2c");
+        assert_eq!((mode, answered, rest), (Some(ThemeMode::Dark), true, Vec::new()));
+
+        // Cut off by the timeout: nothing half-read passes as typed input
+        let (mode, answered, rest) = terminal_replies(b"x\x1b]11;rgb:ff");
+        assert_eq!((mode, answered, rest), (None, false, b"x".to_vec()));
+    }
+
+    /// Answers the background query the way a terminal would, after the
+    /// user has typed `typed`, and returns what ask wrote to the screen.
+    fn answer_query(master: &mut fs::File, typed: &[u8], reply: &[u8]) -> Vec<u8> {
+        let mut seen = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !seen.ends_with(BACKGROUND_QUERY) {
+            assert!(Instant::now() < deadline, "never saw the query in {seen:?}");
+            let mut buf = [0u8; 64];
+            let n = master.read(&mut buf).unwrap();
+            seen.extend_from_slice(&buf[..n]);
+        }
+        master.write_all(typed).unwrap();
+        master.write_all(reply).unwrap();
+        seen
+    }
+
+    fn echo_is_on(pty: &Pty) -> bool {
+        let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+        assert_eq!(unsafe { libc::tcgetattr(pty.slave.as_raw_fd(), &mut termios) }, 0);
+        termios.c_lflag & libc::ECHO != 0
+    }
+
+    /// Everything waiting on `file` within 100ms.
+    fn drain(file: &fs::File) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut poll = libc::pollfd { fd: file.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        while unsafe { libc::poll(&mut poll, 1, 100) } == 1 {
+            let mut buf = [0u8; 256];
+            match (&*file).read(&mut buf) {
+                Ok(n) if n > 0 => out.extend_from_slice(&buf[..n]),
+                _ => break,
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn background_query_keeps_typed_keys_and_echoes_nothing() {
+        let pty = Pty::open();
+        let mut master = pty.master.as_ref().unwrap().try_clone().unwrap();
+        let terminal = std::thread::spawn(move || {
+            let reply = b"\x1b]11;rgb:fdfd/f6f6/e3e3\x1b\\\x1b[?62;22c";
+            answer_query(&mut master, b"ls", reply);
+            master
+        });
+        let answer = query_background(&pty.slave, Duration::from_secs(5));
+        let master = terminal.join().unwrap();
+        assert_eq!(answer, BackgroundAnswer { mode: Some(ThemeMode::Light), typeahead: b"ls".to_vec() });
+        // The reply was read with echo off, and echo is back on afterwards
+        assert_eq!(drain(&master), b"");
+        assert!(echo_is_on(&pty));
+    }
+
+    #[test]
+    fn background_query_gives_up_when_the_terminal_is_silent() {
+        let pty = Pty::open();
+        let mut master = pty.master.as_ref().unwrap().try_clone().unwrap();
+        let started = Instant::now();
+        let answer = query_background(&pty.slave, Duration::from_millis(100));
+        assert_eq!(answer, BackgroundAnswer::default());
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(echo_is_on(&pty));
+        let mut seen = [0u8; BACKGROUND_QUERY.len()];
+        master.read_exact(&mut seen).unwrap();
+        assert_eq!(seen, BACKGROUND_QUERY);
+    }
+
+    #[test]
+    fn background_query_is_skipped_while_input_is_waiting() {
+        let pty = Pty::open();
+        let mut master = pty.master.as_ref().unwrap().try_clone().unwrap();
+        // Raw mode, so the typed key is readable without a newline
+        let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+        unsafe { libc::tcgetattr(pty.slave.as_raw_fd(), &mut termios) };
+        termios.c_lflag &= !(libc::ICANON | libc::ECHO);
+        unsafe { libc::tcsetattr(pty.slave.as_raw_fd(), libc::TCSANOW, &termios) };
+        master.write_all(b"y").unwrap();
+        let mut poll = libc::pollfd { fd: pty.slave.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        assert_eq!(unsafe { libc::poll(&mut poll, 1, 5000) }, 1);
+
+        assert_eq!(query_background(&pty.slave, Duration::from_secs(5)), BackgroundAnswer::default());
+        // Nothing was sent, and the key is still there to read
+        assert_eq!(drain(&master), b"");
+        assert_eq!(drain(&pty.slave), b"y");
+    }
+}
@@ -0,0 +1,338 @@
+//! Requests to the model against a local HTTP server: what is sent, and how
+//! replies and errors are read back.
+
+use ask::api::{Endpoint, Provider, RequestSettings};
+use ask::exec::{CommandOutput, CommandRunner, ConfirmResponse, PromptResult, process_prompt_with_context};
+use ask::history::ConversationContext;
+use ask::safety::SafetyPolicy;
+use ask::theme::Theme;
+use serde_json::json;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::process::ExitStatus;
+
+#[path = "../src/test_server.rs"]
+mod test_server;
+use test_server::*;
+
+fn reply(content: &str) -> String {
+    http_response("200 OK", "", &json!({ "choices": [{ "message": { "content": content } }] }).to_string())
+}
+
+/// Fails the test if the model's reply ever gets as far as running something.
+struct NoShell;
+
+impl CommandRunner for NoShell {
+    fn confirm(&mut self, command: &str, _: &Theme, _: &SafetyPolicy) -> Result<ConfirmResponse, io::Error> {
+        panic!("asked to confirm {command}");
+    }
+
+    fn run(&mut self, command: &str) -> Result<CommandOutput, Box<dyn std::error::Error>> {
+        panic!("asked to run {command}");
+    }
+
+    fn keep_going(&mut self, command: &str, _: ExitStatus, _: &Theme) -> Result<bool, io::Error> {
+        panic!("asked to go on after {command}");
+    }
+}
+
+fn ask(
+    url: String,
+    api_key: Option<&str>,
+    prompt: &str,
+    history: &[ConversationContext],
+) -> Result<PromptResult, Box<dyn std::error::Error>> {
+    ask_endpoint(Endpoint::new(url, api_key.map(str::to_string)), "test-model", prompt, history)
+}
+
+fn ask_endpoint(
+    endpoint: Endpoint,
+    model: &str,
+    prompt: &str,
+    history: &[ConversationContext],
+) -> Result<PromptResult, Box<dyn std::error::Error>> {
+    let settings = RequestSettings::new(model, endpoint);
+    let mut policy = SafetyPolicy::new(false, &[], 5);
+    policy.can_prompt = false;
+    process_prompt_with_context(prompt, &settings, &Theme::plain(), history, None, &policy, &mut NoShell)
+}
+
+// --- what is sent ---
+
+#[test]
+fn request_carries_the_key_and_json_body() {
+    let (url, recorded) = fake_api(vec![reply("# Hello")]);
+    ask(url, Some("sk-test-123"), "hello there", &[]).unwrap();
+
+    let recorded = recorded.lock().unwrap();
+    let request = &recorded[0];
+    assert_eq!(request.request_line, "POST /v1/chat/completions HTTP/1.1");
+    assert_eq!(request.header("authorization"), Some("Bearer sk-test-123"));
+    assert_eq!(request.header("content-type"), Some("application/json"));
+    assert_eq!(request.body["model"], "test-model");
+    for key in ["temperature", "top_p", "max_tokens"] {
+        assert!(request.body.get(key).is_none(), "{key} is left to the provider");
+    }
+}
+
+#[test]
+fn local_servers_get_no_authorization_header() {
+    let (url, recorded) = fake_api(vec![reply("# Hello")]);
+    ask(url, None, "hello there", &[]).unwrap();
+    assert_eq!(recorded.lock().unwrap()[0].header("authorization"), None);
+}
+
+#[test]
+fn first_prompt_is_a_single_user_message() {
+    let (url, recorded) = fake_api(vec![reply("ls")]);
+    let mut policy = SafetyPolicy::new(false, &[], 5);
+    policy.print_only = true;
+    let settings = RequestSettings::new("test-model", Endpoint::new(url, None));
+    process_prompt_with_context("list the files here", &settings, &Theme::plain(), &[], None, &policy, &mut NoShell)
+        .unwrap();
+
+    let messages = recorded.lock().unwrap()[0].body["messages"].as_array().unwrap().clone();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0]["role"], "user");
+    let content = messages[0]["content"].as_str().unwrap();
+    assert!(content.contains("**User request:** list the files here"), "{content}");
+}
+
+#[test]
+fn history_goes_first_as_alternating_messages() {
+    let earlier = ConversationContext {
+        prompt: "show disk usage".to_string(),
+        commands: vec!["df -h".to_string()],
+        outputs: vec!["/dev/disk1 50%\n".to_string()],
+        exit_codes: vec![],
+        request: None,
+        pinned: false,
+        forgotten: false,
+    };
+    let (url, recorded) = fake_api(vec![reply("# About half full")]);
+    ask(url, None, "is that a lot?", &[earlier]).unwrap();
+
+    let messages = recorded.lock().unwrap()[0].body["messages"].as_array().unwrap().clone();
+    let roles: Vec<&str> = messages.iter().map(|m| m["role"].as_str().unwrap()).collect();
+    assert_eq!(roles, ["user", "assistant", "user"]);
+    assert_eq!(messages[0]["content"], "show disk usage");
+    assert_eq!(messages[1]["content"], "df -h");
+    let last = messages[2]["content"].as_str().unwrap();
+    let output = last.find("/dev/disk1 50%").expect("earlier output sent");
+    assert!(output < last.find("is that a lot?").expect("new prompt sent"));
+}
+
+#[test]
+fn one_endpoint_keeps_its_connection_across_prompts() {
+    // Accepts a single connection, then stops listening: a second
+    // connection would be refused, so both replies must share the first
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/v1/chat/completions", listener.local_addr().unwrap());
+    let server = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        drop(listener);
+        let mut served = Vec::new();
+        while let Some(request) = read_request(&mut stream) {
+            let body = json!({ "choices": [{ "message": { "content": format!("# Reply {}", served.len() + 1) } }] });
+            let body = body.to_string();
+            let response = format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}", body.len());
+            stream.write_all(response.as_bytes()).unwrap();
+            served.push(request.header("authorization").map(str::to_string));
+        }
+        served
+    });
+
+    let settings = RequestSettings::new("test-model", Endpoint::new(url, Some("sk-test".to_string())));
+    let mut policy = SafetyPolicy::new(false, &[], 5);
+    policy.can_prompt = false;
+    for expected in ["# Reply 1", "# Reply 2"] {
+        let result = process_prompt_with_context("hi", &settings, &Theme::plain(), &[], None, &policy, &mut NoShell)
+            .unwrap();
+        assert_eq!(result.turn.request.unwrap().response, [expected]);
+    }
+    drop(settings);
+    let served = server.join().unwrap();
+    assert_eq!(served, [Some("Bearer sk-test".to_string()), Some("Bearer sk-test".to_string())]);
+}
+
+#[test]
+fn a_request_cut_short_is_served_as_far_as_it_got() {
+    let (url, recorded) = fake_api(vec![reply("# Hello")]);
+    let address = url.trim_start_matches("http://").split('/').next().unwrap();
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(b"POST /v1/chat/completions HTTP/1.1\r\nContent-Length: 100\r\n\r\n{").unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+    assert_eq!(recorded.lock().unwrap()[0].request_line, "POST /v1/chat/completions HTTP/1.1");
+}
+
+// --- what comes back ---
+
+#[test]
+fn extra_fields_in_the_reply_are_ignored() {
+    let body = json!({
+        "id": "gen-1",
+        "object": "chat.completion",
+        "provider": "someone",
+        "choices": [{ "index": 0, "logprobs": null, "message": { "role": "assistant", "content": "# Hi", "refusal": null } }],
+        "usage": { "prompt_tokens": 10, "completion_tokens": 2, "total_tokens": 12 }
+    });
+    let (url, _) = fake_api(vec![http_response("200 OK", "", &body.to_string())]);
+    let result = ask(url, None, "hi", &[]).unwrap();
+    assert_eq!(result.turn.request.unwrap().response, ["# Hi"]);
+}
+
+#[test]
+fn empty_choices_is_no_command_returned() {
+    let (url, _) = fake_api(vec![http_response("200 OK", "", r#"{"choices": []}"#)]);
+    let err = ask(url, None, "hi", &[]).err().expect("an empty reply is an error");
+    assert_eq!(err.to_string(), "No command returned from the model.");
+}
+
+#[test]
+fn reply_without_choices_fails_to_parse() {
+    let (url, _) = fake_api(vec![http_response("200 OK", "", r#"{"error": {"message": "no providers"}}"#)]);
+    let err = ask(url, None, "hi", &[]).err().expect("a reply without choices is an error");
+    assert!(err.to_string().contains("choices"), "{err}");
+}
+
+#[test]
+fn rate_limit_error_gives_the_reason_after_retries() {
+    let limited = || http_response("429 Too Many Requests", "Retry-After: 0\r\n", r#"{"error":"slow down"}"#);
+    let (url, recorded) = fake_api(vec![limited(), limited(), limited(), limited()]);
+    let err = ask(url, None, "hi", &[]).err().expect("rate limited every time");
+    assert_eq!(
+        err.to_string(),
+        "Rate limited (slow down)\nWait a minute and try again, or list other models in fallback_models"
+    );
+    assert_eq!(recorded.lock().unwrap().len(), 4);
+}
+
+#[test]
+fn server_error_is_retried_then_succeeds() {
+    let (url, recorded) = fake_api(vec![
+        http_response("500 Internal Server Error", "Retry-After: 0\r\n", "upstream timeout"),
+        reply("# Recovered"),
+    ]);
+    let result = ask(url, None, "hi", &[]).unwrap();
+    assert_eq!(result.turn.request.unwrap().response, ["# Recovered"]);
+    assert_eq!(recorded.lock().unwrap().len(), 2);
+}
+
+#[test]
+fn auth_error_is_not_retried() {
+    let (url, recorded) = fake_api(vec![http_response("401 Unauthorized", "", r#"{"error":"bad key"}"#)]);
+    let err = ask(url, Some("wrong"), "hi", &[]).err().expect("unauthorized");
+    assert_eq!(err.to_string(), "Your OPENROUTER_ASK_API_KEY was rejected — check it at openrouter.ai/keys");
+    assert_eq!(recorded.lock().unwrap().len(), 1);
+}
+
+// --- Ollama ---
+
+fn ollama(url: &str) -> Endpoint {
+    Endpoint::for_provider(Provider::Ollama, url.replace("/v1/chat/completions", "/api/chat"), None)
+}
+
+#[test]
+fn ollama_round_trip_uses_the_native_chat_api() {
+    let body = json!({
+        "model": "llama3.1",
+        "created_at": "2024-07-22T20:33:28.123Z",
+        "message": { "role": "assistant", "content": "# Hello from a plane" },
+        "done": true,
+        "done_reason": "stop",
+        "prompt_eval_count": 26,
+        "eval_count": 8
+    });
+    let (url, recorded) = fake_api(vec![http_response("200 OK", "", &body.to_string())]);
+    let result = ask_endpoint(ollama(&url), "ollama/llama3.1", "hello there", &[]).unwrap();
+    let request = result.turn.request.unwrap();
+    assert_eq!(request.response, ["# Hello from a plane"]);
+    assert_eq!(request.model, "ollama/llama3.1");
+
+    let recorded = recorded.lock().unwrap();
+    assert_eq!(recorded[0].request_line, "POST /api/chat HTTP/1.1");
+    assert_eq!(recorded[0].header("authorization"), None);
+    assert_eq!(recorded[0].body["model"], "llama3.1");
+    assert_eq!(recorded[0].body["stream"], false);
+    assert_eq!(recorded[0].body["messages"][0]["role"], "user");
+}
+
+#[test]
+fn ollama_missing_model_suggests_pulling_it() {
+    let (url, _) = fake_api(vec![http_response("404 Not Found", "", r#"{"error":"model \"llama9\" not found, try pulling it first"}"#)]);
+    let err = ask_endpoint(ollama(&url), "ollama/llama9", "hi", &[]).err().expect("no such model");
+    assert_eq!(
+        err.to_string(),
+        r#"Ollama error 404: model "llama9" not found, try pulling it first (try `ollama pull llama9`)"#
+    );
+}
+
+#[test]
+fn ollama_not_running_says_so_without_retrying() {
+    // Bind then drop a listener for a port nothing is listening on
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let url = format!("http://127.0.0.1:{port}/api/chat");
+    let endpoint = Endpoint::for_provider(Provider::Ollama, url.clone(), None);
+    let err = ask_endpoint(endpoint, "llama3.1", "hi", &[]).err().expect("connection refused");
+    assert_eq!(
+        err.to_string(),
+        format!("Could not connect to Ollama at {url}. Is Ollama running? Start it with `ollama serve`.")
+    );
+}
+
+// --- Anthropic ---
+
+fn anthropic(url: &str, key: &str) -> Endpoint {
+    Endpoint::for_provider(Provider::Anthropic, url.replace("/chat/completions", "/messages"), Some(key.to_string()))
+}
+
+#[test]
+fn anthropic_round_trip_uses_the_messages_api() {
+    let body = json!({
+        "id": "msg_01",
+        "type": "message",
+        "role": "assistant",
+        "model": "claude-sonnet-4-0",
+        "content": [{ "type": "text", "text": "# Use ls" }, { "type": "text", "text": "\n# Add -a for hidden files" }],
+        "stop_reason": "end_turn",
+        "usage": { "input_tokens": 30, "output_tokens": 6 }
+    });
+    let (url, recorded) = fake_api(vec![http_response("200 OK", "", &body.to_string())]);
+    let result = ask_endpoint(anthropic(&url, "sk-ant-test"), "anthropic/claude-sonnet-4-0", "list files", &[]).unwrap();
+    assert_eq!(result.turn.request.unwrap().response, ["# Use ls", "# Add -a for hidden files"]);
+
+    let recorded = recorded.lock().unwrap();
+    let request = &recorded[0];
+    assert_eq!(request.request_line, "POST /v1/messages HTTP/1.1");
+    assert_eq!(request.header("x-api-key"), Some("sk-ant-test"));
+    assert_eq!(request.header("anthropic-version"), Some("2023-06-01"));
+    assert_eq!(request.header("authorization"), None);
+    assert_eq!(request.body["model"], "claude-sonnet-4-0");
+    assert!(request.body["max_tokens"].as_u64().is_some());
+    assert_eq!(request.body["messages"][0]["role"], "user");
+}
+
+#[test]
+fn anthropic_errors_read_like_the_openrouter_ones() {
+    let body = r#"{"type":"error","error":{"type":"authentication_error","message":"invalid x-api-key"}}"#;
+    let (url, recorded) = fake_api(vec![http_response("401 Unauthorized", "", body)]);
+    let err = ask_endpoint(anthropic(&url, "wrong"), "claude-sonnet-4-0", "hi", &[]).err().expect("unauthorized");
+    assert_eq!(err.to_string(), "Your ANTHROPIC_API_KEY was rejected — check it at console.anthropic.com/settings/keys");
+    assert_eq!(recorded.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn anthropic_overloaded_is_retried() {
+    let overloaded = r#"{"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#;
+    let ok = json!({ "content": [{ "type": "text", "text": "# Back" }], "stop_reason": "end_turn" });
+    let (url, recorded) = fake_api(vec![
+        http_response("529 Overloaded", "Retry-After: 0\r\n", overloaded),
+        http_response("200 OK", "", &ok.to_string()),
+    ]);
+    let result = ask_endpoint(anthropic(&url, "sk-ant-test"), "claude-sonnet-4-0", "hi", &[]).unwrap();
+    assert_eq!(result.turn.request.unwrap().response, ["# Back"]);
+    assert_eq!(recorded.lock().unwrap().len(), 2);
+}
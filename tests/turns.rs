@@ -0,0 +1,344 @@
+//! Whole turns driven through the library with a scripted model and a
+//! scripted user: what gets confirmed, what runs, and what the turn records.
+
+use ask::api::{ApiFailure, ApiResponse, Endpoint, LlmClient, RequestSettings};
+use ask::exec::{CommandOutput, CommandRunner, ConfirmResponse, Executed, execute_response, process_prompt_with_context};
+use ask::history::ConversationContext;
+use ask::safety::SafetyPolicy;
+use ask::theme::Theme;
+use std::collections::VecDeque;
+use std::io;
+use std::process::ExitStatus;
+use std::sync::{Arc, Mutex};
+
+/// Answers each request with the next canned reply and keeps the request
+/// bodies for inspection.
+#[derive(Clone, Default)]
+struct ScriptedModel {
+    // Reply messages, text or tool calls
+    replies: Arc<Mutex<VecDeque<serde_json::Value>>>,
+    requests: Arc<Mutex<Vec<serde_json::Value>>>,
+}
+
+impl ScriptedModel {
+    fn new(replies: &[&str]) -> Self {
+        Self::with_messages(replies.iter().map(|r| serde_json::json!({ "content": r })).collect())
+    }
+
+    fn with_messages(messages: Vec<serde_json::Value>) -> Self {
+        let model = Self::default();
+        model.replies.lock().unwrap().extend(messages);
+        model
+    }
+
+    /// The text of every message sent in request `index`, in order.
+    fn messages(&self, index: usize) -> Vec<String> {
+        self.requests.lock().unwrap()[index]["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["content"].as_str().unwrap().to_string())
+            .collect()
+    }
+}
+
+impl LlmClient for ScriptedModel {
+    fn send(&self, body: &serde_json::Value, _: &Endpoint) -> Result<ApiResponse, ApiFailure> {
+        self.requests.lock().unwrap().push(body.clone());
+        let message = self.replies.lock().unwrap().pop_front().expect("no reply scripted");
+        let reply = serde_json::json!({ "choices": [{ "message": message }] });
+        Ok(serde_json::from_value(reply).unwrap())
+    }
+}
+
+/// Gives the scripted answers at each confirmation and records what was
+/// asked about and what ran instead of touching the shell.
+#[derive(Default)]
+struct ScriptedUser {
+    answers: VecDeque<ConfirmResponse>,
+    asked: Vec<String>,
+    ran: Vec<String>,
+    // Commands that exit 1, and the answer when asked whether to go on
+    failing: Vec<String>,
+    keep_going: bool,
+}
+
+impl ScriptedUser {
+    fn new(answers: Vec<ConfirmResponse>) -> Self {
+        Self { answers: answers.into(), ..Self::default() }
+    }
+}
+
+impl CommandRunner for ScriptedUser {
+    fn confirm(&mut self, command: &str, _: &Theme, _: &SafetyPolicy) -> Result<ConfirmResponse, io::Error> {
+        self.asked.push(command.to_string());
+        Ok(self.answers.pop_front().expect("no answer scripted"))
+    }
+
+    fn run(&mut self, command: &str) -> Result<CommandOutput, Box<dyn std::error::Error>> {
+        self.ran.push(command.to_string());
+        let code = if self.failing.iter().any(|c| c == command) { 1 } else { 0 };
+        Ok(CommandOutput::exited(format!("output of {command}\n"), code))
+    }
+
+    fn keep_going(&mut self, command: &str, _: ExitStatus, _: &Theme) -> Result<bool, io::Error> {
+        self.asked.push(format!("keep going after {command}?"));
+        Ok(self.keep_going)
+    }
+}
+
+fn settings(model: &ScriptedModel) -> RequestSettings {
+    let endpoint = Endpoint::new("http://127.0.0.1:9/v1/chat/completions".to_string(), None);
+    RequestSettings::new("test-model", endpoint.with_client(model.clone()))
+}
+
+/// Someone is at the terminal, and nothing runs without asking.
+fn attended() -> SafetyPolicy {
+    let mut policy = SafetyPolicy::new(false, &[], 5);
+    policy.can_prompt = true;
+    policy
+}
+
+fn commands(lines: &[&str]) -> Vec<String> {
+    lines.iter().map(|l| l.to_string()).collect()
+}
+
+// --- confirm loop ---
+
+#[test]
+fn each_command_is_confirmed_before_it_runs() {
+    let mut user = ScriptedUser::new(vec![ConfirmResponse::Yes, ConfirmResponse::Yes]);
+    let Executed { commands: ran, outputs, .. } =
+        execute_response(&commands(&["# List then count", "ls", "wc -l notes"]), &Theme::plain(), &attended(), &mut user)
+            .unwrap();
+    assert_eq!(user.asked, ["ls", "wc -l notes"]);
+    assert_eq!(ran, ["ls", "wc -l notes"]);
+    assert_eq!(outputs, ["output of ls\n", "output of wc -l notes\n"]);
+}
+
+#[test]
+fn no_stops_the_turn_and_keeps_what_ran() {
+    let mut user = ScriptedUser::new(vec![ConfirmResponse::Yes, ConfirmResponse::No]);
+    let Executed { commands: ran, .. } = execute_response(&commands(&["ls", "rm notes", "ls"]), &Theme::plain(), &attended(), &mut user).unwrap();
+    assert_eq!(user.asked, ["ls", "rm notes"]);
+    assert_eq!(ran, ["ls"]);
+    assert_eq!(user.ran, ["ls"]);
+}
+
+#[test]
+fn all_runs_the_rest_without_asking() {
+    let mut user = ScriptedUser::new(vec![ConfirmResponse::All]);
+    let Executed { commands: ran, .. } = execute_response(&commands(&["ls", "pwd", "date"]), &Theme::plain(), &attended(), &mut user).unwrap();
+    assert_eq!(user.asked, ["ls"]);
+    assert_eq!(ran, ["ls", "pwd", "date"]);
+}
+
+#[test]
+fn edited_command_runs_and_is_recorded_instead() {
+    let mut user = ScriptedUser::new(vec![ConfirmResponse::Edit("ls -la".to_string())]);
+    let Executed { commands: ran, outputs, .. } = execute_response(&commands(&["ls"]), &Theme::plain(), &attended(), &mut user).unwrap();
+    assert_eq!(ran, ["ls -la"]);
+    assert_eq!(outputs, ["output of ls -la\n"]);
+}
+
+#[test]
+fn comment_only_replies_never_ask_or_run() {
+    let mut user = ScriptedUser::default();
+    let Executed { commands: ran, outputs, .. } =
+        execute_response(&commands(&["# Glad it helped!"]), &Theme::plain(), &attended(), &mut user).unwrap();
+    assert!(user.asked.is_empty() && user.ran.is_empty());
+    assert!(ran.is_empty() && outputs.is_empty());
+}
+
+// --- skip and instruct ---
+
+#[test]
+fn skip_moves_on_to_the_next_command() {
+    let mut user = ScriptedUser::new(vec![ConfirmResponse::Skip, ConfirmResponse::Yes]);
+    let Executed { commands: ran, .. } = execute_response(&commands(&["make clean", "make"]), &Theme::plain(), &attended(), &mut user).unwrap();
+    assert_eq!(user.asked, ["make clean", "make"]);
+    assert_eq!(ran, ["make"]);
+}
+
+#[test]
+fn copy_runs_nothing_and_moves_on_to_the_next_command() {
+    let mut user = ScriptedUser::new(vec![ConfirmResponse::Copy, ConfirmResponse::Yes]);
+    let Executed { commands: ran, .. } =
+        execute_response(&commands(&["ssh prod uptime", "uptime"]), &Theme::plain(), &attended(), &mut user).unwrap();
+    assert_eq!(user.asked, ["ssh prod uptime", "uptime"]);
+    assert_eq!(ran, ["uptime"]);
+    assert_eq!(user.ran, ["uptime"]);
+}
+
+#[test]
+fn instruct_runs_a_detour_then_asks_about_the_original_again() {
+    let mut user = ScriptedUser::new(vec![ConfirmResponse::Instruct("git status".to_string()), ConfirmResponse::Yes]);
+    let Executed { commands: ran, outputs, .. } = execute_response(&commands(&["git pull"]), &Theme::plain(), &attended(), &mut user).unwrap();
+    assert_eq!(user.asked, ["git pull", "git pull"]);
+    assert_eq!(user.ran, ["git status", "git pull"]);
+    // The detour is the user's own; the turn records only what the model suggested
+    assert_eq!(ran, ["git pull"]);
+    assert_eq!(outputs, ["output of git pull\n"]);
+}
+
+#[test]
+fn instruct_inside_instruct_skips_the_command() {
+    let mut user = ScriptedUser::new(vec![
+        ConfirmResponse::Instruct(String::new()),
+        ConfirmResponse::Instruct("pwd".to_string()),
+        ConfirmResponse::Yes,
+    ]);
+    let Executed { commands: ran, .. } = execute_response(&commands(&["ls", "date"]), &Theme::plain(), &attended(), &mut user).unwrap();
+    assert!(user.ran.iter().all(|c| c != "pwd" && c != "ls"));
+    assert_eq!(ran, ["date"]);
+}
+
+// --- history recording ---
+
+#[test]
+fn a_turn_records_the_prompt_reply_and_what_ran() {
+    let model = ScriptedModel::new(&["# Listing the folder\nls\nrm -rf build"]);
+    let mut user = ScriptedUser::new(vec![ConfirmResponse::Yes, ConfirmResponse::Skip]);
+    let result = process_prompt_with_context(
+        "tidy up",
+        &settings(&model),
+        &Theme::plain(),
+        &[],
+        None,
+        &attended(),
+        &mut user,
+    )
+    .unwrap();
+
+    let turn = result.turn;
+    assert_eq!(turn.prompt, "tidy up");
+    assert_eq!(turn.commands, ["ls"]);
+    assert_eq!(turn.outputs, ["output of ls\n"]);
+    let request = turn.request.unwrap();
+    assert_eq!(request.model, "test-model");
+    assert_eq!(request.response, ["# Listing the folder", "ls", "rm -rf build"]);
+}
+
+#[test]
+fn recorded_turns_are_sent_back_as_context() {
+    let model = ScriptedModel::new(&["ls", "# It listed the folder"]);
+    let settings = settings(&model);
+    let mut history: Vec<ConversationContext> = Vec::new();
+
+    let mut user = ScriptedUser::new(vec![ConfirmResponse::Yes]);
+    let first = process_prompt_with_context("list files", &settings, &Theme::plain(), &history, None, &attended(), &mut user)
+        .unwrap();
+    history.push(first.turn);
+
+    let mut user = ScriptedUser::default();
+    let second =
+        process_prompt_with_context("what did that do?", &settings, &Theme::plain(), &history, None, &attended(), &mut user)
+            .unwrap();
+    assert!(user.ran.is_empty());
+    assert!(second.turn.commands.is_empty());
+
+    let first_request = model.messages(0);
+    assert!(!first_request.iter().any(|m| m.contains("output of ls")));
+    // The earlier turn comes first, its output framed as data, then the new prompt
+    let sent = model.messages(1).join("\n");
+    let earlier = sent.find("list files").expect("earlier prompt sent");
+    let output = sent.find("<command-output>\n$ ls\noutput of ls").expect("earlier output sent");
+    let current = sent.find("what did that do?").expect("new prompt sent");
+    assert!(earlier < output && output < current);
+}
+
+#[test]
+fn a_failed_command_asks_before_running_the_rest() {
+    for keep_going in [true, false] {
+        let model = ScriptedModel::new(&["make\nmake install"]);
+        let mut user = ScriptedUser::new(vec![ConfirmResponse::Yes, ConfirmResponse::Yes]);
+        user.failing = vec!["make".to_string()];
+        user.keep_going = keep_going;
+        let result =
+            process_prompt_with_context("build it", &settings(&model), &Theme::plain(), &[], None, &attended(), &mut user)
+                .unwrap();
+
+        assert_eq!(user.asked[..2], ["make", "keep going after make?"]);
+        let turn = result.turn;
+        if keep_going {
+            assert_eq!(user.ran, ["make", "make install"]);
+            assert_eq!(turn.exit_codes, [Some(1), Some(0)]);
+        } else {
+            assert_eq!(user.ran, ["make"]);
+            assert_eq!(turn.exit_codes, [Some(1)]);
+        }
+        assert_eq!(turn.outputs[0], "output of make\n");
+    }
+}
+
+#[test]
+fn under_yes_a_failed_command_stops_the_rest_without_asking() {
+    let model = ScriptedModel::new(&["make\nmake install"]);
+    let mut user = ScriptedUser::new(vec![]);
+    user.failing = vec!["make".to_string()];
+    user.keep_going = true;
+    let policy = SafetyPolicy { auto_yes: true, ..attended() };
+    let result =
+        process_prompt_with_context("build it", &settings(&model), &Theme::plain(), &[], None, &policy, &mut user).unwrap();
+
+    assert!(user.asked.is_empty(), "{:?}", user.asked);
+    assert_eq!(user.ran, ["make"]);
+    assert_eq!(result.turn.exit_codes, [Some(1)]);
+}
+
+// --- looking around with tools ---
+
+/// A reply that only asks to run `command` with run_readonly_command.
+fn tool_call(id: &str, command: &str) -> serde_json::Value {
+    let arguments = serde_json::json!({ "command": command }).to_string();
+    serde_json::json!({
+        "content": null,
+        "tool_calls": [{ "id": id, "type": "function", "function": { "name": "run_readonly_command", "arguments": arguments } }]
+    })
+}
+
+#[test]
+fn the_model_can_look_around_with_read_only_commands_before_answering() {
+    let model = ScriptedModel::with_messages(vec![
+        tool_call("call_1", "head -1 Cargo.toml"),
+        tool_call("call_2", "rm -rf ~/Downloads"),
+        serde_json::json!({ "content": "# Downloads is the big one\ndu -sh ~/Downloads" }),
+    ]);
+    let mut user = ScriptedUser::new(vec![ConfirmResponse::Skip]);
+    let result =
+        process_prompt_with_context("why is my disk full?", &settings(&model), &Theme::plain(), &[], None, &attended(), &mut user)
+            .unwrap();
+
+    let requests = model.requests.lock().unwrap().clone();
+    assert_eq!(requests.len(), 3);
+    assert_eq!(requests[0]["tools"][0]["function"]["name"], "run_readonly_command");
+    // Each call goes back with its result, tied to the call's id
+    let looked = &requests[1]["messages"].as_array().unwrap()[..];
+    let [.., call, output] = looked else { panic!("{looked:?}") };
+    assert_eq!(call["tool_calls"][0]["id"], "call_1");
+    assert_eq!(output["role"], "tool");
+    assert_eq!(output["tool_call_id"], "call_1");
+    assert!(output["content"].as_str().unwrap().contains("$ head -1 Cargo.toml\n[package]"));
+    // Anything that isn't read-only is refused, never run
+    let refused = requests[2]["messages"].as_array().unwrap().last().unwrap().clone();
+    assert!(refused["content"].as_str().unwrap().starts_with("Not run:"), "{refused}");
+
+    // Only the answer goes through the confirm loop and into the turn
+    assert_eq!(user.asked, ["du -sh ~/Downloads"]);
+    assert!(user.ran.is_empty());
+    assert_eq!(result.turn.request.unwrap().response, ["# Downloads is the big one", "du -sh ~/Downloads"]);
+}
+
+#[test]
+fn after_the_last_round_of_tools_the_model_has_to_answer() {
+    let mut replies: Vec<serde_json::Value> = (0..5).map(|i| tool_call(&format!("call_{i}"), "pwd")).collect();
+    replies.push(serde_json::json!({ "content": "# Nothing to run" }));
+    let model = ScriptedModel::with_messages(replies);
+    let mut user = ScriptedUser::default();
+    process_prompt_with_context("where am I?", &settings(&model), &Theme::plain(), &[], None, &attended(), &mut user).unwrap();
+
+    let requests = model.requests.lock().unwrap();
+    assert_eq!(requests.len(), 6);
+    assert!(requests[4].get("tool_choice").is_none());
+    assert_eq!(requests[5]["tool_choice"], "none");
+}